@@ -0,0 +1,67 @@
+//! Standard base64 (RFC 4648) with padding, shared by the SCTE-35 and CENC
+//! modules.
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let value = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |value, (index, &byte)| {
+                value | u32::from(byte) << (16 - index * 8)
+            });
+        for index in 0..4 {
+            if index <= chunk.len() {
+                out.push(char::from(
+                    BASE64_ALPHABET[(value >> (18 - index * 6)) as usize & 0x3f],
+                ));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub(crate) fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim().trim_end_matches('=');
+    let mut out = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut value = 0_u32;
+    let mut bits = 0;
+    for byte in encoded.bytes() {
+        let digit = BASE64_ALPHABET.iter().position(|&symbol| symbol == byte)? as u32;
+        value = (value << 6) | digit;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((value >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded).as_deref(), Some(data.as_bytes()));
+        }
+        assert_eq!(base64_decode("Zm9v!"), None);
+    }
+}
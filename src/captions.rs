@@ -0,0 +1,822 @@
+use crate::webvtt::WebVttCue;
+use access_unit::AccessUnit;
+
+const NAL_UNIT_TYPE_SEI: u8 = 6;
+const SEI_USER_DATA_REGISTERED_ITU_T_T35: u32 = 4;
+const ITU_T_T35_COUNTRY_CODE_USA: u8 = 0xb5;
+const ITU_T_T35_PROVIDER_CODE_ATSC: u16 = 0x0031;
+const ATSC_USER_IDENTIFIER: &[u8; 4] = b"GA94";
+const ATSC_USER_DATA_TYPE_CC_DATA: u8 = 0x03;
+
+const CC_TYPE_608_FIELD_1: u8 = 0;
+const CC_TYPE_608_FIELD_2: u8 = 1;
+const CC_TYPE_DTVCC_DATA: u8 = 2;
+const CC_TYPE_DTVCC_START: u8 = 3;
+
+/// One `cc_data` construct from an ATSC A/53 caption payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CcTriplet {
+    pub cc_valid: bool,
+    /// 0 and 1 are CEA-608 field 1 and 2; 2 and 3 carry CEA-708 DTVCC data.
+    pub cc_type: u8,
+    pub data: [u8; 2],
+}
+
+/// Caption data carried by one video access unit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptionFrame {
+    /// Presentation time of the access unit, in its own timebase.
+    pub pts: u64,
+    pub triplets: Vec<CcTriplet>,
+}
+
+impl CaptionFrame {
+    /// Valid CEA-608 byte pairs for `field` 1 or 2, excluding null padding.
+    pub fn cea608_pairs(&self, field: u8) -> impl Iterator<Item = [u8; 2]> + '_ {
+        let cc_type = if field == 1 {
+            CC_TYPE_608_FIELD_1
+        } else {
+            CC_TYPE_608_FIELD_2
+        };
+        self.triplets
+            .iter()
+            .filter(move |triplet| triplet.cc_valid && triplet.cc_type == cc_type)
+            .map(|triplet| triplet.data)
+            .filter(|data| data[0] & 0x7f != 0 || data[1] & 0x7f != 0)
+    }
+}
+
+/// Collect the ATSC A/53 `cc_data` triplets from the
+/// `user_data_registered_itu_t_t35` SEI messages of an Annex B H.264 access
+/// unit, such as those produced by `rtmp::extract_video_access_unit`.
+pub fn extract_cc_data(access_unit: &AccessUnit) -> Option<CaptionFrame> {
+    let mut triplets = Vec::new();
+    for nal in annex_b_nal_units(&access_unit.data) {
+        if nal.first().map(|header| header & 0x1f) != Some(NAL_UNIT_TYPE_SEI) {
+            continue;
+        }
+        let rbsp = remove_emulation_prevention(&nal[1..]);
+        let mut offset = 0;
+        // Stop before the rbsp_trailing_bits byte.
+        while offset + 1 < rbsp.len() {
+            let payload_type = read_sei_value(&rbsp, &mut offset)?;
+            let payload_size = read_sei_value(&rbsp, &mut offset)? as usize;
+            let end = offset.checked_add(payload_size)?;
+            let payload = rbsp.get(offset..end)?;
+            offset = end;
+            if payload_type == SEI_USER_DATA_REGISTERED_ITU_T_T35 {
+                parse_atsc_cc_data(payload, &mut triplets);
+            }
+        }
+    }
+
+    (!triplets.is_empty()).then_some(CaptionFrame {
+        pts: access_unit.pts,
+        triplets,
+    })
+}
+
+fn annex_b_nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index..index + 3] == [0, 0, 1] {
+            starts.push(index + 3);
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+    let ends: Vec<usize> = starts
+        .iter()
+        .skip(1)
+        .map(|&start| {
+            // Trailing zero bytes belong to the next start code.
+            let mut end = start - 3;
+            while end > 0 && data[end - 1] == 0 {
+                end -= 1;
+            }
+            end
+        })
+        .chain(std::iter::once(data.len()))
+        .collect();
+    starts
+        .into_iter()
+        .zip(ends)
+        .filter_map(move |(start, end)| data.get(start..end))
+        .filter(|nal| !nal.is_empty())
+}
+
+fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeroes = 0;
+    for &byte in data {
+        if zeroes >= 2 && byte == 0x03 {
+            zeroes = 0;
+            continue;
+        }
+        zeroes = if byte == 0 { zeroes + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+fn read_sei_value(data: &[u8], offset: &mut usize) -> Option<u32> {
+    let mut value = 0_u32;
+    loop {
+        let byte = *data.get(*offset)?;
+        *offset += 1;
+        value = value.checked_add(u32::from(byte))?;
+        if byte != 0xff {
+            return Some(value);
+        }
+    }
+}
+
+fn parse_atsc_cc_data(payload: &[u8], triplets: &mut Vec<CcTriplet>) {
+    let Some(header) = payload.get(..8) else {
+        return;
+    };
+    if header[0] != ITU_T_T35_COUNTRY_CODE_USA
+        || u16::from_be_bytes([header[1], header[2]]) != ITU_T_T35_PROVIDER_CODE_ATSC
+        || &header[3..7] != ATSC_USER_IDENTIFIER
+        || header[7] != ATSC_USER_DATA_TYPE_CC_DATA
+    {
+        return;
+    }
+    let Some(&flags) = payload.get(8) else {
+        return;
+    };
+    let process_cc_data = flags & 0x40 != 0;
+    if !process_cc_data {
+        return;
+    }
+    let cc_count = usize::from(flags & 0x1f);
+    // Skip the flags byte and em_data.
+    let Some(constructs) = payload.get(10..10 + cc_count * 3) else {
+        return;
+    };
+    for construct in constructs.chunks_exact(3) {
+        triplets.push(CcTriplet {
+            cc_valid: construct[0] & 0x04 != 0,
+            cc_type: construct[0] & 0x03,
+            data: [construct[1], construct[2]],
+        });
+    }
+}
+
+/// Which caption stream a decoded cue came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum CaptionService {
+    /// CEA-608 data channel CC1 to CC4.
+    Cea608(u8),
+    /// CEA-708 service number 1 to 63.
+    Cea708(u8),
+}
+
+/// Caption text shown from `start` until `end`, in access-unit pts units.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptionCue {
+    pub service: CaptionService,
+    pub start: u64,
+    pub end: u64,
+    pub text: String,
+}
+
+impl CaptionCue {
+    /// Convert to a WebVTT cue for a subtitle track. Pts values are used as
+    /// cue times unchanged, so they should already be in milliseconds.
+    pub fn to_webvtt_cue(&self) -> WebVttCue {
+        WebVttCue {
+            start: self.start,
+            end: self.end,
+            id: None,
+            settings: None,
+            payload: self.text.clone(),
+        }
+    }
+}
+
+/// Text-oriented CEA-608 and CEA-708 decoder.
+///
+/// Positioning, pen attributes and window styles are not modelled; the decoder
+/// tracks which text is on screen for each service and emits a cue each time
+/// that text changes.
+#[derive(Debug, Default)]
+pub struct CaptionDecoder {
+    fields: [Cea608Field; 2],
+    dtvcc_packet: Vec<u8>,
+    services: Vec<Cea708Service>,
+    cues: Vec<CaptionCue>,
+}
+
+impl CaptionDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_access_unit(&mut self, access_unit: &AccessUnit) {
+        if let Some(frame) = extract_cc_data(access_unit) {
+            self.push_frame(&frame);
+        }
+    }
+
+    pub fn push_frame(&mut self, frame: &CaptionFrame) {
+        for triplet in &frame.triplets {
+            match triplet.cc_type {
+                CC_TYPE_608_FIELD_1 | CC_TYPE_608_FIELD_2 if triplet.cc_valid => {
+                    let field = usize::from(triplet.cc_type);
+                    self.fields[field].push_pair(triplet.data, frame.pts, field, &mut self.cues);
+                }
+                CC_TYPE_DTVCC_START => {
+                    self.flush_dtvcc_packet(frame.pts);
+                    if triplet.cc_valid {
+                        self.dtvcc_packet.extend_from_slice(&triplet.data);
+                    }
+                }
+                CC_TYPE_DTVCC_DATA if triplet.cc_valid && !self.dtvcc_packet.is_empty() => {
+                    self.dtvcc_packet.extend_from_slice(&triplet.data);
+                }
+                _ => {}
+            }
+            if self.dtvcc_packet_complete() {
+                self.flush_dtvcc_packet(frame.pts);
+            }
+        }
+    }
+
+    /// Close any cue still on screen at `pts`.
+    pub fn finish(&mut self, pts: u64) {
+        self.flush_dtvcc_packet(pts);
+        for (field_index, field) in self.fields.iter_mut().enumerate() {
+            for (channel_index, channel) in field.channels.iter_mut().enumerate() {
+                let service = cea608_service(field_index, channel_index);
+                channel
+                    .display
+                    .show(service, pts, String::new(), &mut self.cues);
+            }
+        }
+        for service in &mut self.services {
+            service.display.show(
+                CaptionService::Cea708(service.number),
+                pts,
+                String::new(),
+                &mut self.cues,
+            );
+        }
+    }
+
+    /// Cues completed so far, in the order they ended.
+    pub fn take_cues(&mut self) -> Vec<CaptionCue> {
+        std::mem::take(&mut self.cues)
+    }
+
+    fn dtvcc_packet_complete(&self) -> bool {
+        self.dtvcc_packet
+            .first()
+            .is_some_and(|&header| self.dtvcc_packet.len() >= dtvcc_packet_size(header))
+    }
+
+    fn flush_dtvcc_packet(&mut self, pts: u64) {
+        let packet = std::mem::take(&mut self.dtvcc_packet);
+        let Some(&header) = packet.first() else {
+            return;
+        };
+        let end = dtvcc_packet_size(header).min(packet.len());
+        let mut offset = 1;
+        while offset < end {
+            let block_header = packet[offset];
+            offset += 1;
+            let mut service_number = block_header >> 5;
+            let block_size = usize::from(block_header & 0x1f);
+            if service_number == 7 {
+                let Some(&extended) = packet.get(offset) else {
+                    return;
+                };
+                service_number = extended & 0x3f;
+                offset += 1;
+            }
+            if service_number == 0 || block_size == 0 {
+                // Null service block header: the rest is padding.
+                return;
+            }
+            let block_end = (offset + block_size).min(end);
+            let block = &packet[offset..block_end];
+            offset = block_end;
+            let index = match self
+                .services
+                .iter()
+                .position(|service| service.number == service_number)
+            {
+                Some(index) => index,
+                None => {
+                    self.services.push(Cea708Service::new(service_number));
+                    self.services.len() - 1
+                }
+            };
+            self.services[index].decode_block(block, pts, &mut self.cues);
+        }
+    }
+}
+
+fn dtvcc_packet_size(header: u8) -> usize {
+    match header & 0x3f {
+        0 => 128,
+        code => usize::from(code) * 2,
+    }
+}
+
+fn cea608_service(field_index: usize, channel_index: usize) -> CaptionService {
+    CaptionService::Cea608((field_index * 2 + channel_index + 1) as u8)
+}
+
+/// Tracks which text is visible and turns changes into cues.
+#[derive(Debug, Default)]
+struct Display {
+    showing: Option<(u64, String)>,
+}
+
+impl Display {
+    fn show(
+        &mut self,
+        service: CaptionService,
+        pts: u64,
+        text: String,
+        cues: &mut Vec<CaptionCue>,
+    ) {
+        if self.showing.as_ref().map(|(_, current)| current.as_str()) == Some(text.as_str()) {
+            return;
+        }
+        if let Some((start, text)) = self.showing.take() {
+            if pts > start {
+                cues.push(CaptionCue {
+                    service,
+                    start,
+                    end: pts,
+                    text,
+                });
+            }
+        }
+        if !text.is_empty() {
+            self.showing = Some((pts, text));
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Cea608Mode {
+    #[default]
+    PopOn,
+    RollUp(usize),
+    PaintOn,
+}
+
+#[derive(Debug, Default)]
+struct Cea608Channel {
+    mode: Cea608Mode,
+    displayed: Vec<String>,
+    non_displayed: Vec<String>,
+    display: Display,
+}
+
+impl Cea608Channel {
+    fn target(&mut self) -> &mut Vec<String> {
+        match self.mode {
+            Cea608Mode::PopOn => &mut self.non_displayed,
+            Cea608Mode::RollUp(_) | Cea608Mode::PaintOn => &mut self.displayed,
+        }
+    }
+
+    fn push_char(&mut self, character: char) {
+        let rows = self.target();
+        if rows.is_empty() {
+            rows.push(String::new());
+        }
+        if let Some(row) = rows.last_mut() {
+            row.push(character);
+        }
+    }
+
+    fn backspace(&mut self) {
+        if let Some(row) = self.target().last_mut() {
+            row.pop();
+        }
+    }
+
+    fn new_row(&mut self) {
+        let rows = self.target();
+        if rows.last().is_some_and(|row| !row.trim().is_empty()) {
+            rows.push(String::new());
+        }
+    }
+
+    fn update(&mut self, service: CaptionService, pts: u64, cues: &mut Vec<CaptionCue>) {
+        let text = rows_text(&self.displayed);
+        self.display.show(service, pts, text, cues);
+    }
+}
+
+fn rows_text(rows: &[String]) -> String {
+    rows.iter()
+        .map(|row| row.trim())
+        .filter(|row| !row.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, Default)]
+struct Cea608Field {
+    active_channel: usize,
+    last_control: Option<[u8; 2]>,
+    channels: [Cea608Channel; 2],
+}
+
+impl Cea608Field {
+    fn push_pair(
+        &mut self,
+        pair: [u8; 2],
+        pts: u64,
+        field_index: usize,
+        cues: &mut Vec<CaptionCue>,
+    ) {
+        let (first, second) = (pair[0] & 0x7f, pair[1] & 0x7f);
+        if (0x10..=0x1f).contains(&first) {
+            // Control codes are sent twice for robustness; act on the first.
+            if self.last_control == Some([first, second]) {
+                self.last_control = None;
+                return;
+            }
+            self.last_control = Some([first, second]);
+            self.active_channel = usize::from(first & 0x08 != 0);
+            let service = cea608_service(field_index, self.active_channel);
+            self.control(first & 0xf7, second, pts, service, cues);
+            return;
+        }
+
+        self.last_control = None;
+        let channel = &mut self.channels[self.active_channel];
+        for byte in [first, second] {
+            if byte >= 0x20 {
+                channel.push_char(cea608_basic_char(byte));
+            }
+        }
+    }
+
+    fn control(
+        &mut self,
+        first: u8,
+        second: u8,
+        pts: u64,
+        service: CaptionService,
+        cues: &mut Vec<CaptionCue>,
+    ) {
+        let channel = &mut self.channels[self.active_channel];
+        match (first, second) {
+            // Miscellaneous control codes; field 2 uses 0x15 in place of 0x14.
+            (0x14 | 0x15, 0x20) => channel.mode = Cea608Mode::PopOn,
+            (0x14 | 0x15, 0x21) => channel.backspace(),
+            (0x14 | 0x15, 0x25..=0x27) => {
+                let rows = usize::from(second - 0x23);
+                if channel.mode == Cea608Mode::PopOn {
+                    channel.displayed.clear();
+                }
+                channel.mode = Cea608Mode::RollUp(rows);
+                channel.update(service, pts, cues);
+            }
+            (0x14 | 0x15, 0x29) => channel.mode = Cea608Mode::PaintOn,
+            (0x14 | 0x15, 0x2c) => {
+                channel.displayed.clear();
+                channel.update(service, pts, cues);
+            }
+            (0x14 | 0x15, 0x2d) => {
+                if let Cea608Mode::RollUp(rows) = channel.mode {
+                    channel.update(service, pts, cues);
+                    channel.displayed.push(String::new());
+                    let excess = channel.displayed.len().saturating_sub(rows);
+                    channel.displayed.drain(..excess);
+                } else {
+                    channel.new_row();
+                }
+            }
+            (0x14 | 0x15, 0x2e) => channel.non_displayed.clear(),
+            (0x14 | 0x15, 0x2f) => {
+                std::mem::swap(&mut channel.displayed, &mut channel.non_displayed);
+                channel.update(service, pts, cues);
+            }
+            (0x17, 0x21..=0x23) => {
+                for _ in 0..(second - 0x20) {
+                    channel.push_char(' ');
+                }
+            }
+            // Mid-row style changes display as a space.
+            (0x11, 0x20..=0x2f) => channel.push_char(' '),
+            (0x11, 0x30..=0x3f) => {
+                channel.push_char(CEA608_SPECIAL_CHARS[usize::from(second - 0x30)]);
+            }
+            // Extended characters replace the standard fallback sent before them.
+            (0x12, 0x20..=0x3f) => {
+                channel.backspace();
+                channel.push_char(CEA608_EXTENDED_CHARS_12[usize::from(second - 0x20)]);
+            }
+            (0x13, 0x20..=0x3f) => {
+                channel.backspace();
+                channel.push_char(CEA608_EXTENDED_CHARS_13[usize::from(second - 0x20)]);
+            }
+            // Preamble address codes move the cursor to a new row.
+            (0x10..=0x17, 0x40..=0x7f) => {
+                channel.new_row();
+                if channel.mode == Cea608Mode::PaintOn {
+                    channel.update(service, pts, cues);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn cea608_basic_char(byte: u8) -> char {
+    match byte {
+        0x2a => 'á',
+        0x5c => 'é',
+        0x5e => 'í',
+        0x5f => 'ó',
+        0x60 => 'ú',
+        0x7b => 'ç',
+        0x7c => '÷',
+        0x7d => 'Ñ',
+        0x7e => 'ñ',
+        0x7f => '█',
+        _ => char::from(byte),
+    }
+}
+
+const CEA608_SPECIAL_CHARS: [char; 16] = [
+    '®', '°', '½', '¿', '™', '¢', '£', '♪', 'à', ' ', 'è', 'â', 'ê', 'î', 'ô', 'û',
+];
+
+const CEA608_EXTENDED_CHARS_12: [char; 32] = [
+    'Á', 'É', 'Ó', 'Ú', 'Ü', 'ü', '‘', '¡', '*', '\'', '—', '©', '℠', '•', '“', '”', 'À', 'Â', 'Ç',
+    'È', 'Ê', 'Ë', 'ë', 'Î', 'Ï', 'ï', 'Ô', 'Ù', 'ù', 'Û', '«', '»',
+];
+
+const CEA608_EXTENDED_CHARS_13: [char; 32] = [
+    'Ã', 'ã', 'Í', 'Ì', 'ì', 'Ò', 'ò', 'Õ', 'õ', '{', '}', '\\', '^', '_', '|', '~', 'Ä', 'ä', 'Ö',
+    'ö', 'ß', '¥', '¤', '│', 'Å', 'å', 'Ø', 'ø', '┌', '┐', '└', '┘',
+];
+
+#[derive(Debug)]
+struct Cea708Service {
+    number: u8,
+    rows: Vec<String>,
+    visible: bool,
+    display: Display,
+}
+
+impl Cea708Service {
+    fn new(number: u8) -> Self {
+        Self {
+            number,
+            rows: Vec::new(),
+            visible: true,
+            display: Display::default(),
+        }
+    }
+
+    fn decode_block(&mut self, block: &[u8], pts: u64, cues: &mut Vec<CaptionCue>) {
+        let service = CaptionService::Cea708(self.number);
+        let mut offset = 0;
+        while let Some(&code) = block.get(offset) {
+            offset += 1;
+            match code {
+                // ETX ends a run of text.
+                0x03 => self.update(service, pts, cues),
+                0x08 => {
+                    if let Some(row) = self.rows.last_mut() {
+                        row.pop();
+                    }
+                }
+                // FF clears the window.
+                0x0c => {
+                    self.rows.clear();
+                    self.update(service, pts, cues);
+                }
+                0x0d => {
+                    self.update(service, pts, cues);
+                    self.rows.push(String::new());
+                }
+                0x0e => {
+                    if let Some(row) = self.rows.last_mut() {
+                        row.clear();
+                    }
+                }
+                // EXT1 selects the extended code space; skip its code and
+                // any parameters as text-only decoding does not use them.
+                0x10 => offset += cea708_ext1_length(block.get(offset).copied()),
+                0x11..=0x17 => offset += 1,
+                0x18..=0x1f => offset += 2,
+                0x20..=0x7e => self.push_char(char::from(code)),
+                0x7f => self.push_char('♪'),
+                // DSW, TGW: show the window.
+                0x89 | 0x8b => {
+                    offset += 1;
+                    self.visible = true;
+                    self.update(service, pts, cues);
+                }
+                // HDW: hide it.
+                0x8a => {
+                    offset += 1;
+                    self.visible = false;
+                    self.update(service, pts, cues);
+                }
+                // CLW, DLW: clear or delete the window.
+                0x88 | 0x8c => {
+                    offset += 1;
+                    self.rows.clear();
+                    self.update(service, pts, cues);
+                }
+                0x8d => offset += 1,
+                // RST and DLC reset the service.
+                0x8e | 0x8f => {
+                    self.rows.clear();
+                    self.update(service, pts, cues);
+                }
+                0x90 | 0x92 => offset += 2,
+                0x91 => offset += 3,
+                0x97 => offset += 4,
+                0x98..=0x9f => offset += 6,
+                0xa0..=0xff => self.push_char(char::from(code)),
+                _ => {}
+            }
+        }
+    }
+
+    fn push_char(&mut self, character: char) {
+        if self.rows.is_empty() {
+            self.rows.push(String::new());
+        }
+        if let Some(row) = self.rows.last_mut() {
+            row.push(character);
+        }
+    }
+
+    fn update(&mut self, service: CaptionService, pts: u64, cues: &mut Vec<CaptionCue>) {
+        let text = if self.visible {
+            rows_text(&self.rows)
+        } else {
+            String::new()
+        };
+        self.display.show(service, pts, text, cues);
+    }
+}
+
+fn cea708_ext1_length(code: Option<u8>) -> usize {
+    match code {
+        Some(0x00..=0x07) => 1,
+        Some(0x08..=0x0f) => 2,
+        Some(0x10..=0x17) => 3,
+        Some(0x18..=0x1f) => 4,
+        Some(0x80..=0x87) => 5,
+        Some(0x88..=0x8f) => 6,
+        Some(_) => 1,
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use access_unit::PSI_STREAM_H264;
+    use bytes::Bytes;
+
+    fn odd_parity(byte: u8) -> u8 {
+        if byte.count_ones().is_multiple_of(2) {
+            byte | 0x80
+        } else {
+            byte
+        }
+    }
+
+    fn field1(first: u8, second: u8) -> CcTriplet {
+        CcTriplet {
+            cc_valid: true,
+            cc_type: CC_TYPE_608_FIELD_1,
+            data: [odd_parity(first), odd_parity(second)],
+        }
+    }
+
+    fn sei_access_unit(pts: u64, triplets: &[CcTriplet]) -> AccessUnit {
+        let mut payload = vec![0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03];
+        payload.push(0x40 | triplets.len() as u8);
+        payload.push(0xff);
+        for triplet in triplets {
+            payload.push(0xf8 | (u8::from(triplet.cc_valid) << 2) | triplet.cc_type);
+            payload.extend_from_slice(&triplet.data);
+        }
+        payload.push(0xff);
+
+        let mut data = vec![0, 0, 0, 1, 0x06, 0x04, payload.len() as u8];
+        data.extend_from_slice(&payload);
+        data.push(0x80);
+        data.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x88]);
+        AccessUnit {
+            key: true,
+            pts,
+            dts: pts,
+            data: Bytes::from(data),
+            stream_type: PSI_STREAM_H264,
+            id: 0,
+        }
+    }
+
+    #[test]
+    fn extracts_cc_data_from_sei_and_ignores_slices() {
+        let triplets = [field1(0x14, 0x20), field1(b'H', b'I')];
+        let frame = extract_cc_data(&sei_access_unit(1_000, &triplets)).expect("captions");
+
+        assert_eq!(frame.pts, 1_000);
+        assert_eq!(frame.triplets, triplets);
+        assert_eq!(frame.cea608_pairs(1).count(), 2);
+        assert_eq!(frame.cea608_pairs(2).count(), 0);
+    }
+
+    #[test]
+    fn emulation_prevention_bytes_are_removed() {
+        assert_eq!(
+            remove_emulation_prevention(&[0, 0, 3, 1, 0, 0, 3, 0]),
+            vec![0, 0, 1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn decodes_cea608_pop_on_caption_into_a_cue() {
+        let mut decoder = CaptionDecoder::new();
+        let frames = [
+            (0, vec![field1(0x14, 0x20), field1(0x14, 0x20)]),
+            (33, vec![field1(b'H', b'I'), field1(0x14, 0x2d)]),
+            (66, vec![field1(b'T', b'H'), field1(b'E', b'R')]),
+            (100, vec![field1(b'E', 0x80), field1(0x14, 0x2f)]),
+            (2_000, vec![field1(0x14, 0x2c)]),
+        ];
+        for (pts, triplets) in frames {
+            decoder.push_access_unit(&sei_access_unit(pts, &triplets));
+        }
+
+        assert_eq!(
+            decoder.take_cues(),
+            vec![CaptionCue {
+                service: CaptionService::Cea608(1),
+                start: 100,
+                end: 2_000,
+                text: "HI\nTHERE".to_owned(),
+            }]
+        );
+    }
+
+    fn dtvcc_triplets(sequence: u8, blocks: &[u8]) -> Vec<CcTriplet> {
+        let mut packet = vec![0];
+        packet.extend_from_slice(blocks);
+        if packet.len() % 2 == 1 {
+            packet.push(0);
+        }
+        packet[0] = (sequence << 6) | (packet.len() / 2) as u8;
+        packet
+            .chunks(2)
+            .enumerate()
+            .map(|(index, pair)| CcTriplet {
+                cc_valid: true,
+                cc_type: if index == 0 {
+                    CC_TYPE_DTVCC_START
+                } else {
+                    CC_TYPE_DTVCC_DATA
+                },
+                data: [pair[0], pair[1]],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_cea708_service_text_until_window_is_cleared() {
+        let mut decoder = CaptionDecoder::new();
+        decoder.push_frame(&CaptionFrame {
+            pts: 500,
+            // Service 1: DSW, "Hey", ETX.
+            triplets: dtvcc_triplets(0, &[(1 << 5) | 6, 0x89, 0x01, b'H', b'e', b'y', 0x03]),
+        });
+        decoder.push_frame(&CaptionFrame {
+            pts: 1_500,
+            // Service 1: CLW.
+            triplets: dtvcc_triplets(1, &[(1 << 5) | 2, 0x88, 0x01]),
+        });
+        decoder.finish(2_000);
+
+        assert_eq!(
+            decoder.take_cues(),
+            vec![CaptionCue {
+                service: CaptionService::Cea708(1),
+                start: 500,
+                end: 1_500,
+                text: "Hey".to_owned(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,775 @@
+use crate::base64::base64_encode;
+use crate::mp4::FragmentSample;
+pub use crate::mp4::{ProtectionSystemHeader, SampleEncryptionEntry, Subsample, TrackEncryption};
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncryptMut, KeyIvInit, StreamCipher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// `cenc` counter blocks are the 8-byte IV followed by a 64-bit block count.
+type Aes128Ctr = ctr::Ctr64BE<aes::Aes128>;
+type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+
+/// W3C Common PSSH box format, also used for Clear Key.
+pub const COMMON_SYSTEM_ID: [u8; 16] = [
+    0x10, 0x77, 0xef, 0xec, 0xc0, 0xb2, 0x4d, 0x02, 0xac, 0xe3, 0x3c, 0x1e, 0x52, 0xe2, 0xfb, 0x4b,
+];
+pub const WIDEVINE_SYSTEM_ID: [u8; 16] = [
+    0xed, 0xef, 0x8b, 0xa9, 0x79, 0xd6, 0x4a, 0xce, 0xa3, 0xc8, 0x27, 0xdc, 0xd5, 0x1d, 0x21, 0xed,
+];
+pub const PLAYREADY_SYSTEM_ID: [u8; 16] = [
+    0x9a, 0x04, 0xf0, 0x79, 0x98, 0x40, 0x42, 0x86, 0xab, 0x92, 0xe6, 0x5b, 0xe0, 0x88, 0x5f, 0x95,
+];
+pub const FAIRPLAY_SYSTEM_ID: [u8; 16] = [
+    0x94, 0xce, 0x86, 0xfb, 0x07, 0xff, 0x4f, 0x43, 0xad, 0xb8, 0x93, 0xd2, 0xfa, 0x96, 0x8c, 0xa2,
+];
+
+/// Common Encryption scheme from ISO/IEC 23001-7.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionScheme {
+    /// AES-CTR over every protected byte, with 8-byte per-sample IVs.
+    Cenc,
+    /// AES-CBC with a constant IV. Video uses a 1:9 block pattern; audio
+    /// encrypts every whole block.
+    Cbcs,
+}
+
+impl EncryptionScheme {
+    pub fn scheme_type(self) -> [u8; 4] {
+        match self {
+            EncryptionScheme::Cenc => *b"cenc",
+            EncryptionScheme::Cbcs => *b"cbcs",
+        }
+    }
+}
+
+/// Key and IV used to protect the video and audio tracks of one output.
+///
+/// Clones share the `cenc` sample counter, so every fragment of an output
+/// should use clones of one value. Build a new one with [`Encryption::new`]
+/// for each output: renditions or a restarted encode under the same key must
+/// not share an IV base, or their keystreams repeat.
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    pub scheme: EncryptionScheme,
+    pub key_id: [u8; 16],
+    pub key: [u8; 16],
+    /// For `cenc` the upper 8 bytes are the base of the per-sample IVs; for
+    /// `cbcs` this is the constant IV of every sample.
+    pub iv: [u8; 16],
+    /// `pssh` boxes written to the init segment's `moov`.
+    pub protection_systems: Vec<ProtectionSystemHeader>,
+    /// `cenc` IVs handed out so far, added to the IV base.
+    samples: Arc<AtomicU64>,
+}
+
+impl Encryption {
+    /// Protection under `key` with a random IV. Returns `None` when the
+    /// system has no randomness to offer.
+    pub fn new(
+        scheme: EncryptionScheme,
+        key_id: [u8; 16],
+        key: [u8; 16],
+        protection_systems: Vec<ProtectionSystemHeader>,
+    ) -> Option<Self> {
+        let mut iv = [0; 16];
+        getrandom::getrandom(&mut iv).ok()?;
+        Some(Self {
+            scheme,
+            key_id,
+            key,
+            iv,
+            protection_systems,
+            samples: Arc::default(),
+        })
+    }
+
+    /// `tenc` defaults for a video or audio track.
+    pub fn track_encryption(&self, video: bool) -> TrackEncryption {
+        let (crypt_byte_block, skip_byte_block) = self.pattern(video);
+        match self.scheme {
+            EncryptionScheme::Cenc => TrackEncryption {
+                scheme_type: self.scheme.scheme_type(),
+                key_id: self.key_id,
+                per_sample_iv_size: 8,
+                constant_iv: None,
+                crypt_byte_block,
+                skip_byte_block,
+            },
+            EncryptionScheme::Cbcs => TrackEncryption {
+                scheme_type: self.scheme.scheme_type(),
+                key_id: self.key_id,
+                per_sample_iv_size: 0,
+                constant_iv: Some(self.iv),
+                crypt_byte_block,
+                skip_byte_block,
+            },
+        }
+    }
+
+    fn pattern(&self, video: bool) -> (u8, u8) {
+        match self.scheme {
+            EncryptionScheme::Cbcs if video => (1, 9),
+            EncryptionScheme::Cenc | EncryptionScheme::Cbcs => (0, 0),
+        }
+    }
+
+    /// The `senc` IV of the next sample. `cenc` IVs are the IV base plus a
+    /// count of the samples before, so no two samples of an output share
+    /// one; `cbcs` samples carry no IV.
+    pub fn next_sample_iv(&self) -> Vec<u8> {
+        match self.scheme {
+            EncryptionScheme::Cenc => {
+                let base = u64::from_be_bytes(self.iv[..8].try_into().unwrap_or_default());
+                let count = self.samples.fetch_add(1, Ordering::Relaxed);
+                base.wrapping_add(count).to_be_bytes().to_vec()
+            }
+            EncryptionScheme::Cbcs => Vec::new(),
+        }
+    }
+
+    /// Encrypt `sample` in place. `iv` is the sample's `senc` IV, or empty
+    /// for the constant IV; without subsamples the whole sample is protected.
+    pub fn encrypt_sample(
+        &self,
+        video: bool,
+        iv: &[u8],
+        sample: &mut [u8],
+        subsamples: &[Subsample],
+    ) -> Option<()> {
+        let mut block_iv = [0; 16];
+        if iv.is_empty() {
+            block_iv = self.iv;
+        } else {
+            block_iv.get_mut(..iv.len())?.copy_from_slice(iv);
+        }
+        let whole = [Subsample {
+            clear_bytes: 0,
+            protected_bytes: u32::try_from(sample.len()).ok()?,
+        }];
+        let subsamples = if subsamples.is_empty() {
+            &whole[..]
+        } else {
+            subsamples
+        };
+        // The keystream carries on across the protected ranges of a sample.
+        let mut ctr = Aes128Ctr::new(&self.key.into(), &block_iv.into());
+        let mut position = 0_usize;
+        for subsample in subsamples {
+            position = position.checked_add(usize::from(subsample.clear_bytes))?;
+            let end = position.checked_add(usize::try_from(subsample.protected_bytes).ok()?)?;
+            let protected = sample.get_mut(position..end)?;
+            match self.scheme {
+                EncryptionScheme::Cenc => ctr.apply_keystream(protected),
+                EncryptionScheme::Cbcs => {
+                    let (crypt, skip) = self.pattern(video);
+                    cbc_pattern(&self.key, &block_iv, crypt, skip, protected);
+                }
+            }
+            position = end;
+        }
+        (position <= sample.len()).then_some(())
+    }
+
+    /// Encrypt the samples of one track fragment in place and return their
+    /// `senc` entries. Video samples are length-prefixed AVC NAL units and
+    /// get subsamples from `avc_subsamples`.
+    pub(crate) fn encrypt_track(
+        &self,
+        video: bool,
+        samples: &[FragmentSample],
+        data: &mut [u8],
+    ) -> Option<Vec<SampleEncryptionEntry>> {
+        let mut entries = Vec::with_capacity(samples.len());
+        let mut position = 0_usize;
+        for sample in samples {
+            let end = position.checked_add(usize::try_from(sample.size?).ok()?)?;
+            let sample_data = data.get_mut(position..end)?;
+            let iv = self.next_sample_iv();
+            let subsamples = if video {
+                avc_subsamples(sample_data, self.scheme)
+            } else {
+                Vec::new()
+            };
+            self.encrypt_sample(video, &iv, sample_data, &subsamples)?;
+            entries.push(SampleEncryptionEntry { iv, subsamples });
+            position = end;
+        }
+        Some(entries)
+    }
+}
+
+impl ProtectionSystemHeader {
+    /// Version 1 W3C common `pssh` listing the key ids.
+    pub fn common(key_ids: &[[u8; 16]]) -> Self {
+        Self {
+            system_id: COMMON_SYSTEM_ID,
+            key_ids: key_ids.to_vec(),
+            data: Vec::new(),
+        }
+    }
+
+    /// Version 0 Widevine `pssh`; the key ids travel in the data.
+    pub fn widevine(
+        key_ids: &[[u8; 16]],
+        content_id: Option<&[u8]>,
+        scheme: EncryptionScheme,
+    ) -> Self {
+        Self {
+            system_id: WIDEVINE_SYSTEM_ID,
+            key_ids: Vec::new(),
+            data: widevine_pssh_data(key_ids, content_id, scheme),
+        }
+    }
+
+    /// Version 1 PlayReady `pssh` carrying a PlayReady Header Object.
+    /// `None` when the header is too large for the object's length fields.
+    pub fn playready(
+        key_ids: &[[u8; 16]],
+        license_url: Option<&str>,
+        scheme: EncryptionScheme,
+    ) -> Option<Self> {
+        Some(Self {
+            system_id: PLAYREADY_SYSTEM_ID,
+            key_ids: key_ids.to_vec(),
+            data: playready_header_object(key_ids, license_url, scheme)?,
+        })
+    }
+
+    /// Version 1 FairPlay `pssh`. FairPlay gets its key URI from the
+    /// playlist, so only the key ids are carried.
+    pub fn fairplay(key_ids: &[[u8; 16]]) -> Self {
+        Self {
+            system_id: FAIRPLAY_SYSTEM_ID,
+            key_ids: key_ids.to_vec(),
+            data: Vec::new(),
+        }
+    }
+}
+
+/// The `WidevinePsshData` protobuf message: `key_ids` (field 2),
+/// `content_id` (field 4) and `protection_scheme` (field 9).
+pub fn widevine_pssh_data(
+    key_ids: &[[u8; 16]],
+    content_id: Option<&[u8]>,
+    scheme: EncryptionScheme,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    for key_id in key_ids {
+        write_protobuf_bytes(&mut out, 2, key_id);
+    }
+    if let Some(content_id) = content_id {
+        write_protobuf_bytes(&mut out, 4, content_id);
+    }
+    write_varint(&mut out, 9 << 3);
+    write_varint(
+        &mut out,
+        u64::from(u32::from_be_bytes(scheme.scheme_type())),
+    );
+    out
+}
+
+fn write_protobuf_bytes(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_varint(out, (field << 3) | 2);
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A PlayReady Header Object holding one version 4.3 `WRMHEADER` record.
+/// Key ids are written as little-endian GUIDs, as PlayReady expects.
+/// `None` when the record does not fit its 16-bit length.
+pub fn playready_header_object(
+    key_ids: &[[u8; 16]],
+    license_url: Option<&str>,
+    scheme: EncryptionScheme,
+) -> Option<Vec<u8>> {
+    let algorithm = match scheme {
+        EncryptionScheme::Cenc => "AESCTR",
+        EncryptionScheme::Cbcs => "AESCBC",
+    };
+    let mut header = String::from(
+        "<WRMHEADER xmlns=\"http://schemas.microsoft.com/DRM/2007/03/PlayReadyHeader\" \
+         version=\"4.3.0.0\"><DATA><PROTECTINFO><KIDS>",
+    );
+    for key_id in key_ids {
+        let mut guid = *key_id;
+        guid[..4].reverse();
+        guid[4..6].reverse();
+        guid[6..8].reverse();
+        header.push_str(&format!(
+            "<KID ALGID=\"{algorithm}\" VALUE=\"{}\"></KID>",
+            base64_encode(&guid)
+        ));
+    }
+    header.push_str("</KIDS></PROTECTINFO>");
+    if let Some(license_url) = license_url {
+        header.push_str(&format!("<LA_URL>{}</LA_URL>", escape_xml(license_url)));
+    }
+    header.push_str("</DATA></WRMHEADER>");
+
+    let record: Vec<u8> = header.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let record_len = u16::try_from(record.len()).ok()?;
+    let mut out = Vec::with_capacity(record.len() + 10);
+    out.extend_from_slice(&(u32::from(record_len) + 10).to_le_bytes());
+    out.extend_from_slice(&1_u16.to_le_bytes());
+    // Record type 1 is a rights management header.
+    out.extend_from_slice(&1_u16.to_le_bytes());
+    out.extend_from_slice(&record_len.to_le_bytes());
+    out.extend_from_slice(&record);
+    Some(out)
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Bytes at the start of a slice NAL unit, header byte included, that `cbcs`
+/// leaves clear so the slice header stays readable, as in Apple's sample
+/// encryption.
+const CBCS_CLEAR_NAL_BYTES: usize = 32;
+
+/// Subsamples for a sample of 4-byte length-prefixed AVC NAL units. Slice
+/// NAL units keep their length and header byte clear under `cenc`, and their
+/// first 32 bytes under `cbcs`, and protect the rest; `cenc` moves any
+/// partial block to the clear bytes so each protected range is block
+/// aligned. Other NAL units stay clear. A malformed length protects
+/// everything after the last whole NAL unit.
+pub fn avc_subsamples(sample: &[u8], scheme: EncryptionScheme) -> Vec<Subsample> {
+    let mut subsamples = Vec::new();
+    let mut clear = 0_usize;
+    let mut position = 0_usize;
+    while position < sample.len() {
+        let nal_len = sample
+            .get(position..position + 4)
+            .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+        let Some(nal_len) = nal_len.filter(|&len| position + 4 + len <= sample.len()) else {
+            push_subsample(&mut subsamples, &mut clear, sample.len() - position);
+            return subsamples;
+        };
+        let nal_type = sample.get(position + 4).map_or(0, |header| header & 0x1f);
+        let header_len = match scheme {
+            EncryptionScheme::Cenc => 4 + 1,
+            EncryptionScheme::Cbcs => 4 + CBCS_CLEAR_NAL_BYTES,
+        };
+        let mut protected = if (1..=5).contains(&nal_type) {
+            (4 + nal_len).saturating_sub(header_len)
+        } else {
+            0
+        };
+        if scheme == EncryptionScheme::Cenc {
+            protected -= protected % 16;
+        }
+        clear += 4 + nal_len - protected;
+        if protected > 0 {
+            push_subsample(&mut subsamples, &mut clear, protected);
+        }
+        position += 4 + nal_len;
+    }
+    if clear > 0 || subsamples.is_empty() {
+        push_subsample(&mut subsamples, &mut clear, 0);
+    }
+    subsamples
+}
+
+/// Push `clear` bytes followed by `protected` bytes, splitting clear runs
+/// longer than a subsample can hold.
+fn push_subsample(subsamples: &mut Vec<Subsample>, clear: &mut usize, protected: usize) {
+    while *clear > usize::from(u16::MAX) {
+        subsamples.push(Subsample {
+            clear_bytes: u16::MAX,
+            protected_bytes: 0,
+        });
+        *clear -= usize::from(u16::MAX);
+    }
+    subsamples.push(Subsample {
+        clear_bytes: *clear as u16,
+        protected_bytes: protected as u32,
+    });
+    *clear = 0;
+}
+
+/// CBC over the whole blocks of `data` following a crypt:skip block pattern,
+/// restarting from `iv`. A pattern of 0:0 encrypts every whole block and a
+/// trailing partial block stays clear.
+fn cbc_pattern(key: &[u8; 16], iv: &[u8; 16], crypt: u8, skip: u8, data: &mut [u8]) {
+    let (crypt, skip) = if crypt == 0 && skip == 0 {
+        (1, 0)
+    } else {
+        (usize::from(crypt), usize::from(skip))
+    };
+    // Skipped blocks stay out of the chain, so one encryptor carries it
+    // across the encrypted blocks.
+    let mut encryptor = Aes128CbcEnc::new(key.into(), iv.into());
+    for (index, block) in data.chunks_exact_mut(16).enumerate() {
+        if index % (crypt + skip) < crypt {
+            encryptor.encrypt_block_mut(GenericArray::from_mut_slice(block));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::tests::{audio_track, config};
+    use crate::fmp4::{box_fmp4_with_tracks, Config, FileType, FragmentMedia, TrackMetadata};
+    use crate::mp4::{read_boxes, write_init_segment, MediaInit, TrackInit, VideoInit};
+    use access_unit::{AccessUnit, PSI_STREAM_H264};
+    use aes::cipher::{BlockEncrypt, KeyInit};
+    use bytes::Bytes;
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&value[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn block(value: &str) -> [u8; 16] {
+        hex(value).try_into().unwrap()
+    }
+
+    fn encryption(scheme: EncryptionScheme, iv: &str) -> Encryption {
+        Encryption {
+            scheme,
+            key_id: [0x11; 16],
+            key: block("2b7e151628aed2a6abf7158809cf4f3c"),
+            iv: block(iv),
+            protection_systems: Vec::new(),
+            samples: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn aes_matches_fips_197_and_sp_800_38a_vectors() {
+        let cipher = aes::Aes128::new(&block("000102030405060708090a0b0c0d0e0f").into());
+        let mut state = block("00112233445566778899aabbccddeeff").into();
+        cipher.encrypt_block(&mut state);
+        assert_eq!(state[..], block("69c4e0d86a7b0430d8cdb78070b4c55a"));
+
+        let plaintext = hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        let ctr = encryption(EncryptionScheme::Cenc, "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        let mut sample = plaintext.clone();
+        ctr.encrypt_sample(false, &ctr.iv, &mut sample, &[])
+            .unwrap();
+        assert_eq!(
+            sample,
+            hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff")
+        );
+
+        let cbc = encryption(EncryptionScheme::Cbcs, "000102030405060708090a0b0c0d0e0f");
+        let mut sample = plaintext.clone();
+        sample.push(0xaa);
+        cbc.encrypt_sample(false, &[], &mut sample, &[]).unwrap();
+        assert_eq!(
+            sample,
+            hex("7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2aa")
+        );
+
+        // The 1:9 video pattern encrypts the first block and skips the next.
+        let mut sample = plaintext.clone();
+        cbc.encrypt_sample(true, &[], &mut sample, &[]).unwrap();
+        assert_eq!(sample[..16], block("7649abac8119b246cee98e9b12e9197d"));
+        assert_eq!(sample[16..], plaintext[16..]);
+    }
+
+    #[test]
+    fn ctr_keystream_continues_across_subsamples() {
+        let ctr = encryption(EncryptionScheme::Cenc, "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff");
+        let plaintext = hex("6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51");
+        let mut sample = vec![0xee; 3];
+        sample.extend_from_slice(&plaintext[..20]);
+        sample.extend_from_slice(&[0xee; 2]);
+        sample.extend_from_slice(&plaintext[20..]);
+        let subsamples = [
+            Subsample {
+                clear_bytes: 3,
+                protected_bytes: 20,
+            },
+            Subsample {
+                clear_bytes: 2,
+                protected_bytes: 12,
+            },
+        ];
+        ctr.encrypt_sample(false, &ctr.iv, &mut sample, &subsamples)
+            .unwrap();
+        let expected = hex("874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff");
+        assert_eq!(sample[..3], [0xee; 3]);
+        assert_eq!(sample[3..23], expected[..20]);
+        assert_eq!(sample[23..25], [0xee; 2]);
+        assert_eq!(sample[25..], expected[20..]);
+    }
+
+    #[test]
+    fn avc_subsamples_keep_headers_and_non_slice_units_clear() {
+        let mut sample = vec![0, 0, 0, 2, 0x09, 0xf0];
+        sample.extend_from_slice(&[0, 0, 0, 41, 0x65]);
+        sample.extend_from_slice(&[0x88; 40]);
+        assert_eq!(
+            avc_subsamples(&sample, EncryptionScheme::Cenc),
+            [Subsample {
+                clear_bytes: 6 + 5 + 8,
+                protected_bytes: 32,
+            }]
+        );
+        assert_eq!(
+            avc_subsamples(&sample, EncryptionScheme::Cbcs),
+            [Subsample {
+                clear_bytes: 6 + 4 + 32,
+                protected_bytes: 9,
+            }]
+        );
+        assert_eq!(
+            avc_subsamples(&[0, 0, 0, 9, 0x65], EncryptionScheme::Cbcs),
+            [Subsample {
+                clear_bytes: 0,
+                protected_bytes: 5,
+            }]
+        );
+    }
+
+    #[test]
+    fn cbcs_leaves_the_slice_header_clear() {
+        let mut sample = vec![0, 0, 0, 100, 0x65];
+        sample.extend((0..99).map(|byte| byte as u8));
+        let original = sample.clone();
+        let cbc = encryption(EncryptionScheme::Cbcs, "000102030405060708090a0b0c0d0e0f");
+        let subsamples = avc_subsamples(&sample, EncryptionScheme::Cbcs);
+        cbc.encrypt_sample(true, &[], &mut sample, &subsamples)
+            .unwrap();
+
+        // The slice header sits right after the NAL header byte.
+        assert_eq!(sample[..4 + 32], original[..4 + 32]);
+        assert_ne!(sample[36..52], original[36..52]);
+    }
+
+    #[test]
+    fn cenc_ivs_count_samples_from_a_random_base() {
+        let encryption = encryption(EncryptionScheme::Cenc, "0102030405060708ffffffffffffffff");
+        let samples = [FragmentSample {
+            duration: Some(0),
+            size: Some(4),
+            flags: None,
+            composition_time_offset: None,
+        }; 2];
+        // Samples sharing a decode time, and clones, still get distinct IVs.
+        let entries = encryption
+            .clone()
+            .encrypt_track(false, &samples, &mut [0; 8])
+            .unwrap();
+        assert_eq!(entries[0].iv, hex("0102030405060708"));
+        assert_eq!(entries[1].iv, hex("0102030405060709"));
+        assert_eq!(encryption.next_sample_iv(), hex("010203040506070a"));
+
+        let new = || Encryption::new(EncryptionScheme::Cenc, [0x11; 16], [0x22; 16], Vec::new());
+        assert_ne!(new().unwrap().iv, new().unwrap().iv);
+    }
+
+    #[test]
+    fn encrypted_fragment_writes_protected_entry_and_sample_encryption() {
+        let mut nal = vec![0, 0, 0, 41, 0x65];
+        nal.extend_from_slice(&[0x88; 40]);
+        let config = Config {
+            encryption: Some(encryption(
+                EncryptionScheme::Cenc,
+                "0102030405060708ffffffffffffffff",
+            )),
+            ..config()
+        };
+        let fmp4 = box_fmp4_with_tracks(
+            1,
+            config,
+            FragmentMedia {
+                video: vec![AccessUnit {
+                    key: true,
+                    pts: 0,
+                    dts: 0,
+                    data: Bytes::from(nal.clone()),
+                    stream_type: PSI_STREAM_H264,
+                    id: 0,
+                }],
+                ..FragmentMedia::default()
+            },
+            3_000,
+            true,
+        );
+
+        let init = fmp4.init.unwrap();
+        let moov = read_boxes(&init).unwrap()[1];
+        let stsd = moov.children(*b"trak").unwrap()[0]
+            .child(*b"mdia")
+            .and_then(|mdia| mdia.child(*b"minf"))
+            .and_then(|minf| minf.child(*b"stbl"))
+            .and_then(|stbl| stbl.child(*b"stsd"))
+            .unwrap();
+        let (_, _, stsd) = stsd.full_box().unwrap();
+        let entry = read_boxes(&stsd[4..]).unwrap()[0];
+        assert_eq!(entry.name, *b"encv");
+        let sinf = read_boxes(&entry.payload[78..]).unwrap()[1];
+        assert_eq!(sinf.name, *b"sinf");
+        assert_eq!(sinf.child(*b"frma").unwrap().payload, b"avc1");
+        let (_, _, schm) = sinf.child(*b"schm").unwrap().full_box().unwrap();
+        assert_eq!(&schm[..4], b"cenc");
+        let tenc = sinf.child(*b"schi").and_then(|schi| schi.child(*b"tenc"));
+        let (version, _, tenc) = tenc.unwrap().full_box().unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(tenc[..4], [0, 0, 1, 8]);
+        assert_eq!(tenc[4..20], [0x11; 16]);
+
+        let boxes = read_boxes(&fmp4.data).unwrap();
+        let (moof, mdat) = (boxes[0], boxes[1]);
+        let traf = moof.child(*b"traf").unwrap();
+        let (_, _, saiz) = traf.child(*b"saiz").unwrap().full_box().unwrap();
+        assert_eq!(saiz, [8 + 2 + 6, 0, 0, 0, 1]);
+        let (_, _, saio) = traf.child(*b"saio").unwrap().full_box().unwrap();
+        let info_offset = u32::from_be_bytes(saio[4..8].try_into().unwrap()) as usize;
+        let (_, flags, senc) = traf.child(*b"senc").unwrap().full_box().unwrap();
+        assert_eq!(flags, 2);
+        let iv = hex("0102030405060708");
+        assert_eq!(&fmp4.data[info_offset..info_offset + 8], iv);
+        assert_eq!(senc[4..12], iv);
+        assert_eq!(senc[12..], [0, 1, 0, 13, 0, 0, 0, 32]);
+
+        assert_eq!(mdat.payload[..13], nal[..13]);
+        assert_ne!(mdat.payload[13..], nal[13..]);
+        let mut decrypted = mdat.payload.to_vec();
+        let mut counter = [0; 16];
+        counter[..8].copy_from_slice(&iv);
+        let key = block("2b7e151628aed2a6abf7158809cf4f3c");
+        Aes128Ctr::new(&key.into(), &counter.into()).apply_keystream(&mut decrypted[13..]);
+        assert_eq!(decrypted, nal);
+    }
+
+    #[test]
+    fn cbcs_audio_tenc_uses_version_1_without_a_pattern() {
+        let config = Config {
+            encryption: Some(encryption(
+                EncryptionScheme::Cbcs,
+                "000102030405060708090a0b0c0d0e0f",
+            )),
+            ..config()
+        };
+        let fmp4 = box_fmp4_with_tracks(
+            1,
+            config,
+            FragmentMedia {
+                audio: vec![audio_track(2, *b"eng", "English")],
+                ..FragmentMedia::default()
+            },
+            3_000,
+            true,
+        );
+
+        let init = fmp4.init.unwrap();
+        let moov = read_boxes(&init).unwrap()[1];
+        let stsd = moov.children(*b"trak").unwrap()[1]
+            .child(*b"mdia")
+            .and_then(|mdia| mdia.child(*b"minf"))
+            .and_then(|minf| minf.child(*b"stbl"))
+            .and_then(|stbl| stbl.child(*b"stsd"))
+            .unwrap();
+        let (_, _, stsd) = stsd.full_box().unwrap();
+        let entry = read_boxes(&stsd[4..]).unwrap()[0];
+        assert_eq!(entry.name, *b"enca");
+        let sinf = read_boxes(&entry.payload[28..])
+            .unwrap()
+            .into_iter()
+            .find(|child| child.name == *b"sinf")
+            .unwrap();
+        assert_eq!(sinf.child(*b"frma").unwrap().payload, b"mp4a");
+        let tenc = sinf.child(*b"schi").and_then(|schi| schi.child(*b"tenc"));
+        let (version, _, tenc) = tenc.unwrap().full_box().unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(tenc[..4], [0, 0, 1, 0]);
+        assert_eq!(tenc[20], 16);
+    }
+
+    #[test]
+    fn init_segment_carries_pssh_boxes_for_each_system() {
+        let key_id: [u8; 16] = std::array::from_fn(|index| index as u8);
+        let widevine =
+            ProtectionSystemHeader::widevine(&[[0x11; 16]], None, EncryptionScheme::Cenc);
+        let mut expected = vec![0x12, 0x10];
+        expected.extend_from_slice(&[0x11; 16]);
+        expected.extend_from_slice(&[0x48, 0xe3, 0xdc, 0x95, 0x9b, 0x06]);
+        assert_eq!(widevine.data, expected);
+
+        let playready = ProtectionSystemHeader::playready(
+            &[key_id],
+            Some("https://license.example/?a=1&b=2"),
+            EncryptionScheme::Cbcs,
+        )
+        .expect("playready header");
+        let object = &playready.data;
+        assert_eq!(object[..4], (object.len() as u32).to_le_bytes());
+        assert_eq!(object[4..8], [1, 0, 1, 0]);
+        let record: Vec<u16> = object[10..]
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        let header = String::from_utf16(&record).unwrap();
+        assert!(header.contains("<KID ALGID=\"AESCBC\" VALUE=\"AwIBAAUEBwYICQoLDA0ODw==\"></KID>"));
+        assert!(header.contains("<LA_URL>https://license.example/?a=1&amp;b=2</LA_URL>"));
+        let long_url = "a".repeat(40_000);
+        assert!(ProtectionSystemHeader::playready(
+            &[key_id],
+            Some(&long_url),
+            EncryptionScheme::Cbcs
+        )
+        .is_none());
+
+        let mut init = Vec::new();
+        write_init_segment(
+            &mut init,
+            &FileType::default(),
+            90_000,
+            &[TrackInit {
+                media: MediaInit::Video(VideoInit {
+                    track_id: 1,
+                    width: 640,
+                    height: 360,
+                    avcc: config().avcc.expect("avcc"),
+                }),
+                metadata: TrackMetadata::default(),
+                encryption: None,
+            }],
+            &[
+                widevine,
+                playready.clone(),
+                ProtectionSystemHeader::fairplay(&[key_id]),
+            ],
+        )
+        .unwrap();
+        let moov = read_boxes(&init).unwrap()[1];
+        let pssh = moov.children(*b"pssh").unwrap();
+        assert_eq!(pssh.len(), 3);
+
+        let (version, _, widevine) = pssh[0].full_box().unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(widevine[..16], WIDEVINE_SYSTEM_ID);
+        assert_eq!(widevine[16..20], (expected.len() as u32).to_be_bytes());
+
+        let (version, _, playready_box) = pssh[1].full_box().unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(playready_box[..16], PLAYREADY_SYSTEM_ID);
+        assert_eq!(playready_box[16..20], [0, 0, 0, 1]);
+        assert_eq!(playready_box[20..36], key_id);
+        assert_eq!(playready_box[40..], playready.data);
+
+        let (version, _, fairplay) = pssh[2].full_box().unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(fairplay[..16], FAIRPLAY_SYSTEM_ID);
+        assert_eq!(fairplay[36..], [0, 0, 0, 0]);
+    }
+}
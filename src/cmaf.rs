@@ -0,0 +1,295 @@
+use crate::mp4::{read_boxes, read_init_tracks, read_track_fragments, BoxRef, InitTrack};
+
+/// A CMAF track constraint (ISO/IEC 23000-19) broken by a header or segment.
+/// `segment` indexes the segments passed to `validate_cmaf_track`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CmafViolation {
+    MalformedHeader,
+    /// `ftyp` lacks the `cmfc` track brand.
+    MissingTrackBrand,
+    /// A CMAF header describes exactly one track.
+    TrackCount(usize),
+    /// Every track needs a `trex` in `mvex`.
+    MissingTrackExtends,
+    MalformedSegment {
+        segment: usize,
+    },
+    MissingFragment {
+        segment: usize,
+    },
+    /// Each `moof` holds exactly one `traf`.
+    TrackFragmentCount {
+        segment: usize,
+        count: usize,
+    },
+    UnknownTrack {
+        segment: usize,
+        track_id: u32,
+    },
+    MissingDecodeTime {
+        segment: usize,
+    },
+    /// `tfhd` must set default-base-is-moof and omit base_data_offset.
+    BaseNotMoof {
+        segment: usize,
+    },
+    MissingDataOffset {
+        segment: usize,
+    },
+    SequenceNumberNotIncreasing {
+        segment: usize,
+    },
+    /// Fragments must be contiguous on the decode timeline.
+    DecodeTimeGap {
+        segment: usize,
+        expected: u64,
+        actual: u64,
+    },
+    /// Video segments start with a sync sample.
+    StartsWithoutSync {
+        segment: usize,
+    },
+}
+
+/// Check a CMAF header and its segments against the structural constraints
+/// of a CMAF track, returning every violation found. An empty result means the
+/// track conforms as far as these checks go.
+pub fn validate_cmaf_track(init: &[u8], segments: &[impl AsRef<[u8]>]) -> Vec<CmafViolation> {
+    let mut violations = Vec::new();
+    let Some(tracks) = validate_header(init, &mut violations) else {
+        violations.push(CmafViolation::MalformedHeader);
+        return violations;
+    };
+    let is_video = tracks
+        .first()
+        .is_some_and(|track| track.handler_type == *b"vide");
+
+    let mut sequence_number = None;
+    let mut decode_times: Vec<(u32, u64)> = Vec::new();
+    for (segment, data) in segments.iter().enumerate() {
+        let Some(moofs) = read_boxes(data.as_ref()).map(|boxes| {
+            boxes
+                .into_iter()
+                .filter(|top| top.name == *b"moof")
+                .collect::<Vec<_>>()
+        }) else {
+            violations.push(CmafViolation::MalformedSegment { segment });
+            continue;
+        };
+        if moofs.is_empty() {
+            violations.push(CmafViolation::MissingFragment { segment });
+        }
+        for (index, moof) in moofs.iter().enumerate() {
+            let Some(number) = read_sequence_number(moof) else {
+                violations.push(CmafViolation::MalformedSegment { segment });
+                continue;
+            };
+            if sequence_number.is_some_and(|previous| number <= previous) {
+                violations.push(CmafViolation::SequenceNumberNotIncreasing { segment });
+            }
+            sequence_number = Some(number);
+
+            let Some(fragments) = read_track_fragments(moof, &tracks) else {
+                violations.push(CmafViolation::MalformedSegment { segment });
+                continue;
+            };
+            if fragments.len() != 1 {
+                violations.push(CmafViolation::TrackFragmentCount {
+                    segment,
+                    count: fragments.len(),
+                });
+            }
+            for fragment in fragments {
+                if !tracks
+                    .iter()
+                    .any(|track| track.track_id == fragment.track_id)
+                {
+                    violations.push(CmafViolation::UnknownTrack {
+                        segment,
+                        track_id: fragment.track_id,
+                    });
+                }
+                if !fragment.default_base_is_moof || fragment.base_data_offset.is_some() {
+                    violations.push(CmafViolation::BaseNotMoof { segment });
+                }
+                if fragment.runs.iter().any(|run| run.data_offset.is_none()) {
+                    violations.push(CmafViolation::MissingDataOffset { segment });
+                }
+                let mut samples = fragment.runs.iter().flat_map(|run| &run.samples);
+                if is_video
+                    && index == 0
+                    && samples
+                        .clone()
+                        .next()
+                        .is_some_and(|sample| !sample.is_sync())
+                {
+                    violations.push(CmafViolation::StartsWithoutSync { segment });
+                }
+                let Some(actual) = fragment.base_media_decode_time else {
+                    violations.push(CmafViolation::MissingDecodeTime { segment });
+                    continue;
+                };
+                let previous = decode_times
+                    .iter()
+                    .position(|(track_id, _)| *track_id == fragment.track_id);
+                if let Some(expected) = previous
+                    .map(|position| decode_times[position].1)
+                    .filter(|&expected| expected != actual)
+                {
+                    violations.push(CmafViolation::DecodeTimeGap {
+                        segment,
+                        expected,
+                        actual,
+                    });
+                }
+                let end = samples.try_fold(actual, |time, sample| {
+                    time.checked_add(u64::from(sample.duration))
+                });
+                match (previous, end) {
+                    (Some(position), Some(end)) => decode_times[position].1 = end,
+                    (None, Some(end)) => decode_times.push((fragment.track_id, end)),
+                    (Some(position), None) => {
+                        decode_times.remove(position);
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+    }
+    violations
+}
+
+fn validate_header(init: &[u8], violations: &mut Vec<CmafViolation>) -> Option<Vec<InitTrack>> {
+    let boxes = read_boxes(init)?;
+    let ftyp = boxes.iter().find(|top| top.name == *b"ftyp")?;
+    let has_track_brand = ftyp.payload.get(..4)? == b"cmfc"
+        || ftyp
+            .payload
+            .get(8..)?
+            .chunks_exact(4)
+            .any(|brand| brand == b"cmfc");
+    if !has_track_brand {
+        violations.push(CmafViolation::MissingTrackBrand);
+    }
+
+    let tracks = read_init_tracks(init)?;
+    if tracks.len() != 1 {
+        violations.push(CmafViolation::TrackCount(tracks.len()));
+    }
+    let moov = boxes.iter().find(|top| top.name == *b"moov")?;
+    let trex_count = match moov.child(*b"mvex") {
+        Some(mvex) => mvex.children(*b"trex")?.len(),
+        None => 0,
+    };
+    if trex_count < tracks.len() {
+        violations.push(CmafViolation::MissingTrackExtends);
+    }
+    Some(tracks)
+}
+
+fn read_sequence_number(moof: &BoxRef<'_>) -> Option<u32> {
+    let (_, _, mfhd) = moof.child(*b"mfhd")?.full_box()?;
+    Some(u32::from_be_bytes(mfhd.get(..4)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::{
+        box_fmp4_with_tracks, AudioTrack, Config, FileType, FragmentMedia, TrackMetadata,
+    };
+    use access_unit::{AccessUnit, PSI_STREAM_AAC, PSI_STREAM_H264};
+    use bytes::Bytes;
+
+    fn config(file_type: FileType) -> Config {
+        Config {
+            file_type,
+            segment_type: Some(FileType::cmaf_segment()),
+            ..crate::fmp4::tests::config()
+        }
+    }
+
+    fn unit(stream_type: u8, dts: u64, key: bool) -> AccessUnit {
+        AccessUnit {
+            key,
+            pts: dts,
+            dts,
+            data: if stream_type == PSI_STREAM_H264 {
+                Bytes::from_static(&[0, 0, 0, 1, 0x65])
+            } else {
+                let mut data = access_unit::aac::create_adts_header(0x66, 2, 48_000, 1, false);
+                data.push(0x21);
+                Bytes::from(data)
+            },
+            stream_type,
+            id: 0,
+        }
+    }
+
+    fn segment(
+        config: Config,
+        seq: u32,
+        dts: u64,
+        key: bool,
+        audio: Vec<AudioTrack>,
+    ) -> access_unit::Fmp4 {
+        box_fmp4_with_tracks(
+            seq,
+            config,
+            FragmentMedia {
+                video: vec![unit(PSI_STREAM_H264, dts, key)],
+                audio,
+                ..FragmentMedia::default()
+            },
+            dts + 3_000,
+            true,
+        )
+    }
+
+    #[test]
+    fn cmaf_brands_and_styp_produce_a_conforming_video_track() {
+        let first = segment(config(FileType::cmaf_header()), 1, 0, true, Vec::new());
+        let second = segment(config(FileType::cmaf_header()), 2, 3_000, true, Vec::new());
+        let init = first.init.clone().expect("init segment");
+
+        assert_eq!(&init[4..12], b"ftypcmf2");
+        assert_eq!(&first.data[4..12], b"stypcmfs");
+        assert_eq!(
+            validate_cmaf_track(&init, &[first.data, second.data]),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn reports_muxed_tracks_missing_brand_gaps_and_non_sync_starts() {
+        let audio = vec![AudioTrack {
+            track_id: 2,
+            config: None,
+            metadata: TrackMetadata::default(),
+            units: vec![unit(PSI_STREAM_AAC, 0, false)],
+        }];
+        let first = segment(config(FileType::default()), 1, 0, true, audio);
+        let second = segment(config(FileType::default()), 1, 6_000, false, Vec::new());
+        let init = first.init.clone().expect("init segment");
+        let violations = validate_cmaf_track(&init, &[first.data, second.data]);
+
+        assert_eq!(
+            violations,
+            vec![
+                CmafViolation::MissingTrackBrand,
+                CmafViolation::TrackCount(2),
+                CmafViolation::TrackFragmentCount {
+                    segment: 0,
+                    count: 2
+                },
+                CmafViolation::SequenceNumberNotIncreasing { segment: 1 },
+                CmafViolation::StartsWithoutSync { segment: 1 },
+                CmafViolation::DecodeTimeGap {
+                    segment: 1,
+                    expected: 3_000,
+                    actual: 6_000
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use crate::fmp4::{AudioTrackConfig, PcmSampleKind};
+use crate::mp4::{
+    read_boxes, read_init_tracks, read_sample_entries, AacProfile, AdtsHeader,
+    AvcDecoderConfigurationRecord, BoxRef,
+};
+
+impl AvcDecoderConfigurationRecord {
+    /// The RFC 6381 `avc1.PPCCLL` codecs parameter for this configuration.
+    pub fn codec_string(&self) -> String {
+        format!(
+            "avc1.{:02X}{:02X}{:02X}",
+            self.profile_idc, self.constraint_set_flag, self.level_idc
+        )
+    }
+}
+
+impl AdtsHeader {
+    /// `mp4a.40.<audio object type>` for the AAC profile in this header.
+    pub fn codec_string(&self) -> String {
+        aac_codec_string(self.profile)
+    }
+}
+
+impl AudioTrackConfig {
+    /// The codecs parameter of the sample entry the boxer writes for this
+    /// configuration. AAC returns `None`: its profile comes from the ADTS
+    /// headers, so use `AdtsHeader::codec_string` instead.
+    pub fn codec_string(&self) -> Option<String> {
+        match self {
+            AudioTrackConfig::Aac => None,
+            AudioTrackConfig::Pcm(pcm) => Some(pcm_codec_string(matches!(
+                pcm.sample_kind,
+                PcmSampleKind::Float
+            ))),
+            AudioTrackConfig::Opus(_) => Some("opus".to_owned()),
+        }
+    }
+}
+
+fn aac_codec_string(profile: AacProfile) -> String {
+    // The ADTS profile is the MPEG-4 audio object type minus one.
+    format!("mp4a.40.{}", profile as u8 + 1)
+}
+
+fn pcm_codec_string(floating_point: bool) -> String {
+    if floating_point { "fpcm" } else { "ipcm" }.to_owned()
+}
+
+/// The `CODECS` value for every audio, video and subtitle track in an init
+/// segment, comma separated in track order.
+pub fn codecs_from_init(init: &[u8]) -> Option<String> {
+    let tracks = read_init_tracks(init)?;
+    let entries = read_sample_entries(init)?;
+    let codecs: Vec<String> = tracks
+        .iter()
+        .zip(&entries)
+        .filter(|(track, _)| matches!(&track.handler_type, b"vide" | b"soun" | b"text" | b"subt"))
+        .map(|(_, entry)| sample_entry_codec(entry))
+        .collect::<Option<_>>()?;
+    (!codecs.is_empty()).then(|| codecs.join(","))
+}
+
+/// The codecs parameter of one sample entry. Entries without a configuration
+/// box to refine them are named by their four-character code, and protected
+/// entries by the format they wrap.
+pub(crate) fn sample_entry_codec(entry: &BoxRef<'_>) -> Option<String> {
+    let format = original_format(entry)?;
+    let name = String::from_utf8_lossy(&format).into_owned();
+    match &format {
+        b"avc1" | b"avc3" => {
+            let avcc = visual_config(entry, *b"avcC")?;
+            let profile = avcc.payload.get(1..4)?;
+            Some(format!(
+                "{name}.{:02X}{:02X}{:02X}",
+                profile[0], profile[1], profile[2]
+            ))
+        }
+        b"hvc1" | b"hev1" => {
+            let hvcc = visual_config(entry, *b"hvcC")?;
+            Some(format!("{name}.{}", hevc_parameters(hvcc.payload)?))
+        }
+        b"av01" => {
+            let av1c = visual_config(entry, *b"av1C")?;
+            Some(format!("{name}.{}", av1_parameters(av1c.payload)?))
+        }
+        b"mp4a" => {
+            let esds = read_boxes(entry.payload.get(28..)?)?
+                .into_iter()
+                .find(|child| child.name == *b"esds")?;
+            let (_, _, descriptors) = esds.full_box()?;
+            let (object_type, audio_object_type) = read_esds_object_types(descriptors)?;
+            Some(match audio_object_type {
+                Some(audio_object_type) => format!("mp4a.{object_type:02x}.{audio_object_type}"),
+                None => format!("mp4a.{object_type:02x}"),
+            })
+        }
+        // The registered codecs name is lower case, unlike the entry.
+        b"Opus" => Some("opus".to_owned()),
+        b"stpp" => Some(format!("stpp.ttml.{}", ttml_profile(entry)?)),
+        _ => Some(name),
+    }
+}
+
+/// The format an `encv` or `enca` entry wraps, from its `sinf/frma`. Other
+/// entries are their own format.
+fn original_format(entry: &BoxRef<'_>) -> Option<[u8; 4]> {
+    let header_len = match &entry.name {
+        b"encv" => 78,
+        b"enca" => 28,
+        _ => return Some(entry.name),
+    };
+    let sinf = read_boxes(entry.payload.get(header_len..)?)?
+        .into_iter()
+        .find(|child| child.name == *b"sinf")?;
+    sinf.child(*b"frma")?.payload.try_into().ok()
+}
+
+/// The IMSC1 profile of an `stpp` entry: the `codecs` parameter of its
+/// `mime` box, or `im1i` when it references images and `im1t` otherwise.
+fn ttml_profile(entry: &BoxRef<'_>) -> Option<String> {
+    // namespace, schema_location and auxiliary_mime_types, then the boxes.
+    let mut fields = entry.payload.get(8..)?.splitn(4, |&byte| byte == 0);
+    let auxiliary_mime_types = fields.nth(2)?;
+    let mime = fields
+        .next()
+        .and_then(read_boxes)
+        .and_then(|boxes| boxes.into_iter().find(|child| child.name == *b"mime"))
+        .and_then(|mime| Some(String::from_utf8_lossy(mime.full_box()?.2).into_owned()));
+    let codecs = mime.as_deref().and_then(|mime| {
+        mime.trim_end_matches('\0')
+            .split(';')
+            .find_map(|parameter| parameter.trim().strip_prefix("codecs="))
+            .map(|codecs| codecs.trim_matches('"').to_owned())
+    });
+    Some(codecs.unwrap_or_else(|| {
+        if String::from_utf8_lossy(auxiliary_mime_types).contains("image/") {
+            "im1i".to_owned()
+        } else {
+            "im1t".to_owned()
+        }
+    }))
+}
+
+/// A configuration box following the 78-byte visual sample entry header.
+fn visual_config<'a>(entry: &BoxRef<'a>, name: [u8; 4]) -> Option<BoxRef<'a>> {
+    read_boxes(entry.payload.get(78..)?)?
+        .into_iter()
+        .find(|child| child.name == name)
+}
+
+/// `<space><profile>.<compatibility>.<tier><level>[.<constraints>]` from an
+/// `HEVCDecoderConfigurationRecord` (ISO/IEC 14496-15 annex E).
+fn hevc_parameters(hvcc: &[u8]) -> Option<String> {
+    let profile = *hvcc.get(1)?;
+    let profile_space = ["", "A", "B", "C"][usize::from(profile >> 6)];
+    let tier = if profile & 0x20 != 0 { 'H' } else { 'L' };
+    let compatibility = u32::from_be_bytes(hvcc.get(2..6)?.try_into().ok()?).reverse_bits();
+    let constraints = hvcc.get(6..12)?;
+    let level = *hvcc.get(12)?;
+    let mut out = format!(
+        "{profile_space}{}.{compatibility:X}.{tier}{level}",
+        profile & 0x1f
+    );
+    let used = constraints
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |last| last + 1);
+    for byte in &constraints[..used] {
+        out.push_str(&format!(".{byte:02X}"));
+    }
+    Some(out)
+}
+
+/// `<profile>.<level><tier>.<bit depth>` from an `AV1CodecConfigurationRecord`.
+fn av1_parameters(av1c: &[u8]) -> Option<String> {
+    let profile = *av1c.get(1)?;
+    let flags = *av1c.get(2)?;
+    let tier = if flags & 0x80 != 0 { 'H' } else { 'M' };
+    let bit_depth = match (flags & 0x40 != 0, flags & 0x20 != 0) {
+        (true, true) => 12,
+        (true, false) => 10,
+        _ => 8,
+    };
+    Some(format!(
+        "{}.{:02}{tier}.{bit_depth:02}",
+        profile >> 5,
+        profile & 0x1f
+    ))
+}
+
+/// `objectTypeIndication` and, for MPEG-4 audio, the audio object type from
+/// an ES_Descriptor.
+fn read_esds_object_types(data: &[u8]) -> Option<(u8, Option<u8>)> {
+    let (tag, es) = read_descriptor(data)?;
+    if tag != 0x03 {
+        return None;
+    }
+    let flags = *es.get(2)?;
+    let mut position = 3;
+    if flags & 0x80 != 0 {
+        position += 2;
+    }
+    if flags & 0x40 != 0 {
+        position += 1 + usize::from(*es.get(position)?);
+    }
+    if flags & 0x20 != 0 {
+        position += 2;
+    }
+    let (tag, decoder_config) = read_descriptor(es.get(position..)?)?;
+    if tag != 0x04 {
+        return None;
+    }
+    let object_type = *decoder_config.first()?;
+    if object_type != 0x40 {
+        return Some((object_type, None));
+    }
+    let audio_object_type = read_descriptor(decoder_config.get(13..)?)
+        .filter(|(tag, _)| *tag == 0x05)
+        .and_then(|(_, specific)| {
+            let first = *specific.first()? >> 3;
+            if first == 31 {
+                let extended =
+                    (u16::from(specific[0] & 0x07) << 3) | u16::from(*specific.get(1)? >> 5);
+                Some(32 + extended as u8)
+            } else {
+                Some(first)
+            }
+        });
+    Some((object_type, audio_object_type))
+}
+
+/// Tag and body of an MPEG-4 descriptor with its variable-length size.
+fn read_descriptor(data: &[u8]) -> Option<(u8, &[u8])> {
+    let tag = *data.first()?;
+    let mut size = 0_usize;
+    let mut position = 1;
+    loop {
+        let byte = *data.get(position)?;
+        size = (size << 7) | usize::from(byte & 0x7f);
+        position += 1;
+        if byte & 0x80 == 0 || position > 4 {
+            break;
+        }
+    }
+    Some((tag, data.get(position..position.checked_add(size)?)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::FileType;
+    use crate::fmp4::{
+        box_fmp4_with_tracks, AudioTrack, Config, FragmentMedia, OpusAudioConfig, PcmAudioConfig,
+        TrackMetadata,
+    };
+    use crate::mp4::{write_init_segment, AudioInit, MediaInit, TextInit, TrackInit};
+    use access_unit::{AccessUnit, PSI_STREAM_H264};
+    use bytes::Bytes;
+
+    fn visual_entry(name: &[u8; 4], config_name: &[u8; 4], config: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0; 78];
+        entry.extend_from_slice(&(8 + config.len() as u32).to_be_bytes());
+        entry.extend_from_slice(config_name);
+        entry.extend_from_slice(config);
+        let mut out = (8 + entry.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(name);
+        out.extend_from_slice(&entry);
+        out
+    }
+
+    fn codec_of(entry: &[u8]) -> Option<String> {
+        sample_entry_codec(read_boxes(entry)?.first()?)
+    }
+
+    #[test]
+    fn init_codecs_match_configuration_codec_strings() {
+        let avcc = AvcDecoderConfigurationRecord {
+            profile_idc: 100,
+            constraint_set_flag: 0,
+            level_idc: 31,
+            sequence_parameter_set: Bytes::from_static(&[0x67, 0x64, 0x00, 0x1f]),
+            picture_parameter_set: Bytes::from_static(&[0x68, 0xce, 0x06, 0xe2]),
+        };
+        let adts = access_unit::aac::create_adts_header(0x66, 2, 48_000, 1, false);
+        let header = AdtsHeader::read_from(&adts).expect("adts header");
+        let init = box_fmp4_with_tracks(
+            1,
+            Config {
+                width: 1280,
+                height: 720,
+                avcc: Some(avcc.clone()),
+                ..Config::default()
+            },
+            FragmentMedia {
+                video: vec![AccessUnit {
+                    key: true,
+                    pts: 0,
+                    dts: 0,
+                    data: Bytes::from_static(&[0, 0, 0, 1, 0x65]),
+                    stream_type: PSI_STREAM_H264,
+                    id: 0,
+                }],
+                audio: vec![AudioTrack {
+                    track_id: 2,
+                    config: None,
+                    metadata: TrackMetadata::default(),
+                    units: vec![AccessUnit {
+                        key: true,
+                        pts: 0,
+                        dts: 0,
+                        data: Bytes::from([adts.as_slice(), &[0x21]].concat()),
+                        stream_type: 0,
+                        id: 1,
+                    }],
+                }],
+                ..FragmentMedia::default()
+            },
+            3_000,
+            true,
+        )
+        .init
+        .expect("init segment");
+
+        assert_eq!(avcc.codec_string(), "avc1.64001F");
+        assert_eq!(header.codec_string(), "mp4a.40.2");
+        assert_eq!(
+            codecs_from_init(&init),
+            Some(format!("{},{}", avcc.codec_string(), header.codec_string()))
+        );
+    }
+
+    #[test]
+    fn audio_configs_name_their_sample_entries() {
+        let opus = AudioTrackConfig::Opus(OpusAudioConfig {
+            input_sample_rate: 48_000,
+            channel_count: 2,
+            pre_skip: 312,
+            output_gain: 0,
+        });
+
+        assert_eq!(opus.codec_string().as_deref(), Some("opus"));
+        assert_eq!(AudioTrackConfig::Aac.codec_string(), None);
+        assert_eq!(
+            AudioTrackConfig::Pcm(PcmAudioConfig {
+                sample_rate: 48_000,
+                channel_count: 2,
+                sample_size: 32,
+                little_endian: true,
+                sample_kind: PcmSampleKind::Float,
+            })
+            .codec_string()
+            .as_deref(),
+            Some("fpcm")
+        );
+    }
+
+    #[test]
+    fn opus_and_ttml_entries_use_registered_codec_names() {
+        let ttml = |track_id: u32, auxiliary_mime_types: &str, mime_type: Option<&str>| {
+            MediaInit::Text(TextInit::Ttml {
+                track_id,
+                namespace: "http://www.w3.org/ns/ttml".to_owned(),
+                schema_location: String::new(),
+                auxiliary_mime_types: auxiliary_mime_types.to_owned(),
+                mime_type: mime_type.map(str::to_owned),
+            })
+        };
+        let tracks = [
+            MediaInit::Audio(AudioInit::Opus {
+                track_id: 1,
+                input_sample_rate: 48_000,
+                channel_count: 2,
+                pre_skip: 312,
+                output_gain: 0,
+            }),
+            ttml(2, "", None),
+            ttml(3, "image/png", None),
+            ttml(4, "", Some("application/ttml+xml;codecs=im2t")),
+        ]
+        .map(|media| TrackInit {
+            media,
+            metadata: TrackMetadata::default(),
+            encryption: None,
+        });
+        let mut init = Vec::new();
+        write_init_segment(&mut init, &FileType::default(), 1_000, &tracks, &[])
+            .expect("init segment");
+
+        assert_eq!(
+            codecs_from_init(&init).as_deref(),
+            Some("opus,stpp.ttml.im1t,stpp.ttml.im1i,stpp.ttml.im2t")
+        );
+    }
+
+    #[test]
+    fn derives_hevc_av1_and_he_aac_parameters() {
+        // Main profile, compatibility flags 0x60000000, level 3.1, progressive
+        // source constraint only.
+        let hvcc = [1, 0x01, 0x60, 0, 0, 0, 0x90, 0, 0, 0, 0, 0, 93];
+        // Main profile, level 8 (4.0), main tier, 8-bit.
+        let av1c = [0x81, 0x08, 0x0c, 0];
+
+        assert_eq!(
+            codec_of(&visual_entry(b"hvc1", b"hvcC", &hvcc)).as_deref(),
+            Some("hvc1.1.6.L93.90")
+        );
+        assert_eq!(
+            codec_of(&visual_entry(b"av01", b"av1C", &av1c)).as_deref(),
+            Some("av01.0.08M.08")
+        );
+        // ES_Descriptor > DecoderConfigDescriptor > AudioSpecificConfig with
+        // audio object type 5 (SBR).
+        let descriptors = [
+            0x03, 0x16, 0, 1, 0, 0x04, 0x11, 0x40, 0x15, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x05,
+            0x02, 0x2b, 0x92,
+        ];
+        assert_eq!(read_esds_object_types(&descriptors), Some((0x40, Some(5))));
+    }
+}
@@ -0,0 +1,574 @@
+use crate::codecs::sample_entry_codec;
+use crate::hls::format_date_time;
+use crate::mp4::{read_boxes, read_init_tracks, read_sample_entries, read_track_fragments};
+use std::fmt::Write as _;
+use std::time::SystemTime;
+
+const AUDIO_CHANNEL_CONFIGURATION_SCHEME: &str =
+    "urn:mpeg:dash:23003:3:audio_channel_configuration:2011";
+const ROLE_SCHEME: &str = "urn:mpeg:dash:role:2011";
+
+/// Whether the MPD describes finished content or a live presentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MpdType {
+    Static {
+        media_presentation_duration_ms: u64,
+    },
+    Dynamic {
+        availability_start_time: SystemTime,
+        publish_time: SystemTime,
+        time_shift_buffer_depth_ms: u64,
+        minimum_update_period_ms: u64,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mpd {
+    pub mpd_type: MpdType,
+    pub min_buffer_time_ms: u64,
+    pub periods: Vec<Period>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Period {
+    pub id: String,
+    pub start_ms: u64,
+    pub adaptation_sets: Vec<AdaptationSet>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    Video,
+    Audio,
+    Text,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AdaptationSet {
+    pub id: u32,
+    pub content_type: ContentType,
+    pub lang: Option<String>,
+    /// `Role` values in the DASH role scheme, such as `main` or `alternate`.
+    pub roles: Vec<String>,
+    pub representations: Vec<Representation>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Representation {
+    pub id: String,
+    pub bandwidth: u64,
+    pub codecs: String,
+    pub mime_type: String,
+    pub resolution: Option<(u16, u16)>,
+    pub audio_sampling_rate: Option<u32>,
+    pub audio_channels: Option<u16>,
+    pub segment_template: SegmentTemplate,
+}
+
+impl Representation {
+    /// A representation of one track of an init segment. Codecs, MIME type,
+    /// resolution, audio settings and the template timescale come from the
+    /// sample entry the boxer wrote.
+    pub fn from_init(
+        id: impl Into<String>,
+        bandwidth: u64,
+        init: &[u8],
+        track_id: u32,
+        segment_template: SegmentTemplate,
+    ) -> Option<Self> {
+        let tracks = read_init_tracks(init)?;
+        let entries = read_sample_entries(init)?;
+        let (track, entry) = tracks
+            .iter()
+            .zip(&entries)
+            .find(|(track, _)| track.track_id == track_id)?;
+        let mut representation = Self {
+            id: id.into(),
+            bandwidth,
+            codecs: sample_entry_codec(entry)?,
+            mime_type: "application/mp4".to_owned(),
+            resolution: None,
+            audio_sampling_rate: None,
+            audio_channels: None,
+            segment_template: SegmentTemplate {
+                timescale: track.timescale,
+                ..segment_template
+            },
+        };
+        match &track.handler_type {
+            b"vide" => {
+                let size = entry.payload.get(24..28)?;
+                representation.mime_type = "video/mp4".to_owned();
+                representation.resolution = Some((
+                    u16::from_be_bytes([size[0], size[1]]),
+                    u16::from_be_bytes([size[2], size[3]]),
+                ));
+            }
+            b"soun" => {
+                // channelcount and the integer part of the 16.16 samplerate
+                // in the audio sample entry header. The track timescale is
+                // not the sample rate for every codec.
+                let channels = entry.payload.get(16..18)?;
+                let sample_rate = entry.payload.get(24..26)?;
+                representation.mime_type = "audio/mp4".to_owned();
+                representation.audio_channels =
+                    Some(u16::from_be_bytes([channels[0], channels[1]]));
+                representation.audio_sampling_rate = Some(u32::from(u16::from_be_bytes([
+                    sample_rate[0],
+                    sample_rate[1],
+                ])));
+            }
+            _ => {}
+        }
+        Some(representation)
+    }
+}
+
+/// `SegmentTemplate` with an explicit `SegmentTimeline`. `media` may use the
+/// `$Number$` or `$Time$` identifiers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentTemplate {
+    pub timescale: u32,
+    pub initialization: String,
+    pub media: String,
+    pub start_number: u64,
+    pub timeline: Vec<TimelineEntry>,
+}
+
+impl SegmentTemplate {
+    pub fn new(initialization: impl Into<String>, media: impl Into<String>) -> Self {
+        Self {
+            timescale: 1,
+            initialization: initialization.into(),
+            media: media.into(),
+            start_number: 1,
+            timeline: Vec::new(),
+        }
+    }
+}
+
+/// An `S` element: `repeat` more segments of `duration` follow the first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimelineEntry {
+    pub start: u64,
+    pub duration: u64,
+    pub repeat: u32,
+}
+
+impl TimelineEntry {
+    fn end(self) -> u64 {
+        self.start + self.duration * (u64::from(self.repeat) + 1)
+    }
+}
+
+/// Build a `SegmentTimeline` for one track from its media segments, using
+/// each fragment's base decode time and the sum of its sample durations.
+/// Segments that continue the previous one with the same duration are folded
+/// into its repeat count.
+pub fn segment_timeline(
+    init: &[u8],
+    track_id: u32,
+    segments: &[impl AsRef<[u8]>],
+) -> Option<Vec<TimelineEntry>> {
+    let tracks = read_init_tracks(init)?;
+    let mut timeline: Vec<TimelineEntry> = Vec::new();
+    for segment in segments {
+        let mut start = None;
+        let mut duration = 0_u64;
+        for moof in read_boxes(segment.as_ref())?
+            .iter()
+            .filter(|top| top.name == *b"moof")
+        {
+            for fragment in read_track_fragments(moof, &tracks)? {
+                if fragment.track_id != track_id {
+                    continue;
+                }
+                start.get_or_insert(fragment.base_media_decode_time?);
+                duration += fragment
+                    .runs
+                    .iter()
+                    .flat_map(|run| &run.samples)
+                    .map(|sample| u64::from(sample.duration))
+                    .sum::<u64>();
+            }
+        }
+        let start = start?;
+        match timeline.last_mut() {
+            Some(last) if last.end() == start && last.duration == duration => last.repeat += 1,
+            _ => timeline.push(TimelineEntry {
+                start,
+                duration,
+                repeat: 0,
+            }),
+        }
+    }
+    Some(timeline)
+}
+
+impl Mpd {
+    pub fn render(&self) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out.push_str("<MPD xmlns=\"urn:mpeg:dash:schema:mpd:2011\"");
+        out.push_str(" profiles=\"urn:mpeg:dash:profile:isoff-live:2011\"");
+        match self.mpd_type {
+            MpdType::Static {
+                media_presentation_duration_ms,
+            } => {
+                let _ = write!(
+                    out,
+                    " type=\"static\" mediaPresentationDuration=\"{}\"",
+                    duration(media_presentation_duration_ms)
+                );
+            }
+            MpdType::Dynamic {
+                availability_start_time,
+                publish_time,
+                time_shift_buffer_depth_ms,
+                minimum_update_period_ms,
+            } => {
+                let _ = write!(
+                    out,
+                    " type=\"dynamic\" availabilityStartTime=\"{}\" publishTime=\"{}\" \
+                     timeShiftBufferDepth=\"{}\" minimumUpdatePeriod=\"{}\"",
+                    format_date_time(availability_start_time),
+                    format_date_time(publish_time),
+                    duration(time_shift_buffer_depth_ms),
+                    duration(minimum_update_period_ms)
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            " minBufferTime=\"{}\">",
+            duration(self.min_buffer_time_ms)
+        );
+        for period in &self.periods {
+            let _ = writeln!(
+                out,
+                "  <Period id=\"{}\" start=\"{}\">",
+                escape(&period.id),
+                duration(period.start_ms)
+            );
+            for adaptation_set in &period.adaptation_sets {
+                render_adaptation_set(&mut out, adaptation_set);
+            }
+            out.push_str("  </Period>\n");
+        }
+        out.push_str("</MPD>\n");
+        out
+    }
+}
+
+fn render_adaptation_set(out: &mut String, adaptation_set: &AdaptationSet) {
+    let content_type = match adaptation_set.content_type {
+        ContentType::Video => "video",
+        ContentType::Audio => "audio",
+        ContentType::Text => "text",
+    };
+    let _ = write!(
+        out,
+        "    <AdaptationSet id=\"{}\" contentType=\"{content_type}\" segmentAlignment=\"true\"",
+        adaptation_set.id
+    );
+    if let Some(lang) = &adaptation_set.lang {
+        let _ = write!(out, " lang=\"{}\"", escape(lang));
+    }
+    out.push_str(">\n");
+    for role in &adaptation_set.roles {
+        let _ = writeln!(
+            out,
+            "      <Role schemeIdUri=\"{ROLE_SCHEME}\" value=\"{}\"/>",
+            escape(role)
+        );
+    }
+    for representation in &adaptation_set.representations {
+        render_representation(out, representation);
+    }
+    out.push_str("    </AdaptationSet>\n");
+}
+
+fn render_representation(out: &mut String, representation: &Representation) {
+    let _ = write!(
+        out,
+        "      <Representation id=\"{}\" mimeType=\"{}\" codecs=\"{}\" bandwidth=\"{}\"",
+        escape(&representation.id),
+        escape(&representation.mime_type),
+        escape(&representation.codecs),
+        representation.bandwidth
+    );
+    if let Some((width, height)) = representation.resolution {
+        let _ = write!(out, " width=\"{width}\" height=\"{height}\"");
+    }
+    if let Some(rate) = representation.audio_sampling_rate {
+        let _ = write!(out, " audioSamplingRate=\"{rate}\"");
+    }
+    out.push_str(">\n");
+    if let Some(channels) = representation.audio_channels {
+        let _ = writeln!(
+            out,
+            "        <AudioChannelConfiguration schemeIdUri=\"{AUDIO_CHANNEL_CONFIGURATION_SCHEME}\" \
+             value=\"{channels}\"/>"
+        );
+    }
+    let template = &representation.segment_template;
+    let _ = writeln!(
+        out,
+        "        <SegmentTemplate timescale=\"{}\" initialization=\"{}\" media=\"{}\" \
+         startNumber=\"{}\">",
+        template.timescale,
+        escape(&template.initialization),
+        escape(&template.media),
+        template.start_number
+    );
+    out.push_str("          <SegmentTimeline>\n");
+    let mut next_start = None;
+    for entry in &template.timeline {
+        out.push_str("            <S");
+        // `t` is only needed where the timeline does not continue on.
+        if next_start != Some(entry.start) {
+            let _ = write!(out, " t=\"{}\"", entry.start);
+        }
+        let _ = write!(out, " d=\"{}\"", entry.duration);
+        if entry.repeat > 0 {
+            let _ = write!(out, " r=\"{}\"", entry.repeat);
+        }
+        out.push_str("/>\n");
+        next_start = Some(entry.end());
+    }
+    out.push_str("          </SegmentTimeline>\n");
+    out.push_str("        </SegmentTemplate>\n");
+    out.push_str("      </Representation>\n");
+}
+
+/// An `xs:duration` in seconds with millisecond precision.
+fn duration(ms: u64) -> String {
+    match ms % 1_000 {
+        0 => format!("PT{}S", ms / 1_000),
+        fraction => format!("PT{}.{fraction:03}S", ms / 1_000),
+    }
+}
+
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::tests::config;
+    use crate::fmp4::{
+        box_fmp4_with_tracks, AudioTrack, Config, FileType, FragmentMedia, TrackMetadata,
+    };
+    use crate::mp4::{write_init_segment, AudioInit, MediaInit, TrackInit};
+    use access_unit::{AccessUnit, Fmp4, PSI_STREAM_H264};
+    use bytes::Bytes;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn segment(seq: u32, dts: u64, next_dts: u64) -> Fmp4 {
+        let aac = access_unit::aac::create_adts_header(0x66, 2, 48_000, 1, false);
+        box_fmp4_with_tracks(
+            seq,
+            Config {
+                file_type: FileType::dash_header(),
+                segment_type: Some(FileType::dash_segment()),
+                ..config()
+            },
+            FragmentMedia {
+                video: vec![AccessUnit {
+                    key: true,
+                    pts: dts,
+                    dts,
+                    data: Bytes::from_static(&[0, 0, 0, 1, 0x65]),
+                    stream_type: PSI_STREAM_H264,
+                    id: 0,
+                }],
+                audio: vec![AudioTrack {
+                    track_id: 2,
+                    config: None,
+                    metadata: TrackMetadata::default(),
+                    units: vec![AccessUnit {
+                        key: true,
+                        pts: dts / 90,
+                        dts: dts / 90,
+                        data: Bytes::from([aac.as_slice(), &[0x21]].concat()),
+                        stream_type: 0,
+                        id: 1,
+                    }],
+                }],
+                ..FragmentMedia::default()
+            },
+            next_dts,
+            true,
+        )
+    }
+
+    #[test]
+    fn timeline_folds_equal_contiguous_segments_and_restarts_after_gaps() {
+        let segments = [
+            segment(1, 0, 3_000),
+            segment(2, 3_000, 6_000),
+            segment(3, 9_000, 10_500),
+        ];
+        let init = segments[0].init.clone().expect("init segment");
+        let data: Vec<Bytes> = segments
+            .iter()
+            .map(|segment| segment.data.clone())
+            .collect();
+
+        assert_eq!(
+            segment_timeline(&init, 1, &data),
+            Some(vec![
+                TimelineEntry {
+                    start: 0,
+                    duration: 3_000,
+                    repeat: 1,
+                },
+                TimelineEntry {
+                    start: 9_000,
+                    duration: 1_500,
+                    repeat: 0,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn static_mpd_snapshot_describes_boxer_output() {
+        let first = segment(1, 0, 3_000);
+        let second = segment(2, 3_000, 6_000);
+        let init = first.init.clone().expect("init segment");
+        let data = [first.data, second.data];
+        let template = |kind: &str| {
+            SegmentTemplate::new(format!("{kind}/init.mp4"), format!("{kind}/$Number$.m4s"))
+        };
+        let mut video = Representation::from_init("video", 800_000, &init, 1, template("video"))
+            .expect("video");
+        video.segment_template.timeline = segment_timeline(&init, 1, &data).expect("timeline");
+        let mut audio = Representation::from_init("audio", 128_000, &init, 2, template("audio"))
+            .expect("audio");
+        audio.segment_template.timeline = segment_timeline(&init, 2, &data).expect("timeline");
+        let mpd = Mpd {
+            mpd_type: MpdType::Static {
+                media_presentation_duration_ms: 67,
+            },
+            min_buffer_time_ms: 2_000,
+            periods: vec![Period {
+                id: "0".to_owned(),
+                start_ms: 0,
+                adaptation_sets: vec![
+                    AdaptationSet {
+                        id: 0,
+                        content_type: ContentType::Video,
+                        lang: None,
+                        roles: Vec::new(),
+                        representations: vec![video],
+                    },
+                    AdaptationSet {
+                        id: 1,
+                        content_type: ContentType::Audio,
+                        lang: Some("en".to_owned()),
+                        roles: vec!["main".to_owned()],
+                        representations: vec![audio],
+                    },
+                ],
+            }],
+        };
+
+        assert_eq!(
+            mpd.render(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" type="static" mediaPresentationDuration="PT0.067S" minBufferTime="PT2S">
+  <Period id="0" start="PT0S">
+    <AdaptationSet id="0" contentType="video" segmentAlignment="true">
+      <Representation id="video" mimeType="video/mp4" codecs="avc1.42001E" bandwidth="800000" width="640" height="360">
+        <SegmentTemplate timescale="90000" initialization="video/init.mp4" media="video/$Number$.m4s" startNumber="1">
+          <SegmentTimeline>
+            <S t="0" d="3000" r="1"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet id="1" contentType="audio" segmentAlignment="true" lang="en">
+      <Role schemeIdUri="urn:mpeg:dash:role:2011" value="main"/>
+      <Representation id="audio" mimeType="audio/mp4" codecs="mp4a.40.2" bandwidth="128000" audioSamplingRate="48000">
+        <AudioChannelConfiguration schemeIdUri="urn:mpeg:dash:23003:3:audio_channel_configuration:2011" value="2"/>
+        <SegmentTemplate timescale="48000" initialization="audio/init.mp4" media="audio/$Number$.m4s" startNumber="1">
+          <SegmentTimeline>
+            <S t="0" d="1024"/>
+            <S t="1584" d="1024"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>
+"#
+        );
+    }
+
+    #[test]
+    fn flac_representation_takes_sampling_rate_from_the_sample_entry() {
+        let mut init = Vec::new();
+        write_init_segment(
+            &mut init,
+            &FileType::dash_header(),
+            1_000,
+            &[TrackInit {
+                media: MediaInit::Audio(AudioInit::Flac {
+                    track_id: 1,
+                    channel_count: 2,
+                    sample_size: 16,
+                    sample_rate: 44_100,
+                    streaminfo: vec![0; 34],
+                }),
+                metadata: TrackMetadata::default(),
+                encryption: None,
+            }],
+            &[],
+        )
+        .expect("init segment");
+        let flac = Representation::from_init(
+            "flac",
+            900_000,
+            &init,
+            1,
+            SegmentTemplate::new("init.mp4", "$Number$.m4s"),
+        )
+        .expect("representation");
+
+        assert_eq!(flac.codecs, "fLaC");
+        assert_eq!(flac.audio_sampling_rate, Some(44_100));
+        assert_eq!(flac.audio_channels, Some(2));
+        assert_eq!(flac.segment_template.timescale, 1_000);
+    }
+
+    #[test]
+    fn dynamic_mpd_writes_availability_and_time_shift() {
+        let mpd = Mpd {
+            mpd_type: MpdType::Dynamic {
+                availability_start_time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                publish_time: UNIX_EPOCH + Duration::from_millis(1_700_000_030_500),
+                time_shift_buffer_depth_ms: 30_000,
+                minimum_update_period_ms: 2_000,
+            },
+            min_buffer_time_ms: 1_500,
+            periods: Vec::new(),
+        };
+
+        assert!(mpd.render().contains(
+            " type=\"dynamic\" availabilityStartTime=\"2023-11-14T22:13:20.000Z\" \
+             publishTime=\"2023-11-14T22:13:50.500Z\" timeShiftBufferDepth=\"PT30S\" \
+             minimumUpdatePeriod=\"PT2S\" minBufferTime=\"PT1.500S\">"
+        ));
+    }
+}
@@ -1,13 +1,23 @@
+use crate::captions::CaptionFrame;
+use crate::cenc::Encryption;
+use crate::id3::ID3_MIME_FORMAT;
 use crate::mp4::{
-    self, AacProfile, AudioInit, ChannelConfiguration, FragmentSample, FragmentTrack, SampleFlags,
-    SamplingFrequency, VideoInit,
+    self, AacProfile, AudioInit, ChannelConfiguration, FragmentSample, FragmentTrack, MediaInit,
+    MetadataInit, ProducerReferenceTime, SampleEncryptionEntry, SampleFlags, SamplingFrequency,
+    SegmentPrefix, TextInit, TrackInit, VideoInit,
 };
-pub use crate::mp4::{AdtsHeader, AvcDecoderConfigurationRecord};
+pub use crate::mp4::{
+    AdtsHeader, AvcDecoderConfigurationRecord, EventMessage, EventPresentationTime, FileType,
+    TrackKind, TrackMetadata,
+};
+use crate::webvtt::{webvtt_samples, WebVttCue};
 use access_unit::aac::extract_aac_data;
 use access_unit::flac::{create_streaminfo, decode_frame_header};
 use access_unit::{detect_audio, Fmp4};
 use access_unit::{AccessUnit, AudioType};
 use bytes::Bytes;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn ticks_to_hz(ticks: u64, target_hz: u32) -> u64 {
     ticks
@@ -24,6 +34,21 @@ pub fn ticks_to_ms(ticks: u64) -> u64 {
     ticks_to_hz(ticks, 1_000)
 }
 
+/// Audio presentation time in milliseconds. AAC units boxed with
+/// `AudioTrackConfig::Aac` are timed in the sample-rate clock of their ADTS
+/// header; everything else in milliseconds.
+pub(crate) fn audio_time_ms(unit: &AccessUnit, config: Option<AudioTrackConfig>) -> u64 {
+    match config {
+        Some(AudioTrackConfig::Aac) => AdtsHeader::read_from(&unit.data)
+            .map(|header| header.sampling_frequency.as_u32())
+            .filter(|rate| *rate > 0)
+            .map_or(unit.pts, |rate| {
+                unit.pts.saturating_mul(1_000) / u64::from(rate)
+            }),
+        _ => unit.pts,
+    }
+}
+
 fn u64_to_u32_saturating(value: u64) -> u32 {
     value.min(u64::from(u32::MAX)) as u32
 }
@@ -60,6 +85,33 @@ pub struct Config {
     pub width: u16,
     pub height: u16,
     pub avcc: Option<AvcDecoderConfigurationRecord>,
+    /// Track id of the video track, 1 by default. It must differ from the
+    /// ids of every other track in the output.
+    pub video_track_id: u32,
+    /// Labels for the video track's init segment entry.
+    pub video_metadata: TrackMetadata,
+    /// Brands of the init segment's `ftyp`.
+    pub file_type: FileType,
+    /// When set, each media segment starts with an `styp` of these brands.
+    pub segment_type: Option<FileType>,
+    /// Protects the video and audio tracks with Common Encryption. Subtitle
+    /// and metadata tracks stay clear.
+    pub encryption: Option<Encryption>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            avcc: None,
+            video_track_id: 1,
+            video_metadata: TrackMetadata::default(),
+            file_type: FileType::default(),
+            segment_type: None,
+            encryption: None,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -107,6 +159,31 @@ pub enum AudioTrackConfig {
     Opus(OpusAudioConfig),
 }
 
+impl AudioTrackConfig {
+    /// The sample entry of a PCM or Opus track, which the configuration
+    /// fully describes. AAC needs an ADTS header for its parameters.
+    fn init(self, track_id: u32) -> Option<AudioInit> {
+        match self {
+            AudioTrackConfig::Aac => None,
+            AudioTrackConfig::Pcm(pcm) => Some(AudioInit::Pcm {
+                track_id,
+                channel_count: pcm.channel_count,
+                sample_size: pcm.sample_size,
+                sample_rate: pcm.sample_rate,
+                little_endian: pcm.little_endian,
+                floating_point: matches!(pcm.sample_kind, PcmSampleKind::Float),
+            }),
+            AudioTrackConfig::Opus(opus) => opus.is_valid().then_some(AudioInit::Opus {
+                track_id,
+                input_sample_rate: opus.input_sample_rate,
+                channel_count: opus.channel_count,
+                pre_skip: opus.pre_skip,
+                output_gain: opus.output_gain,
+            }),
+        }
+    }
+}
+
 pub const OPUS_OUTPUT_SAMPLE_RATE: u32 = 48_000;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -197,17 +274,403 @@ pub fn box_fmp4_with_init_and_audio_config(
     include_init: bool,
     audio_config: Option<AudioTrackConfig>,
 ) -> Fmp4 {
-    let mut fmp4_data: Vec<u8> = Vec::new();
-    let mut init_data: Vec<u8> = Vec::new();
+    let audio_track_id = if config.avcc.is_some() && config.video_track_id == 1 {
+        2
+    } else {
+        1
+    };
+    box_fmp4_with_tracks(
+        seq,
+        config,
+        FragmentMedia {
+            video: avcs,
+            audio: vec![AudioTrack {
+                track_id: audio_track_id,
+                config: audio_config,
+                metadata: TrackMetadata::default(),
+                units: audio_units,
+            }],
+            subtitles: Vec::new(),
+            metadata: Vec::new(),
+            events: Vec::new(),
+            producer_reference: None,
+        },
+        next_dts,
+        include_init,
+    )
+}
+
+/// One audio rendition in a fragment, such as an alternate language or a
+/// commentary track.
+#[derive(Clone, Debug)]
+pub struct AudioTrack {
+    /// Must be unique within the output and must not be
+    /// `Config::video_track_id` when a video track is configured.
+    pub track_id: u32,
+    /// `None` detects the codec from the access units, as `box_fmp4` does.
+    /// PCM and Opus tracks are described in the init segment from this
+    /// configuration; AAC and detected tracks need a unit in the fragment
+    /// that carries the init segment.
+    pub config: Option<AudioTrackConfig>,
+    pub metadata: TrackMetadata,
+    pub units: Vec<AccessUnit>,
+}
+
+/// WebVTT cues for one fragment window, timed in milliseconds.
+#[derive(Clone, Debug)]
+pub struct WebVttTrack {
+    pub track_id: u32,
+    pub metadata: TrackMetadata,
+    /// WebVTT file header stored in `vttC`, normally just `WEBVTT`.
+    pub header: String,
+    /// The fragment covers `start..end` without gaps; intervals with no cue
+    /// are written as empty `vtte` samples.
+    pub start: u64,
+    pub end: u64,
+    pub cues: Vec<WebVttCue>,
+}
+
+/// One TTML document and the images it references, presented from `start`
+/// (milliseconds) for `duration` milliseconds.
+#[derive(Clone, Debug)]
+pub struct TtmlSample {
+    pub start: u64,
+    pub duration: u32,
+    pub document: Bytes,
+    /// Images such as IMSC1 PNG subtitles, stored after the document in the
+    /// same sample and described by `subs`.
+    pub images: Vec<Bytes>,
+}
+
+/// TTML or IMSC1 documents for one fragment, in an `stpp` track.
+#[derive(Clone, Debug)]
+pub struct TtmlTrack {
+    pub track_id: u32,
+    pub metadata: TrackMetadata,
+    /// Space-separated XML namespaces, normally `http://www.w3.org/ns/ttml`.
+    pub namespace: String,
+    pub schema_location: String,
+    /// MIME types of embedded resources, such as `image/png`.
+    pub auxiliary_mime_types: String,
+    pub mime_type: Option<String>,
+    /// Samples in presentation order. Each sample lasts until the next one
+    /// starts so the track has no gaps; the last uses its own duration.
+    pub samples: Vec<TtmlSample>,
+}
+
+/// CEA-608 caption data carried as a sidecar `c608` track.
+#[derive(Clone, Debug)]
+pub struct Cea608Track {
+    pub track_id: u32,
+    pub metadata: TrackMetadata,
+    /// Caption data per video frame, with pts in milliseconds. Each frame's
+    /// sample lasts until the next frame with CEA-608 data.
+    pub frames: Vec<CaptionFrame>,
+    /// End of the fragment window in milliseconds; the last sample runs
+    /// until here.
+    pub end: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum SubtitleTrack {
+    WebVtt(WebVttTrack),
+    Ttml(TtmlTrack),
+    Cea608(Cea608Track),
+}
+
+impl SubtitleTrack {
+    fn track_id(&self) -> u32 {
+        match self {
+            SubtitleTrack::WebVtt(track) => track.track_id,
+            SubtitleTrack::Ttml(track) => track.track_id,
+            SubtitleTrack::Cea608(track) => track.track_id,
+        }
+    }
+
+    fn metadata(&self) -> &TrackMetadata {
+        match self {
+            SubtitleTrack::WebVtt(track) => &track.metadata,
+            SubtitleTrack::Ttml(track) => &track.metadata,
+            SubtitleTrack::Cea608(track) => &track.metadata,
+        }
+    }
+
+    /// The sample entry comes from the track alone, so the init segment
+    /// describes the track even when a fragment has no samples for it.
+    fn init(&self) -> TextInit {
+        match self {
+            SubtitleTrack::WebVtt(track) => TextInit::WebVtt {
+                track_id: track.track_id,
+                config: track.header.clone(),
+            },
+            SubtitleTrack::Ttml(track) => TextInit::Ttml {
+                track_id: track.track_id,
+                namespace: track.namespace.clone(),
+                schema_location: track.schema_location.clone(),
+                auxiliary_mime_types: track.auxiliary_mime_types.clone(),
+                mime_type: track.mime_type.clone(),
+            },
+            SubtitleTrack::Cea608(track) => TextInit::Cea608 {
+                track_id: track.track_id,
+            },
+        }
+    }
+}
+
+/// One timed metadata sample, such as an ID3 tag, presented from `start`
+/// (milliseconds) for `duration` milliseconds.
+#[derive(Clone, Debug)]
+pub struct MetadataSample {
+    pub start: u64,
+    pub duration: u32,
+    pub data: Bytes,
+}
+
+/// Sample entry of a timed metadata track.
+#[derive(Clone, Debug)]
+pub enum MetadataFormat {
+    /// `mett` with the MIME type of the samples.
+    Text {
+        content_encoding: String,
+        mime_format: String,
+    },
+    /// `metx` with the XML namespace of the samples.
+    Xml {
+        content_encoding: String,
+        namespace: String,
+        schema_location: String,
+    },
+}
+
+impl MetadataFormat {
+    /// `mett` for samples that are complete ID3v2 tags.
+    pub fn id3() -> Self {
+        MetadataFormat::Text {
+            content_encoding: String::new(),
+            mime_format: ID3_MIME_FORMAT.to_owned(),
+        }
+    }
+
+    fn init(&self, track_id: u32) -> MetadataInit {
+        match self.clone() {
+            MetadataFormat::Text {
+                content_encoding,
+                mime_format,
+            } => MetadataInit::Text {
+                track_id,
+                content_encoding,
+                mime_format,
+            },
+            MetadataFormat::Xml {
+                content_encoding,
+                namespace,
+                schema_location,
+            } => MetadataInit::Xml {
+                track_id,
+                content_encoding,
+                namespace,
+                schema_location,
+            },
+        }
+    }
+}
+
+/// Timed metadata for one fragment, in a `meta` handler track.
+#[derive(Clone, Debug)]
+pub struct MetadataTrack {
+    pub track_id: u32,
+    pub metadata: TrackMetadata,
+    pub format: MetadataFormat,
+    /// Samples in presentation order, timed like `TtmlTrack` samples.
+    pub samples: Vec<MetadataSample>,
+}
+
+/// Access units for every track carried by one fragment.
+#[derive(Clone, Debug, Default)]
+pub struct FragmentMedia {
+    pub video: Vec<AccessUnit>,
+    pub audio: Vec<AudioTrack>,
+    pub subtitles: Vec<SubtitleTrack>,
+    pub metadata: Vec<MetadataTrack>,
+    /// In-band events written as `emsg` boxes ahead of the fragment's `moof`.
+    pub events: Vec<EventMessage>,
+    /// Written as a `prft` box ahead of the fragment's `moof`.
+    pub producer_reference: Option<ProducerReference>,
+}
+
+/// Source of wall-clock time for `prft` boxes, so tests can use a fixed clock.
+pub trait WallClock {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl WallClock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl<F: Fn() -> SystemTime> WallClock for F {
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
+
+/// The moment a `prft` wall-clock time describes, from ISO/IEC 14496-12.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProducerReferenceKind {
+    #[default]
+    EncoderInput,
+    EncoderOutput,
+    Finalized,
+    Written,
+    /// A fixed but otherwise unspecified relation to the media.
+    Consistent,
+    Captured,
+}
+
+impl ProducerReferenceKind {
+    fn flags(self) -> u32 {
+        match self {
+            ProducerReferenceKind::EncoderInput => 0,
+            ProducerReferenceKind::EncoderOutput => 1,
+            ProducerReferenceKind::Finalized => 2,
+            ProducerReferenceKind::Written => 4,
+            ProducerReferenceKind::Consistent => 8,
+            ProducerReferenceKind::Captured => 24,
+        }
+    }
+}
+
+/// Wall-clock time of the first sample of `reference_track_id` in the
+/// fragment. The track's base media decode time becomes the `prft` media time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProducerReference {
+    pub reference_track_id: u32,
+    pub wall_clock: SystemTime,
+    pub kind: ProducerReferenceKind,
+}
+
+impl ProducerReference {
+    pub fn from_clock(
+        clock: &impl WallClock,
+        reference_track_id: u32,
+        kind: ProducerReferenceKind,
+    ) -> Self {
+        Self {
+            reference_track_id,
+            wall_clock: clock.now(),
+            kind,
+        }
+    }
+}
+
+/// Convert to a 64-bit NTP timestamp. Times before 1970 are rejected; seconds
+/// wrap at the NTP era boundary in 2036 as the format requires.
+pub fn ntp_timestamp(time: SystemTime) -> Option<u64> {
+    const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+    let since_unix = time.duration_since(UNIX_EPOCH).ok()?;
+    let seconds = since_unix.as_secs().wrapping_add(NTP_UNIX_OFFSET_SECS) & 0xffff_ffff;
+    let fraction = (u64::from(since_unix.subsec_nanos()) << 32) / 1_000_000_000;
+    Some((seconds << 32) | fraction)
+}
+
+/// Box one fragment containing the video track (id 1) and any number of audio,
+/// subtitle and timed metadata tracks. Each track gets its own `trak`/`trex` in the init segment and its
+/// own `traf` in a single `moof`; media data is laid out track by track in one
+/// `mdat`.
+pub fn box_fmp4_with_tracks(
+    seq: u32,
+    config: Config,
+    media: FragmentMedia,
+    next_dts: u64,
+    include_init: bool,
+) -> Fmp4 {
+    box_fmp4_chunks(seq, config, media, next_dts, include_init).into_fmp4()
+}
+
+/// A media segment as a list of buffers for vectored I/O: the boxes up to the
+/// `mdat` payload, then the sample payloads.
+#[derive(Clone, Debug, Default)]
+pub struct Fmp4Chunks {
+    pub init: Option<Bytes>,
+    pub duration: u32,
+    pub key: bool,
+    /// Concatenated, these are the segment's bytes. Empty when the segment
+    /// could not be written.
+    pub chunks: Vec<Bytes>,
+}
+
+impl Fmp4Chunks {
+    /// Segment length in bytes.
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(Bytes::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.iter().all(Bytes::is_empty)
+    }
+
+    /// Write the segment, without the init segment, chunk by chunk.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for chunk in &self.chunks {
+            writer.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Join the chunks into one buffer, copying only when there is more
+    /// than one.
+    pub fn into_fmp4(self) -> Fmp4 {
+        let data = match <[Bytes; 1]>::try_from(self.chunks) {
+            Ok([chunk]) => chunk,
+            Err(chunks) => {
+                let mut data = Vec::with_capacity(chunks.iter().map(Bytes::len).sum());
+                for chunk in &chunks {
+                    data.extend_from_slice(chunk);
+                }
+                Bytes::from(data)
+            }
+        };
+        Fmp4 {
+            init: self.init,
+            duration: self.duration,
+            key: self.key,
+            data,
+        }
+    }
+}
+
+/// Box one fragment like [`box_fmp4_with_tracks`] without copying the media:
+/// the first chunk holds the boxes up to the `mdat` payload and the rest
+/// share the access units' buffers. Encrypted tracks are copied once to be
+/// encrypted.
+pub fn box_fmp4_chunks(
+    seq: u32,
+    config: Config,
+    mut media: FragmentMedia,
+    next_dts: u64,
+    include_init: bool,
+) -> Fmp4Chunks {
+    let mut header: Vec<u8> = Vec::new();
     let mut total_ticks: u64 = 0;
     let mut is_key = false;
     let has_video_track = config.avcc.is_some();
-    let mut avc_data = Vec::with_capacity(avcs.iter().map(|unit| unit.data.len()).sum());
-    let mut audio_data: Vec<u8> =
-        Vec::with_capacity(audio_units.iter().map(|unit| unit.data.len()).sum());
+    let track_ids = has_video_track
+        .then_some(config.video_track_id)
+        .into_iter()
+        .chain(media.audio.iter().map(|track| track.track_id))
+        .chain(media.subtitles.iter().map(SubtitleTrack::track_id))
+        .chain(media.metadata.iter().map(|track| track.track_id));
+    if mp4::has_duplicate_track_ids(track_ids) {
+        return Fmp4Chunks::default();
+    }
+    let avcs = std::mem::take(&mut media.video);
+    let mut avc_payloads = Vec::with_capacity(avcs.len());
 
     let mut avc_samples = Vec::with_capacity(avcs.len());
-    let mut audio_samples = Vec::with_capacity(audio_units.len());
 
     let mut avc_timestamps = Vec::with_capacity(avcs.len().saturating_add(1));
     let mut video_base_media_decode_time = None;
@@ -218,9 +681,8 @@ pub fn box_fmp4_with_init_and_audio_config(
                 is_key = true;
             }
 
-            let prev_data_len = avc_data.len();
-            avc_data.extend_from_slice(&a.data);
-            let sample_size = (avc_data.len() - prev_data_len) as u32;
+            let sample_size = a.data.len() as u32;
+            avc_payloads.push(a.data.clone());
             let sample_composition_time_offset = composition_time_offset(a.pts, a.dts);
 
             avc_timestamps.push(a.dts);
@@ -267,17 +729,725 @@ pub fn box_fmp4_with_init_and_audio_config(
         is_key = true
     }
 
-    let audio_track_id = if has_video_track { 2 } else { 1 };
+    let mut audio_fragments: Vec<AudioFragment> = media
+        .audio
+        .iter()
+        .map(|track| box_audio_track(track.track_id, &track.units, track.config))
+        .collect();
+    let mut subtitle_fragments: Vec<SubtitleFragment> =
+        media.subtitles.iter().map(box_subtitle_track).collect();
+    let mut metadata_fragments: Vec<SubtitleFragment> = media
+        .metadata
+        .iter()
+        .map(|track| box_metadata_track(track).unwrap_or_default())
+        .collect();
+
+    let (video_encryption, audio_encryption) = match config.encryption.as_ref() {
+        Some(encryption) => {
+            let video = (has_video_track && !avc_samples.is_empty())
+                .then(|| (&avc_samples[..], &mut avc_payloads));
+            match encrypt_fragment(encryption, video, &mut audio_fragments) {
+                Some(entries) => entries,
+                // Never write media that should be protected in the clear.
+                None => return Fmp4Chunks::default(),
+            }
+        }
+        None => (Vec::new(), vec![Vec::new(); audio_fragments.len()]),
+    };
+
+    let mut tracks = Vec::with_capacity(
+        audio_fragments
+            .len()
+            .saturating_add(subtitle_fragments.len())
+            .saturating_add(metadata_fragments.len())
+            .saturating_add(1),
+    );
+    if payloads_len(&avc_payloads) > 0 && !avc_samples.is_empty() {
+        tracks.push(FragmentTrack {
+            track_id: config.video_track_id,
+            base_media_decode_time: video_base_media_decode_time.unwrap_or(0),
+            samples: avc_samples,
+            data: &avc_payloads,
+            subsample_sizes: Vec::new(),
+            sample_encryption: video_encryption,
+        });
+    }
+    for ((track, fragment), sample_encryption) in media
+        .audio
+        .iter()
+        .zip(&audio_fragments)
+        .zip(audio_encryption)
+    {
+        if fragment.init.is_some()
+            && payloads_len(&fragment.payloads) > 0
+            && !fragment.samples.is_empty()
+        {
+            tracks.push(FragmentTrack {
+                track_id: track.track_id,
+                base_media_decode_time: fragment.base_media_decode_time.unwrap_or(0),
+                samples: fragment.samples.clone(),
+                data: &fragment.payloads,
+                subsample_sizes: Vec::new(),
+                sample_encryption,
+            });
+        }
+    }
+    let subtitle_payloads: Vec<[Bytes; 1]> = subtitle_fragments
+        .iter_mut()
+        .map(|fragment| [Bytes::from(std::mem::take(&mut fragment.data))])
+        .collect();
+    for ((track, fragment), payload) in media
+        .subtitles
+        .iter()
+        .zip(&subtitle_fragments)
+        .zip(&subtitle_payloads)
+    {
+        if !fragment.samples.is_empty() {
+            tracks.push(FragmentTrack {
+                track_id: track.track_id(),
+                base_media_decode_time: fragment.base_media_decode_time,
+                samples: fragment.samples.clone(),
+                data: payload,
+                subsample_sizes: fragment.subsample_sizes.clone(),
+                sample_encryption: Vec::new(),
+            });
+        }
+    }
+    let metadata_payloads: Vec<[Bytes; 1]> = metadata_fragments
+        .iter_mut()
+        .map(|fragment| [Bytes::from(std::mem::take(&mut fragment.data))])
+        .collect();
+    for ((track, fragment), payload) in media
+        .metadata
+        .iter()
+        .zip(&metadata_fragments)
+        .zip(&metadata_payloads)
+    {
+        if !fragment.samples.is_empty() {
+            tracks.push(FragmentTrack {
+                track_id: track.track_id,
+                base_media_decode_time: fragment.base_media_decode_time,
+                samples: fragment.samples.clone(),
+                data: payload,
+                subsample_sizes: Vec::new(),
+                sample_encryption: Vec::new(),
+            });
+        }
+    }
+    let producer_reference = media.producer_reference.and_then(|reference| {
+        let track = tracks
+            .iter()
+            .find(|track| track.track_id == reference.reference_track_id)?;
+        Some(ProducerReferenceTime {
+            reference_track_id: reference.reference_track_id,
+            ntp_timestamp: ntp_timestamp(reference.wall_clock)?,
+            media_time: track.base_media_decode_time,
+            flags: reference.kind.flags(),
+        })
+    });
+    let written = mp4::write_media_segment_header(
+        &mut header,
+        seq,
+        &tracks,
+        SegmentPrefix {
+            segment_type: config.segment_type.as_ref(),
+            producer_reference: producer_reference.as_ref(),
+            events: &media.events,
+        },
+    );
+    let chunks = match written {
+        Some(()) => std::iter::once(Bytes::from(header))
+            .chain(tracks.iter().flat_map(|track| track.data.iter().cloned()))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let init = if include_init {
+        let audio_inits = audio_fragments.iter().map(|fragment| fragment.init.clone());
+        match fragment_init(&config, &media, audio_inits) {
+            Some(init) => Some(init),
+            None => return Fmp4Chunks::default(),
+        }
+    } else {
+        None
+    };
+
+    Fmp4Chunks {
+        init,
+        duration: if avcs.is_empty() {
+            audio_fragments
+                .iter()
+                .map(|fragment| fragment.duration_ms)
+                .chain(
+                    subtitle_fragments
+                        .iter()
+                        .chain(&metadata_fragments)
+                        .map(|fragment| fragment.duration_ms),
+                )
+                .max()
+                .unwrap_or(0)
+        } else {
+            ticks_to_ms(total_ticks) as u32
+        },
+        key: is_key,
+        chunks,
+    }
+}
+
+/// The init segment for the tracks of `media`, with the audio sample entries
+/// in `audio_inits`. An audio track without one is left out.
+fn fragment_init(
+    config: &Config,
+    media: &FragmentMedia,
+    audio_inits: impl IntoIterator<Item = Option<AudioInit>>,
+) -> Option<Bytes> {
+    let has_video_track = config.avcc.is_some();
+    let mut track_inits = Vec::new();
+    if let Some(avcc) = config.avcc.as_ref() {
+        track_inits.push(TrackInit {
+            media: MediaInit::Video(VideoInit {
+                track_id: config.video_track_id,
+                width: config.width,
+                height: config.height,
+                avcc: avcc.clone(),
+            }),
+            metadata: config.video_metadata.clone(),
+            encryption: config
+                .encryption
+                .as_ref()
+                .map(|encryption| encryption.track_encryption(true)),
+        });
+    }
+    for (track, audio_init) in media.audio.iter().zip(audio_inits) {
+        if let Some(audio_init) = audio_init {
+            track_inits.push(TrackInit {
+                media: MediaInit::Audio(audio_init),
+                metadata: track.metadata.clone(),
+                encryption: config
+                    .encryption
+                    .as_ref()
+                    .map(|encryption| encryption.track_encryption(false)),
+            });
+        }
+    }
+    for track in &media.subtitles {
+        track_inits.push(TrackInit {
+            media: MediaInit::Text(track.init()),
+            metadata: track.metadata().clone(),
+            encryption: None,
+        });
+    }
+    for track in &media.metadata {
+        track_inits.push(TrackInit {
+            media: MediaInit::Metadata(track.format.init(track.track_id)),
+            metadata: track.metadata.clone(),
+            encryption: None,
+        });
+    }
+    let movie_timescale = if has_video_track {
+        90_000
+    } else {
+        track_inits
+            .first()
+            .map(|track| track.media.timescale())
+            .unwrap_or(1_000)
+    };
+    let mut init = Vec::new();
+    mp4::write_init_segment(
+        &mut init,
+        &config.file_type,
+        movie_timescale,
+        &track_inits,
+        config
+            .encryption
+            .as_ref()
+            .map_or(&[][..], |encryption| &encryption.protection_systems),
+    )?;
+    Some(Bytes::from(init))
+}
+
+fn payloads_len(payloads: &[Bytes]) -> usize {
+    payloads.iter().map(Bytes::len).sum()
+}
+
+/// Encrypt a fragment's video and audio payloads, each track into one new
+/// buffer, returning the `senc` entries of the video track and of each audio
+/// track.
+fn encrypt_fragment(
+    encryption: &Encryption,
+    video: Option<(&[FragmentSample], &mut Vec<Bytes>)>,
+    audio_fragments: &mut [AudioFragment],
+) -> Option<(Vec<SampleEncryptionEntry>, Vec<Vec<SampleEncryptionEntry>>)> {
+    let video_entries = match video {
+        Some((samples, payloads)) => encrypt_payloads(payloads, |data| {
+            encryption.encrypt_track(true, samples, data)
+        })?,
+        None => Vec::new(),
+    };
+    let audio_entries = audio_fragments
+        .iter_mut()
+        .map(|fragment| {
+            encrypt_payloads(&mut fragment.payloads, |data| {
+                encryption.encrypt_track(false, &fragment.samples, data)
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((video_entries, audio_entries))
+}
+
+/// Copy a track's payloads into one buffer, encrypt it in place with
+/// `encrypt` and make it the track's only payload.
+fn encrypt_payloads<T>(
+    payloads: &mut Vec<Bytes>,
+    encrypt: impl FnOnce(&mut [u8]) -> Option<T>,
+) -> Option<T> {
+    let mut data = Vec::with_capacity(payloads_len(payloads));
+    for payload in payloads.iter() {
+        data.extend_from_slice(payload);
+    }
+    let result = encrypt(&mut data)?;
+    *payloads = vec![Bytes::from(data)];
+    Some(result)
+}
+
+/// One `moof`+`mdat` chunk of a chunked segment, such as an LL-HLS part.
+#[derive(Clone, Debug)]
+pub struct Fmp4Part {
+    /// Byte offset of the chunk within the segment data.
+    pub offset: usize,
+    pub data: Bytes,
+    pub duration: u32,
+    /// The chunk starts with a video sync sample, or carries no video.
+    pub independent: bool,
+}
+
+/// A segment written as several chunks, and where each chunk lies.
+#[derive(Clone, Debug)]
+pub struct ChunkedFmp4 {
+    /// The whole segment; `data` is every part concatenated.
+    pub segment: Fmp4,
+    pub parts: Vec<Fmp4Part>,
+}
+
+/// Box one segment as consecutive `moof`+`mdat` chunks of about
+/// `part_target_ms` each, for low-latency CMAF and LL-HLS parts.
+///
+/// Chunks are cut at video samples, or at samples of the first audio track
+/// when there is no video; a chunk closes at the first sample at or past the
+/// target. Other tracks are split on the same timeline. Chunks take sequence
+/// numbers `seq`, `seq + 1`, ..., so the next segment should start at
+/// `seq + parts.len()`. Events and the producer reference are written with
+/// the first chunk; when `config.segment_type` is set, later chunks get a
+/// `cmfl` `styp`.
+pub fn box_fmp4_chunked(
+    seq: u32,
+    config: Config,
+    media: FragmentMedia,
+    next_dts: u64,
+    include_init: bool,
+    part_target_ms: u32,
+) -> ChunkedFmp4 {
+    // The init segment describes every track of the segment, including those
+    // with no samples in the first chunk.
+    let init = if include_init {
+        let audio_inits = media
+            .audio
+            .iter()
+            .map(|track| box_audio_track(track.track_id, &track.units, track.config).init);
+        match fragment_init(&config, &media, audio_inits) {
+            Some(init) => Some(init),
+            None => {
+                return ChunkedFmp4 {
+                    segment: Fmp4Chunks::default().into_fmp4(),
+                    parts: Vec::new(),
+                }
+            }
+        }
+    } else {
+        None
+    };
+    let part_media = split_fragment_media(media, next_dts, config.avcc.is_some(), part_target_ms);
+    let mut data = Vec::new();
+    let mut parts = Vec::with_capacity(part_media.len());
+    for (index, (media, part_next_dts)) in part_media.into_iter().enumerate() {
+        let mut part_config = config.clone();
+        if index > 0 && part_config.segment_type.is_some() {
+            part_config.segment_type = Some(FileType::cmaf_chunk());
+        }
+        let has_video = part_config.avcc.is_some() && !media.video.is_empty();
+        let independent = !has_video || media.video.first().is_some_and(|unit| unit.key);
+        let fragment = box_fmp4_with_tracks(
+            seq.wrapping_add(index as u32),
+            part_config,
+            media,
+            part_next_dts,
+            false,
+        );
+        parts.push(Fmp4Part {
+            offset: data.len(),
+            data: fragment.data.clone(),
+            duration: fragment.duration,
+            independent,
+        });
+        data.extend_from_slice(&fragment.data);
+    }
+
+    ChunkedFmp4 {
+        segment: Fmp4 {
+            init,
+            key: parts.first().is_some_and(|part| part.independent),
+            data: Bytes::from(data),
+            duration: parts.iter().map(|part| part.duration).sum(),
+        },
+        parts,
+    }
+}
+
+/// Split one fragment's media into chunks, returning each chunk's media and
+/// the decode time that follows its last video sample.
+fn split_fragment_media(
+    media: FragmentMedia,
+    next_dts: u64,
+    has_video_track: bool,
+    part_target_ms: u32,
+) -> Vec<(FragmentMedia, u64)> {
+    // Chunk start times in milliseconds, after the first chunk.
+    let mut cuts = Vec::new();
+    let mut video_cuts = Vec::new();
+    if has_video_track && !media.video.is_empty() {
+        let mut part_start = media.video[0].dts;
+        for (index, unit) in media.video.iter().enumerate().skip(1) {
+            if ticks_to_ms(unit.dts.saturating_sub(part_start)) >= u64::from(part_target_ms) {
+                cuts.push(pts_to_ms_timescale(unit.dts));
+                video_cuts.push(index);
+                part_start = unit.dts;
+            }
+        }
+    } else if let Some(track) = media.audio.first() {
+        if let Some(first) = track.units.first() {
+            let mut part_start = audio_time_ms(first, track.config);
+            for unit in &track.units[1..] {
+                let time = audio_time_ms(unit, track.config);
+                if time.saturating_sub(part_start) >= u64::from(part_target_ms) {
+                    cuts.push(time);
+                    part_start = time;
+                }
+            }
+        }
+    }
+    if cuts.is_empty() {
+        return vec![(media, next_dts)];
+    }
+
+    let part_count = cuts.len() + 1;
+    let range = |index: usize| {
+        let start = if index == 0 { 0 } else { cuts[index - 1] };
+        let end = cuts.get(index).copied().unwrap_or(u64::MAX);
+        start..end
+    };
+    let mut parts = Vec::with_capacity(part_count);
+    for index in 0..part_count {
+        let times = range(index);
+        let video = if video_cuts.is_empty() {
+            Vec::new()
+        } else {
+            let first = if index == 0 { 0 } else { video_cuts[index - 1] };
+            let last = video_cuts.get(index).copied().unwrap_or(media.video.len());
+            media.video[first..last].to_vec()
+        };
+        let part_next_dts = video_cuts
+            .get(index)
+            .map_or(next_dts, |&cut| media.video[cut].dts);
+        let audio = media
+            .audio
+            .iter()
+            .map(|track| AudioTrack {
+                units: track
+                    .units
+                    .iter()
+                    .filter(|unit| times.contains(&audio_time_ms(unit, track.config)))
+                    .cloned()
+                    .collect(),
+                ..track.clone()
+            })
+            .collect();
+        let subtitles = media
+            .subtitles
+            .iter()
+            .filter_map(|track| split_subtitle_track(track, &times))
+            .collect();
+        let metadata = media
+            .metadata
+            .iter()
+            .map(|track| MetadataTrack {
+                samples: split_timed_samples(
+                    &track.samples,
+                    &times,
+                    |sample| sample.start,
+                    |sample, duration| sample.duration = duration,
+                ),
+                ..track.clone()
+            })
+            .collect();
+        parts.push((
+            FragmentMedia {
+                video,
+                audio,
+                subtitles,
+                metadata,
+                events: if index == 0 {
+                    media.events.clone()
+                } else {
+                    Vec::new()
+                },
+                producer_reference: if index == 0 {
+                    media.producer_reference
+                } else {
+                    None
+                },
+            },
+            part_next_dts,
+        ));
+    }
+    parts
+}
+
+fn split_subtitle_track(
+    track: &SubtitleTrack,
+    times: &std::ops::Range<u64>,
+) -> Option<SubtitleTrack> {
+    Some(match track {
+        SubtitleTrack::WebVtt(track) => {
+            let start = track.start.max(times.start);
+            let end = track.end.min(times.end);
+            if end <= start {
+                return None;
+            }
+            SubtitleTrack::WebVtt(WebVttTrack {
+                start,
+                end,
+                ..track.clone()
+            })
+        }
+        SubtitleTrack::Ttml(track) => SubtitleTrack::Ttml(TtmlTrack {
+            samples: split_timed_samples(
+                &track.samples,
+                times,
+                |sample| sample.start,
+                |sample, duration| sample.duration = duration,
+            ),
+            ..track.clone()
+        }),
+        SubtitleTrack::Cea608(track) => SubtitleTrack::Cea608(Cea608Track {
+            frames: track
+                .frames
+                .iter()
+                .filter(|frame| times.contains(&frame.pts))
+                .cloned()
+                .collect(),
+            end: track.end.min(times.end),
+            ..track.clone()
+        }),
+    })
+}
+
+/// Keep the samples starting within `times`. Each sample normally lasts until
+/// the next one starts, so a chunk's last sample is stretched to the next
+/// chunk's first sample.
+fn split_timed_samples<T: Clone>(
+    samples: &[T],
+    times: &std::ops::Range<u64>,
+    start: impl Fn(&T) -> u64,
+    set_duration: impl Fn(&mut T, u32),
+) -> Vec<T> {
+    let mut part: Vec<T> = samples
+        .iter()
+        .filter(|sample| times.contains(&start(sample)))
+        .cloned()
+        .collect();
+    let next = samples.iter().map(&start).find(|&time| time >= times.end);
+    if let (Some(last), Some(next)) = (part.last_mut(), next) {
+        let duration = next.saturating_sub(start(last));
+        set_duration(last, u64_to_u32_saturating(duration));
+    }
+    part
+}
+
+/// Samples collected for one subtitle or metadata track. A track with no
+/// samples in the fragment, or samples that cannot be written, is left out of
+/// the `moof`.
+#[derive(Default)]
+struct SubtitleFragment {
+    samples: Vec<FragmentSample>,
+    data: Vec<u8>,
+    subsample_sizes: Vec<Vec<u32>>,
+    base_media_decode_time: u64,
+    duration_ms: u32,
+}
+
+fn box_subtitle_track(track: &SubtitleTrack) -> SubtitleFragment {
+    match track {
+        SubtitleTrack::WebVtt(track) => box_webvtt_samples(track),
+        SubtitleTrack::Ttml(track) => box_ttml_samples(&track.samples),
+        SubtitleTrack::Cea608(track) => box_cea608_samples(&track.frames, track.end),
+    }
+    .unwrap_or_default()
+}
+
+fn box_webvtt_samples(track: &WebVttTrack) -> Option<SubtitleFragment> {
+    let samples = webvtt_samples(&track.cues, track.start, track.end)?;
+    let mut fragment = SubtitleFragment {
+        samples: Vec::with_capacity(samples.len()),
+        base_media_decode_time: track.start,
+        duration_ms: u64_to_u32_saturating(track.end - track.start),
+        ..SubtitleFragment::default()
+    };
+    for sample in samples {
+        fragment.samples.push(FragmentSample {
+            duration: Some(sample.duration),
+            size: Some(u32::try_from(sample.data.len()).ok()?),
+            flags: None,
+            composition_time_offset: None,
+        });
+        fragment.data.extend_from_slice(&sample.data);
+    }
+    Some(fragment)
+}
+
+fn box_metadata_track(track: &MetadataTrack) -> Option<SubtitleFragment> {
+    box_timed_samples(
+        &track.samples,
+        |sample| (sample.start, sample.duration),
+        |sample| [&sample.data],
+    )
+}
+
+/// Each sample holds a `cdat` box of field 1 byte pairs and a `cdt2` box of
+/// field 2 byte pairs, as QuickTime closed caption tracks do.
+fn box_cea608_samples(frames: &[CaptionFrame], end: u64) -> Option<SubtitleFragment> {
+    let frames: Vec<&CaptionFrame> = frames
+        .iter()
+        .filter(|frame| {
+            frame
+                .cea608_pairs(1)
+                .chain(frame.cea608_pairs(2))
+                .next()
+                .is_some()
+        })
+        .collect();
+    let first = frames.first()?;
+    let mut fragment = SubtitleFragment {
+        samples: Vec::with_capacity(frames.len()),
+        base_media_decode_time: first.pts,
+        ..SubtitleFragment::default()
+    };
+    for (index, frame) in frames.iter().enumerate() {
+        let next = frames.get(index + 1).map_or(end, |next| next.pts);
+        let duration = u32::try_from(next.checked_sub(frame.pts)?).ok()?;
+        let start = fragment.data.len();
+        for (field, name) in [(1, *b"cdat"), (2, *b"cdt2")] {
+            if frame.cea608_pairs(field).next().is_none() {
+                continue;
+            }
+            mp4::write_box(&mut fragment.data, name, |out| {
+                for pair in frame.cea608_pairs(field) {
+                    out.extend_from_slice(&pair);
+                }
+                Some(())
+            })?;
+        }
+        fragment.samples.push(FragmentSample {
+            duration: Some(duration),
+            size: Some(u32::try_from(fragment.data.len() - start).ok()?),
+            flags: None,
+            composition_time_offset: None,
+        });
+        fragment.duration_ms = fragment.duration_ms.saturating_add(duration);
+    }
+    Some(fragment)
+}
+
+fn box_ttml_samples(samples: &[TtmlSample]) -> Option<SubtitleFragment> {
+    box_timed_samples(
+        samples,
+        |sample| (sample.start, sample.duration),
+        |sample| std::iter::once(&sample.document).chain(&sample.images),
+    )
+}
+
+/// Box samples that each last until the next one starts, the last for its
+/// own duration. `timing` gives a sample's start and duration, and `parts`
+/// the buffers written back to back as its payload; a sample of more than
+/// one part gets a subsample per part.
+fn box_timed_samples<'a, T, P>(
+    samples: &'a [T],
+    timing: impl Fn(&T) -> (u64, u32),
+    parts: impl Fn(&'a T) -> P,
+) -> Option<SubtitleFragment>
+where
+    P: IntoIterator<Item = &'a Bytes>,
+{
+    let (first_start, _) = timing(samples.first()?);
+    let mut fragment = SubtitleFragment {
+        samples: Vec::with_capacity(samples.len()),
+        subsample_sizes: Vec::with_capacity(samples.len()),
+        base_media_decode_time: first_start,
+        ..SubtitleFragment::default()
+    };
+    for (index, sample) in samples.iter().enumerate() {
+        let (start, own_duration) = timing(sample);
+        let duration = match samples.get(index + 1) {
+            Some(next) => u32::try_from(timing(next).0.checked_sub(start)?).ok()?,
+            None => own_duration,
+        };
+        let offset = fragment.data.len();
+        let mut sizes = Vec::new();
+        for part in parts(sample) {
+            sizes.push(u32::try_from(part.len()).ok()?);
+            fragment.data.extend_from_slice(part);
+        }
+        fragment.samples.push(FragmentSample {
+            duration: Some(duration),
+            size: Some(u32::try_from(fragment.data.len() - offset).ok()?),
+            flags: None,
+            composition_time_offset: None,
+        });
+        // A single-part sample needs no subsample map.
+        if sizes.len() < 2 {
+            sizes.clear();
+        }
+        fragment.subsample_sizes.push(sizes);
+        fragment.duration_ms = fragment.duration_ms.saturating_add(duration);
+    }
+    Some(fragment)
+}
+
+/// Samples and codec configuration collected for one audio track.
+struct AudioFragment {
+    samples: Vec<FragmentSample>,
+    /// Sample payloads, sharing the access units' buffers.
+    payloads: Vec<Bytes>,
+    base_media_decode_time: Option<u64>,
+    init: Option<AudioInit>,
+    duration_ms: u32,
+}
+
+fn box_audio_track(
+    audio_track_id: u32,
+    audio_units: &[AccessUnit],
+    audio_config: Option<AudioTrackConfig>,
+) -> AudioFragment {
+    let mut audio_payloads = Vec::with_capacity(audio_units.len());
+    let mut audio_samples = Vec::with_capacity(audio_units.len());
 
     let mut frame_info = None;
-    let mut has_audio_track = false;
     let mut audio_base_media_decode_time = None;
-    let mut audio_init = None;
 
+    let mut audio_init = audio_config.and_then(|config| config.init(audio_track_id));
     let (audio_type, offset) = if audio_config.is_some() {
         (AudioType::Unknown, 0)
     } else {
-        detect_audio_with_offset(&audio_units)
+        detect_audio_with_offset(audio_units)
     };
 
     let mut audio_ms: u32 = 0;
@@ -291,7 +1461,7 @@ pub fn box_fmp4_with_init_and_audio_config(
             let mut channel_configuration = ChannelConfiguration::TwoChannels;
             let mut profile = AacProfile::Main;
 
-            for access_unit in &audio_units {
+            for access_unit in audio_units {
                 let Some(header) = AdtsHeader::read_from(&access_unit.data) else {
                     continue;
                 };
@@ -312,7 +1482,7 @@ pub fn box_fmp4_with_init_and_audio_config(
                     flags: None,
                     composition_time_offset: None,
                 });
-                audio_data.extend_from_slice(&frame);
+                audio_payloads.push(frame);
                 audio_base_media_decode_time.get_or_insert(access_unit.pts);
             }
 
@@ -323,12 +1493,11 @@ pub fn box_fmp4_with_init_and_audio_config(
                     frequency: sampling_frequency,
                     channel_configuration,
                 });
-                has_audio_track = true;
             }
         }
         Some(AudioTrackConfig::Pcm(pcm)) => {
             if let Some(bytes_per_frame) = pcm.bytes_per_frame() {
-                for access_unit in &audio_units {
+                for access_unit in audio_units {
                     if access_unit.data.is_empty()
                         || !access_unit.data.len().is_multiple_of(bytes_per_frame)
                     {
@@ -354,24 +1523,13 @@ pub fn box_fmp4_with_init_and_audio_config(
                         flags: None,
                         composition_time_offset: None,
                     });
-                    audio_data.extend_from_slice(&access_unit.data);
+                    audio_payloads.push(access_unit.data.clone());
                     audio_base_media_decode_time.get_or_insert(access_unit.pts);
                 }
-                if !audio_samples.is_empty() {
-                    audio_init = Some(AudioInit::Pcm {
-                        track_id: audio_track_id,
-                        channel_count: pcm.channel_count,
-                        sample_size: pcm.sample_size,
-                        sample_rate: pcm.sample_rate,
-                        little_endian: pcm.little_endian,
-                        floating_point: matches!(pcm.sample_kind, PcmSampleKind::Float),
-                    });
-                    has_audio_track = true;
-                }
             }
         }
         Some(AudioTrackConfig::Opus(opus)) if opus.is_valid() => {
-            for access_unit in &audio_units {
+            for access_unit in audio_units {
                 let Some(packet_info) = opus_packet_info(&access_unit.data) else {
                     continue;
                 };
@@ -384,7 +1542,7 @@ pub fn box_fmp4_with_init_and_audio_config(
                     flags: None,
                     composition_time_offset: None,
                 });
-                audio_data.extend_from_slice(&access_unit.data);
+                audio_payloads.push(access_unit.data.clone());
                 opus_duration_samples =
                     opus_duration_samples.saturating_add(u64::from(packet_info.duration_samples));
                 audio_base_media_decode_time.get_or_insert_with(|| {
@@ -393,28 +1551,18 @@ pub fn box_fmp4_with_init_and_audio_config(
                         .saturating_mul(u64::from(OPUS_OUTPUT_SAMPLE_RATE) / 1_000)
                 });
             }
-            if !audio_samples.is_empty() {
-                audio_init = Some(AudioInit::Opus {
-                    track_id: audio_track_id,
-                    input_sample_rate: opus.input_sample_rate,
-                    channel_count: opus.channel_count,
-                    pre_skip: opus.pre_skip,
-                    output_gain: opus.output_gain,
-                });
-                has_audio_track = true;
-                audio_ms = u64_to_u32_saturating(
-                    opus_duration_samples
-                        .saturating_mul(1_000)
-                        .saturating_add(u64::from(OPUS_OUTPUT_SAMPLE_RATE) / 2)
-                        / u64::from(OPUS_OUTPUT_SAMPLE_RATE),
-                );
-            }
+            audio_ms = u64_to_u32_saturating(
+                opus_duration_samples
+                    .saturating_mul(1_000)
+                    .saturating_add(u64::from(OPUS_OUTPUT_SAMPLE_RATE) / 2)
+                    / u64::from(OPUS_OUTPUT_SAMPLE_RATE),
+            );
         }
         Some(AudioTrackConfig::Opus(_)) => {}
         None => match audio_type {
             AudioType::Unknown => {}
             AudioType::FLAC => {
-                for a in &audio_units {
+                for a in audio_units {
                     let Some(raw_audio) = a.data.get(offset..) else {
                         continue;
                     };
@@ -436,17 +1584,13 @@ pub fn box_fmp4_with_init_and_audio_config(
                         flags: None,
                         composition_time_offset: None,
                     });
-                    audio_data.extend_from_slice(raw_audio);
+                    audio_payloads.push(a.data.slice(offset..));
                     audio_base_media_decode_time.get_or_insert(a.pts);
 
                     if frame_info.is_none() {
                         frame_info = Some(info);
                     }
                 }
-
-                if !audio_samples.is_empty() {
-                    has_audio_track = true;
-                }
             }
             AudioType::AAC => {
                 let mut sampling_frequency = SamplingFrequency::Hz48000;
@@ -470,7 +1614,7 @@ pub fn box_fmp4_with_init_and_audio_config(
                             flags: None,
                             composition_time_offset: None,
                         });
-                        audio_data.extend_from_slice(&frame);
+                        audio_payloads.push(frame);
                         audio_base_media_decode_time.get_or_insert_with(|| {
                             a.pts
                                 .saturating_mul(u64::from(aac_sample_rate))
@@ -487,7 +1631,6 @@ pub fn box_fmp4_with_init_and_audio_config(
                         frequency: sampling_frequency,
                         channel_configuration,
                     });
-                    has_audio_track = true;
                 }
             }
             _ => {}
@@ -496,86 +1639,36 @@ pub fn box_fmp4_with_init_and_audio_config(
 
     if aac_sample_rate > 0 {
         audio_ms = u64_to_u32_saturating(
-            aac_duration_samples
-                .saturating_mul(1_000)
-                .saturating_add(u64::from(aac_sample_rate) / 2)
-                / u64::from(aac_sample_rate),
-        );
-    }
-
-    if matches!(audio_type, AudioType::FLAC) {
-        if let Some(frame_info) = frame_info {
-            audio_init = Some(AudioInit::Flac {
-                track_id: audio_track_id,
-                channel_count: frame_info.channels.into(),
-                sample_size: frame_info.bps.into(),
-                sample_rate: frame_info.sample_rate,
-                streaminfo: create_streaminfo(&frame_info),
-            });
-        }
-    }
-
-    let mut tracks = Vec::with_capacity(2);
-    if !avc_data.is_empty() && !avc_samples.is_empty() {
-        tracks.push(FragmentTrack {
-            track_id: 1,
-            base_media_decode_time: video_base_media_decode_time.unwrap_or(0),
-            samples: avc_samples,
-            data: &avc_data,
-        });
-    }
-    if has_audio_track && !audio_data.is_empty() && !audio_samples.is_empty() {
-        tracks.push(FragmentTrack {
-            track_id: audio_track_id,
-            base_media_decode_time: audio_base_media_decode_time.unwrap_or(0),
-            samples: audio_samples,
-            data: &audio_data,
-        });
-    }
-    let _ = mp4::write_media_segment(&mut fmp4_data, seq, &tracks);
-
-    if include_init {
-        let video_init = config.avcc.as_ref().map(|avcc| VideoInit {
-            track_id: 1,
-            width: config.width,
-            height: config.height,
-            avcc: avcc.clone(),
-        });
-        let movie_timescale = if has_video_track {
-            90_000
-        } else {
-            audio_init
-                .as_ref()
-                .map(AudioInit::timescale)
-                .unwrap_or(1_000)
-        };
-        let _ = mp4::write_init_segment(
-            &mut init_data,
-            movie_timescale,
-            video_init.as_ref(),
-            audio_init.as_ref(),
+            aac_duration_samples
+                .saturating_mul(1_000)
+                .saturating_add(u64::from(aac_sample_rate) / 2)
+                / u64::from(aac_sample_rate),
         );
     }
 
-    let mut init: Option<Bytes> = None;
-    if !init_data.is_empty() {
-        init = Some(Bytes::from(init_data))
+    if matches!(audio_type, AudioType::FLAC) {
+        if let Some(frame_info) = frame_info {
+            audio_init = Some(AudioInit::Flac {
+                track_id: audio_track_id,
+                channel_count: frame_info.channels.into(),
+                sample_size: frame_info.bps.into(),
+                sample_rate: frame_info.sample_rate,
+                streaminfo: create_streaminfo(&frame_info),
+            });
+        }
     }
 
-    Fmp4 {
-        init,
-        duration: if avcs.is_empty() {
-            audio_ms
-        } else {
-            ticks_to_ms(total_ticks) as u32
-        },
-        key: is_key,
-        data: Bytes::from(fmp4_data),
+    AudioFragment {
+        samples: audio_samples,
+        payloads: audio_payloads,
+        base_media_decode_time: audio_base_media_decode_time,
+        init: audio_init,
+        duration_ms: audio_ms,
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use access_unit::PSI_STREAM_H264;
 
@@ -622,10 +1715,28 @@ mod tests {
         data.get(type_offset + 4..start.checked_add(size)?)
     }
 
-    fn config() -> Config {
+    /// Samples of the `traf` at `index` with `tfhd` and `trex` defaults
+    /// resolved.
+    fn traf_samples(init: &[u8], data: &[u8], index: usize) -> Vec<mp4::RunSample> {
+        let tracks = mp4::read_init_tracks(init).expect("init tracks");
+        let moof = mp4::read_boxes(data)
+            .expect("segment boxes")
+            .into_iter()
+            .find(|top| top.name == *b"moof")
+            .expect("moof");
+        mp4::read_track_fragments(&moof, &tracks).expect("track fragments")[index]
+            .runs
+            .iter()
+            .flat_map(|run| run.samples.clone())
+            .collect()
+    }
+
+    /// A 640x360 H.264 Baseline configuration, shared by every module's
+    /// tests.
+    pub(crate) fn config() -> Config {
         Config {
-            width: 1920,
-            height: 1080,
+            width: 640,
+            height: 360,
             avcc: Some(AvcDecoderConfigurationRecord {
                 profile_idc: 66,
                 constraint_set_flag: 0,
@@ -633,6 +1744,7 @@ mod tests {
                 sequence_parameter_set: Bytes::from_static(&[0x67, 0x42, 0x00, 0x1e]),
                 picture_parameter_set: Bytes::from_static(&[0x68, 0xce, 0x06, 0xe2]),
             }),
+            ..Config::default()
         }
     }
 
@@ -730,17 +1842,7 @@ mod tests {
             .expect("aac payload")
             .len();
 
-        let fmp4 = box_fmp4(
-            4,
-            Config {
-                width: 0,
-                height: 0,
-                avcc: None,
-            },
-            Vec::new(),
-            vec![aac],
-            0,
-        );
+        let fmp4 = box_fmp4(4, Config::default(), Vec::new(), vec![aac], 0);
         let init = fmp4.init.as_ref().expect("init segment");
 
         assert_eq!(full_box_u32_values(&fmp4.data, b"tfhd"), vec![1]);
@@ -762,11 +1864,7 @@ mod tests {
 
         let fmp4 = box_fmp4_with_init_and_audio_config(
             10,
-            Config {
-                width: 0,
-                height: 0,
-                avcc: None,
-            },
+            Config::default(),
             Vec::new(),
             vec![first, second],
             0,
@@ -776,22 +1874,126 @@ mod tests {
         let init = fmp4.init.as_ref().expect("AAC init segment");
         let mdhd = box_type_offsets(init, b"mdhd")[0];
         let tfdt = box_type_offsets(&fmp4.data, b"tfdt")[0];
-        let trun = box_type_offsets(&fmp4.data, b"trun")[0];
+        let samples = traf_samples(init, &fmp4.data, 0);
 
         assert_eq!(fmp4.duration, 43);
         assert_eq!(read_u32(&init[mdhd + 16..mdhd + 20]), 48_000);
         assert_eq!(read_u32(&fmp4.data[tfdt + 8..tfdt + 12]), 48_000);
-        assert_eq!(read_u32(&fmp4.data[trun + 8..trun + 12]), 2);
-        assert_eq!(read_u32(&fmp4.data[trun + 16..trun + 20]), 1_024);
-        assert_eq!(
-            read_u32(&fmp4.data[trun + 20..trun + 24]),
-            first_payload_len as u32
-        );
-        assert_eq!(read_u32(&fmp4.data[trun + 24..trun + 28]), 1_024);
-        assert_eq!(
-            read_u32(&fmp4.data[trun + 28..trun + 32]),
-            second_payload_len as u32
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].duration, 1_024);
+        assert_eq!(samples[0].size, first_payload_len as u32);
+        assert_eq!(samples[1].duration, 1_024);
+        assert_eq!(samples[1].size, second_payload_len as u32);
+    }
+
+    #[test]
+    fn constant_sample_values_move_to_tfhd_defaults() {
+        let fmp4 = box_fmp4_with_init(
+            1,
+            config(),
+            vec![
+                video_unit(0, 0, true),
+                video_unit(3_000, 3_000, false),
+                video_unit(6_000, 6_000, false),
+            ],
+            vec![aac_unit_at(0), aac_unit_at(1_024)],
+            9_000,
+            true,
         );
+        let init = fmp4.init.as_ref().expect("init segment");
+        let tfhd = box_payload(&fmp4.data, b"tfhd").expect("tfhd payload");
+        let trun = box_payload(&fmp4.data, b"trun").expect("trun payload");
+
+        assert_eq!(read_u32(&tfhd[..4]) & 0x00ff_ffff, 0x02_0038);
+        assert_eq!(read_u32(&tfhd[8..12]), 3_000);
+        assert_eq!(read_u32(&tfhd[12..16]), 5);
+        assert_eq!(read_u32(&tfhd[16..20]), 0x0101_0000);
+        // Only the data offset and the sync first sample's flags remain.
+        assert_eq!(read_u32(&trun[..4]) & 0x00ff_ffff, 0x00_0005);
+        assert_eq!(trun.len(), 16);
+        assert_eq!(read_u32(&trun[12..16]), 0x0200_0000);
+
+        let samples = traf_samples(init, &fmp4.data, 0);
+        assert!(samples[0].is_sync());
+        assert!(!samples[1].is_sync() && !samples[2].is_sync());
+        assert!(samples.iter().all(|sample| sample.duration == 3_000));
+
+        let trex = box_type_offsets(init, b"trex");
+        assert_eq!(read_u32(&init[trex[1] + 16..trex[1] + 20]), 1_024);
+        assert_eq!(read_u32(&init[trex[1] + 24..trex[1] + 28]), 0x0200_0000);
+        assert_eq!(traf_samples(init, &fmp4.data, 1)[1].duration, 1_024);
+    }
+
+    #[test]
+    fn mixed_sample_fields_split_into_consistent_truns() {
+        let sample = |duration, flags, composition_time_offset| FragmentSample {
+            duration,
+            size: Some(2),
+            flags,
+            composition_time_offset,
+        };
+        let sync = SampleFlags {
+            sample_depends_on: 2,
+            ..SampleFlags::default()
+        };
+        let samples = vec![
+            sample(Some(10), Some(sync), Some(5)),
+            sample(Some(20), Some(sync), None),
+            sample(Some(30), None, None),
+            sample(Some(40), None, Some(-5)),
+        ];
+        let data = [
+            Bytes::from_static(&[1, 1, 2, 2]),
+            Bytes::from_static(&[3, 3, 4, 4]),
+        ];
+        let track = |samples: Vec<FragmentSample>, data| FragmentTrack {
+            track_id: 1,
+            base_media_decode_time: 0,
+            samples,
+            data,
+            subsample_sizes: Vec::new(),
+            sample_encryption: Vec::new(),
+        };
+        let mut segment = Vec::new();
+        mp4::write_media_segment_header(
+            &mut segment,
+            1,
+            &[track(samples.clone(), &data)],
+            SegmentPrefix::default(),
+        )
+        .expect("media segment");
+        segment.extend_from_slice(&data.concat());
+
+        let truns = box_type_offsets(&segment, b"trun");
+        assert_eq!(truns.len(), 2);
+        // Only the first run carries a data offset.
+        assert_eq!(read_u32(&segment[truns[0] + 4..]) & 0x00ff_ffff, 0x00_0d01);
+        assert_eq!(read_u32(&segment[truns[1] + 4..]) & 0x00ff_ffff, 0x00_0900);
+
+        let moof = mp4::read_boxes(&segment).unwrap()[0];
+        let fragments = mp4::read_track_fragments(&moof, &[]).unwrap();
+        let runs = &fragments[0].runs;
+        assert_eq!((runs[0].samples.len(), runs[1].samples.len()), (2, 2));
+        assert!(runs[1].data_offset.is_none());
+        let offsets: Vec<i32> = runs
+            .iter()
+            .flat_map(|run| &run.samples)
+            .map(|sample| sample.composition_time_offset)
+            .collect();
+        assert_eq!(offsets, [5, 0, 0, -5]);
+        let data_offset = runs[0].data_offset.unwrap() as usize;
+        assert_eq!(segment[data_offset..], [1, 1, 2, 2, 3, 3, 4, 4]);
+
+        // Samples whose sizes do not cover the data are rejected.
+        let mut rejected = Vec::new();
+        assert!(mp4::write_media_segment_header(
+            &mut rejected,
+            1,
+            &[track(samples, &data[..1])],
+            SegmentPrefix::default(),
+        )
+        .is_none());
+        assert!(rejected.is_empty());
     }
 
     #[test]
@@ -813,11 +2015,7 @@ mod tests {
     fn short_unknown_audio_does_not_panic() {
         let fmp4 = box_fmp4(
             6,
-            Config {
-                width: 0,
-                height: 0,
-                avcc: None,
-            },
+            Config::default(),
             Vec::new(),
             vec![AccessUnit {
                 key: true,
@@ -840,11 +2038,7 @@ mod tests {
             .collect();
         let fmp4 = box_fmp4_with_init_and_pcm(
             7,
-            Config {
-                width: 0,
-                height: 0,
-                avcc: None,
-            },
+            Config::default(),
             Vec::new(),
             vec![AccessUnit {
                 key: true,
@@ -889,11 +2083,7 @@ mod tests {
     fn floating_point_pcm_uses_fpcm_sample_entry() {
         let fmp4 = box_fmp4_with_init_and_pcm(
             8,
-            Config {
-                width: 0,
-                height: 0,
-                avcc: None,
-            },
+            Config::default(),
             Vec::new(),
             vec![AccessUnit {
                 key: true,
@@ -944,11 +2134,7 @@ mod tests {
 
             let fmp4 = box_fmp4_with_init_and_audio_config(
                 9,
-                Config {
-                    width: 0,
-                    height: 0,
-                    avcc: None,
-                },
+                Config::default(),
                 Vec::new(),
                 units,
                 0,
@@ -964,7 +2150,7 @@ mod tests {
             let opus = box_type_offsets(init, b"Opus")[0];
             let dops = box_payload(init, b"dOps").expect("dOps payload");
             let mdhd = box_type_offsets(init, b"mdhd")[0];
-            let trun = box_type_offsets(&fmp4.data, b"trun")[0];
+            let samples = traf_samples(init, &fmp4.data, 0);
             let tfdt = box_type_offsets(&fmp4.data, b"tfdt")[0];
             let mut expected_payload = first;
             expected_payload.extend_from_slice(&second);
@@ -982,17 +2168,14 @@ mod tests {
                 &[0, channel_count as u8, 0, 0, 0, 0, 0xbb, 0x80, 0, 0, 0]
             );
             assert_eq!(read_u32(&fmp4.data[tfdt + 8..tfdt + 12]), 480);
-            assert_eq!(read_u32(&fmp4.data[trun + 8..trun + 12]), 2);
-            assert_eq!(read_u32(&fmp4.data[trun + 16..trun + 20]), 240);
+            assert_eq!(samples.len(), 2);
+            assert_eq!(samples[0].duration, 240);
             assert_eq!(
-                read_u32(&fmp4.data[trun + 20..trun + 24]),
+                samples[0].size,
                 expected_payload.len() as u32 - second.len() as u32
             );
-            assert_eq!(read_u32(&fmp4.data[trun + 24..trun + 28]), 240);
-            assert_eq!(
-                read_u32(&fmp4.data[trun + 28..trun + 32]),
-                second.len() as u32
-            );
+            assert_eq!(samples[1].duration, 240);
+            assert_eq!(samples[1].size, second.len() as u32);
         }
     }
 
@@ -1037,11 +2220,7 @@ mod tests {
 
         let fmp4 = box_fmp4_with_init_and_audio_config(
             1,
-            Config {
-                width: 0,
-                height: 0,
-                avcc: None,
-            },
+            Config::default(),
             Vec::new(),
             vec![AccessUnit {
                 key: true,
@@ -1063,8 +2242,8 @@ mod tests {
 
         assert_eq!(fmp4.duration, 10);
         assert_eq!(box_payload(&fmp4.data, b"mdat"), Some(packet.as_slice()));
-        let trun = box_type_offsets(&fmp4.data, b"trun")[0];
-        assert_eq!(read_u32(&fmp4.data[trun + 16..trun + 20]), 480);
+        let init = fmp4.init.as_ref().expect("Opus init segment");
+        assert_eq!(traf_samples(init, &fmp4.data, 0)[0].duration, 480);
     }
 
     #[test]
@@ -1072,11 +2251,7 @@ mod tests {
         let packet = [(17 << 3) | (1 << 2)];
         let fmp4 = box_fmp4_with_init_and_audio_config(
             1,
-            Config {
-                width: 0,
-                height: 0,
-                avcc: None,
-            },
+            Config::default(),
             Vec::new(),
             vec![AccessUnit {
                 key: true,
@@ -1102,4 +2277,750 @@ mod tests {
         assert_eq!(box_payload(&fmp4.data, b"mdat"), Some(packet.as_slice()));
         assert_eq!(fmp4.duration, 5);
     }
+
+    pub(crate) fn audio_track(track_id: u32, language: [u8; 3], handler_name: &str) -> AudioTrack {
+        AudioTrack {
+            track_id,
+            config: Some(AudioTrackConfig::Aac),
+            metadata: TrackMetadata {
+                language: Some(language),
+                handler_name: Some(handler_name.to_owned()),
+                ..TrackMetadata::default()
+            },
+            units: vec![aac_unit_at(0)],
+        }
+    }
+
+    #[test]
+    fn multiple_audio_tracks_share_one_moof_with_a_traf_each() {
+        let fmp4 = box_fmp4_with_tracks(
+            11,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                audio: vec![
+                    audio_track(2, *b"eng", "English"),
+                    audio_track(3, *b"spa", "Commentary"),
+                ],
+                subtitles: Vec::new(),
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            3_000,
+            true,
+        );
+        let init = fmp4.init.as_ref().expect("multi-track init segment");
+        let languages: Vec<u16> = box_type_offsets(init, b"mdhd")
+            .into_iter()
+            .map(|mdhd| read_u16(&init[mdhd + 24..mdhd + 26]))
+            .collect();
+
+        assert_eq!(box_type_offsets(&fmp4.data, b"moof").len(), 1);
+        assert_eq!(box_type_offsets(&fmp4.data, b"mdat").len(), 1);
+        assert_eq!(full_box_u32_values(&fmp4.data, b"tfhd"), vec![1, 2, 3]);
+        assert_eq!(full_box_u32_values(init, b"trex"), vec![1, 2, 3]);
+        assert_eq!(box_type_offsets(init, b"mp4a").len(), 2);
+        // "und", then "eng" and "spa" packed as 5-bit letters.
+        assert_eq!(languages, vec![0x55c4, 0x15c7, 0x4e01]);
+        assert!(init.windows(8).any(|window| window == b"English\0"));
+        assert!(init.windows(11).any(|window| window == b"Commentary\0"));
+    }
+
+    #[test]
+    fn configured_audio_track_starting_after_the_init_fragment_is_in_moov() {
+        let commentary = |units| AudioTrack {
+            track_id: 3,
+            config: Some(AudioTrackConfig::Pcm(PcmAudioConfig {
+                sample_rate: 1_000,
+                channel_count: 1,
+                sample_size: 16,
+                little_endian: true,
+                sample_kind: PcmSampleKind::Integer,
+            })),
+            metadata: TrackMetadata::default(),
+            units,
+        };
+        let pcm = AccessUnit {
+            key: true,
+            pts: 1_000,
+            dts: 1_000,
+            data: Bytes::from(vec![0; 40]),
+            stream_type: 0,
+            id: 2,
+        };
+        let media = |dts, units| FragmentMedia {
+            video: vec![video_unit(dts, dts, true)],
+            audio: vec![audio_track(2, *b"eng", "English"), commentary(units)],
+            ..FragmentMedia::default()
+        };
+        let first = box_fmp4_with_tracks(1, config(), media(0, Vec::new()), 90_000, true);
+        let second = box_fmp4_with_tracks(2, config(), media(90_000, vec![pcm]), 180_000, false);
+        let init = first.init.as_ref().expect("init segment");
+
+        assert_eq!(full_box_u32_values(init, b"trex"), vec![1, 2, 3]);
+        assert_eq!(box_type_offsets(init, b"ipcm").len(), 1);
+        assert_eq!(full_box_u32_values(&first.data, b"tfhd"), vec![1, 2]);
+        assert_eq!(full_box_u32_values(&second.data, b"tfhd"), vec![1, 2, 3]);
+        assert_eq!(traf_samples(init, &second.data, 2).len(), 1);
+    }
+
+    #[test]
+    fn chunks_share_access_unit_buffers() {
+        let video = vec![video_unit(0, 0, true), video_unit(3_000, 3_000, false)];
+        let audio = audio_track(2, *b"eng", "English");
+        let media = || FragmentMedia {
+            video: video.clone(),
+            audio: vec![audio.clone()],
+            subtitles: Vec::new(),
+            metadata: Vec::new(),
+            events: Vec::new(),
+            producer_reference: None,
+        };
+        let chunked = box_fmp4_chunks(4, config(), media(), 6_000, true);
+        let fmp4 = box_fmp4_with_tracks(4, config(), media(), 6_000, true);
+
+        // The header, both video samples, then the AAC frame past its ADTS
+        // header.
+        let chunks = &chunked.chunks;
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[1].as_ptr(), video[0].data.as_ptr());
+        assert_eq!(chunks[2].as_ptr(), video[1].data.as_ptr());
+        assert_eq!(chunks[3].as_ptr(), audio.units[0].data[7..].as_ptr());
+
+        let mut written = Vec::new();
+        chunked.write_to(&mut written).unwrap();
+        assert_eq!(written, fmp4.data);
+        assert_eq!(chunked.len(), written.len());
+        assert_eq!(chunked.init, fmp4.init);
+        assert_eq!(
+            read_u32(&chunks[0][chunks[0].len() - 4..]),
+            u32::from_be_bytes(*b"mdat")
+        );
+    }
+
+    #[test]
+    fn chunks_are_empty_when_the_segment_cannot_be_written() {
+        let media = FragmentMedia {
+            video: vec![video_unit(0, 0, true)],
+            audio: vec![audio_track(1, *b"eng", "English")],
+            subtitles: Vec::new(),
+            metadata: Vec::new(),
+            events: Vec::new(),
+            producer_reference: None,
+        };
+        let chunked = box_fmp4_chunks(4, config(), media, 3_000, false);
+        assert!(chunked.chunks.is_empty());
+        assert!(chunked.is_empty());
+        assert!(chunked.into_fmp4().data.is_empty());
+    }
+
+    #[test]
+    fn duplicate_audio_track_ids_are_rejected() {
+        let fmp4 = box_fmp4_with_tracks(
+            12,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                audio: vec![audio_track(1, *b"eng", "English")],
+                subtitles: Vec::new(),
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            3_000,
+            true,
+        );
+
+        assert!(fmp4.init.is_none());
+        assert!(fmp4.data.is_empty());
+    }
+
+    #[test]
+    fn configured_video_track_id_is_used_in_init_and_fragment() {
+        let mut video_config = config();
+        video_config.video_track_id = 7;
+
+        let fmp4 = box_fmp4_with_tracks(
+            12,
+            video_config,
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                audio: vec![audio_track(1, *b"eng", "English")],
+                subtitles: Vec::new(),
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            3_000,
+            true,
+        );
+
+        let init = fmp4.init.expect("init segment");
+        assert_eq!(full_box_u32_values(&init, b"trex"), vec![7, 1]);
+        assert_eq!(full_box_u32_values(&fmp4.data, b"tfhd"), vec![7, 1]);
+    }
+
+    #[test]
+    fn video_track_id_clashing_with_metadata_track_is_rejected() {
+        let fmp4 = box_fmp4_with_tracks(
+            12,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                audio: Vec::new(),
+                subtitles: Vec::new(),
+                metadata: vec![MetadataTrack {
+                    track_id: 1,
+                    metadata: TrackMetadata::default(),
+                    format: MetadataFormat::id3(),
+                    samples: Vec::new(),
+                }],
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            3_000,
+            true,
+        );
+
+        assert!(fmp4.init.is_none());
+        assert!(fmp4.data.is_empty());
+    }
+
+    #[test]
+    fn init_segment_failure_drops_the_fragment() {
+        let fmp4 = box_fmp4_with_tracks(
+            12,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                audio: vec![audio_track(2, *b"ENG", "English")],
+                subtitles: Vec::new(),
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            3_000,
+            true,
+        );
+
+        assert!(fmp4.init.is_none());
+        assert!(fmp4.data.is_empty());
+    }
+
+    #[test]
+    fn track_metadata_sets_tkhd_flags_alternate_group_and_kind() {
+        let mut commentary = audio_track(2, *b"eng", "Commentary");
+        commentary.metadata.enabled = false;
+        commentary.metadata.alternate_group = 1;
+        commentary.metadata.kinds = vec![TrackKind::dash_role("commentary")];
+        let mut video_config = config();
+        video_config.video_metadata.in_preview = false;
+
+        let fmp4 = box_fmp4_with_tracks(
+            13,
+            video_config,
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                audio: vec![commentary],
+                subtitles: Vec::new(),
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            3_000,
+            true,
+        );
+        let init = fmp4.init.as_ref().expect("init segment");
+        let tkhd = box_type_offsets(init, b"tkhd");
+        let kind = box_payload(init, b"kind").expect("kind payload");
+
+        assert_eq!(read_u32(&init[tkhd[0] + 4..tkhd[0] + 8]), 0x0000_0003);
+        assert_eq!(read_u32(&init[tkhd[1] + 4..tkhd[1] + 8]), 0x0000_0006);
+        assert_eq!(read_u16(&init[tkhd[0] + 38..tkhd[0] + 40]), 0);
+        assert_eq!(read_u16(&init[tkhd[1] + 38..tkhd[1] + 40]), 1);
+        assert_eq!(box_type_offsets(init, b"udta").len(), 1);
+        assert_eq!(&kind[4..], b"urn:mpeg:dash:role:2011\0commentary\0");
+    }
+
+    #[test]
+    fn webvtt_track_writes_wvtt_entry_and_gapless_cue_samples() {
+        let fmp4 = box_fmp4_with_tracks(
+            14,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(180_000, 180_000, true)],
+                audio: Vec::new(),
+                subtitles: vec![SubtitleTrack::WebVtt(WebVttTrack {
+                    track_id: 3,
+                    metadata: TrackMetadata {
+                        language: Some(*b"eng"),
+                        ..TrackMetadata::default()
+                    },
+                    header: "WEBVTT".to_owned(),
+                    start: 2_000,
+                    end: 4_000,
+                    cues: vec![WebVttCue {
+                        start: 2_500,
+                        end: 3_000,
+                        id: None,
+                        settings: None,
+                        payload: "Hello".to_owned(),
+                    }],
+                })],
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            183_000,
+            true,
+        );
+        let init = fmp4.init.as_ref().expect("init segment");
+        let tfdt = box_type_offsets(&fmp4.data, b"tfdt")[1];
+        let trun = box_type_offsets(&fmp4.data, b"trun")[1];
+
+        assert_eq!(full_box_u32_values(&fmp4.data, b"tfhd"), vec![1, 3]);
+        assert_eq!(box_payload(init, b"vttC"), Some(b"WEBVTT".as_slice()));
+        assert!(init.windows(4).any(|window| window == b"text"));
+        assert!(!box_type_offsets(init, b"sthd").is_empty());
+        assert_eq!(read_u32(&fmp4.data[tfdt + 8..tfdt + 12]), 2_000);
+        assert_eq!(read_u32(&fmp4.data[trun + 8..trun + 12]), 3);
+        assert_eq!(read_u32(&fmp4.data[trun + 16..trun + 20]), 500);
+        assert_eq!(read_u32(&fmp4.data[trun + 24..trun + 28]), 500);
+        assert_eq!(read_u32(&fmp4.data[trun + 32..trun + 36]), 1_000);
+        assert_eq!(box_type_offsets(&fmp4.data, b"vtte").len(), 2);
+        assert_eq!(box_payload(&fmp4.data, b"payl"), Some(b"Hello".as_slice()));
+    }
+
+    #[test]
+    fn ttml_track_writes_stpp_and_subsamples_for_embedded_images() {
+        let document = Bytes::from_static(b"<tt xmlns=\"http://www.w3.org/ns/ttml\"/>");
+        let image = Bytes::from_static(b"\x89PNG");
+        let fmp4 = box_fmp4_with_tracks(
+            15,
+            Config::default(),
+            FragmentMedia {
+                video: Vec::new(),
+                audio: Vec::new(),
+                subtitles: vec![SubtitleTrack::Ttml(TtmlTrack {
+                    track_id: 1,
+                    metadata: TrackMetadata::default(),
+                    namespace: "http://www.w3.org/ns/ttml".to_owned(),
+                    schema_location: String::new(),
+                    auxiliary_mime_types: "image/png".to_owned(),
+                    mime_type: Some("application/ttml+xml;codecs=im1i".to_owned()),
+                    samples: vec![
+                        TtmlSample {
+                            start: 0,
+                            duration: 1_000,
+                            document: document.clone(),
+                            images: Vec::new(),
+                        },
+                        TtmlSample {
+                            start: 2_000,
+                            duration: 2_000,
+                            document: document.clone(),
+                            images: vec![image.clone()],
+                        },
+                    ],
+                })],
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            0,
+            true,
+        );
+        let init = fmp4.init.as_ref().expect("init segment");
+        let stpp = box_payload(init, b"stpp").expect("stpp payload");
+        let mime = box_payload(init, b"mime").expect("mime payload");
+        let subs = box_payload(&fmp4.data, b"subs").expect("subs payload");
+        let samples = traf_samples(init, &fmp4.data, 0);
+
+        assert_eq!(fmp4.duration, 4_000);
+        assert!(init.windows(4).any(|window| window == b"subt"));
+        assert!(stpp[8..].starts_with(b"http://www.w3.org/ns/ttml\0\0image/png\0"));
+        assert_eq!(&mime[4..], b"application/ttml+xml;codecs=im1i\0");
+        assert_eq!(samples[0].duration, 2_000);
+        assert_eq!(samples[1].duration, 2_000);
+        assert_eq!(subs[0], 1);
+        assert_eq!(read_u32(&subs[4..8]), 1);
+        assert_eq!(read_u32(&subs[8..12]), 2);
+        assert_eq!(read_u16(&subs[12..14]), 2);
+        assert_eq!(read_u32(&subs[14..18]), document.len() as u32);
+        assert_eq!(read_u32(&subs[24..28]), image.len() as u32);
+    }
+
+    #[test]
+    fn cea608_sidecar_track_writes_cdat_samples() {
+        let frame = |pts, data| CaptionFrame {
+            pts,
+            triplets: vec![crate::captions::CcTriplet {
+                cc_valid: true,
+                cc_type: 0,
+                data,
+            }],
+        };
+        let fmp4 = box_fmp4_with_tracks(
+            16,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                audio: Vec::new(),
+                subtitles: vec![SubtitleTrack::Cea608(Cea608Track {
+                    track_id: 2,
+                    metadata: TrackMetadata::default(),
+                    // The null pair in the middle frame carries no captions.
+                    frames: vec![
+                        frame(0, [0x94, 0x20]),
+                        frame(33, [0x80, 0x80]),
+                        frame(66, [0xc8, 0x49]),
+                    ],
+                    end: 100,
+                })],
+                metadata: Vec::new(),
+                events: Vec::new(),
+                producer_reference: None,
+            },
+            9_000,
+            true,
+        );
+        let init = fmp4.init.as_ref().expect("init segment");
+        let samples = traf_samples(init, &fmp4.data, 1);
+
+        assert!(!box_type_offsets(init, b"c608").is_empty());
+        assert!(init.windows(4).any(|window| window == b"clcp"));
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].duration, 66);
+        assert_eq!(samples[1].duration, 34);
+        assert_eq!(box_type_offsets(&fmp4.data, b"cdat").len(), 2);
+        assert_eq!(
+            box_payload(&fmp4.data, b"cdat"),
+            Some([0x94, 0x20].as_slice())
+        );
+    }
+
+    #[test]
+    fn side_tracks_without_samples_still_reach_the_init_segment() {
+        let media = |samples: bool| FragmentMedia {
+            video: vec![video_unit(0, 0, true)],
+            subtitles: vec![
+                SubtitleTrack::Ttml(TtmlTrack {
+                    track_id: 2,
+                    metadata: TrackMetadata::default(),
+                    namespace: "http://www.w3.org/ns/ttml".to_owned(),
+                    schema_location: String::new(),
+                    auxiliary_mime_types: String::new(),
+                    mime_type: None,
+                    samples: Vec::new(),
+                }),
+                SubtitleTrack::Cea608(Cea608Track {
+                    track_id: 3,
+                    metadata: TrackMetadata::default(),
+                    frames: Vec::new(),
+                    end: 1_000,
+                }),
+            ],
+            metadata: vec![MetadataTrack {
+                track_id: 4,
+                metadata: TrackMetadata::default(),
+                format: MetadataFormat::id3(),
+                samples: samples
+                    .then(|| MetadataSample {
+                        start: 1_000,
+                        duration: 1_000,
+                        data: Bytes::from_static(b"ID3"),
+                    })
+                    .into_iter()
+                    .collect(),
+            }],
+            ..FragmentMedia::default()
+        };
+        let first = box_fmp4_with_tracks(20, config(), media(false), 90_000, true);
+        let second = box_fmp4_with_tracks(21, config(), media(true), 180_000, false);
+        let init = first.init.as_ref().expect("init segment");
+        let tracks = mp4::read_init_tracks(init).expect("init tracks");
+        let fragments = |data: &[u8]| -> Vec<u32> {
+            let moof = mp4::read_boxes(data)
+                .expect("segment boxes")
+                .into_iter()
+                .find(|top| top.name == *b"moof")
+                .expect("moof");
+            mp4::read_track_fragments(&moof, &tracks)
+                .expect("track fragments")
+                .iter()
+                .map(|fragment| fragment.track_id)
+                .collect()
+        };
+
+        assert_eq!(tracks.len(), 4);
+        assert_eq!(box_type_offsets(init, b"trex").len(), 4);
+        assert!(!box_type_offsets(init, b"stpp").is_empty());
+        assert!(!box_type_offsets(init, b"c608").is_empty());
+        assert!(!box_type_offsets(init, b"mett").is_empty());
+        assert_eq!(fragments(&first.data), vec![1]);
+        assert_eq!(fragments(&second.data), vec![1, 4]);
+    }
+
+    #[test]
+    fn events_are_written_as_emsg_boxes_ahead_of_moof() {
+        let event = |presentation_time, id| EventMessage {
+            scheme_id_uri: "urn:scte:scte35:2013:bin".to_owned(),
+            value: String::new(),
+            timescale: 90_000,
+            presentation_time,
+            event_duration: 2_700_000,
+            id,
+            message_data: vec![0xfc, 0x30],
+        };
+        let fmp4 = box_fmp4_with_tracks(
+            17,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(90_000, 90_000, true)],
+                audio: Vec::new(),
+                subtitles: Vec::new(),
+                metadata: Vec::new(),
+                events: vec![
+                    event(EventPresentationTime::Delta(3_000), 7),
+                    event(EventPresentationTime::Absolute(93_000), 8),
+                ],
+                producer_reference: None,
+            },
+            93_000,
+            false,
+        );
+        let emsg = box_type_offsets(&fmp4.data, b"emsg");
+        let moof = box_type_offsets(&fmp4.data, b"moof")[0];
+        let v0 = box_payload(&fmp4.data, b"emsg").expect("first emsg");
+        let v1 = &fmp4.data[emsg[1] + 4..moof - 4];
+
+        assert_eq!(emsg.len(), 2);
+        assert_eq!(emsg[0], 4);
+        assert_eq!(v0[0], 0);
+        assert_eq!(
+            &v0[4..],
+            b"urn:scte:scte35:2013:bin\0\0\0\x01\x5f\x90\0\0\x0b\xb8\0\x29\x32\xe0\0\0\0\x07\xfc\x30"
+        );
+        assert_eq!(v1[0], 1);
+        assert_eq!(read_u32(&v1[4..8]), 90_000);
+        assert_eq!(read_u64(&v1[8..16]), 93_000);
+        assert_eq!(read_u32(&v1[20..24]), 8);
+        assert_eq!(&v1[24..], b"urn:scte:scte35:2013:bin\0\0\xfc\x30");
+    }
+
+    #[test]
+    fn id3_metadata_track_writes_mett_entry_and_tag_samples() {
+        let tag = crate::id3::Id3Tag {
+            frames: vec![crate::id3::Id3Frame::Priv {
+                owner: "com.example".to_owned(),
+                data: vec![1, 2, 3],
+            }],
+        }
+        .to_bytes()
+        .expect("id3 tag");
+        let fmp4 = box_fmp4_with_tracks(
+            18,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(0, 0, true)],
+                metadata: vec![MetadataTrack {
+                    track_id: 2,
+                    metadata: TrackMetadata::default(),
+                    format: MetadataFormat::id3(),
+                    samples: vec![
+                        MetadataSample {
+                            start: 0,
+                            duration: 0,
+                            data: Bytes::from(tag.clone()),
+                        },
+                        MetadataSample {
+                            start: 500,
+                            duration: 500,
+                            data: Bytes::from(tag.clone()),
+                        },
+                    ],
+                }],
+                ..FragmentMedia::default()
+            },
+            90_000,
+            true,
+        );
+        let init = fmp4.init.as_ref().expect("init segment");
+        let mett = box_payload(init, b"mett").expect("mett payload");
+        let samples = traf_samples(init, &fmp4.data, 1);
+
+        assert!(init.windows(4).any(|window| window == b"meta"));
+        assert!(init.windows(4).any(|window| window == b"nmhd"));
+        assert_eq!(&mett[8..], b"\0application/id3\0");
+        assert_eq!(samples[0].duration, 500);
+        assert_eq!(samples[0].size, tag.len() as u32);
+        assert!(fmp4
+            .data
+            .ends_with(&[tag.as_slice(), tag.as_slice()].concat()));
+    }
+
+    #[test]
+    fn producer_reference_writes_prft_from_injected_clock() {
+        let clock = || UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_500);
+        let fmp4 = box_fmp4_with_tracks(
+            19,
+            config(),
+            FragmentMedia {
+                video: vec![video_unit(90_000, 90_000, true)],
+                producer_reference: Some(ProducerReference::from_clock(
+                    &clock,
+                    1,
+                    ProducerReferenceKind::Captured,
+                )),
+                ..FragmentMedia::default()
+            },
+            93_000,
+            false,
+        );
+        let prft = box_payload(&fmp4.data, b"prft").expect("prft payload");
+
+        assert_eq!(box_type_offsets(&fmp4.data, b"prft"), vec![4]);
+        assert_eq!(read_u32(&prft[0..4]), 24);
+        assert_eq!(read_u32(&prft[4..8]), 1);
+        assert_eq!(read_u32(&prft[8..12]), 3_908_988_800);
+        assert_eq!(read_u32(&prft[12..16]), 0x8000_0000);
+        assert_eq!(read_u32(&prft[16..20]), 90_000);
+    }
+
+    #[test]
+    fn chunked_segment_reports_parts_and_independence() {
+        let video = (0..6)
+            .map(|index| video_unit(index * 9_000, index * 9_000, index % 2 == 0 && index < 4))
+            .collect();
+        let audio = vec![AudioTrack {
+            // 100 ms apart in the 48 kHz AAC clock.
+            units: (0..6).map(|index| aac_unit_at(index * 4_800)).collect(),
+            ..audio_track(2, *b"eng", "English")
+        }];
+        let chunked = box_fmp4_chunked(
+            5,
+            Config {
+                segment_type: Some(FileType::cmaf_segment()),
+                encryption: None,
+                ..config()
+            },
+            FragmentMedia {
+                video,
+                audio,
+                ..FragmentMedia::default()
+            },
+            54_000,
+            true,
+            200,
+        );
+        let independent: Vec<bool> = chunked.parts.iter().map(|part| part.independent).collect();
+        let durations: Vec<u32> = chunked.parts.iter().map(|part| part.duration).collect();
+        let sequence_numbers = full_box_u32_values(&chunked.segment.data, b"mfhd");
+        let styp = box_type_offsets(&chunked.segment.data, b"styp");
+
+        assert!(chunked.segment.init.is_some());
+        assert_eq!(independent, vec![true, true, false]);
+        assert_eq!(durations, vec![200, 200, 200]);
+        assert_eq!(chunked.segment.duration, 600);
+        assert_eq!(sequence_numbers, vec![5, 6, 7]);
+        assert_eq!(box_type_offsets(&chunked.segment.data, b"traf").len(), 6);
+        assert_eq!(&chunked.segment.data[styp[0] + 4..styp[0] + 8], b"cmfs");
+        assert_eq!(&chunked.segment.data[styp[1] + 4..styp[1] + 8], b"cmfl");
+        for part in &chunked.parts {
+            assert_eq!(
+                &chunked.segment.data[part.offset..part.offset + part.data.len()],
+                &part.data[..]
+            );
+        }
+    }
+
+    #[test]
+    fn chunked_init_covers_tracks_starting_after_the_first_chunk() {
+        let video = (0..6)
+            .map(|index| video_unit(index * 9_000, index * 9_000, index % 2 == 0))
+            .collect();
+        let audio = vec![AudioTrack {
+            // 300 and 400 ms in the 48 kHz AAC clock, both after the first
+            // 200 ms chunk.
+            units: vec![aac_unit_at(14_400), aac_unit_at(19_200)],
+            ..audio_track(2, *b"eng", "English")
+        }];
+        let subtitles = vec![SubtitleTrack::WebVtt(WebVttTrack {
+            track_id: 3,
+            metadata: TrackMetadata::default(),
+            header: "WEBVTT".to_owned(),
+            start: 400,
+            end: 600,
+            cues: Vec::new(),
+        })];
+        let chunked = box_fmp4_chunked(
+            1,
+            config(),
+            FragmentMedia {
+                video,
+                audio,
+                subtitles,
+                ..FragmentMedia::default()
+            },
+            54_000,
+            true,
+            200,
+        );
+
+        let init = chunked.segment.init.expect("init segment");
+        assert_eq!(full_box_u32_values(&init, b"trex"), vec![1, 2, 3]);
+        assert_eq!(box_type_offsets(&init, b"wvtt").len(), 1);
+        assert_eq!(
+            full_box_u32_values(&chunked.parts[0].data, b"tfhd"),
+            vec![1]
+        );
+        assert_eq!(
+            full_box_u32_values(&chunked.parts[2].data, b"tfhd"),
+            vec![1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn chunks_place_configured_aac_by_its_sample_clock() {
+        let video = (0..6)
+            .map(|index| video_unit(index * 9_000, index * 9_000, index == 0))
+            .collect();
+        // 1,024-sample frames at 48 kHz, about 21 ms each.
+        let audio = vec![AudioTrack {
+            units: (0..28).map(|index| aac_unit_at(index * 1_024)).collect(),
+            ..audio_track(2, *b"eng", "English")
+        }];
+        let chunked = box_fmp4_chunked(
+            1,
+            config(),
+            FragmentMedia {
+                video,
+                audio,
+                ..FragmentMedia::default()
+            },
+            54_000,
+            true,
+            200,
+        );
+        let init = chunked.segment.init.as_ref().expect("init segment");
+        let audio_samples: Vec<usize> = chunked
+            .parts
+            .iter()
+            .map(|part| traf_samples(init, &part.data, 1).len())
+            .collect();
+        let audio_decode_times: Vec<u32> = chunked
+            .parts
+            .iter()
+            .map(|part| {
+                let tfdt = box_type_offsets(&part.data, b"tfdt")[1];
+                read_u32(&part.data[tfdt + 8..tfdt + 12])
+            })
+            .collect();
+
+        // Chunks start at 200 ms and 400 ms: frames 10 (213 ms) and 19
+        // (405 ms) are the first past each cut.
+        assert_eq!(audio_samples, vec![10, 9, 9]);
+        assert_eq!(audio_decode_times, vec![0, 10 * 1_024, 19 * 1_024]);
+    }
 }
@@ -0,0 +1,182 @@
+use crate::fmp4::{audio_time_ms, box_fmp4_with_init_and_audio_config, AudioTrackConfig, Config};
+use access_unit::{AccessUnit, Fmp4, PSI_STREAM_H264};
+
+/// Cuts a live stream of access units into fragments as they arrive.
+///
+/// H.264 units (`PSI_STREAM_H264`) form the video track and every other unit
+/// the audio track. With video, a fragment closes at the first key frame at
+/// least `target_duration_ms` after its first video sample; audio received
+/// up to then goes with it. Audio-only fragments close at the first audio
+/// unit that far past the fragment's first one. The first fragment carries
+/// the init segment, so it should hold a sample of every track. A fragment
+/// that cannot be boxed is returned with empty `data`; its media is dropped
+/// and the next fragment reuses its sequence number.
+#[derive(Clone)]
+pub struct Fragmenter {
+    config: Config,
+    audio_config: Option<AudioTrackConfig>,
+    target_duration_ms: u32,
+    sequence: u32,
+    video: Vec<AccessUnit>,
+    audio: Vec<AccessUnit>,
+    /// Duration of the last closed video sample, to end the stream's last.
+    last_video_duration: u64,
+    init_written: bool,
+}
+
+impl Fragmenter {
+    pub fn new(
+        config: Config,
+        audio_config: Option<AudioTrackConfig>,
+        target_duration_ms: u32,
+    ) -> Self {
+        Self {
+            config,
+            audio_config,
+            target_duration_ms,
+            sequence: 1,
+            video: Vec::new(),
+            audio: Vec::new(),
+            last_video_duration: 0,
+            init_written: false,
+        }
+    }
+
+    /// Add one access unit, returning the fragment it closes, if any.
+    pub fn push(&mut self, unit: AccessUnit) -> Option<Fmp4> {
+        if self.has_video() {
+            if unit.stream_type != PSI_STREAM_H264 {
+                self.audio.push(unit);
+                return None;
+            }
+            let closes = unit.key
+                && self.video.first().is_some_and(|first| {
+                    unit.dts.saturating_sub(first.dts)
+                        >= u64::from(self.target_duration_ms).saturating_mul(90)
+                });
+            let closed = closes.then(|| self.close(unit.dts));
+            if let Some(last) = self.video.last() {
+                self.last_video_duration = unit.dts.saturating_sub(last.dts);
+            }
+            self.video.push(unit);
+            closed
+        } else {
+            if unit.stream_type == PSI_STREAM_H264 {
+                return None;
+            }
+            let closes = self.audio.first().is_some_and(|first| {
+                audio_time_ms(&unit, self.audio_config)
+                    .saturating_sub(audio_time_ms(first, self.audio_config))
+                    >= u64::from(self.target_duration_ms)
+            });
+            let closed = closes.then(|| self.close(0));
+            self.audio.push(unit);
+            closed
+        }
+    }
+
+    /// Close the pending fragment at the end of the stream. The last video
+    /// sample lasts as long as the one before it.
+    pub fn finish(&mut self) -> Option<Fmp4> {
+        if self.video.is_empty() && self.audio.is_empty() {
+            return None;
+        }
+        let next_dts = self
+            .video
+            .last()
+            .map_or(0, |last| last.dts.saturating_add(self.last_video_duration));
+        Some(self.close(next_dts))
+    }
+
+    fn has_video(&self) -> bool {
+        self.config.avcc.is_some()
+    }
+
+    fn close(&mut self, next_dts: u64) -> Fmp4 {
+        let fragment = box_fmp4_with_init_and_audio_config(
+            self.sequence,
+            self.config.clone(),
+            std::mem::take(&mut self.video),
+            std::mem::take(&mut self.audio),
+            next_dts,
+            !self.init_written,
+            self.audio_config,
+        );
+        if !fragment.data.is_empty() {
+            self.sequence = self.sequence.wrapping_add(1);
+        }
+        self.init_written |= fragment.init.is_some();
+        fragment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::tests::config;
+    use bytes::Bytes;
+
+    fn video_unit(dts: u64, key: bool) -> AccessUnit {
+        AccessUnit {
+            key,
+            pts: dts,
+            dts,
+            data: Bytes::from_static(&[0, 0, 0, 1, 0x65]),
+            stream_type: PSI_STREAM_H264,
+            id: 0,
+        }
+    }
+
+    #[test]
+    fn fragments_close_at_key_frames_past_the_target() {
+        let mut fragmenter = Fragmenter::new(config(), None, 1_000);
+        let mut fragments = Vec::new();
+        // A key frame every 60 frames of 1,500 ticks (one second at 60 fps),
+        // then one every 30 frames.
+        for index in 0..150_u64 {
+            let key = index % 60 == 0 || (index > 120 && index % 30 == 0);
+            fragments.extend(fragmenter.push(video_unit(index * 1_500, key)));
+        }
+        fragments.extend(fragmenter.finish());
+
+        let durations: Vec<u32> = fragments.iter().map(|fragment| fragment.duration).collect();
+        assert_eq!(durations, vec![1_000, 1_000, 500]);
+        assert!(fragments[0].init.is_some());
+        assert!(fragments[1..]
+            .iter()
+            .all(|fragment| fragment.init.is_none()));
+        assert!(fragments.iter().all(|fragment| fragment.key));
+        assert_eq!(fragmenter.finish().map(|fragment| fragment.duration), None);
+    }
+
+    #[test]
+    fn audio_only_fragments_close_on_audio_time() {
+        let config = Config {
+            avcc: None,
+            ..config()
+        };
+        let pcm = crate::fmp4::PcmAudioConfig {
+            sample_rate: 1_000,
+            channel_count: 1,
+            sample_size: 16,
+            little_endian: true,
+            sample_kind: crate::fmp4::PcmSampleKind::Integer,
+        };
+        let mut fragmenter = Fragmenter::new(config, Some(AudioTrackConfig::Pcm(pcm)), 100);
+        let mut fragments = Vec::new();
+        for index in 0..25_u64 {
+            fragments.extend(fragmenter.push(AccessUnit {
+                key: true,
+                pts: index * 20,
+                dts: index * 20,
+                data: Bytes::from(vec![0; 40]),
+                stream_type: 0,
+                id: 1,
+            }));
+        }
+        fragments.extend(fragmenter.finish());
+
+        let durations: Vec<u32> = fragments.iter().map(|fragment| fragment.duration).collect();
+        assert_eq!(durations, vec![100, 100, 100, 100, 100]);
+    }
+}
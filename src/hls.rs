@@ -0,0 +1,720 @@
+use crate::codecs::codecs_from_init;
+use crate::mp4::{read_init_tracks, read_sample_entries};
+use access_unit::Fmp4;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `#EXT-X-BYTERANGE` or a `BYTERANGE` attribute. `None` offset continues
+/// from the end of the previous range of the same resource.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub length: u64,
+    pub offset: Option<u64>,
+}
+
+/// Init segment referenced by `#EXT-X-MAP`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InitSection {
+    pub uri: String,
+    pub byte_range: Option<ByteRange>,
+}
+
+/// An LL-HLS partial segment, such as one chunk from `box_fmp4_chunked`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialSegment {
+    pub uri: String,
+    pub duration_ms: u32,
+    pub independent: bool,
+    pub byte_range: Option<ByteRange>,
+}
+
+/// `#EXT-X-PRELOAD-HINT` for the part the server will publish next.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreloadHint {
+    pub uri: String,
+    pub byte_range_start: Option<u64>,
+    pub byte_range_length: Option<u64>,
+}
+
+/// `#EXT-X-DATERANGE`, for example carrying SCTE-35 attributes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DateRange {
+    pub id: String,
+    pub start_date: SystemTime,
+    pub duration: Option<f64>,
+    /// Extra attributes written verbatim, such as `SCTE35-OUT=0x...`.
+    pub attributes: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaSegment {
+    pub uri: String,
+    pub duration_ms: u32,
+    pub byte_range: Option<ByteRange>,
+    /// `None` keeps the previous segment's init section.
+    pub map: Option<InitSection>,
+    pub program_date_time: Option<SystemTime>,
+    pub discontinuity: bool,
+    pub parts: Vec<PartialSegment>,
+    pub date_ranges: Vec<DateRange>,
+}
+
+impl MediaSegment {
+    pub fn new(uri: impl Into<String>, duration_ms: u32) -> Self {
+        Self {
+            uri: uri.into(),
+            duration_ms,
+            byte_range: None,
+            map: None,
+            program_date_time: None,
+            discontinuity: false,
+            parts: Vec::new(),
+            date_ranges: Vec::new(),
+        }
+    }
+
+    /// A segment for a boxed fragment, using its duration.
+    pub fn from_fmp4(uri: impl Into<String>, fmp4: &Fmp4) -> Self {
+        Self::new(uri, fmp4.duration)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaylistType {
+    /// A live sliding window holding at most this many segments.
+    Live {
+        window: usize,
+    },
+    /// Segments are only appended; written as `EVENT`.
+    Event,
+    Vod,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediaPlaylist {
+    pub playlist_type: PlaylistType,
+    pub media_sequence: u64,
+    pub discontinuity_sequence: u64,
+    /// `PART-TARGET` in milliseconds, required when segments carry parts.
+    pub part_target_ms: Option<u32>,
+    /// `PART-HOLD-BACK` in milliseconds, written with `PART-INF`. `None` uses
+    /// three times the part target; the spec requires at least twice.
+    pub part_hold_back_ms: Option<u32>,
+    /// `#EXT-X-TARGETDURATION` in seconds. `None` uses the longest segment
+    /// pushed so far, which never shrinks as segments leave the window.
+    pub target_duration: Option<u32>,
+    pub segments: VecDeque<MediaSegment>,
+    /// Parts of the segment still being written, listed after the last
+    /// complete segment.
+    pub pending_parts: Vec<PartialSegment>,
+    pub preload_hint: Option<PreloadHint>,
+    pub ended: bool,
+    longest_segment_ms: u32,
+}
+
+impl MediaPlaylist {
+    pub fn new(playlist_type: PlaylistType) -> Self {
+        Self {
+            playlist_type,
+            media_sequence: 0,
+            discontinuity_sequence: 0,
+            part_target_ms: None,
+            part_hold_back_ms: None,
+            target_duration: None,
+            segments: VecDeque::new(),
+            pending_parts: Vec::new(),
+            preload_hint: None,
+            ended: false,
+            longest_segment_ms: 0,
+        }
+    }
+
+    /// Append a part of the segment being written.
+    pub fn push_part(&mut self, part: PartialSegment) {
+        self.pending_parts.push(part);
+    }
+
+    /// Append a segment. A segment without parts takes the pending ones, and
+    /// the pending parts are cleared either way. Live playlists then drop the
+    /// oldest segments beyond the window, advancing the media and
+    /// discontinuity sequence numbers.
+    pub fn push_segment(&mut self, mut segment: MediaSegment) {
+        if segment.map.is_none() {
+            segment.map = self.segments.back().and_then(|last| last.map.clone());
+        }
+        let pending_parts = std::mem::take(&mut self.pending_parts);
+        if segment.parts.is_empty() {
+            segment.parts = pending_parts;
+        }
+        self.longest_segment_ms = self.longest_segment_ms.max(segment.duration_ms);
+        self.segments.push_back(segment);
+        if let PlaylistType::Live { window } = self.playlist_type {
+            while self.segments.len() > window.max(1) {
+                if let Some(removed) = self.segments.pop_front() {
+                    self.media_sequence += 1;
+                    if removed.discontinuity {
+                        self.discontinuity_sequence += 1;
+                    }
+                }
+            }
+            // The first segment's discontinuity is implied by the sequence.
+            if let Some(first) = self.segments.front_mut() {
+                if first.discontinuity {
+                    first.discontinuity = false;
+                    self.discontinuity_sequence += 1;
+                }
+            }
+        }
+    }
+
+    /// Mark the playlist complete with `#EXT-X-ENDLIST`.
+    pub fn end(&mut self) {
+        self.ended = true;
+    }
+
+    /// `#EXT-X-TARGETDURATION`: the configured target, or the longest segment
+    /// ever pushed rounded to whole seconds.
+    pub fn target_duration(&self) -> u32 {
+        if let Some(target_duration) = self.target_duration {
+            return target_duration;
+        }
+        let longest_ms = self
+            .segments
+            .iter()
+            .map(|segment| segment.duration_ms)
+            .fold(self.longest_segment_ms, u32::max);
+        (longest_ms.saturating_add(500) / 1_000).max(1)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from("#EXTM3U\n");
+        let _ = writeln!(out, "#EXT-X-VERSION:6");
+        let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", self.target_duration());
+        match self.playlist_type {
+            PlaylistType::Live { .. } => {}
+            PlaylistType::Event => out.push_str("#EXT-X-PLAYLIST-TYPE:EVENT\n"),
+            PlaylistType::Vod => out.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n"),
+        }
+        let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", self.media_sequence);
+        if self.discontinuity_sequence > 0 {
+            let _ = writeln!(
+                out,
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+                self.discontinuity_sequence
+            );
+        }
+        out.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+        if let Some(part_target_ms) = self.part_target_ms {
+            let part_hold_back_ms = self
+                .part_hold_back_ms
+                .unwrap_or(part_target_ms.saturating_mul(3));
+            let _ = writeln!(
+                out,
+                "#EXT-X-SERVER-CONTROL:PART-HOLD-BACK={}",
+                seconds(part_hold_back_ms)
+            );
+            let _ = writeln!(
+                out,
+                "#EXT-X-PART-INF:PART-TARGET={}",
+                seconds(part_target_ms)
+            );
+        }
+
+        let mut current_map = None;
+        for segment in &self.segments {
+            if segment.discontinuity {
+                out.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            if segment.map.is_some() && segment.map.as_ref() != current_map {
+                if let Some(map) = &segment.map {
+                    let _ = write!(out, "#EXT-X-MAP:URI=\"{}\"", map.uri);
+                    if let Some(range) = map.byte_range {
+                        // EXT-X-MAP byte ranges always carry an offset.
+                        let _ = write!(
+                            out,
+                            ",BYTERANGE=\"{}@{}\"",
+                            range.length,
+                            range.offset.unwrap_or(0)
+                        );
+                    }
+                    out.push('\n');
+                }
+                current_map = segment.map.as_ref();
+            }
+            if let Some(time) = segment.program_date_time {
+                let _ = writeln!(out, "#EXT-X-PROGRAM-DATE-TIME:{}", format_date_time(time));
+            }
+            for date_range in &segment.date_ranges {
+                render_date_range(&mut out, date_range);
+            }
+            for part in &segment.parts {
+                render_part(&mut out, part);
+            }
+            let _ = writeln!(out, "#EXTINF:{},", seconds(segment.duration_ms));
+            if let Some(range) = segment.byte_range {
+                let _ = writeln!(out, "#EXT-X-BYTERANGE:{}", format_byte_range(range));
+            }
+            let _ = writeln!(out, "{}", segment.uri);
+        }
+        if self.ended || self.playlist_type == PlaylistType::Vod {
+            out.push_str("#EXT-X-ENDLIST\n");
+            return out;
+        }
+        for part in &self.pending_parts {
+            render_part(&mut out, part);
+        }
+        if let Some(hint) = &self.preload_hint {
+            let _ = write!(out, "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\"", hint.uri);
+            if let Some(start) = hint.byte_range_start {
+                let _ = write!(out, ",BYTERANGE-START={start}");
+            }
+            if let Some(length) = hint.byte_range_length {
+                let _ = write!(out, ",BYTERANGE-LENGTH={length}");
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn render_part(out: &mut String, part: &PartialSegment) {
+    let _ = write!(
+        out,
+        "#EXT-X-PART:DURATION={},URI=\"{}\"",
+        seconds(part.duration_ms),
+        part.uri
+    );
+    if part.independent {
+        out.push_str(",INDEPENDENT=YES");
+    }
+    if let Some(range) = part.byte_range {
+        let _ = write!(out, ",BYTERANGE=\"{}\"", format_byte_range(range));
+    }
+    out.push('\n');
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenditionType {
+    Audio,
+    Subtitles,
+    ClosedCaptions,
+}
+
+/// `#EXT-X-MEDIA`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rendition {
+    pub rendition_type: RenditionType,
+    pub group_id: String,
+    pub name: String,
+    pub language: Option<String>,
+    /// Absent for closed captions, which travel inside the video.
+    pub uri: Option<String>,
+    pub default: bool,
+    pub autoselect: bool,
+    /// `INSTREAM-ID` such as `CC1`, for closed captions.
+    pub instream_id: Option<String>,
+    pub channels: Option<String>,
+}
+
+/// `#EXT-X-STREAM-INF`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    pub uri: String,
+    pub bandwidth: u64,
+    pub average_bandwidth: Option<u64>,
+    pub codecs: String,
+    pub resolution: Option<(u16, u16)>,
+    pub frame_rate: Option<f64>,
+    pub audio: Option<String>,
+    pub subtitles: Option<String>,
+    /// `CLOSED-CAPTIONS` group id; `None` writes `NONE`.
+    pub closed_captions: Option<String>,
+}
+
+impl Variant {
+    /// A variant whose `CODECS` and `RESOLUTION` come from the init segment
+    /// the boxer wrote for it.
+    pub fn from_init(uri: impl Into<String>, bandwidth: u64, init: &[u8]) -> Option<Self> {
+        Some(Self {
+            uri: uri.into(),
+            bandwidth,
+            average_bandwidth: None,
+            codecs: codecs_from_init(init)?,
+            resolution: resolution_from_init(init),
+            frame_rate: None,
+            audio: None,
+            subtitles: None,
+            closed_captions: None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MultivariantPlaylist {
+    pub renditions: Vec<Rendition>,
+    pub variants: Vec<Variant>,
+}
+
+impl MultivariantPlaylist {
+    pub fn render(&self) -> String {
+        let mut out = String::from("#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+        for rendition in &self.renditions {
+            let kind = match rendition.rendition_type {
+                RenditionType::Audio => "AUDIO",
+                RenditionType::Subtitles => "SUBTITLES",
+                RenditionType::ClosedCaptions => "CLOSED-CAPTIONS",
+            };
+            let _ = write!(
+                out,
+                "#EXT-X-MEDIA:TYPE={kind},GROUP-ID=\"{}\",NAME=\"{}\"",
+                rendition.group_id, rendition.name
+            );
+            if let Some(language) = &rendition.language {
+                let _ = write!(out, ",LANGUAGE=\"{language}\"");
+            }
+            let _ = write!(
+                out,
+                ",DEFAULT={},AUTOSELECT={}",
+                yes_no(rendition.default),
+                yes_no(rendition.autoselect)
+            );
+            if let Some(instream_id) = &rendition.instream_id {
+                let _ = write!(out, ",INSTREAM-ID=\"{instream_id}\"");
+            }
+            if let Some(channels) = &rendition.channels {
+                let _ = write!(out, ",CHANNELS=\"{channels}\"");
+            }
+            if let Some(uri) = &rendition.uri {
+                let _ = write!(out, ",URI=\"{uri}\"");
+            }
+            out.push('\n');
+        }
+        for variant in &self.variants {
+            let _ = write!(out, "#EXT-X-STREAM-INF:BANDWIDTH={}", variant.bandwidth);
+            if let Some(average) = variant.average_bandwidth {
+                let _ = write!(out, ",AVERAGE-BANDWIDTH={average}");
+            }
+            let _ = write!(out, ",CODECS=\"{}\"", variant.codecs);
+            if let Some((width, height)) = variant.resolution {
+                let _ = write!(out, ",RESOLUTION={width}x{height}");
+            }
+            if let Some(frame_rate) = variant.frame_rate {
+                let _ = write!(out, ",FRAME-RATE={frame_rate:.3}");
+            }
+            if let Some(audio) = &variant.audio {
+                let _ = write!(out, ",AUDIO=\"{audio}\"");
+            }
+            if let Some(subtitles) = &variant.subtitles {
+                let _ = write!(out, ",SUBTITLES=\"{subtitles}\"");
+            }
+            match &variant.closed_captions {
+                Some(group) => {
+                    let _ = write!(out, ",CLOSED-CAPTIONS=\"{group}\"");
+                }
+                None => out.push_str(",CLOSED-CAPTIONS=NONE"),
+            }
+            let _ = writeln!(out, "\n{}", variant.uri);
+        }
+        out
+    }
+}
+
+fn resolution_from_init(init: &[u8]) -> Option<(u16, u16)> {
+    let tracks = read_init_tracks(init)?;
+    let entries = read_sample_entries(init)?;
+    let (_, entry) = tracks
+        .iter()
+        .zip(&entries)
+        .find(|(track, _)| track.handler_type == *b"vide")?;
+    let size = entry.payload.get(24..28)?;
+    Some((
+        u16::from_be_bytes([size[0], size[1]]),
+        u16::from_be_bytes([size[2], size[3]]),
+    ))
+}
+
+fn render_date_range(out: &mut String, date_range: &DateRange) {
+    let _ = write!(
+        out,
+        "#EXT-X-DATERANGE:ID=\"{}\",START-DATE=\"{}\"",
+        date_range.id,
+        format_date_time(date_range.start_date)
+    );
+    if let Some(duration) = date_range.duration {
+        let _ = write!(out, ",DURATION={duration:.3}");
+    }
+    for (name, value) in &date_range.attributes {
+        let _ = write!(out, ",{name}={value}");
+    }
+    out.push('\n');
+}
+
+fn format_byte_range(range: ByteRange) -> String {
+    match range.offset {
+        Some(offset) => format!("{}@{}", range.length, offset),
+        None => range.length.to_string(),
+    }
+}
+
+fn seconds(ms: u32) -> String {
+    format!("{}.{:03}", ms / 1_000, ms % 1_000)
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "YES"
+    } else {
+        "NO"
+    }
+}
+
+/// ISO 8601 UTC with milliseconds, as `#EXT-X-PROGRAM-DATE-TIME` and MPD
+/// dates expect.
+pub(crate) fn format_date_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days(seconds / 86_400);
+    let seconds_of_day = seconds % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds_of_day / 3_600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Proleptic Gregorian date of a day count since 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cenc::{Encryption, EncryptionScheme};
+    use crate::fmp4::{
+        box_fmp4_with_tracks, AudioTrack, AvcDecoderConfigurationRecord, Config, FragmentMedia,
+        TrackMetadata,
+    };
+    use access_unit::{AccessUnit, PSI_STREAM_H264};
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    fn init_segment() -> Bytes {
+        init_segment_with(None)
+    }
+
+    fn init_segment_with(encryption: Option<Encryption>) -> Bytes {
+        let aac = access_unit::aac::create_adts_header(0x66, 2, 48_000, 1, false);
+        box_fmp4_with_tracks(
+            1,
+            Config {
+                encryption,
+                width: 1280,
+                height: 720,
+                avcc: Some(AvcDecoderConfigurationRecord {
+                    profile_idc: 100,
+                    constraint_set_flag: 0,
+                    level_idc: 31,
+                    sequence_parameter_set: Bytes::from_static(&[0x67, 0x64, 0x00, 0x1f]),
+                    picture_parameter_set: Bytes::from_static(&[0x68, 0xce, 0x06, 0xe2]),
+                }),
+                ..Config::default()
+            },
+            FragmentMedia {
+                video: vec![AccessUnit {
+                    key: true,
+                    pts: 0,
+                    dts: 0,
+                    data: Bytes::from_static(&[0, 0, 0, 1, 0x65]),
+                    stream_type: PSI_STREAM_H264,
+                    id: 0,
+                }],
+                audio: vec![AudioTrack {
+                    track_id: 2,
+                    config: None,
+                    metadata: TrackMetadata::default(),
+                    units: vec![AccessUnit {
+                        key: true,
+                        pts: 0,
+                        dts: 0,
+                        data: Bytes::from([aac.as_slice(), &[0x21]].concat()),
+                        stream_type: 0,
+                        id: 1,
+                    }],
+                }],
+                ..FragmentMedia::default()
+            },
+            3_000,
+            true,
+        )
+        .init
+        .expect("init segment")
+    }
+
+    #[test]
+    fn live_playlist_slides_window_and_tracks_discontinuities() {
+        let map = InitSection {
+            uri: "init.mp4".to_owned(),
+            byte_range: None,
+        };
+        let mut playlist = MediaPlaylist::new(PlaylistType::Live { window: 2 });
+        playlist.push_segment(MediaSegment {
+            map: Some(map),
+            program_date_time: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_250)),
+            ..MediaSegment::new("0.m4s", 6_006)
+        });
+        playlist.push_segment(MediaSegment {
+            discontinuity: true,
+            ..MediaSegment::new("1.m4s", 5_000)
+        });
+        playlist.push_segment(MediaSegment::new("2.m4s", 4_000));
+
+        // The 6 s segment has left the window but still sets the target.
+        assert_eq!(
+            playlist.render(),
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:1\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:5.000,\n1.m4s\n#EXTINF:4.000,\n2.m4s\n"
+        );
+    }
+
+    #[test]
+    fn live_playlist_lists_pending_parts_and_preload_hint() {
+        let part = |uri: &str, independent: bool| PartialSegment {
+            uri: uri.to_owned(),
+            duration_ms: 500,
+            independent,
+            byte_range: None,
+        };
+        let mut playlist = MediaPlaylist::new(PlaylistType::Live { window: 3 });
+        playlist.part_target_ms = Some(500);
+        playlist.push_part(part("0.0.m4s", true));
+        playlist.push_part(part("0.1.m4s", false));
+        playlist.push_segment(MediaSegment::new("0.m4s", 1_000));
+        playlist.push_part(part("1.0.m4s", true));
+        playlist.preload_hint = Some(PreloadHint {
+            uri: "1.1.m4s".to_owned(),
+            byte_range_start: None,
+            byte_range_length: None,
+        });
+
+        assert_eq!(playlist.segments[0].parts.len(), 2);
+        assert!(playlist.render().ends_with(
+            "#EXT-X-PART:DURATION=0.500,URI=\"0.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"0.1.m4s\"\n#EXTINF:1.000,\n0.m4s\n\
+             #EXT-X-PART:DURATION=0.500,URI=\"1.0.m4s\",INDEPENDENT=YES\n\
+             #EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"1.1.m4s\"\n"
+        ));
+
+        playlist.target_duration = Some(4);
+        assert!(playlist.render().contains("#EXT-X-TARGETDURATION:4\n"));
+    }
+
+    #[test]
+    fn vod_playlist_writes_byte_ranges_parts_and_program_date_time() {
+        let mut playlist = MediaPlaylist::new(PlaylistType::Vod);
+        playlist.part_target_ms = Some(500);
+        playlist.push_segment(MediaSegment {
+            map: Some(InitSection {
+                uri: "file.mp4".to_owned(),
+                byte_range: Some(ByteRange {
+                    length: 800,
+                    offset: None,
+                }),
+            }),
+            byte_range: Some(ByteRange {
+                length: 1_000,
+                offset: Some(800),
+            }),
+            program_date_time: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_250)),
+            parts: vec![PartialSegment {
+                uri: "file.mp4".to_owned(),
+                duration_ms: 500,
+                independent: true,
+                byte_range: Some(ByteRange {
+                    length: 400,
+                    offset: Some(800),
+                }),
+            }],
+            ..MediaSegment::new("file.mp4", 2_000)
+        });
+        let rendered = playlist.render();
+
+        assert!(rendered.contains("#EXT-X-PLAYLIST-TYPE:VOD\n"));
+        assert!(rendered.contains(
+            "#EXT-X-SERVER-CONTROL:PART-HOLD-BACK=1.500\n#EXT-X-PART-INF:PART-TARGET=0.500\n"
+        ));
+
+        playlist.part_hold_back_ms = Some(2_000);
+        assert!(playlist
+            .render()
+            .contains("#EXT-X-SERVER-CONTROL:PART-HOLD-BACK=2.000\n"));
+        assert!(rendered.contains("#EXT-X-MAP:URI=\"file.mp4\",BYTERANGE=\"800@0\"\n"));
+        assert!(rendered.contains("#EXT-X-PROGRAM-DATE-TIME:2023-11-14T22:13:20.250Z\n"));
+        assert!(rendered.contains(
+            "#EXT-X-PART:DURATION=0.500,URI=\"file.mp4\",INDEPENDENT=YES,BYTERANGE=\"400@800\"\n"
+        ));
+        assert!(rendered.contains("#EXTINF:2.000,\n#EXT-X-BYTERANGE:1000@800\nfile.mp4\n"));
+        assert!(rendered.ends_with("#EXT-X-ENDLIST\n"));
+    }
+
+    #[test]
+    fn multivariant_codecs_and_resolution_come_from_init_segment() {
+        let variant = Variant {
+            audio: Some("aac".to_owned()),
+            ..Variant::from_init("720p.m3u8", 3_000_000, &init_segment()).expect("variant")
+        };
+        let playlist = MultivariantPlaylist {
+            renditions: vec![Rendition {
+                rendition_type: RenditionType::Audio,
+                group_id: "aac".to_owned(),
+                name: "English".to_owned(),
+                language: Some("en".to_owned()),
+                uri: Some("audio.m3u8".to_owned()),
+                default: true,
+                autoselect: true,
+                instream_id: None,
+                channels: Some("2".to_owned()),
+            }],
+            variants: vec![variant],
+        };
+
+        assert_eq!(
+            playlist.render(),
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",NAME=\"English\",LANGUAGE=\"en\",\
+             DEFAULT=YES,AUTOSELECT=YES,CHANNELS=\"2\",URI=\"audio.m3u8\"\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=3000000,CODECS=\"avc1.64001F,mp4a.40.2\",\
+             RESOLUTION=1280x720,AUDIO=\"aac\",CLOSED-CAPTIONS=NONE\n720p.m3u8\n"
+        );
+    }
+
+    #[test]
+    fn encrypted_init_segment_reports_the_original_codecs() {
+        let encryption =
+            Encryption::new(EncryptionScheme::Cbcs, [0x11; 16], [0x22; 16], Vec::new())
+                .expect("encryption");
+        let variant =
+            Variant::from_init("720p.m3u8", 3_000_000, &init_segment_with(Some(encryption)))
+                .expect("variant");
+
+        assert_eq!(variant.codecs, "avc1.64001F,mp4a.40.2");
+    }
+}
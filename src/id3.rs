@@ -0,0 +1,352 @@
+use crate::mp4::{EventMessage, EventPresentationTime};
+
+/// `emsg` scheme for ID3 timed metadata (AOM "Carriage of ID3 Timed Metadata
+/// in CMAF").
+pub const ID3_EMSG_SCHEME: &str = "https://aomedia.org/emsg/ID3";
+
+/// `mime_format` of a `mett` sample entry whose samples are ID3 tags.
+pub const ID3_MIME_FORMAT: &str = "application/id3";
+
+const ENCODING_LATIN1: u8 = 0;
+const ENCODING_UTF16: u8 = 1;
+const ENCODING_UTF16BE: u8 = 2;
+const ENCODING_UTF8: u8 = 3;
+
+const FLAG_UNSYNCHRONISATION: u8 = 0x80;
+const FLAG_EXTENDED_HEADER: u8 = 0x40;
+
+/// One ID3v2 frame. Text is written as UTF-8, which ID3v2.4 allows for every
+/// field except PRIV owners and GEOB MIME types, which stay ISO-8859-1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Id3Frame {
+    /// User-defined text, such as `TXXX:ad-id`.
+    Txxx { description: String, value: String },
+    /// Private data identified by an owner URL or e-mail address.
+    Priv { owner: String, data: Vec<u8> },
+    /// Title.
+    Tit2(String),
+    /// General encapsulated object.
+    Geob {
+        mime_type: String,
+        filename: String,
+        description: String,
+        data: Vec<u8>,
+    },
+    /// Any other frame, kept as its raw body.
+    Other { id: [u8; 4], data: Vec<u8> },
+}
+
+/// An ID3v2 tag, written as version 2.4.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Id3Tag {
+    pub frames: Vec<Id3Frame>,
+}
+
+impl Id3Tag {
+    /// Decode an ID3v2.3 or ID3v2.4 tag. Unsynchronised tags are rejected.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.get(..3)? != b"ID3" {
+            return None;
+        }
+        let version = *data.get(3)?;
+        let flags = *data.get(5)?;
+        if !matches!(version, 3 | 4) || flags & FLAG_UNSYNCHRONISATION != 0 {
+            return None;
+        }
+        let size = read_syncsafe(data.get(6..10)?)?;
+        let mut body = data.get(10..10 + size)?;
+        if flags & FLAG_EXTENDED_HEADER != 0 {
+            let extended = body.get(..4)?;
+            let skip = if version == 4 {
+                read_syncsafe(extended)?
+            } else {
+                read_u32(extended) as usize + 4
+            };
+            body = body.get(skip..)?;
+        }
+
+        let mut frames = Vec::new();
+        while body.len() >= 10 && body[0] != 0 {
+            let id: [u8; 4] = body[..4].try_into().ok()?;
+            let frame_size = if version == 4 {
+                read_syncsafe(&body[4..8])?
+            } else {
+                read_u32(&body[4..8]) as usize
+            };
+            let frame = body.get(10..10 + frame_size)?;
+            frames.push(parse_frame(id, frame)?);
+            body = &body[10 + frame_size..];
+        }
+        Some(Self { frames })
+    }
+
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let mut frames = Vec::new();
+        for frame in &self.frames {
+            write_frame(&mut frames, frame)?;
+        }
+        let mut out = Vec::with_capacity(frames.len() + 10);
+        out.extend_from_slice(b"ID3");
+        out.extend_from_slice(&[4, 0, 0]);
+        out.extend_from_slice(&syncsafe(frames.len())?);
+        out.extend_from_slice(&frames);
+        Some(out)
+    }
+
+    /// Carry the tag in a version 1 `emsg` box, as the AOM ID3 carriage spec
+    /// requires. `presentation_time` is on the track timeline in `timescale`.
+    pub fn to_event_message(
+        &self,
+        id: u32,
+        timescale: u32,
+        presentation_time: u64,
+        event_duration: u32,
+    ) -> Option<EventMessage> {
+        Some(EventMessage {
+            scheme_id_uri: ID3_EMSG_SCHEME.to_owned(),
+            value: String::new(),
+            timescale,
+            presentation_time: EventPresentationTime::Absolute(presentation_time),
+            event_duration,
+            id,
+            message_data: self.to_bytes()?,
+        })
+    }
+}
+
+fn parse_frame(id: [u8; 4], data: &[u8]) -> Option<Id3Frame> {
+    Some(match &id {
+        b"TXXX" => {
+            let (&encoding, rest) = data.split_first()?;
+            let (description, value) = split_terminated(rest, encoding)?;
+            Id3Frame::Txxx {
+                description: decode_text(description, encoding)?,
+                value: decode_text(trim_terminator(value, encoding), encoding)?,
+            }
+        }
+        b"TIT2" => {
+            let (&encoding, rest) = data.split_first()?;
+            Id3Frame::Tit2(decode_text(trim_terminator(rest, encoding), encoding)?)
+        }
+        b"PRIV" => {
+            let (owner, data) = split_terminated(data, ENCODING_LATIN1)?;
+            Id3Frame::Priv {
+                owner: decode_text(owner, ENCODING_LATIN1)?,
+                data: data.to_vec(),
+            }
+        }
+        b"GEOB" => {
+            let (&encoding, rest) = data.split_first()?;
+            let (mime_type, rest) = split_terminated(rest, ENCODING_LATIN1)?;
+            let (filename, rest) = split_terminated(rest, encoding)?;
+            let (description, data) = split_terminated(rest, encoding)?;
+            Id3Frame::Geob {
+                mime_type: decode_text(mime_type, ENCODING_LATIN1)?,
+                filename: decode_text(filename, encoding)?,
+                description: decode_text(description, encoding)?,
+                data: data.to_vec(),
+            }
+        }
+        _ => Id3Frame::Other {
+            id,
+            data: data.to_vec(),
+        },
+    })
+}
+
+fn write_frame(out: &mut Vec<u8>, frame: &Id3Frame) -> Option<()> {
+    let mut body = Vec::new();
+    let id = match frame {
+        Id3Frame::Txxx { description, value } => {
+            body.push(ENCODING_UTF8);
+            write_terminated(&mut body, description)?;
+            body.extend_from_slice(value.as_bytes());
+            *b"TXXX"
+        }
+        Id3Frame::Tit2(title) => {
+            body.push(ENCODING_UTF8);
+            body.extend_from_slice(title.as_bytes());
+            *b"TIT2"
+        }
+        Id3Frame::Priv { owner, data } => {
+            write_latin1_terminated(&mut body, owner)?;
+            body.extend_from_slice(data);
+            *b"PRIV"
+        }
+        Id3Frame::Geob {
+            mime_type,
+            filename,
+            description,
+            data,
+        } => {
+            body.push(ENCODING_UTF8);
+            write_latin1_terminated(&mut body, mime_type)?;
+            write_terminated(&mut body, filename)?;
+            write_terminated(&mut body, description)?;
+            body.extend_from_slice(data);
+            *b"GEOB"
+        }
+        Id3Frame::Other { id, data } => {
+            body.extend_from_slice(data);
+            *id
+        }
+    };
+    out.extend_from_slice(&id);
+    out.extend_from_slice(&syncsafe(body.len())?);
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(&body);
+    Some(())
+}
+
+/// Write UTF-8 followed by a null terminator; embedded nulls are rejected.
+fn write_terminated(out: &mut Vec<u8>, value: &str) -> Option<()> {
+    if value.contains('\0') {
+        return None;
+    }
+    out.extend_from_slice(value.as_bytes());
+    out.push(0);
+    Some(())
+}
+
+fn write_latin1_terminated(out: &mut Vec<u8>, value: &str) -> Option<()> {
+    for c in value.chars() {
+        out.push(u8::try_from(u32::from(c)).ok().filter(|&byte| byte != 0)?);
+    }
+    out.push(0);
+    Some(())
+}
+
+/// Split at the encoding's terminator, returning the text before it and the
+/// bytes after it.
+fn split_terminated(data: &[u8], encoding: u8) -> Option<(&[u8], &[u8])> {
+    if is_utf16(encoding) {
+        let end = data.chunks_exact(2).position(|unit| unit == [0, 0])? * 2;
+        Some((&data[..end], &data[end + 2..]))
+    } else {
+        let end = data.iter().position(|&byte| byte == 0)?;
+        Some((&data[..end], &data[end + 1..]))
+    }
+}
+
+/// Drop a trailing terminator that some writers append to the last string.
+fn trim_terminator(data: &[u8], encoding: u8) -> &[u8] {
+    if is_utf16(encoding) {
+        data.strip_suffix(&[0, 0]).unwrap_or(data)
+    } else {
+        data.strip_suffix(&[0]).unwrap_or(data)
+    }
+}
+
+fn is_utf16(encoding: u8) -> bool {
+    matches!(encoding, ENCODING_UTF16 | ENCODING_UTF16BE)
+}
+
+fn decode_text(data: &[u8], encoding: u8) -> Option<String> {
+    match encoding {
+        ENCODING_LATIN1 => Some(data.iter().map(|&byte| char::from(byte)).collect()),
+        ENCODING_UTF8 => String::from_utf8(data.to_vec()).ok(),
+        ENCODING_UTF16 | ENCODING_UTF16BE => {
+            let (little_endian, data) = match data {
+                [0xff, 0xfe, rest @ ..] => (true, rest),
+                [0xfe, 0xff, rest @ ..] => (false, rest),
+                // UTF-16 without a BOM is big-endian.
+                _ => (false, data),
+            };
+            let units: Vec<u16> = data
+                .chunks_exact(2)
+                .map(|unit| {
+                    if little_endian {
+                        u16::from_le_bytes([unit[0], unit[1]])
+                    } else {
+                        u16::from_be_bytes([unit[0], unit[1]])
+                    }
+                })
+                .collect();
+            String::from_utf16(&units).ok()
+        }
+        _ => None,
+    }
+}
+
+fn syncsafe(value: usize) -> Option<[u8; 4]> {
+    if value >= 1 << 28 {
+        return None;
+    }
+    Some([
+        (value >> 21) as u8 & 0x7f,
+        (value >> 14) as u8 & 0x7f,
+        (value >> 7) as u8 & 0x7f,
+        value as u8 & 0x7f,
+    ])
+}
+
+fn read_syncsafe(bytes: &[u8]) -> Option<usize> {
+    bytes.get(..4)?.iter().try_fold(0_usize, |value, &byte| {
+        (byte & 0x80 == 0).then_some((value << 7) | usize::from(byte))
+    })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_supported_frames() {
+        let tag = Id3Tag {
+            frames: vec![
+                Id3Frame::Tit2("Live".to_owned()),
+                Id3Frame::Txxx {
+                    description: "ad-id".to_owned(),
+                    value: "42".to_owned(),
+                },
+                Id3Frame::Priv {
+                    owner: "com.example.analytics".to_owned(),
+                    data: vec![0, 1, 2, 0xff],
+                },
+                Id3Frame::Geob {
+                    mime_type: "application/json".to_owned(),
+                    filename: "beacon.json".to_owned(),
+                    description: "beacon".to_owned(),
+                    data: b"{}".to_vec(),
+                },
+            ],
+        };
+        let bytes = tag.to_bytes().expect("bytes");
+
+        assert_eq!(&bytes[..6], b"ID3\x04\0\0");
+        assert_eq!(read_syncsafe(&bytes[6..10]), Some(bytes.len() - 10));
+        assert_eq!(Id3Tag::parse(&bytes), Some(tag));
+    }
+
+    #[test]
+    fn parses_v23_utf16_text_frame() {
+        let mut tag = b"ID3\x03\0\0\0\0\0\x15TIT2\0\0\0\x07\0\0\x01\xff\xfeh\0i\0".to_vec();
+        tag.extend_from_slice(&[0; 4]);
+
+        assert_eq!(
+            Id3Tag::parse(&tag),
+            Some(Id3Tag {
+                frames: vec![Id3Frame::Tit2("hi".to_owned())],
+            })
+        );
+    }
+
+    #[test]
+    fn emsg_uses_aom_id3_scheme_and_absolute_time() {
+        let tag = Id3Tag {
+            frames: vec![Id3Frame::Tit2("x".to_owned())],
+        };
+        let event = tag.to_event_message(7, 90_000, 900_000, 0).expect("event");
+
+        assert_eq!(event.scheme_id_uri, ID3_EMSG_SCHEME);
+        assert_eq!(
+            event.presentation_time,
+            EventPresentationTime::Absolute(900_000)
+        );
+        assert_eq!(Some(event.message_data), tag.to_bytes());
+    }
+}
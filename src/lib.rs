@@ -1,3 +1,19 @@
+mod base64;
+pub mod captions;
+pub mod cenc;
+pub mod cmaf;
+pub mod codecs;
+pub mod dash;
 pub mod fmp4;
+pub mod fragmenter;
+pub mod hls;
+pub mod id3;
 mod mp4;
+pub mod progressive;
 pub mod rtmp;
+pub mod scte35;
+pub mod sidx;
+#[cfg(feature = "async")]
+pub mod stream;
+pub mod ts;
+pub mod webvtt;
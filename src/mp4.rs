@@ -206,21 +206,50 @@ pub struct FragmentSample {
     pub composition_time_offset: Option<i32>,
 }
 
-impl FragmentSample {
-    fn trun_flags(self) -> u32 {
-        (self.duration.is_some() as u32 * 0x00_0100)
-            | (self.size.is_some() as u32 * 0x00_0200)
-            | (self.flags.is_some() as u32 * 0x00_0400)
-            | (self.composition_time_offset.is_some() as u32 * 0x00_0800)
-    }
-}
-
 #[derive(Debug)]
 pub struct FragmentTrack<'a> {
     pub track_id: u32,
     pub base_media_decode_time: u64,
     pub samples: Vec<FragmentSample>,
-    pub data: &'a [u8],
+    /// The track's sample data, split across any number of buffers.
+    pub data: &'a [Bytes],
+    /// Subsample sizes for each sample, written to `subs`. Leave empty, or
+    /// give a sample no sizes, when there is no subsample structure.
+    pub subsample_sizes: Vec<Vec<u32>>,
+    /// One `senc` entry per sample of a protected track, with `saiz` and
+    /// `saio` pointing at them. Leave empty for a clear track.
+    pub sample_encryption: Vec<SampleEncryptionEntry>,
+}
+
+/// A run of clear bytes followed by a run of protected bytes in a sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subsample {
+    pub clear_bytes: u16,
+    pub protected_bytes: u32,
+}
+
+/// The IV and subsample layout of one protected sample.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SampleEncryptionEntry {
+    /// Empty when the track uses a constant IV.
+    pub iv: Vec<u8>,
+    /// Empty when the whole sample is protected.
+    pub subsamples: Vec<Subsample>,
+}
+
+/// `tenc` defaults of a track protected with Common Encryption.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrackEncryption {
+    /// `cenc` or `cbcs`, written to `schm`.
+    pub scheme_type: [u8; 4],
+    pub key_id: [u8; 16],
+    /// 8 or 16, or 0 when every sample uses `constant_iv`.
+    pub per_sample_iv_size: u8,
+    pub constant_iv: Option<[u8; 16]>,
+    /// Encrypted and skipped 16-byte blocks of the pattern. Both are zero
+    /// without pattern encryption.
+    pub crypt_byte_block: u8,
+    pub skip_byte_block: u8,
 }
 
 #[derive(Clone, Debug)]
@@ -263,28 +292,93 @@ pub enum AudioInit {
     },
 }
 
-pub fn write_media_segment(
+/// When an `emsg` event applies on the media timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventPresentationTime {
+    /// Version 0: offset from the earliest presentation time of the segment.
+    Delta(u32),
+    /// Version 1: absolute time on the track timeline.
+    Absolute(u64),
+}
+
+/// An in-band event such as an SCTE-35 cue or ID3 timed metadata, written as
+/// an `emsg` box ahead of the segment's `moof`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventMessage {
+    pub scheme_id_uri: String,
+    pub value: String,
+    pub timescale: u32,
+    pub presentation_time: EventPresentationTime,
+    /// `u32::MAX` marks an event of unknown duration.
+    pub event_duration: u32,
+    pub id: u32,
+    pub message_data: Vec<u8>,
+}
+
+/// A `prft` box relating a wall-clock instant to a media time of one track in
+/// the following `moof`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProducerReferenceTime {
+    pub reference_track_id: u32,
+    /// 64-bit NTP timestamp: seconds since 1900 in the upper 32 bits.
+    pub ntp_timestamp: u64,
+    /// Time on the reference track's timeline, in its timescale.
+    pub media_time: u64,
+    /// What the instant measures, such as 0 for encoder input.
+    pub flags: u32,
+}
+
+/// Boxes written ahead of a media segment's `moof`, in this order.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SegmentPrefix<'a> {
+    pub segment_type: Option<&'a FileType>,
+    pub producer_reference: Option<&'a ProducerReferenceTime>,
+    pub events: &'a [EventMessage],
+}
+
+/// Write a media segment's prefix boxes, `moof` and `mdat` header without
+/// the sample data, so the payloads can be sent straight from their own
+/// buffers. Each track's `data`, in track order, must follow the header.
+pub fn write_media_segment_header(
     out: &mut Vec<u8>,
     sequence_number: u32,
     tracks: &[FragmentTrack<'_>],
+    prefix: SegmentPrefix<'_>,
 ) -> Option<()> {
     let start = out.len();
-    let result = write_media_segment_inner(out, sequence_number, tracks);
+    let result = write_media_segment_header_inner(out, sequence_number, tracks, prefix);
     if result.is_none() {
         out.truncate(start);
     }
     result
 }
 
-fn write_media_segment_inner(
+fn write_media_segment_header_inner(
     out: &mut Vec<u8>,
     sequence_number: u32,
     tracks: &[FragmentTrack<'_>],
+    prefix: SegmentPrefix<'_>,
 ) -> Option<()> {
-    if tracks.is_empty() {
+    if tracks.is_empty() || has_duplicate_track_ids(tracks.iter().map(|track| track.track_id)) {
         return None;
     }
 
+    if let Some(segment_type) = prefix.segment_type {
+        write_file_type(out, *b"styp", segment_type)?;
+    }
+    if let Some(producer_reference) = prefix.producer_reference {
+        if !tracks
+            .iter()
+            .any(|track| track.track_id == producer_reference.reference_track_id)
+        {
+            return None;
+        }
+        write_prft(out, producer_reference)?;
+    }
+    for event in prefix.events {
+        write_emsg(out, event)?;
+    }
+
     let moof_start = out.len();
     let mut trun_data_offset_positions = Vec::with_capacity(tracks.len());
     write_moof(
@@ -299,27 +393,224 @@ fn write_media_segment_inner(
     for (position, track) in trun_data_offset_positions.iter().copied().zip(tracks) {
         let data_offset = moof_size.checked_add(8)?.checked_add(track_data_offset)?;
         patch_i32(out, position, i32::try_from(data_offset).ok()?);
-        track_data_offset = track_data_offset.checked_add(u64::try_from(track.data.len()).ok()?)?;
+        track_data_offset = track_data_offset.checked_add(track_data_len(track)?)?;
     }
 
-    write_box(out, *b"mdat", |out| {
-        for track in tracks {
-            out.extend_from_slice(track.data);
+    let mdat_size = u32::try_from(track_data_offset.checked_add(8)?).ok()?;
+    write_u32(out, mdat_size);
+    out.extend_from_slice(b"mdat");
+    Some(())
+}
+
+/// A track's data length. Readers fall back to the zero `trex` size for
+/// samples without one, so every sample needs a size and together they must
+/// cover the data.
+fn track_data_len(track: &FragmentTrack<'_>) -> Option<u64> {
+    let len = track
+        .samples
+        .iter()
+        .map(|sample| sample.size.map(u64::from))
+        .sum::<Option<u64>>()?;
+    let data_len = track
+        .data
+        .iter()
+        .map(|chunk| chunk.len() as u64)
+        .sum::<u64>();
+    (len == data_len).then_some(len)
+}
+
+#[derive(Clone, Debug)]
+pub enum TextInit {
+    /// ISO/IEC 14496-30 WebVTT. `config` is the WebVTT file header stored in
+    /// `vttC`, normally just `WEBVTT`.
+    WebVtt { track_id: u32, config: String },
+    /// ISO/IEC 14496-30 XML subtitles (TTML, IMSC1) in an `stpp` sample entry.
+    Ttml {
+        track_id: u32,
+        namespace: String,
+        schema_location: String,
+        auxiliary_mime_types: String,
+        /// CMAF `mime` box content, such as
+        /// `application/ttml+xml;codecs=im1t`.
+        mime_type: Option<String>,
+    },
+    /// CEA-608 byte pairs in a `c608` closed caption track.
+    Cea608 { track_id: u32 },
+}
+
+impl TextInit {
+    fn track_id(&self) -> u32 {
+        match self {
+            TextInit::WebVtt { track_id, .. }
+            | TextInit::Ttml { track_id, .. }
+            | TextInit::Cea608 { track_id } => *track_id,
         }
-        Some(())
-    })?;
+    }
+}
 
-    Some(())
+/// Timed metadata sample entries from ISO/IEC 14496-12 section 12.3.
+#[derive(Clone, Debug)]
+pub enum MetadataInit {
+    /// `mett`, for text or binary metadata identified by a MIME type such as
+    /// `application/id3`.
+    Text {
+        track_id: u32,
+        content_encoding: String,
+        mime_format: String,
+    },
+    /// `metx`, for XML metadata identified by namespace.
+    Xml {
+        track_id: u32,
+        content_encoding: String,
+        namespace: String,
+        schema_location: String,
+    },
+}
+
+impl MetadataInit {
+    fn track_id(&self) -> u32 {
+        match self {
+            MetadataInit::Text { track_id, .. } | MetadataInit::Xml { track_id, .. } => *track_id,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum MediaInit {
+    Video(VideoInit),
+    Audio(AudioInit),
+    Text(TextInit),
+    Metadata(MetadataInit),
+}
+
+impl MediaInit {
+    pub(crate) fn track_id(&self) -> u32 {
+        match self {
+            MediaInit::Video(video) => video.track_id,
+            MediaInit::Audio(audio) => audio.track_id(),
+            MediaInit::Text(text) => text.track_id(),
+            MediaInit::Metadata(metadata) => metadata.track_id(),
+        }
+    }
+
+    pub(crate) fn timescale(&self) -> u32 {
+        match self {
+            MediaInit::Video(_) => 90_000,
+            MediaInit::Audio(audio) => audio.timescale(),
+            // Text cues are timed in milliseconds, like RTMP timestamps.
+            MediaInit::Text(_) | MediaInit::Metadata(_) => 1_000,
+        }
+    }
+
+    fn handler(&self) -> ([u8; 4], &'static str) {
+        match self {
+            MediaInit::Video(_) => (*b"vide", "Video Handler"),
+            MediaInit::Audio(_) => (*b"soun", "Sound Handler"),
+            MediaInit::Text(TextInit::WebVtt { .. }) => (*b"text", "Text Handler"),
+            MediaInit::Text(TextInit::Ttml { .. }) => (*b"subt", "Subtitle Handler"),
+            MediaInit::Text(TextInit::Cea608 { .. }) => (*b"clcp", "Closed Caption Handler"),
+            MediaInit::Metadata(_) => (*b"meta", "Metadata Handler"),
+        }
+    }
+}
+
+/// Labels that distinguish otherwise similar tracks, such as alternate
+/// language or commentary audio renditions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackMetadata {
+    /// ISO-639-2/T language code such as `*b"eng"`. `None` writes `und`.
+    pub language: Option<[u8; 3]>,
+    /// Name written into `hdlr`. `None` uses the generic handler name.
+    pub handler_name: Option<String>,
+    /// `tkhd` track_enabled flag. Within an alternate group, players start
+    /// with the enabled track.
+    pub enabled: bool,
+    /// `tkhd` track_in_movie flag.
+    pub in_movie: bool,
+    /// `tkhd` track_in_preview flag.
+    pub in_preview: bool,
+    /// Tracks sharing a non-zero `alternate_group` are alternatives to each
+    /// other, such as the language renditions of one audio programme.
+    pub alternate_group: i16,
+    /// Roles written as `kind` boxes in the track's `udta`.
+    pub kinds: Vec<TrackKind>,
+}
+
+impl Default for TrackMetadata {
+    fn default() -> Self {
+        Self {
+            language: None,
+            handler_name: None,
+            enabled: true,
+            in_movie: true,
+            in_preview: true,
+            alternate_group: 0,
+            kinds: Vec::new(),
+        }
+    }
+}
+
+impl TrackMetadata {
+    fn tkhd_flags(&self) -> u32 {
+        u32::from(self.enabled)
+            | (u32::from(self.in_movie) << 1)
+            | (u32::from(self.in_preview) << 2)
+    }
+}
+
+/// A track role as carried by the ISO BMFF `kind` box.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackKind {
+    pub scheme_uri: String,
+    pub value: String,
+}
+
+impl TrackKind {
+    pub const DASH_ROLE_SCHEME: &'static str = "urn:mpeg:dash:role:2011";
+
+    /// A DASH role such as `main`, `alternate`, `commentary` or `dub`.
+    pub fn dash_role(value: &str) -> Self {
+        Self {
+            scheme_uri: Self::DASH_ROLE_SCHEME.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TrackInit {
+    pub media: MediaInit,
+    pub metadata: TrackMetadata,
+    /// Writes the sample entry as `encv` or `enca` with a `sinf`. Only video
+    /// and audio tracks can be protected.
+    pub encryption: Option<TrackEncryption>,
+}
+
+impl TrackInit {
+    pub(crate) fn track_id(&self) -> u32 {
+        self.media.track_id()
+    }
+}
+
+/// A `pssh` box advertising a DRM system and its initialization data. Key
+/// ids make it a version 1 box.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProtectionSystemHeader {
+    pub system_id: [u8; 16],
+    pub key_ids: Vec<[u8; 16]>,
+    pub data: Vec<u8>,
 }
 
 pub fn write_init_segment(
     out: &mut Vec<u8>,
+    file_type: &FileType,
     movie_timescale: u32,
-    video: Option<&VideoInit>,
-    audio: Option<&AudioInit>,
+    tracks: &[TrackInit],
+    protection_systems: &[ProtectionSystemHeader],
 ) -> Option<()> {
     let start = out.len();
-    let result = write_init_segment_inner(out, movie_timescale, video, audio);
+    let result =
+        write_init_segment_inner(out, file_type, movie_timescale, tracks, protection_systems);
     if result.is_none() {
         out.truncate(start);
     }
@@ -328,40 +619,56 @@ pub fn write_init_segment(
 
 fn write_init_segment_inner(
     out: &mut Vec<u8>,
+    file_type: &FileType,
     movie_timescale: u32,
-    video: Option<&VideoInit>,
-    audio: Option<&AudioInit>,
+    tracks: &[TrackInit],
+    protection_systems: &[ProtectionSystemHeader],
 ) -> Option<()> {
-    if video.is_none() && audio.is_none() {
+    if tracks.is_empty() || has_duplicate_track_ids(tracks.iter().map(TrackInit::track_id)) {
         return None;
     }
 
-    write_ftyp(out)?;
+    write_file_type(out, *b"ftyp", file_type)?;
     write_box(out, *b"moov", |out| {
         write_mvhd(out, movie_timescale, 0)?;
-        if let Some(video) = video {
-            write_trak(out, video.track_id, true, 90_000, 0, Some(video), None)?;
+        for track in tracks {
+            write_trak(out, track, 0)?;
         }
-        if let Some(audio) = audio {
-            write_trak(
-                out,
-                audio.track_id(),
-                false,
-                audio.timescale(),
-                0,
-                None,
-                Some(audio),
-            )?;
+        write_mvex(out, tracks)?;
+        for pssh in protection_systems {
+            write_pssh(out, pssh)?;
         }
-        write_mvex(
-            out,
-            video.map(|v| v.track_id),
-            audio.map(AudioInit::track_id),
-        )?;
         Some(())
     })
 }
 
+fn write_pssh(out: &mut Vec<u8>, pssh: &ProtectionSystemHeader) -> Option<()> {
+    let version = u8::from(!pssh.key_ids.is_empty());
+    write_full_box(out, *b"pssh", version, 0, |out| {
+        out.extend_from_slice(&pssh.system_id);
+        if version == 1 {
+            write_u32(out, u32::try_from(pssh.key_ids.len()).ok()?);
+            for key_id in &pssh.key_ids {
+                out.extend_from_slice(key_id);
+            }
+        }
+        write_u32(out, u32::try_from(pssh.data.len()).ok()?);
+        out.extend_from_slice(&pssh.data);
+        Some(())
+    })
+}
+
+pub(crate) fn has_duplicate_track_ids(track_ids: impl Iterator<Item = u32>) -> bool {
+    let mut seen = Vec::new();
+    for track_id in track_ids {
+        if track_id == 0 || seen.contains(&track_id) {
+            return true;
+        }
+        seen.push(track_id);
+    }
+    false
+}
+
 impl AudioInit {
     fn track_id(&self) -> u32 {
         match self {
@@ -381,19 +688,59 @@ impl AudioInit {
     }
 }
 
+fn write_prft(out: &mut Vec<u8>, prft: &ProducerReferenceTime) -> Option<()> {
+    let version = u8::from(prft.media_time > u64::from(u32::MAX));
+    write_full_box(out, *b"prft", version, prft.flags, |out| {
+        write_u32(out, prft.reference_track_id);
+        write_u64(out, prft.ntp_timestamp);
+        if version == 1 {
+            write_u64(out, prft.media_time);
+        } else {
+            write_u32(out, prft.media_time as u32);
+        }
+        Some(())
+    })
+}
+
+pub(crate) fn write_emsg(out: &mut Vec<u8>, event: &EventMessage) -> Option<()> {
+    match event.presentation_time {
+        EventPresentationTime::Delta(delta) => write_full_box(out, *b"emsg", 0, 0, |out| {
+            write_cstring(out, &event.scheme_id_uri)?;
+            write_cstring(out, &event.value)?;
+            write_u32(out, event.timescale);
+            write_u32(out, delta);
+            write_u32(out, event.event_duration);
+            write_u32(out, event.id);
+            out.extend_from_slice(&event.message_data);
+            Some(())
+        }),
+        EventPresentationTime::Absolute(time) => write_full_box(out, *b"emsg", 1, 0, |out| {
+            write_u32(out, event.timescale);
+            write_u64(out, time);
+            write_u32(out, event.event_duration);
+            write_u32(out, event.id);
+            write_cstring(out, &event.scheme_id_uri)?;
+            write_cstring(out, &event.value)?;
+            out.extend_from_slice(&event.message_data);
+            Some(())
+        }),
+    }
+}
+
 fn write_moof(
     out: &mut Vec<u8>,
     sequence_number: u32,
     tracks: &[FragmentTrack<'_>],
     trun_data_offset_positions: &mut Vec<usize>,
 ) -> Option<()> {
+    let moof_start = out.len();
     write_box(out, *b"moof", |out| {
         write_full_box(out, *b"mfhd", 0, 0, |out| {
             write_u32(out, sequence_number);
             Some(())
         })?;
         for track in tracks {
-            write_traf(out, track, trun_data_offset_positions)?;
+            write_traf(out, track, moof_start, trun_data_offset_positions)?;
         }
         Some(())
     })
@@ -402,15 +749,121 @@ fn write_moof(
 fn write_traf(
     out: &mut Vec<u8>,
     track: &FragmentTrack<'_>,
+    moof_start: usize,
     trun_data_offset_positions: &mut Vec<usize>,
 ) -> Option<()> {
+    track_data_len(track)?;
+    let defaults = FragmentDefaults::from_samples(&track.samples);
     write_box(out, *b"traf", |out| {
-        write_full_box(out, *b"tfhd", 0, 0x02_0000, |out| {
+        write_full_box(out, *b"tfhd", 0, defaults.tfhd_flags(), |out| {
             write_u32(out, track.track_id);
+            for value in [defaults.duration, defaults.size, defaults.flags]
+                .into_iter()
+                .flatten()
+            {
+                write_u32(out, value);
+            }
             Some(())
         })?;
         write_tfdt(out, track.base_media_decode_time)?;
-        write_trun(out, &track.samples, trun_data_offset_positions)?;
+        write_truns(out, &track.samples, &defaults, trun_data_offset_positions)?;
+        if track.subsample_sizes.iter().any(|sizes| !sizes.is_empty()) {
+            write_subs(out, &track.subsample_sizes)?;
+        }
+        if !track.sample_encryption.is_empty() {
+            if track.sample_encryption.len() != track.samples.len() {
+                return None;
+            }
+            write_sample_encryption(out, &track.sample_encryption, moof_start)?;
+        }
+        Some(())
+    })
+}
+
+/// `saiz`, `saio` and `senc` for the samples of a protected track. The
+/// `saio` offset is relative to the `moof`, matching the tfhd
+/// default-base-is-moof flag.
+fn write_sample_encryption(
+    out: &mut Vec<u8>,
+    entries: &[SampleEncryptionEntry],
+    moof_start: usize,
+) -> Option<()> {
+    let use_subsamples = entries.iter().any(|entry| !entry.subsamples.is_empty());
+    let info_sizes = entries
+        .iter()
+        .map(|entry| {
+            let subsamples = if use_subsamples {
+                2 + 6 * entry.subsamples.len()
+            } else {
+                0
+            };
+            u8::try_from(entry.iv.len() + subsamples).ok()
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let sample_count = u32::try_from(entries.len()).ok()?;
+    write_full_box(out, *b"saiz", 0, 0, |out| {
+        if info_sizes.iter().all(|&size| size == info_sizes[0]) {
+            write_u8(out, info_sizes[0]);
+            write_u32(out, sample_count);
+        } else {
+            write_u8(out, 0);
+            write_u32(out, sample_count);
+            out.extend_from_slice(&info_sizes);
+        }
+        Some(())
+    })?;
+    let mut saio_offset_position = 0;
+    write_full_box(out, *b"saio", 0, 0, |out| {
+        write_u32(out, 1);
+        saio_offset_position = out.len();
+        write_u32(out, 0);
+        Some(())
+    })?;
+    let flags = if use_subsamples { 0x00_0002 } else { 0 };
+    write_full_box(out, *b"senc", 0, flags, |out| {
+        write_u32(out, sample_count);
+        let info_offset = i32::try_from(out.len().checked_sub(moof_start)?).ok()?;
+        patch_i32(out, saio_offset_position, info_offset);
+        for entry in entries {
+            out.extend_from_slice(&entry.iv);
+            if use_subsamples {
+                write_u16(out, u16::try_from(entry.subsamples.len()).ok()?);
+                for subsample in &entry.subsamples {
+                    write_u16(out, subsample.clear_bytes);
+                    write_u32(out, subsample.protected_bytes);
+                }
+            }
+        }
+        Some(())
+    })
+}
+
+fn write_subs(out: &mut Vec<u8>, subsample_sizes: &[Vec<u32>]) -> Option<()> {
+    // Version 1 carries 32-bit subsample sizes, which TTML documents with
+    // embedded images can need.
+    write_full_box(out, *b"subs", 1, 0, |out| {
+        let entry_count = subsample_sizes
+            .iter()
+            .filter(|sizes| !sizes.is_empty())
+            .count();
+        write_u32(out, u32::try_from(entry_count).ok()?);
+        let mut previous_sample = 0_usize;
+        for (index, sizes) in subsample_sizes.iter().enumerate() {
+            if sizes.is_empty() {
+                continue;
+            }
+            let sample_number = index + 1;
+            write_u32(out, u32::try_from(sample_number - previous_sample).ok()?);
+            previous_sample = sample_number;
+            write_u16(out, u16::try_from(sizes.len()).ok()?);
+            for &size in sizes {
+                write_u32(out, size);
+                // subsample_priority, discardable and codec_specific_parameters.
+                write_u8(out, 0);
+                write_u8(out, 0);
+                write_u32(out, 0);
+            }
+        }
         Some(())
     })
 }
@@ -431,42 +884,225 @@ fn write_tfdt(out: &mut Vec<u8>, base_media_decode_time: u64) -> Option<()> {
     })
 }
 
-fn write_trun(
+/// Sample values shared across a `traf`, written once in `tfhd` (or as the
+/// `trun` first_sample_flags) instead of per sample.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct FragmentDefaults {
+    duration: Option<u32>,
+    size: Option<u32>,
+    flags: Option<u32>,
+    first_sample_flags: Option<u32>,
+    /// Every composition offset is zero, the value implied by its absence.
+    zero_composition_offsets: bool,
+}
+
+impl FragmentDefaults {
+    fn from_samples(samples: &[FragmentSample]) -> Self {
+        let flags = samples
+            .iter()
+            .map(|sample| sample.flags.map(SampleFlags::as_u32));
+        // A sync sample opening a run of non-sync samples is the usual video
+        // case, so the first sample's flags may differ from the default.
+        let (flags, first_sample_flags) = match shared_value(flags.clone().skip(1)) {
+            Some(rest) => match samples
+                .first()
+                .and_then(|sample| sample.flags)
+                .map(SampleFlags::as_u32)
+            {
+                Some(first) if first == rest => (Some(rest), None),
+                Some(first) => (Some(rest), Some(first)),
+                None => (None, None),
+            },
+            None if samples.len() == 1 => (shared_value(flags), None),
+            None => (None, None),
+        };
+        Self {
+            duration: shared_value(samples.iter().map(|sample| sample.duration)),
+            size: shared_value(samples.iter().map(|sample| sample.size)),
+            flags,
+            first_sample_flags,
+            zero_composition_offsets: shared_value(
+                samples.iter().map(|sample| sample.composition_time_offset),
+            ) == Some(0),
+        }
+    }
+
+    fn tfhd_flags(&self) -> u32 {
+        0x02_0000
+            | (self.duration.is_some() as u32 * 0x00_0008)
+            | (self.size.is_some() as u32 * 0x00_0010)
+            | (self.flags.is_some() as u32 * 0x00_0020)
+    }
+}
+
+/// The value every item has, if all are `Some` and equal.
+fn shared_value<T: PartialEq>(mut values: impl Iterator<Item = Option<T>>) -> Option<T> {
+    let first = values.next()??;
+    for value in values {
+        if value? != first {
+            return None;
+        }
+    }
+    Some(first)
+}
+
+/// Write the samples of a `traf` as `trun`s. Samples carrying different
+/// optional fields go in separate runs so each `trun` has one consistent
+/// field set; only the first run has a data offset, and the rest follow it
+/// contiguously. A missing composition offset is written as zero when other
+/// samples have one.
+fn write_truns(
     out: &mut Vec<u8>,
     samples: &[FragmentSample],
+    defaults: &FragmentDefaults,
     trun_data_offset_positions: &mut Vec<usize>,
 ) -> Option<()> {
-    let sample_flags = samples.first().copied().unwrap_or_default().trun_flags();
-    write_full_box(out, *b"trun", 1, 0x00_0001 | sample_flags, |out| {
+    let write_offsets = !defaults.zero_composition_offsets
+        && samples
+            .iter()
+            .any(|sample| sample.composition_time_offset.is_some());
+    let fields = |sample: &FragmentSample| {
+        (u32::from(defaults.duration.is_none() && sample.duration.is_some()) * 0x00_0100)
+            | (u32::from(defaults.size.is_none() && sample.size.is_some()) * 0x00_0200)
+            | (u32::from(defaults.flags.is_none() && sample.flags.is_some()) * 0x00_0400)
+            | (u32::from(write_offsets) * 0x00_0800)
+    };
+    let mut start = 0;
+    loop {
+        let run_fields = samples.get(start).map_or(0, fields);
+        let end = start
+            + samples[start..]
+                .iter()
+                .take_while(|&sample| fields(sample) == run_fields)
+                .count();
+        if start == 0 {
+            write_trun(
+                out,
+                &samples[..end],
+                run_fields,
+                defaults.first_sample_flags,
+                Some(trun_data_offset_positions),
+            )?;
+        } else {
+            write_trun(out, &samples[start..end], run_fields, None, None)?;
+        }
+        if end >= samples.len() {
+            return Some(());
+        }
+        start = end;
+    }
+}
+
+fn write_trun(
+    out: &mut Vec<u8>,
+    samples: &[FragmentSample],
+    fields: u32,
+    first_sample_flags: Option<u32>,
+    trun_data_offset_positions: Option<&mut Vec<usize>>,
+) -> Option<()> {
+    let flags = fields
+        | u32::from(trun_data_offset_positions.is_some())
+        | (u32::from(first_sample_flags.is_some()) * 0x00_0004);
+    write_full_box(out, *b"trun", 1, flags, |out| {
         write_u32(out, u32::try_from(samples.len()).ok()?);
-        trun_data_offset_positions.push(out.len());
-        write_i32(out, 0);
+        if let Some(positions) = trun_data_offset_positions {
+            positions.push(out.len());
+            write_i32(out, 0);
+        }
+        if let Some(flags) = first_sample_flags {
+            write_u32(out, flags);
+        }
         for sample in samples {
-            if let Some(duration) = sample.duration {
-                write_u32(out, duration);
+            if fields & 0x00_0100 != 0 {
+                write_u32(out, sample.duration?);
             }
-            if let Some(size) = sample.size {
-                write_u32(out, size);
+            if fields & 0x00_0200 != 0 {
+                write_u32(out, sample.size?);
             }
-            if let Some(flags) = sample.flags {
-                write_u32(out, flags.as_u32());
+            if fields & 0x00_0400 != 0 {
+                write_u32(out, sample.flags?.as_u32());
             }
-            if let Some(offset) = sample.composition_time_offset {
-                write_i32(out, offset);
+            if fields & 0x00_0800 != 0 {
+                write_i32(out, sample.composition_time_offset.unwrap_or(0));
             }
         }
         Some(())
     })
 }
 
-fn write_ftyp(out: &mut Vec<u8>) -> Option<()> {
-    write_box(out, *b"ftyp", |out| {
-        out.extend_from_slice(b"mp42");
-        write_u32(out, 1);
-        out.extend_from_slice(b"mp41");
-        out.extend_from_slice(b"mp42");
-        out.extend_from_slice(b"isom");
-        out.extend_from_slice(b"hlsf");
+/// Brands written as `ftyp` in init segments or `styp` in media segments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileType {
+    pub major_brand: [u8; 4],
+    pub minor_version: u32,
+    pub compatible_brands: Vec<[u8; 4]>,
+}
+
+impl FileType {
+    pub fn new(major_brand: [u8; 4], compatible_brands: &[[u8; 4]]) -> Self {
+        Self {
+            major_brand,
+            minor_version: 0,
+            compatible_brands: compatible_brands.to_vec(),
+        }
+    }
+
+    /// CMAF header of a track file: `cmfc` track brand on `iso6`.
+    pub fn cmaf_header() -> Self {
+        Self::new(*b"cmf2", &[*b"cmf2", *b"cmfc", *b"iso6"])
+    }
+
+    /// `styp` of a CMAF segment.
+    pub fn cmaf_segment() -> Self {
+        Self::new(*b"cmfs", &[*b"cmfs", *b"cmff"])
+    }
+
+    /// `styp` of a CMAF chunk that does not start a segment.
+    pub fn cmaf_chunk() -> Self {
+        Self::new(*b"cmfl", &[*b"cmfl"])
+    }
+
+    /// DASH ISO BMFF live profile initialization segment.
+    pub fn dash_header() -> Self {
+        Self::new(*b"iso6", &[*b"iso6", *b"dash"])
+    }
+
+    /// `styp` of a DASH media segment that is also indexable.
+    pub fn dash_segment() -> Self {
+        Self::new(*b"msdh", &[*b"msdh", *b"msix"])
+    }
+
+    /// Brands of a progressive (non-fragmented) file.
+    pub fn progressive() -> Self {
+        Self::new(*b"isom", &[*b"isom", *b"iso2", *b"mp41"])
+    }
+
+    pub fn has_brand(&self, brand: [u8; 4]) -> bool {
+        self.major_brand == brand || self.compatible_brands.contains(&brand)
+    }
+}
+
+impl Default for FileType {
+    fn default() -> Self {
+        Self {
+            major_brand: *b"mp42",
+            minor_version: 1,
+            compatible_brands: vec![*b"mp41", *b"mp42", *b"isom", *b"hlsf"],
+        }
+    }
+}
+
+pub(crate) fn write_file_type(
+    out: &mut Vec<u8>,
+    name: [u8; 4],
+    file_type: &FileType,
+) -> Option<()> {
+    write_box(out, name, |out| {
+        out.extend_from_slice(&file_type.major_brand);
+        write_u32(out, file_type.minor_version);
+        for brand in &file_type.compatible_brands {
+            out.extend_from_slice(brand);
+        }
         Some(())
     })
 }
@@ -488,41 +1124,39 @@ fn write_mvhd(out: &mut Vec<u8>, timescale: u32, duration: u32) -> Option<()> {
     })
 }
 
-fn write_trak(
-    out: &mut Vec<u8>,
-    track_id: u32,
-    is_video: bool,
-    timescale: u32,
-    duration: u32,
-    video: Option<&VideoInit>,
-    audio: Option<&AudioInit>,
-) -> Option<()> {
+fn write_trak(out: &mut Vec<u8>, track: &TrackInit, duration: u32) -> Option<()> {
     write_box(out, *b"trak", |out| {
-        let (width, height) = video
-            .map(|video| (u32::from(video.width) << 16, u32::from(video.height) << 16))
-            .unwrap_or((0, 0));
-        write_tkhd(out, track_id, duration, width, height)?;
-        write_mdia(out, is_video, timescale, duration, video, audio)?;
+        let (width, height) = match &track.media {
+            MediaInit::Video(video) => {
+                (u32::from(video.width) << 16, u32::from(video.height) << 16)
+            }
+            MediaInit::Audio(_) | MediaInit::Text(_) | MediaInit::Metadata(_) => (0, 0),
+        };
+        write_tkhd(out, track, duration, width, height)?;
+        write_mdia(out, track, duration)?;
+        if !track.metadata.kinds.is_empty() {
+            write_udta_kinds(out, &track.metadata.kinds)?;
+        }
         Some(())
     })
 }
 
 fn write_tkhd(
     out: &mut Vec<u8>,
-    track_id: u32,
+    track: &TrackInit,
     duration: u32,
     width: u32,
     height: u32,
 ) -> Option<()> {
-    write_full_box(out, *b"tkhd", 0, 0x00_0007, |out| {
+    write_full_box(out, *b"tkhd", 0, track.metadata.tkhd_flags(), |out| {
         write_u32(out, 0);
         write_u32(out, 0);
-        write_u32(out, track_id);
+        write_u32(out, track.track_id());
         write_zeroes(out, 4);
         write_u32(out, duration);
         write_zeroes(out, 8);
         write_i16(out, 0);
-        write_i16(out, 0);
+        write_i16(out, track.metadata.alternate_group);
         write_i16(out, 256);
         write_zeroes(out, 2);
         write_matrix(out);
@@ -532,64 +1166,84 @@ fn write_tkhd(
     })
 }
 
-fn write_mdia(
-    out: &mut Vec<u8>,
-    is_video: bool,
-    timescale: u32,
-    duration: u32,
-    video: Option<&VideoInit>,
-    audio: Option<&AudioInit>,
-) -> Option<()> {
+fn write_udta_kinds(out: &mut Vec<u8>, kinds: &[TrackKind]) -> Option<()> {
+    write_box(out, *b"udta", |out| {
+        for kind in kinds {
+            write_full_box(out, *b"kind", 0, 0, |out| {
+                write_cstring(out, &kind.scheme_uri)?;
+                write_cstring(out, &kind.value)
+            })?;
+        }
+        Some(())
+    })
+}
+
+fn write_mdia(out: &mut Vec<u8>, track: &TrackInit, duration: u32) -> Option<()> {
     write_box(out, *b"mdia", |out| {
-        write_mdhd(out, timescale, duration)?;
-        write_hdlr(out, is_video)?;
-        write_minf(out, is_video, video, audio)?;
+        write_mdhd(
+            out,
+            track.media.timescale(),
+            duration,
+            track.metadata.language,
+        )?;
+        write_hdlr(out, &track.media, track.metadata.handler_name.as_deref())?;
+        write_minf(out, &track.media, track.encryption.as_ref())?;
         Some(())
     })
 }
 
-fn write_mdhd(out: &mut Vec<u8>, timescale: u32, duration: u32) -> Option<()> {
+fn write_mdhd(
+    out: &mut Vec<u8>,
+    timescale: u32,
+    duration: u32,
+    language: Option<[u8; 3]>,
+) -> Option<()> {
+    let language = language.map_or(Some(0x55c4), pack_iso639_language)?;
     write_full_box(out, *b"mdhd", 0, 0, |out| {
         write_u32(out, 0);
         write_u32(out, 0);
         write_u32(out, timescale);
         write_u32(out, duration);
-        write_u16(out, 0x55c4);
+        write_u16(out, language);
         write_zeroes(out, 2);
         Some(())
     })
 }
 
-fn write_hdlr(out: &mut Vec<u8>, is_video: bool) -> Option<()> {
-    let (handler_type, name): ([u8; 4], &[u8]) = if is_video {
-        (*b"vide", b"Video Handler\0")
-    } else {
-        (*b"soun", b"Sound Handler\0")
-    };
+/// Pack an ISO-639-2/T code into the 15-bit `mdhd` form: three lowercase
+/// letters, each stored as five bits offset from 0x60.
+fn pack_iso639_language(language: [u8; 3]) -> Option<u16> {
+    language.iter().try_fold(0_u16, |packed, &letter| {
+        letter
+            .is_ascii_lowercase()
+            .then(|| (packed << 5) | u16::from(letter - 0x60))
+    })
+}
 
+fn write_hdlr(out: &mut Vec<u8>, media: &MediaInit, name: Option<&str>) -> Option<()> {
+    let (handler_type, default_name) = media.handler();
     write_full_box(out, *b"hdlr", 0, 0, |out| {
         write_zeroes(out, 4);
         out.extend_from_slice(&handler_type);
         write_zeroes(out, 12);
-        out.extend_from_slice(name);
-        Some(())
+        write_cstring(out, name.unwrap_or(default_name))
     })
 }
 
 fn write_minf(
     out: &mut Vec<u8>,
-    is_video: bool,
-    video: Option<&VideoInit>,
-    audio: Option<&AudioInit>,
+    media: &MediaInit,
+    encryption: Option<&TrackEncryption>,
 ) -> Option<()> {
     write_box(out, *b"minf", |out| {
-        if is_video {
-            write_vmhd(out)?;
-        } else {
-            write_smhd(out)?;
+        match media {
+            MediaInit::Video(_) => write_vmhd(out)?,
+            MediaInit::Audio(_) => write_smhd(out)?,
+            MediaInit::Text(_) => write_sthd(out)?,
+            MediaInit::Metadata(_) => write_full_box(out, *b"nmhd", 0, 0, |_| Some(()))?,
         }
         write_dinf(out)?;
-        write_stbl(out, video, audio)?;
+        write_stbl(out, media, encryption)?;
         Some(())
     })
 }
@@ -610,6 +1264,10 @@ fn write_smhd(out: &mut Vec<u8>) -> Option<()> {
     })
 }
 
+fn write_sthd(out: &mut Vec<u8>) -> Option<()> {
+    write_full_box(out, *b"sthd", 0, 0, |_| Some(()))
+}
+
 fn write_dinf(out: &mut Vec<u8>) -> Option<()> {
     write_box(out, *b"dinf", |out| {
         write_full_box(out, *b"dref", 0, 0, |out| {
@@ -622,43 +1280,185 @@ fn write_dinf(out: &mut Vec<u8>) -> Option<()> {
 
 fn write_stbl(
     out: &mut Vec<u8>,
-    video: Option<&VideoInit>,
-    audio: Option<&AudioInit>,
+    media: &MediaInit,
+    encryption: Option<&TrackEncryption>,
 ) -> Option<()> {
     write_box(out, *b"stbl", |out| {
-        write_stsd(out, video, audio)?;
-        write_full_box(out, *b"stts", 0, 0, |out| {
-            write_u32(out, 0);
-            Some(())
-        })?;
-        write_full_box(out, *b"stsc", 0, 0, |out| {
-            write_u32(out, 0);
+        write_stsd(out, media, encryption)?;
+        // Fragmented files keep their samples in `trun`s.
+        write_sample_tables(out, &SampleTable::default(), 0)
+    })
+}
+
+/// Samples and chunks of one track of a progressive file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SampleTable {
+    pub samples: Vec<RunSample>,
+    /// Offset from the start of the media data and sample count of each
+    /// chunk, in sample order.
+    pub chunks: Vec<(u64, u32)>,
+}
+
+impl SampleTable {
+    pub(crate) fn duration(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|sample| u64::from(sample.duration))
+            .sum()
+    }
+}
+
+/// `stts`, `ctts`, `stss`, `stsc`, `stsz` and `stco` or `co64` for a table.
+/// `ctts` is left out when no sample has a composition offset and `stss`
+/// when every sample is a sync sample. `chunk_base` is added to each chunk
+/// offset.
+fn write_sample_tables(out: &mut Vec<u8>, table: &SampleTable, chunk_base: u64) -> Option<()> {
+    let samples = &table.samples;
+    let durations = run_lengths(samples.iter().map(|sample| sample.duration));
+    write_full_box(out, *b"stts", 0, 0, |out| {
+        write_u32(out, u32::try_from(durations.len()).ok()?);
+        for (count, duration) in &durations {
+            write_u32(out, *count);
+            write_u32(out, *duration);
+        }
+        Some(())
+    })?;
+
+    if samples
+        .iter()
+        .any(|sample| sample.composition_time_offset != 0)
+    {
+        let version = u8::from(
+            samples
+                .iter()
+                .any(|sample| sample.composition_time_offset < 0),
+        );
+        let offsets = run_lengths(samples.iter().map(|sample| sample.composition_time_offset));
+        write_full_box(out, *b"ctts", version, 0, |out| {
+            write_u32(out, u32::try_from(offsets.len()).ok()?);
+            for (count, offset) in &offsets {
+                write_u32(out, *count);
+                write_i32(out, *offset);
+            }
             Some(())
         })?;
-        write_full_box(out, *b"stsz", 0, 0, |out| {
-            write_u32(out, 0);
-            write_u32(out, 0);
+    }
+
+    if !samples.iter().all(RunSample::is_sync) {
+        write_full_box(out, *b"stss", 0, 0, |out| {
+            let sync_samples: Vec<usize> = samples
+                .iter()
+                .enumerate()
+                .filter(|(_, sample)| sample.is_sync())
+                .map(|(index, _)| index + 1)
+                .collect();
+            write_u32(out, u32::try_from(sync_samples.len()).ok()?);
+            for sample_number in sync_samples {
+                write_u32(out, u32::try_from(sample_number).ok()?);
+            }
             Some(())
         })?;
+    }
+
+    write_full_box(out, *b"stsc", 0, 0, |out| {
+        let mut entries: Vec<(usize, u32)> = Vec::new();
+        for (index, (_, sample_count)) in table.chunks.iter().enumerate() {
+            if entries.last().map(|(_, count)| count) != Some(sample_count) {
+                entries.push((index + 1, *sample_count));
+            }
+        }
+        write_u32(out, u32::try_from(entries.len()).ok()?);
+        for (first_chunk, samples_per_chunk) in entries {
+            write_u32(out, u32::try_from(first_chunk).ok()?);
+            write_u32(out, samples_per_chunk);
+            write_u32(out, 1);
+        }
+        Some(())
+    })?;
+
+    write_full_box(out, *b"stsz", 0, 0, |out| {
+        let uniform = samples
+            .first()
+            .map(|first| first.size)
+            .filter(|&size| samples.iter().all(|sample| sample.size == size));
+        write_u32(out, uniform.unwrap_or(0));
+        write_u32(out, u32::try_from(samples.len()).ok()?);
+        if uniform.is_none() {
+            for sample in samples {
+                write_u32(out, sample.size);
+            }
+        }
+        Some(())
+    })?;
+
+    let offsets = table
+        .chunks
+        .iter()
+        .map(|(offset, _)| offset.checked_add(chunk_base))
+        .collect::<Option<Vec<u64>>>()?;
+    let chunk_count = u32::try_from(offsets.len()).ok()?;
+    if offsets.iter().all(|&offset| offset <= u64::from(u32::MAX)) {
         write_full_box(out, *b"stco", 0, 0, |out| {
-            write_u32(out, 0);
+            write_u32(out, chunk_count);
+            for offset in offsets {
+                write_u32(out, offset as u32);
+            }
             Some(())
-        })?;
-        Some(())
-    })
+        })
+    } else {
+        write_full_box(out, *b"co64", 0, 0, |out| {
+            write_u32(out, chunk_count);
+            for offset in offsets {
+                write_u64(out, offset);
+            }
+            Some(())
+        })
+    }
+}
+
+/// Collapse equal neighbours into `(count, value)` pairs.
+fn run_lengths<T: PartialEq>(values: impl Iterator<Item = T>) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+    for value in values {
+        match runs.last_mut() {
+            Some((count, last)) if *last == value => *count += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+    runs
 }
 
 fn write_stsd(
     out: &mut Vec<u8>,
-    video: Option<&VideoInit>,
-    audio: Option<&AudioInit>,
+    media: &MediaInit,
+    encryption: Option<&TrackEncryption>,
 ) -> Option<()> {
     write_full_box(out, *b"stsd", 0, 0, |out| {
         write_u32(out, 1);
-        if let Some(video) = video {
-            write_avc1(out, video)?;
-        } else if let Some(audio) = audio {
-            match audio {
+        let entry_start = out.len();
+        match media {
+            MediaInit::Video(video) => write_avc1(out, video)?,
+            MediaInit::Text(TextInit::WebVtt { config, .. }) => write_wvtt(out, config)?,
+            MediaInit::Text(TextInit::Ttml {
+                namespace,
+                schema_location,
+                auxiliary_mime_types,
+                mime_type,
+                ..
+            }) => write_stpp(
+                out,
+                namespace,
+                schema_location,
+                auxiliary_mime_types,
+                mime_type.as_deref(),
+            )?,
+            MediaInit::Text(TextInit::Cea608 { .. }) => write_box(out, *b"c608", |out| {
+                write_zeroes(out, 6);
+                write_u16(out, 1);
+                Some(())
+            })?,
+            MediaInit::Metadata(metadata) => write_metadata_sample_entry(out, metadata)?,
+            MediaInit::Audio(audio) => match audio {
                 AudioInit::Aac {
                     profile,
                     frequency,
@@ -700,9 +1500,68 @@ fn write_stsd(
                     *pre_skip,
                     *output_gain,
                 )?,
-            }
-        } else {
-            return None;
+            },
+        }
+        if let Some(encryption) = encryption {
+            let protected_name = match media {
+                MediaInit::Video(_) => *b"encv",
+                MediaInit::Audio(_) => *b"enca",
+                MediaInit::Text(_) | MediaInit::Metadata(_) => return None,
+            };
+            protect_sample_entry(out, entry_start, protected_name, encryption)?;
+        }
+        Some(())
+    })
+}
+
+/// Rename the sample entry at `entry_start` and append a `sinf` recording
+/// its original format and the track's encryption defaults.
+fn protect_sample_entry(
+    out: &mut Vec<u8>,
+    entry_start: usize,
+    protected_name: [u8; 4],
+    encryption: &TrackEncryption,
+) -> Option<()> {
+    let original_name: [u8; 4] = out.get(entry_start + 4..entry_start + 8)?.try_into().ok()?;
+    out[entry_start + 4..entry_start + 8].copy_from_slice(&protected_name);
+    write_box(out, *b"sinf", |out| {
+        write_box(out, *b"frma", |out| {
+            out.extend_from_slice(&original_name);
+            Some(())
+        })?;
+        write_full_box(out, *b"schm", 0, 0, |out| {
+            out.extend_from_slice(&encryption.scheme_type);
+            write_u32(out, 0x0001_0000);
+            Some(())
+        })?;
+        write_box(out, *b"schi", |out| write_tenc(out, encryption))
+    })?;
+    let size = u32::try_from(out.len() - entry_start).ok()?;
+    out[entry_start..entry_start + 4].copy_from_slice(&size.to_be_bytes());
+    Some(())
+}
+
+fn write_tenc(out: &mut Vec<u8>, encryption: &TrackEncryption) -> Option<()> {
+    // Version 1 carries the pattern that version 0 reserves. The pattern
+    // schemes require it even for a (0, 0) pattern, as on `cbcs` audio.
+    let version = u8::from(
+        matches!(&encryption.scheme_type, b"cbcs" | b"cens")
+            || encryption.crypt_byte_block != 0
+            || encryption.skip_byte_block != 0,
+    );
+    write_full_box(out, *b"tenc", version, 0, |out| {
+        write_u8(out, 0);
+        write_u8(
+            out,
+            (encryption.crypt_byte_block << 4) | (encryption.skip_byte_block & 0x0f),
+        );
+        write_u8(out, 1);
+        write_u8(out, encryption.per_sample_iv_size);
+        out.extend_from_slice(&encryption.key_id);
+        if encryption.per_sample_iv_size == 0 {
+            let constant_iv = encryption.constant_iv?;
+            write_u8(out, 16);
+            out.extend_from_slice(&constant_iv);
         }
         Some(())
     })
@@ -744,6 +1603,64 @@ fn write_avcc(out: &mut Vec<u8>, avcc: &AvcDecoderConfigurationRecord) -> Option
     })
 }
 
+fn write_wvtt(out: &mut Vec<u8>, config: &str) -> Option<()> {
+    write_box(out, *b"wvtt", |out| {
+        write_zeroes(out, 6);
+        write_u16(out, 1);
+        write_box(out, *b"vttC", |out| {
+            out.extend_from_slice(config.as_bytes());
+            Some(())
+        })
+    })
+}
+
+fn write_stpp(
+    out: &mut Vec<u8>,
+    namespace: &str,
+    schema_location: &str,
+    auxiliary_mime_types: &str,
+    mime_type: Option<&str>,
+) -> Option<()> {
+    write_box(out, *b"stpp", |out| {
+        write_zeroes(out, 6);
+        write_u16(out, 1);
+        write_cstring(out, namespace)?;
+        write_cstring(out, schema_location)?;
+        write_cstring(out, auxiliary_mime_types)?;
+        if let Some(mime_type) = mime_type {
+            write_full_box(out, *b"mime", 0, 0, |out| write_cstring(out, mime_type))?;
+        }
+        Some(())
+    })
+}
+
+fn write_metadata_sample_entry(out: &mut Vec<u8>, metadata: &MetadataInit) -> Option<()> {
+    match metadata {
+        MetadataInit::Text {
+            content_encoding,
+            mime_format,
+            ..
+        } => write_box(out, *b"mett", |out| {
+            write_zeroes(out, 6);
+            write_u16(out, 1);
+            write_cstring(out, content_encoding)?;
+            write_cstring(out, mime_format)
+        }),
+        MetadataInit::Xml {
+            content_encoding,
+            namespace,
+            schema_location,
+            ..
+        } => write_box(out, *b"metx", |out| {
+            write_zeroes(out, 6);
+            write_u16(out, 1);
+            write_cstring(out, content_encoding)?;
+            write_cstring(out, namespace)?;
+            write_cstring(out, schema_location)
+        }),
+    }
+}
+
 fn write_mp4a(
     out: &mut Vec<u8>,
     profile: AacProfile,
@@ -926,38 +1843,487 @@ fn write_chnl_unknown_positions(out: &mut Vec<u8>, channel_count: u16) -> Option
     })
 }
 
-fn write_mvex(
-    out: &mut Vec<u8>,
-    video_track_id: Option<u32>,
-    audio_track_id: Option<u32>,
-) -> Option<()> {
+fn write_mvex(out: &mut Vec<u8>, tracks: &[TrackInit]) -> Option<()> {
     write_box(out, *b"mvex", |out| {
         write_full_box(out, *b"mehd", 0, 0, |out| {
             write_u32(out, 0);
             Some(())
         })?;
-        if let Some(track_id) = video_track_id {
-            write_trex(out, track_id)?;
-        }
-        if let Some(track_id) = audio_track_id {
-            write_trex(out, track_id)?;
+        for track in tracks {
+            write_trex(out, track)?;
         }
         Some(())
     })
 }
 
-fn write_trex(out: &mut Vec<u8>, track_id: u32) -> Option<()> {
+/// `trex` with the defaults known from the codec alone: AAC frames always
+/// span 1024 samples, audio samples are sync samples and most video samples
+/// depend on others.
+fn write_trex(out: &mut Vec<u8>, track: &TrackInit) -> Option<()> {
+    let (duration, flags) = match &track.media {
+        MediaInit::Audio(AudioInit::Aac { .. }) => (1024, 0x0200_0000),
+        MediaInit::Audio(_) => (0, 0x0200_0000),
+        MediaInit::Video(_) => (0, 0x0101_0000),
+        MediaInit::Text(_) | MediaInit::Metadata(_) => (0, 0),
+    };
     write_full_box(out, *b"trex", 0, 0, |out| {
-        write_u32(out, track_id);
+        write_u32(out, track.track_id());
         write_u32(out, 1);
+        write_u32(out, duration);
         write_u32(out, 0);
-        write_u32(out, 0);
-        write_u32(out, 0);
+        write_u32(out, flags);
         Some(())
     })
 }
 
-fn write_full_box<F>(out: &mut Vec<u8>, name: [u8; 4], version: u8, flags: u32, f: F) -> Option<()>
+/// Write a progressive `moov` from an init segment's `moov`: `mvex` is
+/// dropped, `mvhd`, `tkhd` and `mdhd` get the durations of the tables, and
+/// each `stbl` keeps its `stsd` but gets the track's sample tables. Tracks
+/// without a table are written empty.
+pub(crate) fn write_progressive_moov(
+    out: &mut Vec<u8>,
+    init: &[u8],
+    tables: &[(u32, SampleTable)],
+    chunk_base: u64,
+) -> Option<()> {
+    let moov = read_boxes(init)?
+        .into_iter()
+        .find(|top| top.name == *b"moov")?;
+    let movie_timescale = read_header_timescale(&moov.child(*b"mvhd")?)?;
+    let empty = SampleTable::default();
+    let mut tracks = Vec::new();
+    for trak in moov.children(*b"trak")? {
+        let (version, _, tkhd) = trak.child(*b"tkhd")?.full_box()?;
+        let track_id = read_be_u32(tkhd.get(if version == 1 { 16 } else { 8 }..)?)?;
+        let media_timescale = read_header_timescale(&trak.child(*b"mdia")?.child(*b"mdhd")?)?;
+        let table = tables
+            .iter()
+            .find(|(id, _)| *id == track_id)
+            .map_or(&empty, |(_, table)| table);
+        let movie_duration = rescale(table.duration(), media_timescale, movie_timescale)?;
+        tracks.push((trak, table, movie_duration));
+    }
+    let movie_duration = tracks
+        .iter()
+        .map(|(_, _, duration)| *duration)
+        .max()
+        .unwrap_or(0);
+
+    write_box(out, *b"moov", |out| {
+        let mut tracks = tracks.iter();
+        for child in read_boxes(moov.payload)? {
+            match &child.name {
+                b"mvhd" => write_header_duration(out, &child, 4, movie_duration)?,
+                b"mvex" => {}
+                b"trak" => {
+                    let (trak, table, duration) = tracks.next()?;
+                    write_progressive_trak(out, trak, table, *duration, chunk_base)?;
+                }
+                _ => copy_box(out, &child)?,
+            }
+        }
+        Some(())
+    })
+}
+
+fn write_progressive_trak(
+    out: &mut Vec<u8>,
+    trak: &BoxRef<'_>,
+    table: &SampleTable,
+    movie_duration: u64,
+    chunk_base: u64,
+) -> Option<()> {
+    write_box(out, *b"trak", |out| {
+        for child in read_boxes(trak.payload)? {
+            match &child.name {
+                b"tkhd" => write_header_duration(out, &child, 8, movie_duration)?,
+                b"mdia" => write_box(out, *b"mdia", |out| {
+                    for child in read_boxes(child.payload)? {
+                        match &child.name {
+                            b"mdhd" => write_header_duration(out, &child, 4, table.duration())?,
+                            b"minf" => write_box(out, *b"minf", |out| {
+                                for child in read_boxes(child.payload)? {
+                                    if child.name == *b"stbl" {
+                                        write_box(out, *b"stbl", |out| {
+                                            copy_box(out, &child.child(*b"stsd")?)?;
+                                            write_sample_tables(out, table, chunk_base)
+                                        })?;
+                                    } else {
+                                        copy_box(out, &child)?;
+                                    }
+                                }
+                                Some(())
+                            })?,
+                            _ => copy_box(out, &child)?,
+                        }
+                    }
+                    Some(())
+                })?,
+                _ => copy_box(out, &child)?,
+            }
+        }
+        Some(())
+    })
+}
+
+/// Rewrite an `mvhd`, `tkhd` or `mdhd` with a new duration. These share a
+/// layout of creation and modification times, `middle_len` bytes of other
+/// fields and then the duration; version 1 is used once it needs 64 bits.
+fn write_header_duration(
+    out: &mut Vec<u8>,
+    header: &BoxRef<'_>,
+    middle_len: usize,
+    duration: u64,
+) -> Option<()> {
+    let (version, flags, body) = header.full_box()?;
+    let (time_len, duration_len) = if version == 1 { (8, 8) } else { (4, 4) };
+    let read_time = |position: usize| -> Option<u64> {
+        if version == 1 {
+            read_be_u64(body.get(position..)?)
+        } else {
+            read_be_u32(body.get(position..)?).map(u64::from)
+        }
+    };
+    let creation_time = read_time(0)?;
+    let modification_time = read_time(time_len)?;
+    let middle = body.get(2 * time_len..2 * time_len + middle_len)?;
+    let rest = body.get(2 * time_len + middle_len + duration_len..)?;
+    let wide = [creation_time, modification_time, duration]
+        .iter()
+        .any(|&value| value > u64::from(u32::MAX));
+    write_full_box(out, header.name, u8::from(wide), flags, |out| {
+        for value in [creation_time, modification_time] {
+            if wide {
+                write_u64(out, value);
+            } else {
+                write_u32(out, value as u32);
+            }
+        }
+        out.extend_from_slice(middle);
+        if wide {
+            write_u64(out, duration);
+        } else {
+            write_u32(out, duration as u32);
+        }
+        out.extend_from_slice(rest);
+        Some(())
+    })
+}
+
+/// `timescale` of an `mvhd` or `mdhd`.
+fn read_header_timescale(header: &BoxRef<'_>) -> Option<u32> {
+    let (version, _, body) = header.full_box()?;
+    read_be_u32(body.get(if version == 1 { 16 } else { 8 }..)?)
+}
+
+fn rescale(value: u64, from: u32, to: u32) -> Option<u64> {
+    if from == 0 {
+        return None;
+    }
+    u64::try_from(u128::from(value) * u128::from(to) / u128::from(from)).ok()
+}
+
+fn copy_box(out: &mut Vec<u8>, source: &BoxRef<'_>) -> Option<()> {
+    write_box(out, source.name, |out| {
+        out.extend_from_slice(source.payload);
+        Some(())
+    })
+}
+
+/// One box found by `read_boxes`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BoxRef<'a> {
+    pub name: [u8; 4],
+    pub payload: &'a [u8],
+}
+
+impl<'a> BoxRef<'a> {
+    /// Version, flags and the remaining payload of a full box.
+    pub(crate) fn full_box(&self) -> Option<(u8, u32, &'a [u8])> {
+        let header = read_be_u32(self.payload)?;
+        Some((
+            (header >> 24) as u8,
+            header & 0x00ff_ffff,
+            &self.payload[4..],
+        ))
+    }
+
+    pub(crate) fn child(&self, name: [u8; 4]) -> Option<BoxRef<'a>> {
+        read_boxes(self.payload)?
+            .into_iter()
+            .find(|child| child.name == name)
+    }
+
+    pub(crate) fn children(&self, name: [u8; 4]) -> Option<Vec<BoxRef<'a>>> {
+        Some(
+            read_boxes(self.payload)?
+                .into_iter()
+                .filter(|child| child.name == name)
+                .collect(),
+        )
+    }
+}
+
+/// Split `data` into consecutive boxes, failing on truncated or malformed
+/// sizes. A size of zero extends the box to the end of `data`.
+pub(crate) fn read_boxes(mut data: &[u8]) -> Option<Vec<BoxRef<'_>>> {
+    let mut boxes = Vec::new();
+    while !data.is_empty() {
+        let size = read_be_u32(data)?;
+        let name: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+        let (size, header_len) = match size {
+            0 => (data.len(), 8),
+            1 => (usize::try_from(read_be_u64(data.get(8..)?)?).ok()?, 16),
+            size => (usize::try_from(size).ok()?, 8),
+        };
+        if size < header_len {
+            return None;
+        }
+        boxes.push(BoxRef {
+            name,
+            payload: data.get(header_len..size)?,
+        });
+        data = &data[size..];
+    }
+    Some(boxes)
+}
+
+/// Sample defaults from `trex`, overridden per fragment by `tfhd`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SampleDefaults {
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+}
+
+/// A track described by an init segment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct InitTrack {
+    pub track_id: u32,
+    pub timescale: u32,
+    pub handler_type: [u8; 4],
+    pub defaults: SampleDefaults,
+}
+
+/// Read the tracks of an init segment with their `trex` defaults.
+pub(crate) fn read_init_tracks(init: &[u8]) -> Option<Vec<InitTrack>> {
+    let moov = read_boxes(init)?
+        .into_iter()
+        .find(|top| top.name == *b"moov")?;
+    let trexes = match moov.child(*b"mvex") {
+        Some(mvex) => mvex.children(*b"trex")?,
+        None => Vec::new(),
+    };
+    let mut tracks = Vec::new();
+    for trak in moov.children(*b"trak")? {
+        let (version, _, tkhd) = trak.child(*b"tkhd")?.full_box()?;
+        let track_id = read_be_u32(tkhd.get(if version == 1 { 16 } else { 8 }..)?)?;
+        let mdia = trak.child(*b"mdia")?;
+        let (version, _, mdhd) = mdia.child(*b"mdhd")?.full_box()?;
+        let timescale = read_be_u32(mdhd.get(if version == 1 { 16 } else { 8 }..)?)?;
+        let (_, _, hdlr) = mdia.child(*b"hdlr")?.full_box()?;
+        let handler_type = hdlr.get(4..8)?.try_into().ok()?;
+        let mut defaults = SampleDefaults::default();
+        for trex in &trexes {
+            let (_, _, trex) = trex.full_box()?;
+            if read_be_u32(trex)? == track_id {
+                defaults = SampleDefaults {
+                    duration: read_be_u32(trex.get(8..)?)?,
+                    size: read_be_u32(trex.get(12..)?)?,
+                    flags: read_be_u32(trex.get(16..)?)?,
+                };
+            }
+        }
+        tracks.push(InitTrack {
+            track_id,
+            timescale,
+            handler_type,
+            defaults,
+        });
+    }
+    Some(tracks)
+}
+
+/// The first sample entry of each track in an init segment, in track order.
+pub(crate) fn read_sample_entries(init: &[u8]) -> Option<Vec<BoxRef<'_>>> {
+    let moov = read_boxes(init)?
+        .into_iter()
+        .find(|top| top.name == *b"moov")?;
+    let mut entries = Vec::new();
+    for trak in moov.children(*b"trak")? {
+        let stsd = trak
+            .child(*b"mdia")?
+            .child(*b"minf")?
+            .child(*b"stbl")?
+            .child(*b"stsd")?;
+        let (_, _, stsd) = stsd.full_box()?;
+        entries.push(*read_boxes(stsd.get(4..)?)?.first()?);
+    }
+    Some(entries)
+}
+
+/// One sample of a `trun` with every default resolved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RunSample {
+    pub duration: u32,
+    pub size: u32,
+    pub flags: u32,
+    pub composition_time_offset: i32,
+}
+
+impl RunSample {
+    pub(crate) fn is_sync(&self) -> bool {
+        self.flags & 0x0001_0000 == 0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TrackRun {
+    pub data_offset: Option<i32>,
+    pub samples: Vec<RunSample>,
+}
+
+/// A parsed `traf`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TrackFragment {
+    pub track_id: u32,
+    pub base_data_offset: Option<u64>,
+    pub default_base_is_moof: bool,
+    pub base_media_decode_time: Option<u64>,
+    pub runs: Vec<TrackRun>,
+}
+
+/// Parse every `traf` in a `moof`, resolving sample defaults from `tfhd` and
+/// then from the matching init track.
+pub(crate) fn read_track_fragments(
+    moof: &BoxRef<'_>,
+    init_tracks: &[InitTrack],
+) -> Option<Vec<TrackFragment>> {
+    let mut fragments = Vec::new();
+    for traf in moof.children(*b"traf")? {
+        let (_, tfhd_flags, tfhd) = traf.child(*b"tfhd")?.full_box()?;
+        let track_id = read_be_u32(tfhd)?;
+        let mut defaults = init_tracks
+            .iter()
+            .find(|track| track.track_id == track_id)
+            .map(|track| track.defaults)
+            .unwrap_or_default();
+        let mut position = 4;
+        let mut base_data_offset = None;
+        if tfhd_flags & 0x00_0001 != 0 {
+            base_data_offset = Some(read_be_u64(tfhd.get(position..)?)?);
+            position += 8;
+        }
+        if tfhd_flags & 0x00_0002 != 0 {
+            position += 4;
+        }
+        if tfhd_flags & 0x00_0008 != 0 {
+            defaults.duration = read_be_u32(tfhd.get(position..)?)?;
+            position += 4;
+        }
+        if tfhd_flags & 0x00_0010 != 0 {
+            defaults.size = read_be_u32(tfhd.get(position..)?)?;
+            position += 4;
+        }
+        if tfhd_flags & 0x00_0020 != 0 {
+            defaults.flags = read_be_u32(tfhd.get(position..)?)?;
+        }
+
+        let base_media_decode_time = match traf.child(*b"tfdt") {
+            Some(tfdt) => {
+                let (version, _, tfdt) = tfdt.full_box()?;
+                Some(if version == 1 {
+                    read_be_u64(tfdt)?
+                } else {
+                    u64::from(read_be_u32(tfdt)?)
+                })
+            }
+            None => None,
+        };
+
+        let mut runs = Vec::new();
+        for trun in traf.children(*b"trun")? {
+            runs.push(read_trun(&trun, defaults)?);
+        }
+        fragments.push(TrackFragment {
+            track_id,
+            base_data_offset,
+            default_base_is_moof: tfhd_flags & 0x02_0000 != 0,
+            base_media_decode_time,
+            runs,
+        });
+    }
+    Some(fragments)
+}
+
+fn read_trun(trun: &BoxRef<'_>, defaults: SampleDefaults) -> Option<TrackRun> {
+    let (version, flags, trun) = trun.full_box()?;
+    let sample_count = read_be_u32(trun)?;
+    let mut position = 4;
+    let mut data_offset = None;
+    if flags & 0x00_0001 != 0 {
+        data_offset = Some(read_be_u32(trun.get(position..)?)? as i32);
+        position += 4;
+    }
+    let mut first_sample_flags = None;
+    if flags & 0x00_0004 != 0 {
+        first_sample_flags = Some(read_be_u32(trun.get(position..)?)?);
+        position += 4;
+    }
+    let mut samples = Vec::new();
+    for index in 0..sample_count {
+        let mut read_field = |flag: u32| -> Option<Option<u32>> {
+            if flags & flag == 0 {
+                return Some(None);
+            }
+            let value = read_be_u32(trun.get(position..)?)?;
+            position += 4;
+            Some(Some(value))
+        };
+        let duration = read_field(0x00_0100)?.unwrap_or(defaults.duration);
+        let size = read_field(0x00_0200)?.unwrap_or(defaults.size);
+        let sample_flags = read_field(0x00_0400)?;
+        let composition_time_offset = read_field(0x00_0800)?.map_or(0, |offset| {
+            // Version 0 offsets are unsigned.
+            if version == 0 {
+                i32::try_from(offset).unwrap_or(i32::MAX)
+            } else {
+                offset as i32
+            }
+        });
+        let flags = match (index, first_sample_flags, sample_flags) {
+            (_, _, Some(flags)) => flags,
+            (0, Some(flags), None) => flags,
+            _ => defaults.flags,
+        };
+        samples.push(RunSample {
+            duration,
+            size,
+            flags,
+            composition_time_offset,
+        });
+    }
+    Some(TrackRun {
+        data_offset,
+        samples,
+    })
+}
+
+fn read_be_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn read_be_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(..8)?.try_into().ok()?))
+}
+
+pub(crate) fn write_full_box<F>(
+    out: &mut Vec<u8>,
+    name: [u8; 4],
+    version: u8,
+    flags: u32,
+    f: F,
+) -> Option<()>
 where
     F: FnOnce(&mut Vec<u8>) -> Option<()>,
 {
@@ -967,7 +2333,7 @@ where
     })
 }
 
-fn write_box<F>(out: &mut Vec<u8>, name: [u8; 4], f: F) -> Option<()>
+pub(crate) fn write_box<F>(out: &mut Vec<u8>, name: [u8; 4], f: F) -> Option<()>
 where
     F: FnOnce(&mut Vec<u8>) -> Option<()>,
 {
@@ -980,6 +2346,17 @@ where
     Some(())
 }
 
+/// Write a null-terminated UTF-8 string. Embedded nulls would truncate the
+/// value for readers, so they are rejected.
+fn write_cstring(out: &mut Vec<u8>, value: &str) -> Option<()> {
+    if value.as_bytes().contains(&0) {
+        return None;
+    }
+    out.extend_from_slice(value.as_bytes());
+    write_u8(out, 0);
+    Some(())
+}
+
 fn patch_i32(out: &mut [u8], position: usize, value: i32) {
     out[position..position + 4].copy_from_slice(&value.to_be_bytes());
 }
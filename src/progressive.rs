@@ -0,0 +1,567 @@
+use crate::fmp4::{box_fmp4_with_tracks, Config, FileType, FragmentMedia};
+use crate::mp4::{
+    read_boxes, read_init_tracks, read_track_fragments, write_file_type, write_progressive_moov,
+    InitTrack, SampleTable,
+};
+use bytes::Bytes;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// Sample tables rebuilt from the `moof`s of fragmented segments. Each run
+/// becomes one chunk of a single media data area that the caller fills with
+/// the ranges `append` returns.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SampleTables {
+    init_tracks: Vec<InitTrack>,
+    tables: Vec<(u32, SampleTable)>,
+    next_decode_times: Vec<Option<u64>>,
+    media_len: u64,
+}
+
+impl SampleTables {
+    pub(crate) fn new(init: &[u8]) -> Option<Self> {
+        let init_tracks = read_init_tracks(init)?;
+        Some(Self {
+            tables: init_tracks
+                .iter()
+                .map(|track| (track.track_id, SampleTable::default()))
+                .collect(),
+            next_decode_times: vec![None; init_tracks.len()],
+            init_tracks,
+            media_len: 0,
+        })
+    }
+
+    pub(crate) fn tables(&self) -> &[(u32, SampleTable)] {
+        &self.tables
+    }
+
+    /// Record the samples of every `moof` in `segment` and return the ranges
+    /// of `segment` holding their data, in the order they must be appended
+    /// to the media data. Tracks missing from the init segment are dropped,
+    /// and a gap before a fragment's decode time lengthens the track's
+    /// previous sample. A fragment that starts before its track's previous
+    /// one ended is rejected, leaving the tables unchanged.
+    pub(crate) fn append(&mut self, segment: &[u8]) -> Option<Vec<Range<usize>>> {
+        let mut moofs = Vec::new();
+        let mut position = 0;
+        for top in read_boxes(segment)? {
+            let moof_start = position;
+            position =
+                top.payload.as_ptr() as usize - segment.as_ptr() as usize + top.payload.len();
+            if top.name == *b"moof" {
+                moofs.push((moof_start, read_track_fragments(&top, &self.init_tracks)?));
+            }
+        }
+
+        let mut next_decode_times = self.next_decode_times.clone();
+        for fragment in moofs.iter().flat_map(|(_, fragments)| fragments) {
+            let Some(index) = self.track_index(fragment.track_id) else {
+                continue;
+            };
+            if let Some(decode_time) = fragment.base_media_decode_time {
+                if next_decode_times[index].is_some_and(|expected| decode_time < expected) {
+                    return None;
+                }
+                next_decode_times[index] = Some(decode_time);
+            }
+            if let Some(next) = next_decode_times[index].as_mut() {
+                *next += fragment
+                    .runs
+                    .iter()
+                    .flat_map(|run| &run.samples)
+                    .map(|sample| u64::from(sample.duration))
+                    .sum::<u64>();
+            }
+        }
+
+        // Locate every run before touching the tables, so a fragment whose
+        // data lies outside `segment` is rejected as a whole.
+        let mut run_ranges = Vec::new();
+        for (moof_start, fragments) in &moofs {
+            let mut data_end = *moof_start;
+            for fragment in fragments {
+                let base = match fragment.base_data_offset {
+                    Some(offset) => usize::try_from(offset).ok()?,
+                    None if fragment.default_base_is_moof => *moof_start,
+                    None => data_end,
+                };
+                let mut run_start = base;
+                for run in &fragment.runs {
+                    if let Some(offset) = run.data_offset {
+                        run_start = base.checked_add_signed(isize::try_from(offset).ok()?)?;
+                    }
+                    let len = run
+                        .samples
+                        .iter()
+                        .map(|sample| sample.size as usize)
+                        .sum::<usize>();
+                    let run_end = run_start.checked_add(len)?;
+                    if run_end > segment.len() || u32::try_from(run.samples.len()).is_err() {
+                        return None;
+                    }
+                    run_ranges.push(run_start..run_end);
+                    run_start = run_end;
+                }
+                data_end = run_start;
+            }
+        }
+
+        let mut run_ranges = run_ranges.into_iter();
+        let mut ranges = Vec::new();
+        for fragment in moofs.into_iter().flat_map(|(_, fragments)| fragments) {
+            let index = self.track_index(fragment.track_id);
+            if let (Some(index), Some(decode_time)) = (index, fragment.base_media_decode_time) {
+                let table = &mut self.tables[index].1;
+                if let (Some(expected), Some(last)) =
+                    (self.next_decode_times[index], table.samples.last_mut())
+                {
+                    let gap = decode_time - expected;
+                    last.duration = last
+                        .duration
+                        .saturating_add(u32::try_from(gap).unwrap_or(u32::MAX));
+                }
+                self.next_decode_times[index] = Some(decode_time);
+            }
+            for (run, range) in fragment.runs.into_iter().zip(run_ranges.by_ref()) {
+                let Some(index) = index else {
+                    continue;
+                };
+                let table = &mut self.tables[index].1;
+                let duration = run
+                    .samples
+                    .iter()
+                    .map(|sample| u64::from(sample.duration))
+                    .sum::<u64>();
+                if let Some(next) = self.next_decode_times[index].as_mut() {
+                    *next += duration;
+                }
+                // Checked above to fit.
+                table
+                    .chunks
+                    .push((self.media_len, run.samples.len() as u32));
+                table.samples.extend(run.samples);
+                self.media_len += range.len() as u64;
+                ranges.push(range);
+            }
+        }
+        Some(ranges)
+    }
+
+    fn track_index(&self, track_id: u32) -> Option<usize> {
+        self.tables.iter().position(|(id, _)| *id == track_id)
+    }
+}
+
+/// `mdat` header for `media_len` bytes of payload, with a 64-bit size when
+/// the box would not fit a 32-bit one.
+pub(crate) fn mdat_header(media_len: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16);
+    match u32::try_from(media_len + 8) {
+        Ok(size) => {
+            header.extend_from_slice(&size.to_be_bytes());
+            header.extend_from_slice(b"mdat");
+        }
+        Err(_) => {
+            header.extend_from_slice(&1u32.to_be_bytes());
+            header.extend_from_slice(b"mdat");
+            header.extend_from_slice(&(media_len + 16).to_be_bytes());
+        }
+    }
+    header
+}
+
+/// Classic MP4 muxer: samples are accumulated fragment by fragment and
+/// written as one `mdat` with a `moov` carrying full sample tables.
+pub struct ProgressiveMp4 {
+    config: Config,
+    sequence: u32,
+    init: Option<Bytes>,
+    tables: SampleTables,
+    media: Vec<u8>,
+}
+
+impl ProgressiveMp4 {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            sequence: 0,
+            init: None,
+            tables: SampleTables::default(),
+            media: Vec::new(),
+        }
+    }
+
+    /// Add one fragment's worth of media, as `box_fmp4_with_tracks` takes
+    /// it. The first call fixes the tracks of the file, so it must carry
+    /// every track; tracks first seen later are dropped. Media that cannot
+    /// be boxed, or that starts before the previous fragment ended, is
+    /// rejected and leaves the file unchanged.
+    pub fn push(&mut self, media: FragmentMedia, next_dts: u64) -> Option<()> {
+        let sequence = self.sequence.wrapping_add(1);
+        let fmp4 = box_fmp4_with_tracks(
+            sequence,
+            self.config.clone(),
+            media,
+            next_dts,
+            self.init.is_none(),
+        );
+        let ranges = if self.init.is_some() {
+            self.tables.append(&fmp4.data)?
+        } else {
+            let init = fmp4.init?;
+            let mut tables = SampleTables::new(&init)?;
+            let ranges = tables.append(&fmp4.data)?;
+            self.tables = tables;
+            self.init = Some(init);
+            ranges
+        };
+        for range in ranges {
+            self.media.extend_from_slice(&fmp4.data[range]);
+        }
+        self.sequence = sequence;
+        Some(())
+    }
+
+    /// Write the file. With `faststart` the `moov` comes before the `mdat`
+    /// so playback can begin before the whole file is downloaded.
+    pub fn finish(&self, faststart: bool) -> Option<Vec<u8>> {
+        let init = self.init.as_ref()?;
+        let mdat_header = mdat_header(self.media.len() as u64);
+        let mut out = Vec::new();
+        write_file_type(&mut out, *b"ftyp", &FileType::progressive())?;
+        if faststart {
+            // Chunk offsets depend on the `moov` size, which can itself grow
+            // when they need `co64`.
+            let mut moov_len = 0;
+            let moov = loop {
+                let mut moov = Vec::new();
+                let chunk_base = out.len() + moov_len + mdat_header.len();
+                write_progressive_moov(&mut moov, init, self.tables.tables(), chunk_base as u64)?;
+                if moov.len() == moov_len {
+                    break moov;
+                }
+                moov_len = moov.len();
+            };
+            out.extend_from_slice(&moov);
+            out.extend_from_slice(&mdat_header);
+            out.extend_from_slice(&self.media);
+        } else {
+            let chunk_base = out.len() + mdat_header.len();
+            out.extend_from_slice(&mdat_header);
+            out.extend_from_slice(&self.media);
+            write_progressive_moov(&mut out, init, self.tables.tables(), chunk_base as u64)?;
+        }
+        Some(out)
+    }
+}
+
+/// Remux an init segment and the fragments that follow it into a
+/// progressive MP4 on `writer`. Media data is streamed out fragment by
+/// fragment; only the sample tables are held until the `moov` is written at
+/// the end.
+pub struct Defragmenter<W: Write + Seek> {
+    writer: W,
+    init: Vec<u8>,
+    tables: SampleTables,
+    mdat_start: u64,
+    media_len: u64,
+}
+
+impl<W: Write + Seek> Defragmenter<W> {
+    /// Write the `ftyp` and an `mdat` header whose size is patched by
+    /// `finish`.
+    pub fn new(mut writer: W, init: &[u8]) -> io::Result<Self> {
+        let tables = SampleTables::new(init).ok_or_else(|| invalid_data("invalid init segment"))?;
+        let mut ftyp = Vec::new();
+        write_file_type(&mut ftyp, *b"ftyp", &FileType::progressive())
+            .ok_or_else(|| invalid_data("invalid file type"))?;
+        writer.write_all(&ftyp)?;
+        let mdat_start = writer.stream_position()?;
+        // Always a 64-bit size, so the header length is known up front.
+        writer.write_all(&[0, 0, 0, 1, b'm', b'd', b'a', b't', 0, 0, 0, 0, 0, 0, 0, 0])?;
+        Ok(Self {
+            writer,
+            init: init.to_vec(),
+            tables,
+            mdat_start,
+            media_len: 0,
+        })
+    }
+
+    /// Append the samples of one media segment: any `styp`, `emsg` or
+    /// `prft` boxes are skipped and each `moof`'s runs are copied into the
+    /// `mdat`.
+    pub fn push_segment(&mut self, segment: &[u8]) -> io::Result<()> {
+        let ranges = self
+            .tables
+            .append(segment)
+            .ok_or_else(|| invalid_data("invalid media segment"))?;
+        for range in ranges {
+            self.media_len += range.len() as u64;
+            self.writer.write_all(&segment[range])?;
+        }
+        Ok(())
+    }
+
+    /// Patch the `mdat` size, append the `moov` and return the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.mdat_start + 8))?;
+        self.writer
+            .write_all(&(self.media_len + 16).to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        let mut moov = Vec::new();
+        write_progressive_moov(
+            &mut moov,
+            &self.init,
+            self.tables.tables(),
+            self.mdat_start + 16,
+        )
+        .ok_or_else(|| invalid_data("invalid init segment"))?;
+        self.writer.write_all(&moov)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Remux `init` and `segments` into a progressive MP4 on `writer`.
+pub fn defragment<W, I>(writer: W, init: &[u8], segments: I) -> io::Result<W>
+where
+    W: Write + Seek,
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+{
+    let mut defragmenter = Defragmenter::new(writer, init)?;
+    for segment in segments {
+        defragmenter.push_segment(segment.as_ref())?;
+    }
+    defragmenter.finish()
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::tests::config;
+    use crate::fmp4::{AudioTrack, TrackMetadata};
+    use crate::mp4::BoxRef;
+    use access_unit::{AccessUnit, PSI_STREAM_AAC, PSI_STREAM_H264};
+
+    fn video(dts: u64, pts: u64, key: bool, byte: u8) -> AccessUnit {
+        AccessUnit {
+            key,
+            pts,
+            dts,
+            data: Bytes::from(vec![0, 0, 0, 1, 0x65, byte]),
+            stream_type: PSI_STREAM_H264,
+            id: 0,
+        }
+    }
+
+    fn audio(dts: u64) -> AccessUnit {
+        let mut data = access_unit::aac::create_adts_header(0x66, 2, 48_000, 1, false);
+        data.push(0x21);
+        AccessUnit {
+            key: true,
+            pts: dts,
+            dts,
+            data: Bytes::from(data),
+            stream_type: PSI_STREAM_AAC,
+            id: 0,
+        }
+    }
+
+    fn media(dts: u64, audio_dts: u64) -> FragmentMedia {
+        FragmentMedia {
+            video: vec![
+                video(dts, dts + 3_000, true, 1),
+                video(dts + 3_000, dts + 9_000, false, 2),
+                video(dts + 6_000, dts + 6_000, false, 3),
+            ],
+            audio: vec![AudioTrack {
+                track_id: 2,
+                config: None,
+                metadata: TrackMetadata::default(),
+                units: vec![audio(audio_dts), audio(audio_dts + 21)],
+            }],
+            ..FragmentMedia::default()
+        }
+    }
+
+    fn muxer() -> ProgressiveMp4 {
+        let mut mp4 = ProgressiveMp4::new(config());
+        mp4.push(media(0, 0), 9_000).unwrap();
+        mp4.push(media(9_000, 100), 18_000).unwrap();
+        mp4
+    }
+
+    fn stbl<'a>(moov: &BoxRef<'a>, index: usize) -> BoxRef<'a> {
+        moov.children(*b"trak").unwrap()[index]
+            .child(*b"mdia")
+            .and_then(|mdia| mdia.child(*b"minf"))
+            .and_then(|minf| minf.child(*b"stbl"))
+            .unwrap()
+    }
+
+    fn be_u32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes(bytes[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn overlapping_fragment_is_rejected_and_leaves_the_file_unchanged() {
+        let mut mp4 = muxer();
+        let before = mp4.finish(true).unwrap();
+
+        // Video resumes at 12,000 ticks, before the previous fragment's
+        // 18,000; the audio alone would have fit.
+        assert_eq!(mp4.push(media(12_000, 200), 21_000), None);
+        assert_eq!(mp4.finish(true).unwrap(), before);
+        assert_eq!(mp4.push(media(18_000, 200), 27_000), Some(()));
+    }
+
+    #[test]
+    fn moov_after_mdat_has_populated_tables_and_durations() {
+        let file = muxer().finish(false).unwrap();
+        let boxes = read_boxes(&file).unwrap();
+        let names: Vec<_> = boxes.iter().map(|top| top.name).collect();
+        assert_eq!(names, [*b"ftyp", *b"mdat", *b"moov"]);
+        let moov = boxes[2];
+        assert!(moov.child(*b"mvex").is_none());
+
+        let (_, _, mvhd) = moov.child(*b"mvhd").unwrap().full_box().unwrap();
+        let movie_timescale = be_u32(&mvhd[8..]);
+        assert_eq!(
+            u64::from(be_u32(&mvhd[12..])),
+            u64::from(movie_timescale) * 18_000 / 90_000
+        );
+
+        let video = stbl(&moov, 0);
+        let (_, _, stts) = video.child(*b"stts").unwrap().full_box().unwrap();
+        assert_eq!(&stts[..12], [0, 0, 0, 1, 0, 0, 0, 6, 0, 0, 0x0b, 0xb8]);
+        let (_, _, stss) = video.child(*b"stss").unwrap().full_box().unwrap();
+        assert_eq!(stss, [0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 4]);
+        assert!(video.child(*b"ctts").is_some());
+        let (_, _, stsz) = video.child(*b"stsz").unwrap().full_box().unwrap();
+        assert_eq!(&stsz[..8], [0, 0, 0, 6, 0, 0, 0, 6]);
+
+        let (_, _, stco) = video.child(*b"stco").unwrap().full_box().unwrap();
+        assert_eq!(be_u32(stco), 2);
+        let first = be_u32(&stco[4..]) as usize;
+        let second = be_u32(&stco[8..]) as usize;
+        assert_eq!(&file[first..first + 6], [0, 0, 0, 1, 0x65, 1]);
+        assert_eq!(&file[second..second + 6], [0, 0, 0, 1, 0x65, 1]);
+
+        let audio = stbl(&moov, 1);
+        let (_, _, stsz) = audio.child(*b"stsz").unwrap().full_box().unwrap();
+        assert_eq!(&stsz[4..8], [0, 0, 0, 4]);
+        assert!(audio.child(*b"stss").is_none());
+        assert!(audio.child(*b"ctts").is_none());
+    }
+
+    #[test]
+    fn faststart_puts_moov_first_with_offsets_into_mdat() {
+        let mp4 = muxer();
+        let plain = mp4.finish(false).unwrap();
+        let file = mp4.finish(true).unwrap();
+        assert_eq!(file.len(), plain.len());
+        let boxes = read_boxes(&file).unwrap();
+        let names: Vec<_> = boxes.iter().map(|top| top.name).collect();
+        assert_eq!(names, [*b"ftyp", *b"moov", *b"mdat"]);
+
+        let mdat = boxes[2].payload;
+        let mdat_start = mdat.as_ptr() as usize - file.as_ptr() as usize;
+        let (_, _, stco) = stbl(&boxes[1], 1)
+            .child(*b"stco")
+            .unwrap()
+            .full_box()
+            .unwrap();
+        let offset = be_u32(&stco[4..]) as usize;
+        assert!(offset >= mdat_start && offset < mdat_start + mdat.len());
+        assert_eq!(file[offset], 0x21);
+    }
+
+    #[test]
+    fn large_media_uses_64_bit_mdat_size() {
+        assert_eq!(mdat_header(16), [0, 0, 0, 24, b'm', b'd', b'a', b't']);
+        let header = mdat_header(u64::from(u32::MAX));
+        assert_eq!(&header[..8], [0, 0, 0, 1, b'm', b'd', b'a', b't']);
+        assert_eq!(&header[8..], (u64::from(u32::MAX) + 16).to_be_bytes());
+    }
+
+    #[test]
+    fn defragment_matches_progressive_muxer_layout() {
+        let mut fragments = Vec::new();
+        let mut init = Bytes::new();
+        for (seq, (dts, audio_dts)) in [(0, 0), (9_000, 100)].into_iter().enumerate() {
+            let fmp4 = box_fmp4_with_tracks(
+                seq as u32 + 1,
+                config(),
+                media(dts, audio_dts),
+                dts + 9_000,
+                seq == 0,
+            );
+            if let Some(segment_init) = fmp4.init {
+                init = segment_init;
+            }
+            fragments.push(fmp4.data);
+        }
+        let file = defragment(io::Cursor::new(Vec::new()), &init, &fragments)
+            .unwrap()
+            .into_inner();
+
+        let boxes = read_boxes(&file).unwrap();
+        let names: Vec<_> = boxes.iter().map(|top| top.name).collect();
+        assert_eq!(names, [*b"ftyp", *b"mdat", *b"moov"]);
+        assert_eq!(boxes[1].payload.len(), 6 * 6 + 4);
+
+        let expected = muxer().finish(false).unwrap();
+        let expected_boxes = read_boxes(&expected).unwrap();
+        assert_eq!(boxes[1].payload, expected_boxes[1].payload);
+        let video = stbl(&boxes[2], 0);
+        let expected_video = stbl(&expected_boxes[2], 0);
+        for name in [*b"stts", *b"ctts", *b"stss", *b"stsz", *b"stsc"] {
+            assert_eq!(
+                video.child(name).unwrap().payload,
+                expected_video.child(name).unwrap().payload
+            );
+        }
+        let (_, _, stco) = video.child(*b"stco").unwrap().full_box().unwrap();
+        let offset = be_u32(&stco[4..]) as usize;
+        assert_eq!(&file[offset..offset + 6], [0, 0, 0, 1, 0x65, 1]);
+    }
+
+    #[test]
+    fn defragment_rejects_truncated_fragment() {
+        let fmp4 = box_fmp4_with_tracks(1, config(), media(0, 0), 9_000, true);
+        let init = fmp4.init.unwrap();
+        let truncated = &fmp4.data[..fmp4.data.len() - 1];
+        let error = defragment(io::Cursor::new(Vec::new()), &init, [truncated]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // With the `mdat` size patched to match, only the last run falls
+        // outside the segment; the runs before it must not be kept either.
+        let mut short = truncated.to_vec();
+        let mdat_start = fmp4.data.len() - 8 - read_boxes(&fmp4.data).unwrap()[1].payload.len();
+        let mdat_size = be_u32(&short[mdat_start..]) - 1;
+        short[mdat_start..mdat_start + 4].copy_from_slice(&mdat_size.to_be_bytes());
+        let mut defragmenter = Defragmenter::new(io::Cursor::new(Vec::new()), &init).unwrap();
+        assert!(defragmenter.push_segment(&short).is_err());
+        defragmenter.push_segment(&fmp4.data).unwrap();
+        let file = defragmenter.finish().unwrap().into_inner();
+        let expected = defragment(io::Cursor::new(Vec::new()), &init, [&fmp4.data[..]])
+            .unwrap()
+            .into_inner();
+        assert_eq!(file, expected);
+        let moov = read_boxes(&file).unwrap()[2];
+        let video = stbl(&moov, 0);
+        let (_, _, stsz) = video.child(*b"stsz").unwrap().full_box().unwrap();
+        assert_eq!(&stsz[4..8], [0, 0, 0, 3]);
+        let (_, _, stco) = video.child(*b"stco").unwrap().full_box().unwrap();
+        let first = be_u32(&stco[4..]) as usize;
+        assert_eq!(&file[first..first + 6], [0, 0, 0, 1, 0x65, 1]);
+    }
+}
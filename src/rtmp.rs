@@ -2,12 +2,27 @@ use access_unit::aac::ensure_adts_header;
 use access_unit::{AccessUnit, PSI_STREAM_AAC, PSI_STREAM_H264};
 use bytes::{Bytes, BytesMut};
 
+use crate::scte35::SpliceInfoSection;
+
 const VIDEO_CODEC_H264: u8 = 7;
 const VIDEO_FRAME_KEY: u8 = 1;
 const AVC_SEQUENCE_HEADER: u8 = 0;
 const AVC_NALU: u8 = 1;
 const AUDIO_CODEC_AAC: u8 = 10;
 const AAC_SEQUENCE_HEADER: u8 = 0;
+const AMF0_NUMBER: u8 = 0x00;
+const AMF0_BOOLEAN: u8 = 0x01;
+const AMF0_STRING: u8 = 0x02;
+const AMF0_OBJECT: u8 = 0x03;
+const AMF0_NULL: u8 = 0x05;
+const AMF0_UNDEFINED: u8 = 0x06;
+const AMF0_ECMA_ARRAY: u8 = 0x08;
+const AMF0_OBJECT_END: u8 = 0x09;
+const AMF0_STRICT_ARRAY: u8 = 0x0a;
+const AMF0_LONG_STRING: u8 = 0x0c;
+const ON_CUE_POINT: &[u8] = b"onCuePoint";
+/// Deepest AMF0 object/array nesting accepted in cue point data.
+const AMF0_MAX_DEPTH: usize = 32;
 
 #[derive(Debug)]
 pub struct RtmpVideoAccessUnit {
@@ -100,6 +115,92 @@ pub fn extract_aac_access_unit(
     })
 }
 
+/// Extract a SCTE-35 section from an AMF0 `onCuePoint` data message.
+///
+/// Encoders differ in where they put the cue, so every string property of the
+/// cue point object, including nested ones, is tried as a base64-encoded
+/// `splice_info_section`. A leading `@setDataFrame` is skipped.
+pub fn extract_scte35_cue_point(packet: &[u8]) -> Option<SpliceInfoSection> {
+    let mut data = packet;
+    loop {
+        if *data.first()? != AMF0_STRING {
+            return None;
+        }
+        let name = read_amf0_string(&mut data)?;
+        if name == ON_CUE_POINT {
+            break;
+        }
+    }
+
+    let mut strings = Vec::new();
+    while !data.is_empty() {
+        collect_amf0_strings(&mut data, &mut strings, 0)?;
+    }
+    strings
+        .into_iter()
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .find_map(SpliceInfoSection::from_base64)
+}
+
+/// Read one AMF0 string marker and value, advancing `data`.
+fn read_amf0_string<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    *data = data.get(1..)?;
+    read_amf0_utf8(data, 2)
+}
+
+fn read_amf0_utf8<'a>(data: &mut &'a [u8], length_bytes: usize) -> Option<&'a [u8]> {
+    let length = data
+        .get(..length_bytes)?
+        .iter()
+        .fold(0_usize, |length, &byte| (length << 8) | usize::from(byte));
+    let value = data.get(length_bytes..length_bytes.checked_add(length)?)?;
+    *data = &data[length_bytes + length..];
+    Some(value)
+}
+
+/// Skip one AMF0 value, collecting every string it contains. Values nested
+/// deeper than [`AMF0_MAX_DEPTH`] are rejected.
+fn collect_amf0_strings<'a>(
+    data: &mut &'a [u8],
+    strings: &mut Vec<&'a [u8]>,
+    depth: usize,
+) -> Option<()> {
+    if depth > AMF0_MAX_DEPTH {
+        return None;
+    }
+    let marker = *data.first()?;
+    *data = &data[1..];
+    match marker {
+        AMF0_NUMBER => *data = data.get(8..)?,
+        AMF0_BOOLEAN => *data = data.get(1..)?,
+        AMF0_STRING => strings.push(read_amf0_utf8(data, 2)?),
+        AMF0_LONG_STRING => strings.push(read_amf0_utf8(data, 4)?),
+        AMF0_NULL | AMF0_UNDEFINED => {}
+        AMF0_OBJECT | AMF0_ECMA_ARRAY => {
+            if marker == AMF0_ECMA_ARRAY {
+                *data = data.get(4..)?;
+            }
+            loop {
+                let key = read_amf0_utf8(data, 2)?;
+                if key.is_empty() && *data.first()? == AMF0_OBJECT_END {
+                    *data = &data[1..];
+                    break;
+                }
+                collect_amf0_strings(data, strings, depth + 1)?;
+            }
+        }
+        AMF0_STRICT_ARRAY => {
+            let count = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+            *data = &data[4..];
+            for _ in 0..count {
+                collect_amf0_strings(data, strings, depth + 1)?;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
 fn parse_avc_sequence_header(data: &[u8]) -> Option<Bytes> {
     if data.len() < 7 {
         return None;
@@ -224,6 +325,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn extracts_scte35_from_on_cue_point() {
+        let cue = "/DAvAAAAAAAA///wFAVIAACPf+/+c2nALv4AUsz1AAAAAAAKAAhDVUVJAAABNWLbowo=";
+        let mut packet = vec![AMF0_STRING, 0, 10];
+        packet.extend_from_slice(ON_CUE_POINT);
+        packet.extend_from_slice(&[AMF0_ECMA_ARRAY, 0, 0, 0, 2]);
+        packet.extend_from_slice(&[0, 4]);
+        packet.extend_from_slice(b"time");
+        packet.push(AMF0_NUMBER);
+        packet.extend_from_slice(&12.5_f64.to_be_bytes());
+        packet.extend_from_slice(&[0, 10]);
+        packet.extend_from_slice(b"parameters");
+        packet.push(AMF0_OBJECT);
+        packet.extend_from_slice(&[0, 4]);
+        packet.extend_from_slice(b"cue1");
+        packet.extend_from_slice(&[AMF0_STRING, 0, cue.len() as u8]);
+        packet.extend_from_slice(cue.as_bytes());
+        packet.extend_from_slice(&[0, 0, AMF0_OBJECT_END, 0, 0, AMF0_OBJECT_END]);
+
+        let section = extract_scte35_cue_point(&packet).expect("cue");
+
+        assert_eq!(section.to_base64().as_deref(), Some(cue));
+        assert!(extract_scte35_cue_point(&packet[..3]).is_none());
+    }
+
+    #[test]
+    fn rejects_deeply_nested_cue_point_data() {
+        let nested = |depth: usize| {
+            let mut packet = vec![AMF0_STRING, 0, 10];
+            packet.extend_from_slice(ON_CUE_POINT);
+            packet.extend(std::iter::repeat_n([AMF0_STRICT_ARRAY, 0, 0, 0, 1], depth).flatten());
+            packet.push(AMF0_NULL);
+            packet
+        };
+
+        assert!(
+            collect_amf0_strings(&mut &nested(AMF0_MAX_DEPTH)[13..], &mut Vec::new(), 0).is_some()
+        );
+        assert!(extract_scte35_cue_point(&nested(AMF0_MAX_DEPTH + 1)).is_none());
+        assert!(extract_scte35_cue_point(&nested(100_000)).is_none());
+    }
+
     #[test]
     fn skips_aac_sequence_header() {
         assert!(extract_aac_access_unit(
@@ -0,0 +1,889 @@
+use crate::base64::{base64_decode, base64_encode};
+use crate::fmp4::ticks_to_hz;
+use crate::mp4::{EventMessage, EventPresentationTime};
+
+const TABLE_ID: u8 = 0xfc;
+const PTS_MODULUS: u64 = 1 << 33;
+
+const SPLICE_NULL: u8 = 0x00;
+const SPLICE_INSERT: u8 = 0x05;
+const TIME_SIGNAL: u8 = 0x06;
+const BANDWIDTH_RESERVATION: u8 = 0x07;
+const PRIVATE_COMMAND: u8 = 0xff;
+
+const SEGMENTATION_DESCRIPTOR: u8 = 0x02;
+const CUEI_IDENTIFIER: u32 = 0x4355_4549;
+
+/// `emsg` scheme for binary SCTE-35 sections (SCTE 214-3).
+pub const SCTE35_EMSG_SCHEME: &str = "urn:scte:scte35:2013:bin";
+
+/// An unencrypted SCTE-35 `splice_info_section`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpliceInfoSection {
+    pub sap_type: u8,
+    pub protocol_version: u8,
+    /// 33-bit offset added to every `pts_time` in the section.
+    pub pts_adjustment: u64,
+    pub cw_index: u8,
+    pub tier: u16,
+    pub command: SpliceCommand,
+    pub descriptors: Vec<SpliceDescriptor>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpliceCommand {
+    Null,
+    Insert(SpliceInsert),
+    /// `None` means the signal applies immediately.
+    TimeSignal {
+        pts_time: Option<u64>,
+    },
+    BandwidthReservation,
+    Private {
+        identifier: u32,
+        data: Vec<u8>,
+    },
+    /// Commands this module does not model, such as `splice_schedule`,
+    /// preserved byte for byte.
+    Other {
+        command_type: u8,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpliceInsert {
+    pub splice_event_id: u32,
+    pub splice_event_cancel_indicator: bool,
+    pub out_of_network_indicator: bool,
+    pub program_splice_flag: bool,
+    pub splice_immediate_flag: bool,
+    pub event_id_compliance_flag: bool,
+    /// Program splice point; `None` when immediate or component-level.
+    pub pts_time: Option<u64>,
+    /// Component splice points as `(component_tag, pts_time)`.
+    pub components: Vec<(u8, Option<u64>)>,
+    pub break_duration: Option<BreakDuration>,
+    pub unique_program_id: u16,
+    pub avail_num: u8,
+    pub avails_expected: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BreakDuration {
+    pub auto_return: bool,
+    /// 90 kHz ticks.
+    pub duration: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpliceDescriptor {
+    Segmentation(SegmentationDescriptor),
+    Other {
+        tag: u8,
+        identifier: u32,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SegmentationDescriptor {
+    pub segmentation_event_id: u32,
+    pub segmentation_event_cancel_indicator: bool,
+    pub segmentation_event_id_compliance_indicator: bool,
+    pub program_segmentation_flag: bool,
+    /// `None` when delivery is not restricted; otherwise the
+    /// web_delivery_allowed, no_regional_blackout and archive_allowed flags
+    /// and the two-bit device_restrictions.
+    pub delivery_restrictions: Option<DeliveryRestrictions>,
+    /// Component offsets as `(component_tag, pts_offset)`.
+    pub components: Vec<(u8, u64)>,
+    /// 90 kHz ticks.
+    pub segmentation_duration: Option<u64>,
+    pub segmentation_upid_type: u8,
+    pub segmentation_upid: Vec<u8>,
+    pub segmentation_type_id: u8,
+    pub segment_num: u8,
+    pub segments_expected: u8,
+    /// `(sub_segment_num, sub_segments_expected)`, carried only by the
+    /// placement opportunity and ad block start types (0x34, 0x36, 0x38,
+    /// 0x3A, 0x44 and 0x46); ignored for other types.
+    pub sub_segment: Option<(u8, u8)>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeliveryRestrictions {
+    pub web_delivery_allowed: bool,
+    pub no_regional_blackout: bool,
+    pub archive_allowed: bool,
+    pub device_restrictions: u8,
+}
+
+impl SpliceInfoSection {
+    /// A section with the common defaults: SAP type unspecified, protocol
+    /// version 0, no encryption and all tiers.
+    pub fn new(command: SpliceCommand) -> Self {
+        Self {
+            sap_type: 3,
+            protocol_version: 0,
+            pts_adjustment: 0,
+            cw_index: 0,
+            tier: 0x0fff,
+            command,
+            descriptors: Vec::new(),
+        }
+    }
+
+    /// Decode a section, verifying its CRC. Encrypted sections are rejected.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if *data.first()? != TABLE_ID || data.len() < 3 {
+            return None;
+        }
+        let section_length = usize::from(u16::from_be_bytes([data[1], data[2]]) & 0x0fff);
+        let section = data.get(..section_length.checked_add(3)?)?;
+        if section.len() < 4 || crc32_mpeg2(section) != 0 {
+            return None;
+        }
+
+        let mut reader = BitReader::new(&section[..section.len() - 4]);
+        reader.skip(8)?;
+        reader.skip(2)?;
+        let sap_type = reader.read(2)? as u8;
+        reader.skip(12)?;
+        let protocol_version = reader.read(8)? as u8;
+        let encrypted_packet = reader.flag()?;
+        if encrypted_packet {
+            return None;
+        }
+        reader.skip(6)?;
+        let pts_adjustment = reader.read(33)?;
+        let cw_index = reader.read(8)? as u8;
+        let tier = reader.read(12)? as u16;
+        let splice_command_length = reader.read(12)? as usize;
+        let command_type = reader.read(8)? as u8;
+        let command_bytes = if splice_command_length == 0xfff {
+            // Legacy senders leave the length unset; the command must then
+            // be parsed to find its end.
+            None
+        } else {
+            Some(reader.bytes(splice_command_length)?)
+        };
+        let command = match command_bytes {
+            Some(bytes) => parse_command(command_type, &mut BitReader::new(bytes))?,
+            None => parse_command(command_type, &mut reader)?,
+        };
+        let descriptor_loop_length = reader.read(16)? as usize;
+        let descriptors = parse_descriptors(reader.bytes(descriptor_loop_length)?)?;
+
+        Some(Self {
+            sap_type,
+            protocol_version,
+            pts_adjustment,
+            cw_index,
+            tier,
+            command,
+            descriptors,
+        })
+    }
+
+    /// Encode the section, including its CRC-32.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let mut command = BitWriter::default();
+        write_command(&mut command, &self.command)?;
+        let command = command.finish()?;
+        let mut descriptors = Vec::new();
+        for descriptor in &self.descriptors {
+            write_descriptor(&mut descriptors, descriptor)?;
+        }
+
+        let mut writer = BitWriter::default();
+        writer.write(8, u64::from(TABLE_ID));
+        writer.write(1, 0);
+        writer.write(1, 0);
+        writer.write(2, u64::from(self.sap_type & 0x03));
+        // section_length counts everything after this field, CRC included.
+        let section_length = 11 + command.len() + 2 + descriptors.len() + 4;
+        writer.write(
+            12,
+            u64::try_from(section_length)
+                .ok()
+                .filter(|&len| len <= 0xfff)?,
+        );
+        writer.write(8, u64::from(self.protocol_version));
+        writer.write(1, 0);
+        writer.write(6, 0);
+        writer.write(33, self.pts_adjustment % PTS_MODULUS);
+        writer.write(8, u64::from(self.cw_index));
+        writer.write(12, u64::from(self.tier & 0x0fff));
+        writer.write(
+            12,
+            u64::try_from(command.len())
+                .ok()
+                .filter(|&len| len < 0xfff)?,
+        );
+        writer.write(8, u64::from(self.command.command_type()));
+        let mut out = writer.finish()?;
+        out.extend_from_slice(&command);
+        out.extend_from_slice(&u16::try_from(descriptors.len()).ok()?.to_be_bytes());
+        out.extend_from_slice(&descriptors);
+        let crc = crc32_mpeg2(&out);
+        out.extend_from_slice(&crc.to_be_bytes());
+        Some(out)
+    }
+
+    pub fn from_base64(encoded: &str) -> Option<Self> {
+        Self::parse(&base64_decode(encoded)?)
+    }
+
+    pub fn to_base64(&self) -> Option<String> {
+        Some(base64_encode(&self.to_bytes()?))
+    }
+
+    /// The splice point in 90 kHz ticks with `pts_adjustment` applied and
+    /// wrapped to 33 bits, or `None` for immediate and component-level
+    /// splices.
+    pub fn splice_pts(&self) -> Option<u64> {
+        let pts_time = match &self.command {
+            SpliceCommand::Insert(insert) => insert.pts_time?,
+            SpliceCommand::TimeSignal { pts_time } => (*pts_time)?,
+            _ => return None,
+        };
+        Some((pts_time + self.pts_adjustment) % PTS_MODULUS)
+    }
+
+    /// Duration of the break or segment in 90 kHz ticks.
+    pub fn duration(&self) -> Option<u64> {
+        if let SpliceCommand::Insert(SpliceInsert {
+            break_duration: Some(duration),
+            ..
+        }) = &self.command
+        {
+            return Some(duration.duration);
+        }
+        self.descriptors
+            .iter()
+            .find_map(|descriptor| match descriptor {
+                SpliceDescriptor::Segmentation(segmentation) => segmentation.segmentation_duration,
+                SpliceDescriptor::Other { .. } => None,
+            })
+    }
+
+    /// Carry the section in an `emsg` box (SCTE 214-3) on a track with the
+    /// given timescale.
+    ///
+    /// `timeline_origin_pts` is the 90 kHz pts that maps to zero on the track
+    /// timeline. The splice time is unwrapped relative to it, so cues shortly
+    /// after a 33-bit pts wrap still land after the origin.
+    pub fn to_event_message(
+        &self,
+        id: u32,
+        timescale: u32,
+        timeline_origin_pts: u64,
+    ) -> Option<EventMessage> {
+        let presentation_time = self
+            .splice_pts()
+            .map(|pts| pts_to_track_time(pts, timeline_origin_pts, timescale))
+            .unwrap_or(0);
+        let event_duration = self
+            .duration()
+            .map(|duration| u32::try_from(ticks_to_hz(duration, timescale)).unwrap_or(u32::MAX))
+            .unwrap_or(u32::MAX);
+        Some(EventMessage {
+            scheme_id_uri: SCTE35_EMSG_SCHEME.to_owned(),
+            value: String::new(),
+            timescale,
+            presentation_time: EventPresentationTime::Absolute(presentation_time),
+            event_duration,
+            id,
+            message_data: self.to_bytes()?,
+        })
+    }
+
+    /// `#EXT-X-DATERANGE` attributes from RFC 8216 section 4.3.2.7.1:
+    /// `SCTE35-OUT` or `SCTE35-IN` for splice_insert, `SCTE35-CMD` otherwise,
+    /// and `PLANNED-DURATION` in seconds when a duration is signalled.
+    pub fn daterange_attributes(&self) -> Option<Vec<(String, String)>> {
+        let hex = format!("0x{}", hex_upper(&self.to_bytes()?));
+        let name = match &self.command {
+            SpliceCommand::Insert(insert) if insert.out_of_network_indicator => "SCTE35-OUT",
+            SpliceCommand::Insert(_) => "SCTE35-IN",
+            _ => "SCTE35-CMD",
+        };
+        let mut attributes = vec![(name.to_owned(), hex)];
+        if let Some(duration) = self.duration() {
+            attributes.push((
+                "PLANNED-DURATION".to_owned(),
+                format!("{:.3}", duration as f64 / 90_000.0),
+            ));
+        }
+        Some(attributes)
+    }
+}
+
+impl SpliceCommand {
+    fn command_type(&self) -> u8 {
+        match self {
+            SpliceCommand::Null => SPLICE_NULL,
+            SpliceCommand::Insert(_) => SPLICE_INSERT,
+            SpliceCommand::TimeSignal { .. } => TIME_SIGNAL,
+            SpliceCommand::BandwidthReservation => BANDWIDTH_RESERVATION,
+            SpliceCommand::Private { .. } => PRIVATE_COMMAND,
+            SpliceCommand::Other { command_type, .. } => *command_type,
+        }
+    }
+}
+
+/// Map a 90 kHz pts onto a track timeline that starts at
+/// `timeline_origin_pts`, unwrapping across one 33-bit rollover.
+pub fn pts_to_track_time(pts: u64, timeline_origin_pts: u64, timescale: u32) -> u64 {
+    let pts = pts % PTS_MODULUS;
+    let origin = timeline_origin_pts % PTS_MODULUS;
+    let elapsed = if pts >= origin {
+        pts - origin
+    } else {
+        pts + PTS_MODULUS - origin
+    };
+    ticks_to_hz(elapsed, timescale)
+}
+
+fn parse_command(command_type: u8, reader: &mut BitReader<'_>) -> Option<SpliceCommand> {
+    Some(match command_type {
+        SPLICE_NULL => SpliceCommand::Null,
+        SPLICE_INSERT => SpliceCommand::Insert(parse_splice_insert(reader)?),
+        TIME_SIGNAL => SpliceCommand::TimeSignal {
+            pts_time: parse_splice_time(reader)?,
+        },
+        BANDWIDTH_RESERVATION => SpliceCommand::BandwidthReservation,
+        PRIVATE_COMMAND => SpliceCommand::Private {
+            identifier: reader.read(32)? as u32,
+            data: reader.remaining().to_vec(),
+        },
+        command_type => SpliceCommand::Other {
+            command_type,
+            data: reader.remaining().to_vec(),
+        },
+    })
+}
+
+fn parse_splice_insert(reader: &mut BitReader<'_>) -> Option<SpliceInsert> {
+    let mut insert = SpliceInsert {
+        splice_event_id: reader.read(32)? as u32,
+        splice_event_cancel_indicator: reader.flag()?,
+        ..SpliceInsert::default()
+    };
+    reader.skip(7)?;
+    if insert.splice_event_cancel_indicator {
+        return Some(insert);
+    }
+    insert.out_of_network_indicator = reader.flag()?;
+    insert.program_splice_flag = reader.flag()?;
+    let duration_flag = reader.flag()?;
+    insert.splice_immediate_flag = reader.flag()?;
+    insert.event_id_compliance_flag = reader.flag()?;
+    reader.skip(3)?;
+    if insert.program_splice_flag && !insert.splice_immediate_flag {
+        insert.pts_time = parse_splice_time(reader)?;
+    }
+    if !insert.program_splice_flag {
+        let component_count = reader.read(8)?;
+        for _ in 0..component_count {
+            let tag = reader.read(8)? as u8;
+            let pts_time = if insert.splice_immediate_flag {
+                None
+            } else {
+                parse_splice_time(reader)?
+            };
+            insert.components.push((tag, pts_time));
+        }
+    }
+    if duration_flag {
+        let auto_return = reader.flag()?;
+        reader.skip(6)?;
+        insert.break_duration = Some(BreakDuration {
+            auto_return,
+            duration: reader.read(33)?,
+        });
+    }
+    insert.unique_program_id = reader.read(16)? as u16;
+    insert.avail_num = reader.read(8)? as u8;
+    insert.avails_expected = reader.read(8)? as u8;
+    Some(insert)
+}
+
+fn parse_splice_time(reader: &mut BitReader<'_>) -> Option<Option<u64>> {
+    if reader.flag()? {
+        reader.skip(6)?;
+        Some(Some(reader.read(33)?))
+    } else {
+        reader.skip(7)?;
+        Some(None)
+    }
+}
+
+fn parse_descriptors(mut data: &[u8]) -> Option<Vec<SpliceDescriptor>> {
+    let mut descriptors = Vec::new();
+    while !data.is_empty() {
+        let tag = *data.first()?;
+        let length = usize::from(*data.get(1)?);
+        let body = data.get(2..2 + length)?;
+        data = &data[2 + length..];
+        let identifier = u32::from_be_bytes(body.get(..4)?.try_into().ok()?);
+        let payload = &body[4..];
+        descriptors.push(
+            if tag == SEGMENTATION_DESCRIPTOR && identifier == CUEI_IDENTIFIER {
+                SpliceDescriptor::Segmentation(parse_segmentation_descriptor(payload)?)
+            } else {
+                SpliceDescriptor::Other {
+                    tag,
+                    identifier,
+                    data: payload.to_vec(),
+                }
+            },
+        );
+    }
+    Some(descriptors)
+}
+
+fn parse_segmentation_descriptor(data: &[u8]) -> Option<SegmentationDescriptor> {
+    let mut reader = BitReader::new(data);
+    let mut descriptor = SegmentationDescriptor {
+        segmentation_event_id: reader.read(32)? as u32,
+        segmentation_event_cancel_indicator: reader.flag()?,
+        segmentation_event_id_compliance_indicator: reader.flag()?,
+        ..SegmentationDescriptor::default()
+    };
+    reader.skip(6)?;
+    if descriptor.segmentation_event_cancel_indicator {
+        return Some(descriptor);
+    }
+    descriptor.program_segmentation_flag = reader.flag()?;
+    let duration_flag = reader.flag()?;
+    let delivery_not_restricted = reader.flag()?;
+    if delivery_not_restricted {
+        reader.skip(5)?;
+    } else {
+        descriptor.delivery_restrictions = Some(DeliveryRestrictions {
+            web_delivery_allowed: reader.flag()?,
+            no_regional_blackout: reader.flag()?,
+            archive_allowed: reader.flag()?,
+            device_restrictions: reader.read(2)? as u8,
+        });
+    }
+    if !descriptor.program_segmentation_flag {
+        let component_count = reader.read(8)?;
+        for _ in 0..component_count {
+            let tag = reader.read(8)? as u8;
+            reader.skip(7)?;
+            descriptor.components.push((tag, reader.read(33)?));
+        }
+    }
+    if duration_flag {
+        descriptor.segmentation_duration = Some(reader.read(40)?);
+    }
+    descriptor.segmentation_upid_type = reader.read(8)? as u8;
+    let upid_length = reader.read(8)? as usize;
+    descriptor.segmentation_upid = reader.bytes(upid_length)?.to_vec();
+    descriptor.segmentation_type_id = reader.read(8)? as u8;
+    descriptor.segment_num = reader.read(8)? as u8;
+    descriptor.segments_expected = reader.read(8)? as u8;
+    if has_sub_segments(descriptor.segmentation_type_id) && reader.remaining().len() >= 2 {
+        descriptor.sub_segment = Some((reader.read(8)? as u8, reader.read(8)? as u8));
+    }
+    Some(descriptor)
+}
+
+fn has_sub_segments(segmentation_type_id: u8) -> bool {
+    matches!(
+        segmentation_type_id,
+        0x34 | 0x36 | 0x38 | 0x3a | 0x44 | 0x46
+    )
+}
+
+fn write_command(writer: &mut BitWriter, command: &SpliceCommand) -> Option<()> {
+    match command {
+        SpliceCommand::Null | SpliceCommand::BandwidthReservation => {}
+        SpliceCommand::Insert(insert) => write_splice_insert(writer, insert)?,
+        SpliceCommand::TimeSignal { pts_time } => write_splice_time(writer, *pts_time),
+        SpliceCommand::Private { identifier, data } => {
+            writer.write(32, u64::from(*identifier));
+            writer.bytes(data);
+        }
+        SpliceCommand::Other { data, .. } => writer.bytes(data),
+    }
+    Some(())
+}
+
+fn write_splice_insert(writer: &mut BitWriter, insert: &SpliceInsert) -> Option<()> {
+    writer.write(32, u64::from(insert.splice_event_id));
+    writer.flag(insert.splice_event_cancel_indicator);
+    writer.write(7, 0x7f);
+    if insert.splice_event_cancel_indicator {
+        return Some(());
+    }
+    writer.flag(insert.out_of_network_indicator);
+    writer.flag(insert.program_splice_flag);
+    writer.flag(insert.break_duration.is_some());
+    writer.flag(insert.splice_immediate_flag);
+    writer.flag(insert.event_id_compliance_flag);
+    writer.write(3, 0x07);
+    if insert.program_splice_flag && !insert.splice_immediate_flag {
+        write_splice_time(writer, insert.pts_time);
+    }
+    if !insert.program_splice_flag {
+        writer.write(
+            8,
+            u64::try_from(insert.components.len())
+                .ok()
+                .filter(|&count| count <= 0xff)?,
+        );
+        for (tag, pts_time) in &insert.components {
+            writer.write(8, u64::from(*tag));
+            if !insert.splice_immediate_flag {
+                write_splice_time(writer, *pts_time);
+            }
+        }
+    }
+    if let Some(duration) = insert.break_duration {
+        writer.flag(duration.auto_return);
+        writer.write(6, 0x3f);
+        writer.write(33, duration.duration);
+    }
+    writer.write(16, u64::from(insert.unique_program_id));
+    writer.write(8, u64::from(insert.avail_num));
+    writer.write(8, u64::from(insert.avails_expected));
+    Some(())
+}
+
+fn write_splice_time(writer: &mut BitWriter, pts_time: Option<u64>) {
+    match pts_time {
+        Some(pts_time) => {
+            writer.flag(true);
+            writer.write(6, 0x3f);
+            writer.write(33, pts_time % PTS_MODULUS);
+        }
+        None => {
+            writer.flag(false);
+            writer.write(7, 0x7f);
+        }
+    }
+}
+
+fn write_descriptor(out: &mut Vec<u8>, descriptor: &SpliceDescriptor) -> Option<()> {
+    let (tag, identifier, payload) = match descriptor {
+        SpliceDescriptor::Segmentation(segmentation) => (
+            SEGMENTATION_DESCRIPTOR,
+            CUEI_IDENTIFIER,
+            write_segmentation_descriptor(segmentation)?,
+        ),
+        SpliceDescriptor::Other {
+            tag,
+            identifier,
+            data,
+        } => (*tag, *identifier, data.clone()),
+    };
+    out.push(tag);
+    out.push(u8::try_from(payload.len() + 4).ok()?);
+    out.extend_from_slice(&identifier.to_be_bytes());
+    out.extend_from_slice(&payload);
+    Some(())
+}
+
+fn write_segmentation_descriptor(descriptor: &SegmentationDescriptor) -> Option<Vec<u8>> {
+    let mut writer = BitWriter::default();
+    writer.write(32, u64::from(descriptor.segmentation_event_id));
+    writer.flag(descriptor.segmentation_event_cancel_indicator);
+    writer.flag(descriptor.segmentation_event_id_compliance_indicator);
+    writer.write(6, 0x3f);
+    if descriptor.segmentation_event_cancel_indicator {
+        return writer.finish();
+    }
+    writer.flag(descriptor.program_segmentation_flag);
+    writer.flag(descriptor.segmentation_duration.is_some());
+    writer.flag(descriptor.delivery_restrictions.is_none());
+    match descriptor.delivery_restrictions {
+        Some(restrictions) => {
+            writer.flag(restrictions.web_delivery_allowed);
+            writer.flag(restrictions.no_regional_blackout);
+            writer.flag(restrictions.archive_allowed);
+            writer.write(2, u64::from(restrictions.device_restrictions & 0x03));
+        }
+        None => writer.write(5, 0x1f),
+    }
+    if !descriptor.program_segmentation_flag {
+        writer.write(
+            8,
+            u64::try_from(descriptor.components.len())
+                .ok()
+                .filter(|&count| count <= 0xff)?,
+        );
+        for (tag, pts_offset) in &descriptor.components {
+            writer.write(8, u64::from(*tag));
+            writer.write(7, 0x7f);
+            writer.write(33, *pts_offset % PTS_MODULUS);
+        }
+    }
+    if let Some(duration) = descriptor.segmentation_duration {
+        writer.write(40, duration & 0xff_ffff_ffff);
+    }
+    writer.write(8, u64::from(descriptor.segmentation_upid_type));
+    writer.write(
+        8,
+        u64::try_from(descriptor.segmentation_upid.len())
+            .ok()
+            .filter(|&len| len <= 0xff)?,
+    );
+    writer.bytes(&descriptor.segmentation_upid);
+    writer.write(8, u64::from(descriptor.segmentation_type_id));
+    writer.write(8, u64::from(descriptor.segment_num));
+    writer.write(8, u64::from(descriptor.segments_expected));
+    if let Some((sub_segment_num, sub_segments_expected)) = descriptor
+        .sub_segment
+        .filter(|_| has_sub_segments(descriptor.segmentation_type_id))
+    {
+        writer.write(8, u64::from(sub_segment_num));
+        writer.write(8, u64::from(sub_segments_expected));
+    }
+    writer.finish()
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, bit: 0 }
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u64> {
+        let mut value = 0_u64;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.bit / 8)?;
+            let bit = (byte >> (7 - self.bit % 8)) & 1;
+            value = (value << 1) | u64::from(bit);
+            self.bit += 1;
+        }
+        Some(value)
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        Some(self.read(1)? == 1)
+    }
+
+    fn skip(&mut self, bits: usize) -> Option<()> {
+        self.read(bits).map(|_| ())
+    }
+
+    /// Read whole bytes; the reader must be byte aligned.
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if !self.bit.is_multiple_of(8) {
+            return None;
+        }
+        let start = self.bit / 8;
+        let bytes = self.data.get(start..start.checked_add(len)?)?;
+        self.bit += len * 8;
+        Some(bytes)
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        self.data.get(self.bit.div_ceil(8)..).unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, bits: usize, value: u64) {
+        for index in (0..bits).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.out.push(0);
+            }
+            if (value >> index) & 1 == 1 {
+                if let Some(byte) = self.out.last_mut() {
+                    *byte |= 0x80 >> (self.bits % 8);
+                }
+            }
+            self.bits += 1;
+        }
+    }
+
+    fn flag(&mut self, value: bool) {
+        self.write(1, u64::from(value));
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write(8, u64::from(byte));
+        }
+    }
+
+    /// The written bytes; fails unless a whole number of bytes was written.
+    fn finish(self) -> Option<Vec<u8>> {
+        self.bits.is_multiple_of(8).then_some(self.out)
+    }
+}
+
+/// CRC-32/MPEG-2 as used by MPEG-TS PSI and SCTE-35 sections. Running it over
+/// a section including its CRC yields zero.
+pub(crate) fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for &byte in data {
+        crc ^= u32::from(byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn hex_upper(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02X}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SCTE 35 2019 sample 14.2: splice_insert, out of network, 60 s break.
+    const SPLICE_INSERT_BASE64: &str =
+        "/DAvAAAAAAAA///wFAVIAACPf+/+c2nALv4AUsz1AAAAAAAKAAhDVUVJAAABNWLbowo=";
+
+    // SCTE 35 2019 sample 14.1: time_signal with a placement opportunity
+    // start segmentation descriptor.
+    const TIME_SIGNAL_BASE64: &str =
+        "/DA0AAAAAAAA///wBQb+cr0AUAAeAhxDVUVJSAAAjn/PAAGlmbAICAAAAAAsoKGKNAIAmsnRfg==";
+
+    #[test]
+    fn parses_splice_insert_sample() {
+        let section = SpliceInfoSection::from_base64(SPLICE_INSERT_BASE64).expect("section");
+        let SpliceCommand::Insert(insert) = &section.command else {
+            panic!("expected splice_insert");
+        };
+
+        assert_eq!(insert.splice_event_id, 0x4800_008f);
+        assert!(insert.out_of_network_indicator);
+        assert!(insert.program_splice_flag);
+        assert_eq!(insert.pts_time, Some(0x0_7369_c02e));
+        assert_eq!(
+            insert.break_duration,
+            Some(BreakDuration {
+                auto_return: true,
+                duration: 0x0_0052_ccf5,
+            })
+        );
+        assert_eq!(section.splice_pts(), Some(0x0_7369_c02e));
+        assert_eq!(section.to_base64().as_deref(), Some(SPLICE_INSERT_BASE64));
+    }
+
+    #[test]
+    fn parses_time_signal_with_segmentation_descriptor() {
+        let section = SpliceInfoSection::from_base64(TIME_SIGNAL_BASE64).expect("section");
+        let [SpliceDescriptor::Segmentation(segmentation)] = section.descriptors.as_slice() else {
+            panic!("expected one segmentation descriptor");
+        };
+
+        assert_eq!(
+            section.command,
+            SpliceCommand::TimeSignal {
+                pts_time: Some(0x0_72bd_0050)
+            }
+        );
+        assert_eq!(segmentation.segmentation_event_id, 0x4800_008e);
+        assert_eq!(segmentation.segmentation_duration, Some(0x01a5_99b0));
+        assert_eq!(segmentation.segmentation_upid_type, 0x08);
+        assert_eq!(segmentation.segmentation_type_id, 0x34);
+        assert_eq!(segmentation.sub_segment, None);
+        assert_eq!(section.to_base64().as_deref(), Some(TIME_SIGNAL_BASE64));
+    }
+
+    #[test]
+    fn rejects_corrupt_crc() {
+        let mut bytes = base64_decode(SPLICE_INSERT_BASE64).expect("bytes");
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        assert!(SpliceInfoSection::parse(&bytes).is_none());
+    }
+
+    #[test]
+    fn converts_splice_to_emsg_and_daterange() {
+        let section = SpliceInfoSection::from_base64(SPLICE_INSERT_BASE64).expect("section");
+        let origin = 0x0_7369_c02e - 90_000;
+        let event = section.to_event_message(1, 1_000, origin).expect("event");
+        let attributes = section.daterange_attributes().expect("attributes");
+
+        assert_eq!(event.scheme_id_uri, SCTE35_EMSG_SCHEME);
+        assert_eq!(
+            event.presentation_time,
+            EventPresentationTime::Absolute(1_000)
+        );
+        assert_eq!(event.event_duration, 60_294);
+        assert_eq!(attributes[0].0, "SCTE35-OUT");
+        assert!(attributes[0].1.starts_with("0xFC302F"));
+        assert_eq!(
+            attributes[1],
+            ("PLANNED-DURATION".to_owned(), "60.294".to_owned())
+        );
+    }
+
+    #[test]
+    fn track_time_unwraps_across_pts_rollover() {
+        assert_eq!(
+            pts_to_track_time(90_000, PTS_MODULUS - 90_000, 1_000),
+            2_000
+        );
+    }
+
+    #[test]
+    fn builds_time_signal_from_scratch() {
+        let section = SpliceInfoSection::new(SpliceCommand::TimeSignal {
+            pts_time: Some(900_000),
+        });
+        let bytes = section.to_bytes().expect("bytes");
+
+        assert_eq!(SpliceInfoSection::parse(&bytes), Some(section));
+        assert_eq!(crc32_mpeg2(&bytes), 0);
+    }
+
+    #[test]
+    fn only_placement_opportunity_and_ad_block_starts_carry_sub_segments() {
+        let descriptor = |segmentation_type_id, sub_segment| {
+            SpliceDescriptor::Segmentation(SegmentationDescriptor {
+                segmentation_event_id: 7,
+                program_segmentation_flag: true,
+                segmentation_type_id,
+                segment_num: 1,
+                segments_expected: 1,
+                sub_segment,
+                ..SegmentationDescriptor::default()
+            })
+        };
+        let section = |descriptors| SpliceInfoSection {
+            descriptors,
+            ..SpliceInfoSection::new(SpliceCommand::TimeSignal {
+                pts_time: Some(900_000),
+            })
+        };
+        // Provider advertisement start (0x30) has no sub-segment fields.
+        let program_start = section(vec![descriptor(0x30, None)]);
+        let bytes = program_start.to_bytes().expect("bytes");
+        let with_sub_segment = section(vec![descriptor(0x30, Some((1, 2)))]);
+        let opportunity_start = section(vec![descriptor(0x34, Some((1, 2)))]);
+
+        assert_eq!(SpliceInfoSection::parse(&bytes), Some(program_start));
+        assert_eq!(with_sub_segment.to_bytes().as_ref(), Some(&bytes));
+        assert_eq!(
+            SpliceInfoSection::parse(&opportunity_start.to_bytes().expect("bytes")),
+            Some(opportunity_start)
+        );
+    }
+}
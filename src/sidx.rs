@@ -0,0 +1,402 @@
+use crate::mp4::{read_boxes, read_init_tracks, read_track_fragments, write_full_box, InitTrack};
+
+/// How `write_indexed_file` arranges segment indexes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SidxLayout {
+    /// One `sidx` referencing every fragment.
+    Single,
+    /// A top-level `sidx` referencing one child `sidx` per group of
+    /// `subsegments_per_index` fragments; each child precedes its fragments.
+    Hierarchical { subsegments_per_index: usize },
+    /// One `sidx` per group of `subsegments_per_index` fragments, each ending
+    /// with a reference to the next `sidx` and everything after it.
+    DaisyChain { subsegments_per_index: usize },
+}
+
+/// One entry of a `sidx` box.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SidxReference {
+    /// The entry points at another `sidx` rather than at media.
+    pub references_index: bool,
+    pub referenced_size: u32,
+    /// In the index timescale.
+    pub subsegment_duration: u32,
+    pub starts_with_sap: bool,
+    pub sap_type: u8,
+    pub sap_delta_time: u32,
+}
+
+/// A `sidx` box.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SegmentIndex {
+    pub reference_id: u32,
+    pub timescale: u32,
+    pub earliest_presentation_time: u64,
+    /// Bytes between the end of the `sidx` and the first referenced byte.
+    pub first_offset: u64,
+    pub references: Vec<SidxReference>,
+}
+
+impl SegmentIndex {
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let version = u8::from(
+            self.earliest_presentation_time > u64::from(u32::MAX)
+                || self.first_offset > u64::from(u32::MAX),
+        );
+        let mut out = Vec::with_capacity(32 + self.references.len() * 12);
+        write_full_box(&mut out, *b"sidx", version, 0, |out| {
+            out.extend_from_slice(&self.reference_id.to_be_bytes());
+            out.extend_from_slice(&self.timescale.to_be_bytes());
+            if version == 1 {
+                out.extend_from_slice(&self.earliest_presentation_time.to_be_bytes());
+                out.extend_from_slice(&self.first_offset.to_be_bytes());
+            } else {
+                out.extend_from_slice(&(self.earliest_presentation_time as u32).to_be_bytes());
+                out.extend_from_slice(&(self.first_offset as u32).to_be_bytes());
+            }
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(&u16::try_from(self.references.len()).ok()?.to_be_bytes());
+            for reference in &self.references {
+                if reference.referenced_size >= 1 << 31 || reference.sap_type > 7 {
+                    return None;
+                }
+                let size =
+                    (u32::from(reference.references_index) << 31) | reference.referenced_size;
+                let sap = (u32::from(reference.starts_with_sap) << 31)
+                    | (u32::from(reference.sap_type) << 28)
+                    | (reference.sap_delta_time & 0x0fff_ffff);
+                out.extend_from_slice(&size.to_be_bytes());
+                out.extend_from_slice(&reference.subsegment_duration.to_be_bytes());
+                out.extend_from_slice(&sap.to_be_bytes());
+            }
+            Some(())
+        })?;
+        Some(out)
+    }
+}
+
+/// Timing and SAP information of one fragment on the reference track.
+#[derive(Clone, Copy, Debug)]
+struct Subsegment {
+    earliest_presentation_time: u64,
+    duration: u64,
+    size: u64,
+    starts_with_sap: bool,
+    sap_type: u8,
+    sap_delta_time: u64,
+}
+
+/// Index `fragments`, each a complete media segment such as `Fmp4::data`,
+/// with one reference per fragment. The reference track is the video track
+/// when the init segment has one, and the first track otherwise.
+pub fn segment_index(init: &[u8], fragments: &[impl AsRef<[u8]>]) -> Option<SegmentIndex> {
+    let (track, subsegments) = read_subsegments(init, fragments)?;
+    index_subsegments(&track, &subsegments, false)
+}
+
+/// Concatenate `init` and `fragments` into one on-demand file with segment
+/// indexes between the init segment and the first fragment.
+pub fn write_indexed_file(
+    init: &[u8],
+    fragments: &[impl AsRef<[u8]>],
+    layout: SidxLayout,
+) -> Option<Vec<u8>> {
+    let (track, subsegments) = read_subsegments(init, fragments)?;
+    let mut out = init.to_vec();
+    match layout {
+        SidxLayout::Single => {
+            out.extend(index_subsegments(&track, &subsegments, false)?.to_bytes()?);
+            for fragment in fragments {
+                out.extend_from_slice(fragment.as_ref());
+            }
+        }
+        SidxLayout::Hierarchical {
+            subsegments_per_index,
+        } => {
+            let mut children = Vec::new();
+            let mut groups = Vec::new();
+            for (group, fragments) in subsegments
+                .chunks(subsegments_per_index.max(1))
+                .zip(fragments.chunks(subsegments_per_index.max(1)))
+            {
+                let mut child = index_subsegments(&track, group, false)?.to_bytes()?;
+                for fragment in fragments {
+                    child.extend_from_slice(fragment.as_ref());
+                }
+                groups.push(index_group(group, child.len())?);
+                children.push(child);
+            }
+            out.extend(index_subsegments(&track, &groups, true)?.to_bytes()?);
+            for child in children {
+                out.extend(child);
+            }
+        }
+        SidxLayout::DaisyChain {
+            subsegments_per_index,
+        } => {
+            let groups: Vec<(&[Subsegment], &[_])> = subsegments
+                .chunks(subsegments_per_index.max(1))
+                .zip(fragments.chunks(subsegments_per_index.max(1)))
+                .collect();
+            // Build from the last index backwards; each index's final entry
+            // covers every byte that follows it.
+            let mut tail: Vec<u8> = Vec::new();
+            let mut tail_subsegment: Option<Subsegment> = None;
+            for (group, fragments) in groups.into_iter().rev() {
+                let mut index = index_subsegments(&track, group, false)?;
+                if let Some(next) = tail_subsegment {
+                    index.references.push(reference(&next, true)?);
+                }
+                let mut section = index.to_bytes()?;
+                for fragment in fragments {
+                    section.extend_from_slice(fragment.as_ref());
+                }
+                section.extend(tail);
+                let mut covered = group.to_vec();
+                covered.extend(tail_subsegment);
+                tail_subsegment = Some(index_group(&covered, section.len())?);
+                tail = section;
+            }
+            out.extend(tail);
+        }
+    }
+    Some(out)
+}
+
+fn read_subsegments(
+    init: &[u8],
+    fragments: &[impl AsRef<[u8]>],
+) -> Option<(InitTrack, Vec<Subsegment>)> {
+    let tracks = read_init_tracks(init)?;
+    let track = *tracks
+        .iter()
+        .find(|track| track.handler_type == *b"vide")
+        .or_else(|| tracks.first())?;
+    let subsegments = fragments
+        .iter()
+        .map(|fragment| read_subsegment(&track, &tracks, fragment.as_ref()))
+        .collect::<Option<Vec<_>>>()?;
+    (!subsegments.is_empty()).then_some((track, subsegments))
+}
+
+fn read_subsegment(track: &InitTrack, tracks: &[InitTrack], fragment: &[u8]) -> Option<Subsegment> {
+    let mut decode_time = None;
+    let mut duration = 0_u64;
+    let mut earliest_presentation_time: Option<u64> = None;
+    let mut first_sap: Option<(bool, u64)> = None;
+    let mut first_sample = true;
+    for moof in read_boxes(fragment)?
+        .into_iter()
+        .filter(|top| top.name == *b"moof")
+    {
+        for traf in read_track_fragments(&moof, tracks)?
+            .into_iter()
+            .filter(|traf| traf.track_id == track.track_id)
+        {
+            let mut dts = traf
+                .base_media_decode_time
+                .or(decode_time)
+                .unwrap_or_default();
+            for sample in traf.runs.iter().flat_map(|run| &run.samples) {
+                let pts = dts.checked_add_signed(i64::from(sample.composition_time_offset))?;
+                earliest_presentation_time =
+                    Some(earliest_presentation_time.map_or(pts, |earliest| earliest.min(pts)));
+                if first_sap.is_none() && sample.is_sync() {
+                    first_sap = Some((first_sample, pts));
+                }
+                first_sample = false;
+                dts = dts.checked_add(u64::from(sample.duration))?;
+                duration = duration.checked_add(u64::from(sample.duration))?;
+            }
+            decode_time = Some(dts);
+        }
+    }
+    let earliest_presentation_time = earliest_presentation_time?;
+    let (starts_with_sap, sap_type, sap_delta_time) = match first_sap {
+        // Leading samples presented before the SAP make it type 2.
+        Some((starts, pts)) => {
+            let delta = pts - earliest_presentation_time;
+            (starts, if delta == 0 { 1 } else { 2 }, delta)
+        }
+        None => (false, 0, 0),
+    };
+    Some(Subsegment {
+        earliest_presentation_time,
+        duration,
+        size: u64::try_from(fragment.len()).ok()?,
+        starts_with_sap,
+        sap_type,
+        sap_delta_time,
+    })
+}
+
+/// Summarize consecutive subsegments stored in `size` bytes as one.
+fn index_group(group: &[Subsegment], size: usize) -> Option<Subsegment> {
+    let first = group.first()?;
+    Some(Subsegment {
+        duration: group.iter().map(|subsegment| subsegment.duration).sum(),
+        size: u64::try_from(size).ok()?,
+        ..*first
+    })
+}
+
+fn index_subsegments(
+    track: &InitTrack,
+    subsegments: &[Subsegment],
+    references_index: bool,
+) -> Option<SegmentIndex> {
+    Some(SegmentIndex {
+        reference_id: track.track_id,
+        timescale: track.timescale,
+        earliest_presentation_time: subsegments.first()?.earliest_presentation_time,
+        first_offset: 0,
+        references: subsegments
+            .iter()
+            .map(|subsegment| reference(subsegment, references_index))
+            .collect::<Option<_>>()?,
+    })
+}
+
+fn reference(subsegment: &Subsegment, references_index: bool) -> Option<SidxReference> {
+    Some(SidxReference {
+        references_index,
+        referenced_size: u32::try_from(subsegment.size).ok()?,
+        subsegment_duration: u32::try_from(subsegment.duration).ok()?,
+        starts_with_sap: subsegment.starts_with_sap,
+        sap_type: subsegment.sap_type,
+        sap_delta_time: u32::try_from(subsegment.sap_delta_time).ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::tests::config;
+    use crate::fmp4::{box_fmp4_with_tracks, FragmentMedia};
+    use access_unit::{AccessUnit, PSI_STREAM_H264};
+    use bytes::Bytes;
+
+    fn fragment(seq: u32, dts: u64, key: bool) -> access_unit::Fmp4 {
+        let unit = |dts, key| AccessUnit {
+            key,
+            pts: dts,
+            dts,
+            data: Bytes::from_static(&[0, 0, 0, 1, 0x65]),
+            stream_type: PSI_STREAM_H264,
+            id: 0,
+        };
+        box_fmp4_with_tracks(
+            seq,
+            config(),
+            FragmentMedia {
+                video: vec![unit(dts, key), unit(dts + 3_000, false)],
+                ..FragmentMedia::default()
+            },
+            dts + 6_000,
+            true,
+        )
+    }
+
+    fn fragments() -> (Bytes, Vec<Bytes>) {
+        let first = fragment(1, 0, true);
+        let init = first.init.clone().expect("init segment");
+        (
+            init,
+            vec![
+                first.data,
+                fragment(2, 6_000, true).data,
+                fragment(3, 12_000, false).data,
+            ],
+        )
+    }
+
+    fn top_level_sizes(data: &[u8]) -> Vec<([u8; 4], usize)> {
+        read_boxes(data)
+            .expect("boxes")
+            .iter()
+            .map(|top| (top.name, top.payload.len() + 8))
+            .collect()
+    }
+
+    #[test]
+    fn flat_index_references_each_fragment() {
+        let (init, fragments) = fragments();
+        let index = segment_index(&init, &fragments).expect("index");
+
+        assert_eq!(index.reference_id, 1);
+        assert_eq!(index.timescale, 90_000);
+        assert_eq!(index.earliest_presentation_time, 0);
+        assert_eq!(index.references.len(), 3);
+        for (reference, fragment) in index.references.iter().zip(&fragments) {
+            assert!(!reference.references_index);
+            assert_eq!(reference.referenced_size as usize, fragment.len());
+            assert_eq!(reference.subsegment_duration, 6_000);
+        }
+        assert!(index.references[1].starts_with_sap);
+        assert_eq!(index.references[1].sap_type, 1);
+        assert!(!index.references[2].starts_with_sap);
+        assert_eq!(index.references[2].sap_type, 0);
+
+        let file = write_indexed_file(&init, &fragments, SidxLayout::Single).expect("file");
+        let sidx = index.to_bytes().expect("sidx");
+        assert_eq!(&file[init.len()..init.len() + sidx.len()], sidx.as_slice());
+        assert_eq!(sidx.len(), 32 + 3 * 12);
+    }
+
+    #[test]
+    fn hierarchical_index_references_child_indexes() {
+        let (init, fragments) = fragments();
+        let file = write_indexed_file(
+            &init,
+            &fragments,
+            SidxLayout::Hierarchical {
+                subsegments_per_index: 2,
+            },
+        )
+        .expect("file");
+        let boxes = top_level_sizes(&file[init.len()..]);
+        let names: Vec<&[u8; 4]> = boxes.iter().map(|(name, _)| name).collect();
+        let top = &read_boxes(&file[init.len()..]).expect("boxes")[0];
+        let (_, _, top) = top.full_box().expect("top sidx");
+
+        assert_eq!(
+            names,
+            vec![b"sidx", b"sidx", b"moof", b"mdat", b"moof", b"mdat", b"sidx", b"moof", b"mdat"]
+        );
+        let first_child = boxes[1].1 + fragments[0].len() + fragments[1].len();
+        let second_child = boxes[6].1 + fragments[2].len();
+        assert_eq!(u16::from_be_bytes([top[18], top[19]]), 2);
+        assert_eq!(read_u32(&top[20..]), 0x8000_0000 | first_child as u32);
+        assert_eq!(read_u32(&top[24..]), 12_000);
+        assert_eq!(read_u32(&top[32..]), 0x8000_0000 | second_child as u32);
+        assert_eq!(read_u32(&top[36..]), 6_000);
+    }
+
+    #[test]
+    fn daisy_chained_index_links_to_the_next_index() {
+        let (init, fragments) = fragments();
+        let file = write_indexed_file(
+            &init,
+            &fragments,
+            SidxLayout::DaisyChain {
+                subsegments_per_index: 2,
+            },
+        )
+        .expect("file");
+        let body = &file[init.len()..];
+        let first = &read_boxes(body).expect("boxes")[0];
+        let (_, _, first) = first.full_box().expect("first sidx");
+        let first_len = first.len() + 12;
+        let rest = body.len() - first_len - fragments[0].len() - fragments[1].len();
+
+        assert_eq!(u16::from_be_bytes([first[18], first[19]]), 3);
+        assert_eq!(read_u32(&first[20..]), fragments[0].len() as u32);
+        assert_eq!(read_u32(&first[44..]), 0x8000_0000 | rest as u32);
+        assert_eq!(read_u32(&first[48..]), 6_000);
+        assert_eq!(&body[body.len() - rest + 4..body.len() - rest + 8], b"sidx");
+    }
+
+    fn read_u32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes(bytes[..4].try_into().expect("u32"))
+    }
+}
@@ -0,0 +1,362 @@
+use crate::fragmenter::Fragmenter;
+use access_unit::{AccessUnit, Fmp4};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+/// Split `fragmenter` into a sink of access units and a stream of the
+/// fragments they close. At most `capacity` closed fragments wait for the
+/// stream; the sink is not ready again until the stream takes one, so a slow
+/// consumer holds back the producer. The first fragment carries the init
+/// segment. The traits come from `futures-core` and `futures-sink`, so the
+/// pair runs on any executor, tokio included.
+pub fn fragment_channel(fragmenter: Fragmenter, capacity: usize) -> (FragmentSink, FragmentStream) {
+    let shared = Arc::new(Mutex::new(Shared {
+        fragmenter,
+        capacity: capacity.max(1),
+        fragments: VecDeque::new(),
+        closed: false,
+        stream_dropped: false,
+        sink_waker: None,
+        stream_waker: None,
+    }));
+    (
+        FragmentSink {
+            shared: shared.clone(),
+        },
+        FragmentStream { shared },
+    )
+}
+
+struct Shared {
+    fragmenter: Fragmenter,
+    capacity: usize,
+    fragments: VecDeque<Fmp4>,
+    /// The sink was closed or dropped; no more fragments will come once the
+    /// queue drains.
+    closed: bool,
+    /// The stream was dropped; nothing will read further fragments.
+    stream_dropped: bool,
+    sink_waker: Option<Waker>,
+    stream_waker: Option<Waker>,
+}
+
+impl Shared {
+    fn queue(&mut self, fragment: Option<Fmp4>) -> Result<(), FragmentSinkError> {
+        let Some(fragment) = fragment else {
+            return Ok(());
+        };
+        if fragment.data.is_empty() {
+            return Err(FragmentSinkError::Boxing);
+        }
+        if !self.stream_dropped {
+            self.fragments.push_back(fragment);
+            if let Some(waker) = self.stream_waker.take() {
+                waker.wake();
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), FragmentSinkError> {
+        if self.closed {
+            return Ok(());
+        }
+        let fragment = self.fragmenter.finish();
+        let queued = self.queue(fragment);
+        self.closed = true;
+        if let Some(waker) = self.stream_waker.take() {
+            waker.wake();
+        }
+        queued
+    }
+
+    /// Why the sink cannot take another access unit, if it cannot.
+    fn send_error(&self) -> Option<FragmentSinkError> {
+        if self.closed {
+            Some(FragmentSinkError::Closed)
+        } else if self.stream_dropped {
+            Some(FragmentSinkError::StreamDropped)
+        } else {
+            None
+        }
+    }
+}
+
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Why `FragmentSink` refused an access unit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FragmentSinkError {
+    /// The sink was already closed.
+    Closed,
+    /// The `FragmentStream` was dropped, so no fragment would be read.
+    StreamDropped,
+    /// The fragment this unit or the close ended could not be boxed, so its
+    /// media was dropped. The sink stays usable.
+    Boxing,
+}
+
+impl fmt::Display for FragmentSinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentSinkError::Closed => f.write_str("fragment sink is closed"),
+            FragmentSinkError::StreamDropped => f.write_str("fragment stream was dropped"),
+            FragmentSinkError::Boxing => f.write_str("fragment could not be boxed"),
+        }
+    }
+}
+
+impl std::error::Error for FragmentSinkError {}
+
+/// Feeds access units to the fragmenter. Closing it, or dropping it, closes
+/// the last fragment and ends the stream. Once the stream is dropped the
+/// sink fails with `FragmentSinkError::StreamDropped`.
+pub struct FragmentSink {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Sink<AccessUnit> for FragmentSink {
+    type Error = FragmentSinkError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), FragmentSinkError>> {
+        let mut shared = lock(&self.shared);
+        if let Some(error) = shared.send_error() {
+            Poll::Ready(Err(error))
+        } else if shared.fragments.len() < shared.capacity {
+            Poll::Ready(Ok(()))
+        } else {
+            shared.sink_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, unit: AccessUnit) -> Result<(), FragmentSinkError> {
+        let mut shared = lock(&self.shared);
+        if let Some(error) = shared.send_error() {
+            return Err(error);
+        }
+        let fragment = shared.fragmenter.push(unit);
+        shared.queue(fragment)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), FragmentSinkError>> {
+        // Fragments are queued as soon as they close; an open fragment waits
+        // for its closing key frame.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), FragmentSinkError>> {
+        Poll::Ready(lock(&self.shared).close())
+    }
+}
+
+impl Drop for FragmentSink {
+    fn drop(&mut self) {
+        // Nothing is left to report a failed last fragment to.
+        let _ = lock(&self.shared).close();
+    }
+}
+
+/// Yields init and media segments as the fragmenter closes them.
+pub struct FragmentStream {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Stream for FragmentStream {
+    type Item = Fmp4;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Fmp4>> {
+        let mut shared = lock(&self.shared);
+        if let Some(fragment) = shared.fragments.pop_front() {
+            if let Some(waker) = shared.sink_waker.take() {
+                waker.wake();
+            }
+            Poll::Ready(Some(fragment))
+        } else if shared.closed {
+            Poll::Ready(None)
+        } else {
+            shared.stream_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for FragmentStream {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.stream_dropped = true;
+        shared.fragments.clear();
+        if let Some(waker) = shared.sink_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::tests::config;
+    use crate::fmp4::Config;
+    use access_unit::PSI_STREAM_H264;
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+
+    fn fragmenter() -> Fragmenter {
+        Fragmenter::new(config(), None, 1_000)
+    }
+
+    fn key_frame(second: u64) -> AccessUnit {
+        AccessUnit {
+            key: true,
+            pts: second * 90_000,
+            dts: second * 90_000,
+            data: Bytes::from_static(&[0, 0, 0, 1, 0x65]),
+            stream_type: PSI_STREAM_H264,
+            id: 0,
+        }
+    }
+
+    #[test]
+    fn full_queue_holds_back_the_sink_until_the_stream_reads() {
+        let (mut sink, mut stream) = fragment_channel(fragmenter(), 1);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        for second in 0..2 {
+            assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_ready());
+            Pin::new(&mut sink).start_send(key_frame(second)).unwrap();
+        }
+        // The second key frame closed the first fragment and filled the queue.
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_pending());
+
+        let Poll::Ready(Some(first)) = Pin::new(&mut stream).poll_next(&mut cx) else {
+            panic!("first fragment");
+        };
+        assert!(first.init.is_some());
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn closing_the_sink_flushes_and_ends_the_stream() {
+        let (mut sink, mut stream) = fragment_channel(fragmenter(), 4);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        Pin::new(&mut sink).start_send(key_frame(0)).unwrap();
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_pending());
+        drop(sink);
+
+        let Poll::Ready(Some(last)) = Pin::new(&mut stream).poll_next(&mut cx) else {
+            panic!("last fragment");
+        };
+        assert!(last.init.is_some() && !last.data.is_empty());
+        assert!(matches!(
+            Pin::new(&mut stream).poll_next(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn dropping_the_stream_wakes_and_fails_a_waiting_sink() {
+        let (mut sink, mut stream) = fragment_channel(fragmenter(), 1);
+        let flag = Arc::new(Flag(AtomicBool::new(false)));
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        for second in 0..2 {
+            Pin::new(&mut sink).start_send(key_frame(second)).unwrap();
+        }
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_pending());
+        assert!(Pin::new(&mut stream).poll_next(&mut cx).is_ready());
+        assert!(flag.0.swap(false, Ordering::SeqCst));
+
+        Pin::new(&mut sink).start_send(key_frame(2)).unwrap();
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_pending());
+        drop(stream);
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(matches!(
+            Pin::new(&mut sink).poll_ready(&mut cx),
+            Poll::Ready(Err(FragmentSinkError::StreamDropped))
+        ));
+        assert_eq!(
+            Pin::new(&mut sink).start_send(key_frame(3)),
+            Err(FragmentSinkError::StreamDropped)
+        );
+    }
+
+    #[test]
+    fn boxing_failures_are_reported_by_the_sink() {
+        // Track id 0 is invalid, so no fragment can be boxed.
+        let fragmenter = Fragmenter::new(
+            Config {
+                video_track_id: 0,
+                ..config()
+            },
+            None,
+            1_000,
+        );
+        let (mut sink, mut stream) = fragment_channel(fragmenter, 4);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        Pin::new(&mut sink).start_send(key_frame(0)).unwrap();
+        assert_eq!(
+            Pin::new(&mut sink).start_send(key_frame(1)),
+            Err(FragmentSinkError::Boxing)
+        );
+        assert!(Pin::new(&mut sink).poll_ready(&mut cx).is_ready());
+        assert!(matches!(
+            Pin::new(&mut sink).poll_close(&mut cx),
+            Poll::Ready(Err(FragmentSinkError::Boxing))
+        ));
+        assert!(matches!(
+            Pin::new(&mut stream).poll_next(&mut cx),
+            Poll::Ready(None)
+        ));
+    }
+
+    #[test]
+    fn a_closed_sink_rejects_further_units() {
+        let (mut sink, _stream) = fragment_channel(fragmenter(), 4);
+        let mut cx = Context::from_waker(Waker::noop());
+
+        Pin::new(&mut sink).start_send(key_frame(0)).unwrap();
+        assert!(matches!(
+            Pin::new(&mut sink).poll_close(&mut cx),
+            Poll::Ready(Ok(()))
+        ));
+        assert!(matches!(
+            Pin::new(&mut sink).poll_ready(&mut cx),
+            Poll::Ready(Err(FragmentSinkError::Closed))
+        ));
+        assert_eq!(
+            Pin::new(&mut sink).start_send(key_frame(1)),
+            Err(FragmentSinkError::Closed)
+        );
+    }
+}
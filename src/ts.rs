@@ -0,0 +1,1316 @@
+use crate::fmp4::{opus_packet_info, ticks_to_ms, MetadataSample, OPUS_OUTPUT_SAMPLE_RATE};
+use crate::mp4::{AdtsHeader, AvcDecoderConfigurationRecord};
+use crate::scte35::crc32_mpeg2;
+use access_unit::aac::split_adts_frames;
+use access_unit::{
+    AccessUnit, PSI_STREAM_AAC, PSI_STREAM_AUDIO_OPUS, PSI_STREAM_H264, PSI_STREAM_PRIVATE_DATA,
+};
+use bytes::Bytes;
+
+pub const TS_PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+const TABLE_ID_PAT: u8 = 0x00;
+const TABLE_ID_PMT: u8 = 0x02;
+const STREAM_ID_VIDEO: u8 = 0xe0;
+const STREAM_ID_AUDIO: u8 = 0xc0;
+const STREAM_ID_PRIVATE_1: u8 = 0xbd;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_AUD: u8 = 9;
+const ACCESS_UNIT_DELIMITER: [u8; 6] = [0, 0, 0, 1, NAL_TYPE_AUD, 0xf0];
+
+/// PIDs and the program written by `TsMuxer`. A `None` PID leaves that
+/// stream out of the PMT.
+#[derive(Clone, Debug)]
+pub struct TsConfig {
+    pub program_number: u16,
+    pub pmt_pid: u16,
+    pub video_pid: Option<u16>,
+    pub audio_pid: Option<u16>,
+    /// PID of the timed ID3 stream written by `mux_segment_with_metadata`.
+    pub metadata_pid: Option<u16>,
+    /// Parameter sets inserted ahead of key frames that lack them, since a TS
+    /// segment has no init segment to carry them.
+    pub avcc: Option<AvcDecoderConfigurationRecord>,
+    /// How far the PCR runs behind decode time, in 90 kHz ticks: the time a
+    /// decoder gets to buffer each access unit before decoding it.
+    pub pcr_delay: u64,
+}
+
+impl Default for TsConfig {
+    fn default() -> Self {
+        Self {
+            program_number: 1,
+            pmt_pid: 0x1000,
+            video_pid: Some(0x100),
+            audio_pid: Some(0x101),
+            metadata_pid: None,
+            avcc: None,
+            pcr_delay: 63_000,
+        }
+    }
+}
+
+/// Writes H.264 and AAC (ADTS) access units, and optionally timed ID3 tags,
+/// as MPEG-2 transport stream segments. Continuity counters carry over between segments, so one muxer
+/// should produce every segment of a stream.
+#[derive(Clone, Debug)]
+pub struct TsMuxer {
+    config: TsConfig,
+    continuity: Vec<(u16, u8)>,
+}
+
+impl TsMuxer {
+    pub fn new(config: TsConfig) -> Self {
+        Self {
+            config,
+            continuity: Vec::new(),
+        }
+    }
+
+    /// Mux one segment from the same vectors `box_fmp4` accepts: video
+    /// timestamps in 90 kHz ticks and audio timestamps in milliseconds. The
+    /// segment starts with a PAT and PMT, and units are interleaved by decode
+    /// time. PCR rides on the video PID, or on the audio PID when the
+    /// segment has no video units.
+    /// The audio PID is advertised as AAC, so a segment whose audio is not
+    /// all ADTS leaves the audio out.
+    pub fn mux_segment(&mut self, avcs: &[AccessUnit], audio_units: &[AccessUnit]) -> Bytes {
+        self.mux_segment_with_metadata(avcs, audio_units, &[])
+    }
+
+    /// `mux_segment` plus ID3 tags on `TsConfig::metadata_pid`, carried as
+    /// HLS timed metadata: a stream of type 0x15 with ID3 metadata
+    /// descriptors, whose PES packets are stamped with each sample's start.
+    /// The PID is left out when a sample is not an ID3 tag.
+    pub fn mux_segment_with_metadata(
+        &mut self,
+        avcs: &[AccessUnit],
+        audio_units: &[AccessUnit],
+        metadata: &[MetadataSample],
+    ) -> Bytes {
+        let audio_pid = self.config.audio_pid.filter(|_| {
+            audio_units
+                .iter()
+                .all(|unit| AdtsHeader::read_from(&unit.data).is_some())
+        });
+        let metadata_pid = self.config.metadata_pid.filter(|_| {
+            metadata
+                .iter()
+                .all(|sample| sample.data.starts_with(b"ID3"))
+        });
+        let pcr_pid = self
+            .config
+            .video_pid
+            .filter(|_| !avcs.is_empty())
+            .or(audio_pid);
+        let mut out = Vec::new();
+        let pat = self.pat_section();
+        self.write_section(&mut out, PAT_PID, &pat);
+        let pmt = self.pmt_section(pcr_pid, audio_pid, metadata_pid);
+        self.write_section(&mut out, self.config.pmt_pid, &pmt);
+
+        let mut video = avcs.iter().peekable();
+        let mut audio = audio_units.iter().peekable();
+        let mut metadata = metadata.iter().peekable();
+        loop {
+            // Ties go to video, then audio, then metadata.
+            let next = [
+                video.peek().map(|unit| unit.dts),
+                audio.peek().map(|unit| unit.dts.saturating_mul(90)),
+                metadata
+                    .peek()
+                    .map(|sample| sample.start.saturating_mul(90)),
+            ]
+            .into_iter()
+            .enumerate()
+            .filter_map(|(stream, dts)| Some((dts?, stream)))
+            .min();
+            match next {
+                Some((_, 0)) => {
+                    if let Some(unit) = video.next() {
+                        self.write_video(&mut out, unit, pcr_pid);
+                    }
+                }
+                Some((_, 1)) => {
+                    if let Some(unit) = audio.next() {
+                        self.write_audio(&mut out, unit, audio_pid, pcr_pid);
+                    }
+                }
+                Some(_) => {
+                    if let Some(sample) = metadata.next() {
+                        self.write_metadata(&mut out, sample, metadata_pid);
+                    }
+                }
+                None => break,
+            }
+        }
+        Bytes::from(out)
+    }
+
+    /// The PCR written with a unit decoded at `dts`. Stream starts hold the
+    /// PCR at zero rather than wrapping it.
+    fn pcr(&self, dts: u64) -> u64 {
+        dts.saturating_sub(self.config.pcr_delay)
+    }
+
+    fn write_video(&mut self, out: &mut Vec<u8>, unit: &AccessUnit, pcr_pid: Option<u16>) {
+        let Some(pid) = self.config.video_pid else {
+            return;
+        };
+        let mut payload = Vec::with_capacity(unit.data.len() + 64);
+        if !starts_with_nal_type(&unit.data, NAL_TYPE_AUD) {
+            payload.extend_from_slice(&ACCESS_UNIT_DELIMITER);
+        }
+        if unit.key && !contains_nal_type(&unit.data, NAL_TYPE_SPS) {
+            if let Some(avcc) = &self.config.avcc {
+                payload.extend_from_slice(&[0, 0, 0, 1]);
+                payload.extend_from_slice(&avcc.sequence_parameter_set);
+                payload.extend_from_slice(&[0, 0, 0, 1]);
+                payload.extend_from_slice(&avcc.picture_parameter_set);
+            }
+        }
+        payload.extend_from_slice(&unit.data);
+        let pes = pes_packet(STREAM_ID_VIDEO, unit.pts, unit.dts, &payload, false);
+        let pcr = (pcr_pid == Some(pid)).then(|| self.pcr(unit.dts));
+        self.write_pes(out, pid, &pes, pcr, unit.key);
+    }
+
+    fn write_audio(
+        &mut self,
+        out: &mut Vec<u8>,
+        unit: &AccessUnit,
+        audio_pid: Option<u16>,
+        pcr_pid: Option<u16>,
+    ) {
+        let Some(pid) = audio_pid else {
+            return;
+        };
+        let pts = unit.pts.saturating_mul(90);
+        let pes = pes_packet(STREAM_ID_AUDIO, pts, pts, &unit.data, true);
+        let pcr = (pcr_pid == Some(pid)).then(|| self.pcr(pts));
+        // Every AAC frame can start decoding.
+        self.write_pes(out, pid, &pes, pcr, true);
+    }
+
+    fn write_metadata(
+        &mut self,
+        out: &mut Vec<u8>,
+        sample: &MetadataSample,
+        metadata_pid: Option<u16>,
+    ) {
+        let Some(pid) = metadata_pid else {
+            return;
+        };
+        let pts = sample.start.saturating_mul(90);
+        let pes = pes_packet(STREAM_ID_PRIVATE_1, pts, pts, &sample.data, true);
+        self.write_pes(out, pid, &pes, None, false);
+    }
+
+    fn pat_section(&self) -> Vec<u8> {
+        let mut program = Vec::with_capacity(4);
+        program.extend_from_slice(&self.config.program_number.to_be_bytes());
+        program.extend_from_slice(&(0xe000 | self.config.pmt_pid).to_be_bytes());
+        psi_section(TABLE_ID_PAT, 1, &program)
+    }
+
+    fn pmt_section(
+        &self,
+        pcr_pid: Option<u16>,
+        audio_pid: Option<u16>,
+        metadata_pid: Option<u16>,
+    ) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(0xe000 | pcr_pid.unwrap_or(0x1fff)).to_be_bytes());
+        let mut program_info = Vec::new();
+        if metadata_pid.is_some() {
+            program_info.extend_from_slice(&[DESCRIPTOR_METADATA_POINTER, 15]);
+            program_info.extend_from_slice(&ID3_METADATA_FORMAT);
+            // metadata_service_id 0, then no locator record and carriage in
+            // this transport stream.
+            program_info.extend_from_slice(&[0, 0x1f]);
+            program_info.extend_from_slice(&self.config.program_number.to_be_bytes());
+        }
+        body.extend_from_slice(&(0xf000 | program_info.len() as u16).to_be_bytes());
+        body.extend_from_slice(&program_info);
+        let mut metadata_descriptor = vec![DESCRIPTOR_METADATA, 13];
+        metadata_descriptor.extend_from_slice(&ID3_METADATA_FORMAT);
+        // metadata_service_id 0, then no decoder config or DSM-CC flags.
+        metadata_descriptor.extend_from_slice(&[0, 0x0f]);
+        let streams = [
+            (PSI_STREAM_H264, self.config.video_pid, &[][..]),
+            (PSI_STREAM_AAC, audio_pid, &[][..]),
+            (PSI_STREAM_METADATA, metadata_pid, &metadata_descriptor[..]),
+        ];
+        for (stream_type, pid, descriptors) in streams {
+            if let Some(pid) = pid {
+                body.push(stream_type);
+                body.extend_from_slice(&(0xe000 | pid).to_be_bytes());
+                body.extend_from_slice(&(0xf000 | descriptors.len() as u16).to_be_bytes());
+                body.extend_from_slice(descriptors);
+            }
+        }
+        psi_section(TABLE_ID_PMT, self.config.program_number, &body)
+    }
+
+    fn write_section(&mut self, out: &mut Vec<u8>, pid: u16, section: &[u8]) {
+        let mut payload = Vec::with_capacity(section.len() + 1);
+        // pointer_field: the section starts right away.
+        payload.push(0);
+        payload.extend_from_slice(section);
+        let mut remaining = payload.as_slice();
+        let mut first = true;
+        while !remaining.is_empty() {
+            let take = remaining.len().min(TS_PACKET_SIZE - 4);
+            let counter = self.next_continuity(pid);
+            write_header(out, pid, first, 0x10, counter);
+            out.extend_from_slice(&remaining[..take]);
+            out.resize(out.len() + TS_PACKET_SIZE - 4 - take, 0xff);
+            remaining = &remaining[take..];
+            first = false;
+        }
+    }
+
+    fn write_pes(
+        &mut self,
+        out: &mut Vec<u8>,
+        pid: u16,
+        pes: &[u8],
+        pcr: Option<u64>,
+        random_access: bool,
+    ) {
+        let mut remaining = pes;
+        let mut first = true;
+        while !remaining.is_empty() {
+            let pcr = if first { pcr } else { None };
+            let random_access = first && random_access;
+            let flags_len = if pcr.is_some() || random_access {
+                1 + if pcr.is_some() { 6 } else { 0 }
+            } else {
+                0
+            };
+            let payload_space = if flags_len == 0 && remaining.len() >= TS_PACKET_SIZE - 4 {
+                TS_PACKET_SIZE - 4
+            } else {
+                TS_PACKET_SIZE - 5 - flags_len
+            };
+            let take = remaining.len().min(payload_space);
+            let counter = self.next_continuity(pid);
+            if take == TS_PACKET_SIZE - 4 {
+                write_header(out, pid, first, 0x10, counter);
+            } else {
+                write_header(out, pid, first, 0x30, counter);
+                let adaptation_length = TS_PACKET_SIZE - 5 - take;
+                out.push(adaptation_length as u8);
+                if adaptation_length > 0 {
+                    let mut flags = 0;
+                    if random_access {
+                        flags |= 0x40;
+                    }
+                    if pcr.is_some() {
+                        flags |= 0x10;
+                    }
+                    out.push(flags);
+                    if let Some(pcr) = pcr {
+                        write_pcr(out, pcr);
+                    }
+                    out.resize(out.len() + adaptation_length - flags_len.max(1), 0xff);
+                }
+            }
+            out.extend_from_slice(&remaining[..take]);
+            remaining = &remaining[take..];
+            first = false;
+        }
+    }
+
+    fn next_continuity(&mut self, pid: u16) -> u8 {
+        match self.continuity.iter_mut().find(|(known, _)| *known == pid) {
+            Some((_, counter)) => {
+                *counter = (*counter + 1) & 0x0f;
+                *counter
+            }
+            None => {
+                self.continuity.push((pid, 0));
+                0
+            }
+        }
+    }
+}
+
+fn write_header(
+    out: &mut Vec<u8>,
+    pid: u16,
+    unit_start: bool,
+    adaptation_control: u8,
+    counter: u8,
+) {
+    out.push(SYNC_BYTE);
+    out.push(if unit_start { 0x40 } else { 0 } | (pid >> 8) as u8 & 0x1f);
+    out.push(pid as u8);
+    out.push(adaptation_control | counter);
+}
+
+/// A PSI section with the long syntax, version 0 and a trailing CRC.
+fn psi_section(table_id: u8, table_id_extension: u16, body: &[u8]) -> Vec<u8> {
+    let section_length = 5 + body.len() + 4;
+    let mut section = Vec::with_capacity(3 + section_length);
+    section.push(table_id);
+    section.extend_from_slice(&(0xb000 | section_length as u16).to_be_bytes());
+    section.extend_from_slice(&table_id_extension.to_be_bytes());
+    // version 0, current_next_indicator set, section 0 of 0.
+    section.extend_from_slice(&[0xc1, 0, 0]);
+    section.extend_from_slice(body);
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+/// A PES packet with PTS, and DTS when it differs. Video packets leave
+/// `PES_packet_length` at zero, which TS allows for video streams.
+fn pes_packet(stream_id: u8, pts: u64, dts: u64, payload: &[u8], bounded: bool) -> Vec<u8> {
+    let with_dts = pts != dts;
+    let header_length = if with_dts { 10 } else { 5 };
+    let mut pes = Vec::with_capacity(payload.len() + 9 + header_length);
+    pes.extend_from_slice(&[0, 0, 1, stream_id]);
+    let packet_length = 3 + header_length + payload.len();
+    let packet_length = if bounded {
+        u16::try_from(packet_length).unwrap_or(0)
+    } else {
+        0
+    };
+    pes.extend_from_slice(&packet_length.to_be_bytes());
+    pes.push(0x80);
+    pes.push(if with_dts { 0xc0 } else { 0x80 });
+    pes.push(header_length as u8);
+    if with_dts {
+        write_timestamp(&mut pes, 0x3, pts);
+        write_timestamp(&mut pes, 0x1, dts);
+    } else {
+        write_timestamp(&mut pes, 0x2, pts);
+    }
+    pes.extend_from_slice(payload);
+    pes
+}
+
+/// A 33-bit PES timestamp with its four-bit prefix and marker bits.
+fn write_timestamp(out: &mut Vec<u8>, prefix: u8, timestamp: u64) {
+    let timestamp = timestamp & 0x1_ffff_ffff;
+    out.push((prefix << 4) | ((timestamp >> 29) as u8 & 0x0e) | 1);
+    out.extend_from_slice(&((((timestamp >> 14) as u16) & 0xfffe) | 1).to_be_bytes());
+    out.extend_from_slice(&((((timestamp << 1) as u16) & 0xfffe) | 1).to_be_bytes());
+}
+
+/// `program_clock_reference_base` from 90 kHz ticks, with a zero extension.
+fn write_pcr(out: &mut Vec<u8>, ticks: u64) {
+    let base = ticks & 0x1_ffff_ffff;
+    out.extend_from_slice(&((base >> 1) as u32).to_be_bytes());
+    out.push(((base as u8 & 1) << 7) | 0x7e);
+    out.push(0);
+}
+
+fn starts_with_nal_type(data: &[u8], nal_type: u8) -> bool {
+    nal_units(data)
+        .next()
+        .is_some_and(|nal| nal.first().is_some_and(|header| header & 0x1f == nal_type))
+}
+
+fn contains_nal_type(data: &[u8], nal_type: u8) -> bool {
+    nal_units(data).any(|nal| nal.first().is_some_and(|header| header & 0x1f == nal_type))
+}
+
+/// NAL units of an Annex B byte stream, without their start codes.
+fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut index = 0;
+    while index + 3 <= data.len() {
+        if data[index..index + 3] == [0, 0, 1] {
+            starts.push(index + 3);
+            index += 3;
+        } else {
+            index += 1;
+        }
+    }
+    let ends: Vec<usize> = starts
+        .iter()
+        .skip(1)
+        .map(|&start| {
+            let end = start - 3;
+            if end > 0 && data[end - 1] == 0 {
+                end - 1
+            } else {
+                end
+            }
+        })
+        .chain(std::iter::once(data.len()))
+        .collect();
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(move |(start, end)| &data[start..end])
+}
+
+pub const PSI_STREAM_HEVC: u8 = 0x24;
+pub const PSI_STREAM_AAC_LATM: u8 = 0x11;
+/// Metadata carried in PES packets, used for HLS timed ID3.
+pub const PSI_STREAM_METADATA: u8 = 0x15;
+/// ATSC A/52 AC-3, as used by most broadcast feeds.
+pub const PSI_STREAM_AC3: u8 = 0x81;
+const DESCRIPTOR_REGISTRATION: u8 = 0x05;
+const DESCRIPTOR_AC3: u8 = 0x6a;
+const DESCRIPTOR_METADATA_POINTER: u8 = 0x25;
+const DESCRIPTOR_METADATA: u8 = 0x26;
+/// `metadata_application_format` and `metadata_format` of timed ID3, each
+/// followed by its `ID3 ` identifier.
+const ID3_METADATA_FORMAT: [u8; 11] = [
+    0xff, 0xff, b'I', b'D', b'3', b' ', 0xff, b'I', b'D', b'3', b' ',
+];
+const TIMESTAMP_WRAP: u64 = 1 << 33;
+const AAC_FRAME_SAMPLES: u64 = 1_024;
+
+/// A codec the demuxer turns into access units.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsCodec {
+    H264,
+    Hevc,
+    AacAdts,
+    AacLatm,
+    Ac3,
+    Opus,
+    /// Timed ID3 tags, one per PES packet.
+    Id3,
+}
+
+impl TsCodec {
+    pub fn is_video(self) -> bool {
+        matches!(self, TsCodec::H264 | TsCodec::Hevc)
+    }
+
+    /// The `AccessUnit::stream_type` given to this codec's units.
+    pub fn stream_type(self) -> u8 {
+        match self {
+            TsCodec::H264 => PSI_STREAM_H264,
+            TsCodec::Hevc => PSI_STREAM_HEVC,
+            TsCodec::AacAdts => PSI_STREAM_AAC,
+            TsCodec::AacLatm => PSI_STREAM_AAC_LATM,
+            TsCodec::Ac3 => PSI_STREAM_AC3,
+            TsCodec::Opus => PSI_STREAM_AUDIO_OPUS,
+            TsCodec::Id3 => PSI_STREAM_METADATA,
+        }
+    }
+}
+
+/// An elementary stream announced by a PMT. `codec` is `None` for stream
+/// types the demuxer skips.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TsStream {
+    pub pid: u16,
+    pub stream_type: u8,
+    pub codec: Option<TsCodec>,
+}
+
+/// An access unit from one PID. Video units keep 90 kHz timestamps and audio
+/// units use milliseconds, as `box_fmp4_with_init_and_audio_config` expects.
+/// ID3 units are also in milliseconds, like `MetadataSample::start`.
+#[derive(Clone, Debug)]
+pub struct DemuxedUnit {
+    pub pid: u16,
+    pub codec: TsCodec,
+    pub unit: AccessUnit,
+}
+
+/// Reads a transport stream in arbitrarily sized chunks, such as SRT or UDP
+/// datagrams, and yields access units for every stream in the PMT.
+/// Timestamps are unwrapped past the 33-bit rollover per PID.
+#[derive(Clone, Debug, Default)]
+pub struct TsDemuxer {
+    pending: Vec<u8>,
+    pmt_pids: Vec<u16>,
+    sections: Vec<(u16, Vec<u8>)>,
+    streams: Vec<TsStream>,
+    pes: Vec<PesBuffer>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct PesBuffer {
+    pid: u16,
+    data: Vec<u8>,
+    random_access: bool,
+    continuity: Option<u8>,
+    last_pts: Option<u64>,
+    last_dts: Option<u64>,
+}
+
+impl TsDemuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Streams of the most recent PMT.
+    pub fn streams(&self) -> &[TsStream] {
+        &self.streams
+    }
+
+    /// Feed bytes; units whose PES packet completed are returned. Bytes
+    /// before a sync byte are skipped and a trailing partial packet is kept
+    /// for the next call.
+    pub fn push(&mut self, data: &[u8]) -> Vec<DemuxedUnit> {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(data);
+        let mut units = Vec::new();
+        let mut offset = 0;
+        while offset + TS_PACKET_SIZE <= pending.len() {
+            if pending[offset] != SYNC_BYTE {
+                offset += 1;
+                continue;
+            }
+            self.read_packet(&pending[offset..offset + TS_PACKET_SIZE], &mut units);
+            offset += TS_PACKET_SIZE;
+        }
+        pending.drain(..offset);
+        self.pending = pending;
+        units
+    }
+
+    /// Emit the PES packets still being assembled, such as at end of input.
+    pub fn flush(&mut self) -> Vec<DemuxedUnit> {
+        let mut units = Vec::new();
+        for index in 0..self.pes.len() {
+            self.finish_pes(index, &mut units);
+        }
+        units
+    }
+
+    fn read_packet(&mut self, packet: &[u8], units: &mut Vec<DemuxedUnit>) {
+        if packet[1] & 0x80 != 0 {
+            return;
+        }
+        let pid = u16::from_be_bytes([packet[1], packet[2]]) & 0x1fff;
+        let unit_start = packet[1] & 0x40 != 0;
+        let adaptation_control = (packet[3] >> 4) & 0x03;
+        let continuity = packet[3] & 0x0f;
+        let mut random_access = false;
+        let mut payload_start = 4;
+        if adaptation_control & 0x02 != 0 {
+            let adaptation_length = usize::from(packet[4]);
+            random_access = adaptation_length > 0 && packet[5] & 0x40 != 0;
+            payload_start = 5 + adaptation_length;
+        }
+        if adaptation_control & 0x01 == 0 || payload_start >= TS_PACKET_SIZE {
+            return;
+        }
+        let payload = &packet[payload_start..];
+
+        if pid == PAT_PID || self.pmt_pids.contains(&pid) {
+            self.read_psi(pid, unit_start, payload);
+            return;
+        }
+        if !self
+            .streams
+            .iter()
+            .any(|stream| stream.pid == pid && stream.codec.is_some())
+        {
+            return;
+        }
+        let index = match self.pes.iter().position(|buffer| buffer.pid == pid) {
+            Some(index) => index,
+            None => {
+                self.pes.push(PesBuffer {
+                    pid,
+                    ..PesBuffer::default()
+                });
+                self.pes.len() - 1
+            }
+        };
+        let previous = self.pes[index].continuity.replace(continuity);
+        if previous == Some(continuity) {
+            // A duplicate packet.
+            return;
+        }
+        if unit_start {
+            self.finish_pes(index, units);
+            let buffer = &mut self.pes[index];
+            buffer.data.extend_from_slice(payload);
+            buffer.random_access = random_access;
+        } else if previous.is_some_and(|previous| (previous + 1) & 0x0f != continuity) {
+            // Lost packets leave the PES incomplete.
+            self.pes[index].data.clear();
+            return;
+        } else if !self.pes[index].data.is_empty() {
+            self.pes[index].data.extend_from_slice(payload);
+        }
+        let buffer = &self.pes[index];
+        let packet_length = buffer.data.get(4..6).map_or(0, |length| {
+            usize::from(u16::from_be_bytes([length[0], length[1]]))
+        });
+        if packet_length > 0 && buffer.data.len() >= 6 + packet_length {
+            self.finish_pes(index, units);
+        }
+    }
+
+    fn read_psi(&mut self, pid: u16, unit_start: bool, payload: &[u8]) {
+        let partial = self
+            .sections
+            .iter()
+            .position(|(known, _)| *known == pid)
+            .map(|position| self.sections.remove(position).1);
+        let section = if unit_start {
+            let Some(section) = payload
+                .first()
+                .and_then(|&pointer| payload.get(1 + usize::from(pointer)..))
+            else {
+                return;
+            };
+            section.to_vec()
+        } else if let Some(mut section) = partial {
+            section.extend_from_slice(payload);
+            section
+        } else {
+            return;
+        };
+        let Some(length) = section
+            .get(1..3)
+            .map(|length| 3 + usize::from(u16::from_be_bytes([length[0], length[1]]) & 0x0fff))
+        else {
+            self.sections.push((pid, section));
+            return;
+        };
+        if section.len() < length {
+            self.sections.push((pid, section));
+            return;
+        }
+        let section = &section[..length];
+        if length < 12 || crc32_mpeg2(section) != 0 {
+            return;
+        }
+        let body = &section[8..length - 4];
+        match section[0] {
+            TABLE_ID_PAT if pid == PAT_PID => {
+                self.pmt_pids = body
+                    .chunks_exact(4)
+                    .filter(|program| program[..2] != [0, 0])
+                    .map(|program| u16::from_be_bytes([program[2], program[3]]) & 0x1fff)
+                    .collect();
+            }
+            TABLE_ID_PMT => {
+                if let Some(streams) = read_pmt_streams(body) {
+                    self.streams = streams;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish_pes(&mut self, index: usize, units: &mut Vec<DemuxedUnit>) {
+        let buffer = &mut self.pes[index];
+        let data = std::mem::take(&mut buffer.data);
+        let Some(codec) = self
+            .streams
+            .iter()
+            .find(|stream| stream.pid == buffer.pid)
+            .and_then(|stream| stream.codec)
+        else {
+            return;
+        };
+        let Some(pes) = read_pes(&data) else {
+            return;
+        };
+        let Some(raw_pts) = pes.pts else {
+            return;
+        };
+        let pts = unwrap_timestamp(buffer.last_pts, raw_pts);
+        let dts = unwrap_timestamp(buffer.last_dts, pes.dts.unwrap_or(raw_pts));
+        buffer.last_pts = Some(pts);
+        buffer.last_dts = Some(dts);
+        let pid = buffer.pid;
+        let payload = Bytes::copy_from_slice(pes.payload);
+        let unit = |key: bool, pts: u64, dts: u64, data: Bytes| DemuxedUnit {
+            pid,
+            codec,
+            unit: AccessUnit {
+                key,
+                pts,
+                dts,
+                data,
+                stream_type: codec.stream_type(),
+                id: u64::from(pid),
+            },
+        };
+
+        match codec {
+            TsCodec::H264 | TsCodec::Hevc => {
+                let key = buffer.random_access || is_random_access_picture(codec, &payload);
+                units.push(unit(key, pts, dts, payload));
+            }
+            TsCodec::AacAdts => {
+                let Some(frames) = split_adts_frames(payload) else {
+                    return;
+                };
+                let mut ticks = pts;
+                for frame in frames {
+                    let Some(header) = AdtsHeader::read_from(&frame) else {
+                        return;
+                    };
+                    let ms = ticks_to_ms(ticks);
+                    units.push(unit(true, ms, ms, frame));
+                    ticks +=
+                        AAC_FRAME_SAMPLES * 90_000 / u64::from(header.sampling_frequency.as_u32());
+                }
+            }
+            TsCodec::Opus => {
+                let mut ticks = pts;
+                for packet in split_opus_packets(&payload).unwrap_or_default() {
+                    let ms = ticks_to_ms(ticks);
+                    let duration = opus_packet_info(&packet).map_or(0, |info| {
+                        u64::from(info.duration_samples) * 90_000
+                            / u64::from(OPUS_OUTPUT_SAMPLE_RATE)
+                    });
+                    units.push(unit(true, ms, ms, packet));
+                    ticks += duration;
+                }
+            }
+            TsCodec::AacLatm | TsCodec::Ac3 | TsCodec::Id3 => {
+                let ms = ticks_to_ms(pts);
+                units.push(unit(true, ms, ms, payload));
+            }
+        }
+    }
+}
+
+fn read_pmt_streams(body: &[u8]) -> Option<Vec<TsStream>> {
+    let program_info_length =
+        usize::from(u16::from_be_bytes([*body.get(2)?, *body.get(3)?]) & 0x0fff);
+    let mut entries = body.get(4 + program_info_length..)?;
+    let mut streams = Vec::new();
+    while entries.len() >= 5 {
+        let stream_type = entries[0];
+        let pid = u16::from_be_bytes([entries[1], entries[2]]) & 0x1fff;
+        let info_length = usize::from(u16::from_be_bytes([entries[3], entries[4]]) & 0x0fff);
+        let descriptors = entries.get(5..5 + info_length)?;
+        streams.push(TsStream {
+            pid,
+            stream_type,
+            codec: stream_codec(stream_type, descriptors),
+        });
+        entries = &entries[5 + info_length..];
+    }
+    Some(streams)
+}
+
+/// Map a PMT stream type to a codec. Private-data streams are identified by
+/// their registration or AC-3 descriptor.
+fn stream_codec(stream_type: u8, descriptors: &[u8]) -> Option<TsCodec> {
+    match stream_type {
+        PSI_STREAM_H264 => Some(TsCodec::H264),
+        PSI_STREAM_HEVC => Some(TsCodec::Hevc),
+        PSI_STREAM_AAC => Some(TsCodec::AacAdts),
+        PSI_STREAM_AAC_LATM => Some(TsCodec::AacLatm),
+        PSI_STREAM_AC3 => Some(TsCodec::Ac3),
+        PSI_STREAM_METADATA => has_id3_metadata_descriptor(descriptors).then_some(TsCodec::Id3),
+        PSI_STREAM_PRIVATE_DATA => {
+            let mut descriptors = descriptors;
+            while descriptors.len() >= 2 {
+                let tag = descriptors[0];
+                let body = descriptors.get(2..2 + usize::from(descriptors[1]))?;
+                match (tag, body) {
+                    (DESCRIPTOR_REGISTRATION, [b'O', b'p', b'u', b's', ..]) => {
+                        return Some(TsCodec::Opus)
+                    }
+                    (DESCRIPTOR_REGISTRATION, [b'A', b'C', b'-', b'3', ..])
+                    | (DESCRIPTOR_AC3, _) => return Some(TsCodec::Ac3),
+                    _ => {}
+                }
+                descriptors = &descriptors[2 + body.len()..];
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+fn has_id3_metadata_descriptor(mut descriptors: &[u8]) -> bool {
+    while descriptors.len() >= 2 {
+        let Some(body) = descriptors.get(2..2 + usize::from(descriptors[1])) else {
+            return false;
+        };
+        if descriptors[0] == DESCRIPTOR_METADATA && body.starts_with(&ID3_METADATA_FORMAT) {
+            return true;
+        }
+        descriptors = &descriptors[2 + body.len()..];
+    }
+    false
+}
+
+struct Pes<'a> {
+    pts: Option<u64>,
+    dts: Option<u64>,
+    payload: &'a [u8],
+}
+
+fn read_pes(data: &[u8]) -> Option<Pes<'_>> {
+    if data.get(..3)? != [0, 0, 1] {
+        return None;
+    }
+    let packet_length = usize::from(u16::from_be_bytes([*data.get(4)?, *data.get(5)?]));
+    let flags = *data.get(7)?;
+    let header_length = usize::from(*data.get(8)?);
+    let end = if packet_length == 0 {
+        data.len()
+    } else {
+        (6 + packet_length).min(data.len())
+    };
+    let pts = if flags & 0x80 != 0 {
+        Some(read_timestamp(data.get(9..14)?))
+    } else {
+        None
+    };
+    let dts = if flags & 0xc0 == 0xc0 {
+        Some(read_timestamp(data.get(14..19)?))
+    } else {
+        None
+    };
+    Some(Pes {
+        pts,
+        dts,
+        payload: data.get(9 + header_length..end)?,
+    })
+}
+
+fn read_timestamp(bytes: &[u8]) -> u64 {
+    (u64::from(bytes[0] >> 1) & 0x07) << 30
+        | u64::from(u16::from_be_bytes([bytes[1], bytes[2]]) >> 1) << 15
+        | u64::from(u16::from_be_bytes([bytes[3], bytes[4]]) >> 1)
+}
+
+/// Place a 33-bit timestamp on the unwrapped timeline closest to the previous
+/// one, so rollover in either direction keeps time continuous.
+fn unwrap_timestamp(previous: Option<u64>, raw: u64) -> u64 {
+    let Some(previous) = previous else {
+        return raw;
+    };
+    let candidate = (previous & !(TIMESTAMP_WRAP - 1)) | raw;
+    [
+        candidate.checked_sub(TIMESTAMP_WRAP),
+        Some(candidate),
+        candidate.checked_add(TIMESTAMP_WRAP),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(|time| time.abs_diff(previous))
+    .unwrap_or(candidate)
+}
+
+fn is_random_access_picture(codec: TsCodec, data: &[u8]) -> bool {
+    nal_units(data).any(|nal| match (codec, nal.first()) {
+        (TsCodec::H264, Some(header)) => header & 0x1f == 5,
+        (TsCodec::Hevc, Some(header)) => (16..=21).contains(&((header >> 1) & 0x3f)),
+        _ => false,
+    })
+}
+
+/// Strip the `opus_control_header` (ETSI TS 102 366 annex) from each packet
+/// of an Opus PES payload.
+fn split_opus_packets(mut data: &[u8]) -> Option<Vec<Bytes>> {
+    let mut packets = Vec::new();
+    while !data.is_empty() {
+        if data.len() < 2 || data[0] != 0x7f || data[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+        let flags = data[1];
+        let mut position = 2;
+        let mut size = 0;
+        loop {
+            let byte = *data.get(position)?;
+            position += 1;
+            size += usize::from(byte);
+            if byte != 0xff {
+                break;
+            }
+        }
+        if flags & 0x10 != 0 {
+            position += 2;
+        }
+        if flags & 0x08 != 0 {
+            position += 2;
+        }
+        if flags & 0x04 != 0 {
+            position += 1 + usize::from(*data.get(position)?);
+        }
+        packets.push(Bytes::copy_from_slice(data.get(position..position + size)?));
+        data = &data[position + size..];
+    }
+    Some(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fmp4::tests::config;
+    use crate::id3::{Id3Frame, Id3Tag};
+
+    fn pid(packet: &[u8]) -> u16 {
+        u16::from_be_bytes([packet[1], packet[2]]) & 0x1fff
+    }
+
+    fn units() -> (Vec<AccessUnit>, Vec<AccessUnit>) {
+        let mut aac = access_unit::aac::create_adts_header(0x66, 2, 48_000, 1, false);
+        aac.push(0x21);
+        let video = vec![
+            AccessUnit {
+                key: true,
+                pts: 9_000,
+                dts: 6_000,
+                data: Bytes::from([[0, 0, 0, 1, 0x65].as_slice(), &[0xaa; 400]].concat()),
+                stream_type: PSI_STREAM_H264,
+                id: 0,
+            },
+            AccessUnit {
+                key: false,
+                pts: 9_000 + 3_003,
+                dts: 9_000,
+                data: Bytes::from_static(&[0, 0, 0, 1, 0x41, 0xbb]),
+                stream_type: PSI_STREAM_H264,
+                id: 0,
+            },
+        ];
+        let audio = vec![AccessUnit {
+            key: true,
+            pts: 80,
+            dts: 80,
+            data: Bytes::from(aac),
+            stream_type: PSI_STREAM_AAC,
+            id: 1,
+        }];
+        (video, audio)
+    }
+
+    #[test]
+    fn segment_starts_with_pat_and_pmt_and_keeps_continuity_across_segments() {
+        let (video, audio) = units();
+        let mut muxer = TsMuxer::new(TsConfig::default());
+        let first = muxer.mux_segment(&video, &audio);
+        let second = muxer.mux_segment(&video, &audio);
+
+        assert_eq!(first.len() % TS_PACKET_SIZE, 0);
+        let packets: Vec<&[u8]> = first.chunks(TS_PACKET_SIZE).collect();
+        assert!(packets.iter().all(|packet| packet[0] == SYNC_BYTE));
+        assert_eq!(pid(packets[0]), PAT_PID);
+        assert_eq!(pid(packets[1]), 0x1000);
+        let pat = &packets[0][5..5 + 3 + 9 + 4];
+        assert_eq!(crc32_mpeg2(pat), 0);
+        let pmt_length = usize::from(u16::from_be_bytes([packets[1][6], packets[1][7]]) & 0x0fff);
+        let pmt = &packets[1][5..5 + 3 + pmt_length];
+        assert_eq!(crc32_mpeg2(pmt), 0);
+        assert_eq!(&pmt[12..17], &[PSI_STREAM_H264, 0xe1, 0x00, 0xf0, 0x00]);
+        assert_eq!(&pmt[17..22], &[PSI_STREAM_AAC, 0xe1, 0x01, 0xf0, 0x00]);
+
+        // 188-byte packets carry 184 payload bytes, so the 400-byte key frame
+        // spans three packets and the video PID ends the first segment at 3.
+        let video_counters = |segment: &Bytes| -> Vec<u8> {
+            segment
+                .chunks(TS_PACKET_SIZE)
+                .filter(|packet| pid(packet) == 0x100)
+                .map(|packet| packet[3] & 0x0f)
+                .collect()
+        };
+        assert_eq!(video_counters(&first), vec![0, 1, 2, 3]);
+        assert_eq!(video_counters(&second), vec![4, 5, 6, 7]);
+        assert_eq!(packets[1][3] & 0x0f, 0);
+        assert_eq!(
+            second.chunks(TS_PACKET_SIZE).nth(1).map(|p| p[3] & 0x0f),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn non_adts_audio_is_left_out_of_the_pmt_and_segment() {
+        let (video, _) = units();
+        let opus = vec![AccessUnit {
+            key: true,
+            pts: 80,
+            dts: 80,
+            data: Bytes::from_static(&[0xfc, 0xff, 0xfe]),
+            stream_type: PSI_STREAM_AUDIO_OPUS,
+            id: 1,
+        }];
+        let segment = TsMuxer::new(TsConfig::default()).mux_segment(&video, &opus);
+
+        let packets: Vec<&[u8]> = segment.chunks(TS_PACKET_SIZE).collect();
+        let pmt_length = usize::from(u16::from_be_bytes([packets[1][6], packets[1][7]]) & 0x0fff);
+        let pmt = &packets[1][5..5 + 3 + pmt_length];
+        assert_eq!(crc32_mpeg2(pmt), 0);
+        assert_eq!(pmt_length, 9 + 5 + 4);
+        assert_eq!(&pmt[12..17], &[PSI_STREAM_H264, 0xe1, 0x00, 0xf0, 0x00]);
+        assert!(packets.iter().all(|packet| pid(packet) != 0x101));
+    }
+
+    #[test]
+    fn key_frames_carry_pcr_random_access_parameter_sets_and_timestamps() {
+        let (video, audio) = units();
+        let mut muxer = TsMuxer::new(TsConfig {
+            avcc: config().avcc,
+            pcr_delay: 4_500,
+            ..TsConfig::default()
+        });
+        let segment = muxer.mux_segment(&video, &audio);
+        let packets: Vec<&[u8]> = segment.chunks(TS_PACKET_SIZE).collect();
+
+        let key = packets[2];
+        assert_eq!(pid(key), 0x100);
+        assert_eq!(key[1] & 0x40, 0x40);
+        assert_eq!(key[3] & 0x30, 0x30);
+        assert_eq!(key[5], 0x50);
+        let pcr_base = u64::from(u32::from_be_bytes([key[6], key[7], key[8], key[9]])) << 1
+            | u64::from(key[10] >> 7);
+        // The PCR runs the configured delay behind the frame's decode time.
+        assert_eq!(pcr_base, 6_000 - 4_500);
+        let pes = &key[5 + usize::from(key[4])..];
+        assert_eq!(&pes[..4], &[0, 0, 1, STREAM_ID_VIDEO]);
+        assert_eq!(pes[7], 0xc0);
+        assert_eq!(read_timestamp(&pes[9..14]), 9_000);
+        assert_eq!(read_timestamp(&pes[14..19]), 6_000);
+        assert_eq!(
+            &pes[19..37],
+            &[
+                0,
+                0,
+                0,
+                1,
+                NAL_TYPE_AUD,
+                0xf0,
+                0,
+                0,
+                0,
+                1,
+                0x67,
+                0x42,
+                0x00,
+                0x1e,
+                0,
+                0,
+                0,
+                1
+            ]
+        );
+
+        // The audio frame at 80 ms sorts between the frames at 6,000 and
+        // 9,000 ticks and is stamped in 90 kHz.
+        let audio_packet = packets
+            .iter()
+            .find(|packet| pid(packet) == 0x101)
+            .expect("audio packet");
+        let pes = &audio_packet[5 + usize::from(audio_packet[4])..];
+        assert_eq!(&pes[..4], &[0, 0, 1, STREAM_ID_AUDIO]);
+        assert_eq!(read_timestamp(&pes[9..14]), 7_200);
+        assert_eq!(audio_packet[5] & 0x40, 0x40);
+    }
+
+    #[test]
+    fn audio_only_segment_carries_pcr_on_the_audio_pid() {
+        let (_, audio) = units();
+        let segment = TsMuxer::new(TsConfig::default()).mux_segment(&[], &audio);
+        let packets: Vec<&[u8]> = segment.chunks(TS_PACKET_SIZE).collect();
+
+        let pmt = &packets[1][5..];
+        assert_eq!(u16::from_be_bytes([pmt[8], pmt[9]]) & 0x1fff, 0x101);
+        let audio_packet = packets
+            .iter()
+            .find(|packet| pid(packet) == 0x101)
+            .expect("audio packet");
+        assert_eq!(audio_packet[5] & 0x10, 0x10);
+        let pcr_base = u64::from(u32::from_be_bytes([
+            audio_packet[6],
+            audio_packet[7],
+            audio_packet[8],
+            audio_packet[9],
+        ])) << 1
+            | u64::from(audio_packet[10] >> 7);
+        assert_eq!(pcr_base, 0);
+    }
+
+    #[test]
+    fn demuxer_round_trips_muxer_output_fed_in_odd_sized_chunks() {
+        let (video, audio) = units();
+        let mut muxer = TsMuxer::new(TsConfig::default());
+        let segment = muxer.mux_segment(&video, &audio);
+        let mut demuxer = TsDemuxer::new();
+        let mut demuxed = Vec::new();
+        for chunk in segment.chunks(100) {
+            demuxed.extend(demuxer.push(chunk));
+        }
+        demuxed.extend(demuxer.flush());
+
+        assert_eq!(
+            demuxer.streams(),
+            &[
+                TsStream {
+                    pid: 0x100,
+                    stream_type: PSI_STREAM_H264,
+                    codec: Some(TsCodec::H264),
+                },
+                TsStream {
+                    pid: 0x101,
+                    stream_type: PSI_STREAM_AAC,
+                    codec: Some(TsCodec::AacAdts),
+                },
+            ]
+        );
+        let video_units: Vec<&AccessUnit> = demuxed
+            .iter()
+            .filter(|unit| unit.codec == TsCodec::H264)
+            .map(|unit| &unit.unit)
+            .collect();
+        assert_eq!(video_units.len(), 2);
+        for (demuxed, original) in video_units.iter().zip(&video) {
+            assert_eq!(
+                (demuxed.key, demuxed.pts, demuxed.dts),
+                (original.key, original.pts, original.dts)
+            );
+            assert_eq!(&demuxed.data[..6], &ACCESS_UNIT_DELIMITER);
+            assert_eq!(&demuxed.data[6..], &original.data[..]);
+        }
+        let audio_unit = demuxed
+            .iter()
+            .find(|unit| unit.codec == TsCodec::AacAdts)
+            .expect("audio unit");
+        assert_eq!((audio_unit.unit.pts, audio_unit.unit.dts), (80, 80));
+        assert_eq!(audio_unit.unit.data, audio[0].data);
+    }
+
+    #[test]
+    fn timed_id3_is_announced_in_the_pmt_and_demuxes_back() {
+        let (video, audio) = units();
+        let tag = Id3Tag {
+            frames: vec![Id3Frame::Priv {
+                owner: "com.example.analytics".to_owned(),
+                data: vec![1, 2, 3],
+            }],
+        };
+        let metadata = [MetadataSample {
+            start: 50,
+            duration: 1_000,
+            data: Bytes::from(tag.to_bytes().expect("tag")),
+        }];
+        let mut muxer = TsMuxer::new(TsConfig {
+            metadata_pid: Some(0x102),
+            ..TsConfig::default()
+        });
+        let segment = muxer.mux_segment_with_metadata(&video, &audio, &metadata);
+
+        let packets: Vec<&[u8]> = segment.chunks(TS_PACKET_SIZE).collect();
+        let pmt_length = usize::from(u16::from_be_bytes([packets[1][6], packets[1][7]]) & 0x0fff);
+        let pmt = &packets[1][5..5 + 3 + pmt_length];
+        assert_eq!(crc32_mpeg2(pmt), 0);
+        assert_eq!(&pmt[10..12], &[0xf0, 17]);
+        assert_eq!(&pmt[12..14], &[DESCRIPTOR_METADATA_POINTER, 15]);
+        assert_eq!(&pmt[14..25], &ID3_METADATA_FORMAT);
+        assert_eq!(&pmt[39..44], &[PSI_STREAM_METADATA, 0xe1, 0x02, 0xf0, 15]);
+        assert_eq!(&pmt[44..46], &[DESCRIPTOR_METADATA, 13]);
+        let id3_packet = packets
+            .iter()
+            .find(|packet| pid(packet) == 0x102)
+            .expect("metadata packet");
+        let pes = &id3_packet[5 + usize::from(id3_packet[4])..];
+        assert_eq!(&pes[..4], &[0, 0, 1, STREAM_ID_PRIVATE_1]);
+        assert_eq!(read_timestamp(&pes[9..14]), 4_500);
+
+        let mut demuxer = TsDemuxer::new();
+        let mut demuxed = demuxer.push(&segment);
+        demuxed.extend(demuxer.flush());
+        assert!(demuxer.streams().contains(&TsStream {
+            pid: 0x102,
+            stream_type: PSI_STREAM_METADATA,
+            codec: Some(TsCodec::Id3),
+        }));
+        let id3: Vec<&DemuxedUnit> = demuxed
+            .iter()
+            .filter(|unit| unit.codec == TsCodec::Id3)
+            .collect();
+        assert_eq!(id3.len(), 1);
+        assert_eq!(id3[0].unit.pts, 50);
+        assert_eq!(Id3Tag::parse(&id3[0].unit.data), Some(tag));
+    }
+
+    #[test]
+    fn demuxer_unwraps_timestamps_across_33_bit_rollover() {
+        let frame = |dts: u64| AccessUnit {
+            key: true,
+            pts: dts,
+            dts,
+            data: Bytes::from_static(&[0, 0, 0, 1, 0x65, 0x88]),
+            stream_type: PSI_STREAM_H264,
+            id: 0,
+        };
+        let video = [frame(TIMESTAMP_WRAP - 3_000), frame(TIMESTAMP_WRAP + 3_000)];
+        let mut muxer = TsMuxer::new(TsConfig {
+            audio_pid: None,
+            ..TsConfig::default()
+        });
+        let mut demuxer = TsDemuxer::new();
+        let mut demuxed = demuxer.push(&muxer.mux_segment(&video, &[]));
+        demuxed.extend(demuxer.flush());
+
+        let times: Vec<(u64, u64)> = demuxed
+            .iter()
+            .map(|unit| (unit.unit.pts, unit.unit.dts))
+            .collect();
+        assert_eq!(
+            times,
+            vec![
+                (TIMESTAMP_WRAP - 3_000, TIMESTAMP_WRAP - 3_000),
+                (TIMESTAMP_WRAP + 3_000, TIMESTAMP_WRAP + 3_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn demuxer_splits_adts_and_opus_packets_and_reads_registration_descriptors() {
+        let mut muxer = TsMuxer::new(TsConfig::default());
+        let mut stream = Vec::new();
+        let pat = muxer.pat_section();
+        muxer.write_section(&mut stream, PAT_PID, &pat);
+        let mut pmt = vec![0xe1, 0x01, 0xf0, 0x00];
+        pmt.extend_from_slice(&[PSI_STREAM_AAC, 0xe1, 0x01, 0xf0, 0x00]);
+        pmt.extend_from_slice(&[PSI_STREAM_PRIVATE_DATA, 0xe1, 0x02, 0xf0, 0x06]);
+        pmt.extend_from_slice(&[DESCRIPTOR_REGISTRATION, 4, b'O', b'p', b'u', b's']);
+        muxer.write_section(&mut stream, 0x1000, &psi_section(TABLE_ID_PMT, 1, &pmt));
+
+        let mut aac = access_unit::aac::create_adts_header(0x66, 2, 48_000, 1, false);
+        aac.push(0x21);
+        let adts = [aac.as_slice(), aac.as_slice()].concat();
+        muxer.write_pes(
+            &mut stream,
+            0x101,
+            &pes_packet(STREAM_ID_AUDIO, 9_000, 9_000, &adts, true),
+            None,
+            true,
+        );
+        // Two 20 ms CELT packets, each behind an opus_control_header.
+        let opus = [0x7f, 0xe0, 2, 0xf8, 0xff, 0x7f, 0xe0, 2, 0xf8, 0xfe];
+        muxer.write_pes(
+            &mut stream,
+            0x102,
+            &pes_packet(0xbd, 90_000, 90_000, &opus, true),
+            None,
+            true,
+        );
+
+        let demuxed = TsDemuxer::new().push(&stream);
+        let summary: Vec<(TsCodec, u64, Vec<u8>)> = demuxed
+            .iter()
+            .map(|unit| (unit.codec, unit.unit.pts, unit.unit.data.to_vec()))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                (TsCodec::AacAdts, 100, aac.clone()),
+                (TsCodec::AacAdts, 121, aac),
+                (TsCodec::Opus, 1_000, vec![0xf8, 0xff]),
+                (TsCodec::Opus, 1_020, vec![0xf8, 0xfe]),
+            ]
+        );
+        assert!(demuxed
+            .iter()
+            .all(|unit| unit.unit.stream_type == unit.codec.stream_type()));
+    }
+}
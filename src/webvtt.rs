@@ -0,0 +1,146 @@
+use crate::mp4::write_box;
+
+/// One WebVTT cue on a track's millisecond timeline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebVttCue {
+    pub start: u64,
+    pub end: u64,
+    /// Cue identifier, written as `iden`.
+    pub id: Option<String>,
+    /// Cue settings such as `line:0 align:start`, written as `sttg`.
+    pub settings: Option<String>,
+    /// Cue text, written as `payl`.
+    pub payload: String,
+}
+
+/// A `wvtt` sample and its duration in milliseconds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct WebVttSample {
+    pub duration: u32,
+    pub data: Vec<u8>,
+}
+
+/// Split `start..end` into contiguous `wvtt` samples.
+///
+/// ISO/IEC 14496-30 samples may not overlap, so the window is cut at every cue
+/// boundary. Each resulting interval holds a `vttc` for every cue active across
+/// it, or a single `vtte` when no cue is shown. Cues are clipped to the window.
+pub(crate) fn webvtt_samples(
+    cues: &[WebVttCue],
+    start: u64,
+    end: u64,
+) -> Option<Vec<WebVttSample>> {
+    if end <= start {
+        return None;
+    }
+
+    let mut boundaries = vec![start, end];
+    for cue in cues {
+        for time in [cue.start, cue.end] {
+            if time > start && time < end {
+                boundaries.push(time);
+            }
+        }
+    }
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut samples = Vec::with_capacity(boundaries.len() - 1);
+    for interval in boundaries.windows(2) {
+        let (from, to) = (interval[0], interval[1]);
+        let mut data = Vec::new();
+        for cue in cues
+            .iter()
+            .filter(|cue| cue.start <= from && cue.end >= to && cue.start < cue.end)
+        {
+            write_vttc(&mut data, cue)?;
+        }
+        if data.is_empty() {
+            write_box(&mut data, *b"vtte", |_| Some(()))?;
+        }
+        samples.push(WebVttSample {
+            duration: u32::try_from(to - from).ok()?,
+            data,
+        });
+    }
+    Some(samples)
+}
+
+fn write_vttc(out: &mut Vec<u8>, cue: &WebVttCue) -> Option<()> {
+    write_box(out, *b"vttc", |out| {
+        if let Some(id) = &cue.id {
+            write_string_box(out, *b"iden", id)?;
+        }
+        if let Some(settings) = &cue.settings {
+            write_string_box(out, *b"sttg", settings)?;
+        }
+        write_string_box(out, *b"payl", &cue.payload)
+    })
+}
+
+/// WebVTT boxes carry unterminated UTF-8; the box size bounds the string.
+fn write_string_box(out: &mut Vec<u8>, name: [u8; 4], value: &str) -> Option<()> {
+    write_box(out, name, |out| {
+        out.extend_from_slice(value.as_bytes());
+        Some(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue(start: u64, end: u64, payload: &str) -> WebVttCue {
+        WebVttCue {
+            start,
+            end,
+            id: None,
+            settings: None,
+            payload: payload.to_owned(),
+        }
+    }
+
+    #[test]
+    fn overlapping_cues_split_into_non_overlapping_samples() {
+        let samples = webvtt_samples(
+            &[cue(1_000, 3_000, "first"), cue(2_000, 4_000, "second")],
+            0,
+            5_000,
+        )
+        .expect("samples");
+        let durations: Vec<u32> = samples.iter().map(|sample| sample.duration).collect();
+
+        assert_eq!(durations, vec![1_000, 1_000, 1_000, 1_000, 1_000]);
+        assert_eq!(samples[0].data, b"\0\0\0\x08vtte");
+        assert_eq!(
+            samples[1].data,
+            b"\0\0\0\x15vttc\0\0\0\x0dpaylfirst".to_vec()
+        );
+        assert_eq!(
+            samples[2].data.windows(4).filter(|w| w == b"vttc").count(),
+            2
+        );
+        assert_eq!(samples[4].data, b"\0\0\0\x08vtte");
+    }
+
+    #[test]
+    fn cue_identifier_and_settings_precede_payload() {
+        let samples = webvtt_samples(
+            &[WebVttCue {
+                id: Some("1".to_owned()),
+                settings: Some("align:start".to_owned()),
+                ..cue(0, 2_000, "hi")
+            }],
+            500,
+            1_500,
+        )
+        .expect("samples");
+
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].duration, 1_000);
+        assert_eq!(
+            samples[0].data,
+            b"\0\0\0\x2evttc\0\0\0\x09iden1\0\0\0\x13sttgalign:start\0\0\0\x0apaylhi".to_vec()
+        );
+    }
+}